@@ -21,6 +21,9 @@ fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=views/");
 
+    #[cfg(feature = "grpc")]
+    build_admin_service();
+
     let walker = WalkDir::new("./views").into_iter();
 
     for entry in walker {
@@ -76,3 +79,29 @@ fn main() {
         }
     }
 }
+
+/// Generates the server side of the gRPC admin service. The messages are defined in `src/grpc.rs`
+/// rather than a `.proto` file, so building it doesn't need `protoc`.
+#[cfg(feature = "grpc")]
+fn build_admin_service() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route_name: &str, message: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("crate::grpc::{}Request", message))
+            .output_type(format!("crate::grpc::{}Response", message))
+            .codec_path("tonic::codec::ProstCodec")
+            .build()
+    };
+
+    let service = Service::builder()
+        .name("Admin")
+        .package("couchapi.admin.v1")
+        .method(method("status", "Status", "Status"))
+        .method(method("reload_view", "ReloadView", "ReloadView"))
+        .build();
+
+    Builder::new().build_client(false).compile(&[service]);
+}
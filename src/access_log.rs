@@ -0,0 +1,267 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! One line per request in Common Log Format or as JSON, written separately from the tracing
+//! output so it can go straight into a log pipeline.
+
+use crate::common::{db_from_path, REQUEST_ID};
+use crate::config::{AccessLogField, AccessLogFormat, AccessLogSettings};
+use axum::body::{Body, HttpBody};
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Handlers that return documents add this to the response so the access log can record how many
+/// were returned.
+#[derive(Debug, Clone, Copy)]
+pub struct DocsReturned(pub usize);
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    fields: Vec<AccessLogField>,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+#[derive(Debug)]
+struct AccessLogEntry {
+    timestamp: DateTime<Utc>,
+    remote_addr: Option<String>,
+    method: String,
+    path: String,
+    version: String,
+    status: u16,
+    bytes: Option<u64>,
+    duration: Duration,
+    db: Option<String>,
+    docs: Option<usize>,
+    read_through: bool,
+    request_id: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl AccessLog {
+    /// Opens the access log, appending to `path` when one is set and writing to stdout otherwise.
+    pub fn new(settings: &AccessLogSettings) -> std::io::Result<Self> {
+        let writer: Box<dyn Write + Send> = match &settings.path {
+            Some(path) => Box::new(LineWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => Box::new(std::io::stdout()),
+        };
+
+        Ok(AccessLog {
+            format: settings.format.clone(),
+            fields: settings.fields.clone(),
+            writer: Mutex::new(writer),
+        })
+    }
+
+    fn format(&self, entry: &AccessLogEntry) -> String {
+        match self.format {
+            AccessLogFormat::Common => entry.common(&self.fields),
+            AccessLogFormat::Json => entry.json(&self.fields).to_string(),
+        }
+    }
+
+    fn write(&self, entry: &AccessLogEntry) {
+        let line = self.format(entry);
+        let mut writer = self.writer.lock().unwrap();
+
+        if let Err(e) = writeln!(writer, "{}", line) {
+            warn!(error = e.to_string(), "unable to write access log");
+        }
+    }
+}
+
+impl AccessLogEntry {
+    fn field(&self, field: AccessLogField) -> Value {
+        match field {
+            AccessLogField::Timestamp => {
+                json!(self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true))
+            }
+            AccessLogField::RemoteAddr => json!(self.remote_addr),
+            AccessLogField::Method => json!(self.method),
+            AccessLogField::Path => json!(self.path),
+            AccessLogField::Status => json!(self.status),
+            AccessLogField::Bytes => json!(self.bytes),
+            AccessLogField::DurationMs => json!(self.duration.as_secs_f64() * 1000.0),
+            AccessLogField::Db => json!(self.db),
+            AccessLogField::Docs => json!(self.docs),
+            AccessLogField::ReadThrough => json!(self.read_through),
+            AccessLogField::RequestId => json!(self.request_id),
+            AccessLogField::UserAgent => json!(self.user_agent),
+        }
+    }
+
+    fn json(&self, fields: &[AccessLogField]) -> Value {
+        let map = fields
+            .iter()
+            .map(|f| (f.name().to_string(), self.field(*f)))
+            .collect::<Map<_, _>>();
+
+        Value::Object(map)
+    }
+
+    /// Common Log Format, followed by `name=value` pairs for any configured fields it doesn't
+    /// already cover.
+    fn common(&self, fields: &[AccessLogField]) -> String {
+        let mut line = format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            self.remote_addr.as_deref().unwrap_or("-"),
+            self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.path,
+            self.version,
+            self.status,
+            self.bytes.map_or("-".to_string(), |b| b.to_string()),
+        );
+
+        for field in fields.iter().filter(|f| !f.in_common_log_format()) {
+            let value = match self.field(*field) {
+                Value::Null => "-".to_string(),
+                Value::String(s) => s,
+                v => v.to_string(),
+            };
+            line.push_str(&format!(" {}={}", field.name(), value));
+        }
+
+        line
+    }
+}
+
+/// Writes an access log line for every request once the response is ready.
+pub async fn log_access(
+    State(access_log): State<Arc<AccessLog>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let timestamp = Utc::now();
+
+    let remote_addr = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let db = db_from_path(req.uri().path());
+    let method = req.method().to_string();
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or_else(|| req.uri().path().to_string(), |pq| pq.to_string());
+    let version = format!("{:?}", req.version());
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.to_string());
+
+    let res = next.run(req).await;
+
+    let entry = AccessLogEntry {
+        timestamp,
+        remote_addr,
+        db,
+        method,
+        path,
+        version,
+        status: res.status().as_u16(),
+        bytes: res.body().size_hint().exact(),
+        duration: start.elapsed(),
+        docs: res.extensions().get::<DocsReturned>().map(|d| d.0),
+        read_through: res.headers().contains_key("X-Fake-CouchDb-Read-Through"),
+        request_id: REQUEST_ID.try_with(|id| id.clone()).ok(),
+        user_agent,
+    };
+    access_log.write(&entry);
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap(),
+            remote_addr: Some("10.0.0.1".to_string()),
+            method: "GET".to_string(),
+            path: "/orders/_all_docs?limit=2".to_string(),
+            version: "HTTP/1.1".to_string(),
+            status: 200,
+            bytes: Some(321),
+            duration: Duration::from_millis(12),
+            db: Some("orders".to_string()),
+            docs: Some(2),
+            read_through: false,
+            request_id: Some("abc-123".to_string()),
+            user_agent: None,
+        }
+    }
+
+    #[test]
+    fn test_common_log_format() {
+        let line = entry().common(&[
+            AccessLogField::Status,
+            AccessLogField::Db,
+            AccessLogField::Docs,
+            AccessLogField::ReadThrough,
+            AccessLogField::UserAgent,
+        ]);
+
+        assert_eq!(
+            line,
+            "10.0.0.1 - - [02/Jan/2024:03:04:05 +0000] \"GET /orders/_all_docs?limit=2 HTTP/1.1\" \
+             200 321 db=orders docs=2 read_through=false user_agent=-"
+        );
+    }
+
+    #[test]
+    fn test_json_format() {
+        let line = entry().json(&[
+            AccessLogField::Timestamp,
+            AccessLogField::Method,
+            AccessLogField::Status,
+            AccessLogField::Db,
+            AccessLogField::Docs,
+            AccessLogField::ReadThrough,
+            AccessLogField::RequestId,
+            AccessLogField::DurationMs,
+        ]);
+
+        assert_eq!(
+            line,
+            json!({
+                "timestamp": "2024-01-02T03:04:05.000Z",
+                "method": "GET",
+                "status": 200,
+                "db": "orders",
+                "docs": 2,
+                "read_through": false,
+                "request_id": "abc-123",
+                "duration_ms": 12.0,
+            })
+        );
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::state::AppState;
+use axum::extract::State;
+use axum::Json;
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Long running work, such as imports, listed by `_active_tasks` while it runs.
+#[derive(Default, Clone)]
+pub struct ActiveTasks {
+    tasks: Arc<Mutex<BTreeMap<u64, Map<String, Value>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// A running task. It's listed until it's dropped.
+pub struct Task {
+    tasks: ActiveTasks,
+    id: u64,
+}
+
+impl ActiveTasks {
+    /// Lists a task of the given type against a database.
+    pub fn start(&self, task_type: &str, db: &str) -> Task {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Utc::now().timestamp();
+
+        let task = json!({
+            "pid": format!("<0.{}.0>", id),
+            "type": task_type,
+            "database": db,
+            "started_on": now,
+            "updated_on": now,
+        });
+        if let Value::Object(task) = task {
+            self.tasks.lock().unwrap().insert(id, task);
+        }
+
+        Task {
+            tasks: self.clone(),
+            id,
+        }
+    }
+
+    /// The tasks running now.
+    pub fn list(&self) -> Vec<Value> {
+        let tasks = self.tasks.lock().unwrap();
+        tasks.values().cloned().map(Value::Object).collect()
+    }
+}
+
+impl Task {
+    /// Sets the task's progress fields, such as how many documents it has written.
+    pub fn update(&self, progress: Value) {
+        let mut tasks = self.tasks.tasks.lock().unwrap();
+        let Some(task) = tasks.get_mut(&self.id) else {
+            return;
+        };
+
+        if let Value::Object(progress) = progress {
+            task.extend(progress);
+        }
+        task.insert("updated_on".to_string(), json!(Utc::now().timestamp()));
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.tasks.tasks.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// active_tasks lists the tasks running now, as CouchDB's `_active_tasks` does.
+pub async fn active_tasks(State(state): State<Arc<AppState>>) -> Json<Value> {
+    Json(json!(state.active_tasks.list()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_tasks() {
+        let tasks = ActiveTasks::default();
+
+        let task = tasks.start("import", "orders");
+        task.update(json!({ "docs_written": 10 }));
+
+        let listed = tasks.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]["type"], "import");
+        assert_eq!(listed[0]["database"], "orders");
+        assert_eq!(listed[0]["docs_written"], 10);
+
+        drop(task);
+        assert!(tasks.list().is_empty());
+    }
+}
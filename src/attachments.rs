@@ -0,0 +1,683 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::AttachmentDedupSettings;
+use crate::db::{is_duplicate_key, ChangeStream, Database};
+use async_trait::async_trait;
+use base64::Engine;
+use bson::{doc, Bson, Document, RawDocumentBuf};
+use futures_util::StreamExt;
+use mongodb::error::{Error, ErrorKind, GridFsErrorKind};
+use mongodb::gridfs::GridFsBucket;
+use mongodb::options::{
+    AggregateOptions,
+    DeleteOptions,
+    FindOneAndUpdateOptions,
+    FindOneOptions,
+    GridFsBucketOptions,
+    ReplaceOptions,
+    ReturnDocument,
+};
+use mongodb::results::UpdateResult;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::warn;
+
+#[cfg(test)]
+use mockall::*;
+
+/// Holds the SHA-256 of an attachment's contents in place of its `data`.
+const BLOB_FIELD: &str = "_couchapi_blob";
+
+/// Where attachment contents are kept, once each, with a count of the documents holding them.
+#[async_trait]
+#[cfg_attr(test, automock)]
+pub trait BlobStore {
+    /// Counts another reference to a blob, storing it if it's new.
+    async fn add_reference(&self, hash: &str, data: Vec<u8>) -> Result<(), Error>;
+
+    /// Drops a reference to a blob, deleting it with the last one.
+    async fn release(&self, hash: &str) -> Result<(), Error>;
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>, Error>;
+}
+
+/// Blobs in a GridFS bucket, each file's id being its hash, with their reference counts in
+/// `<bucket>.refs`.
+pub struct GridFsStore {
+    bucket: GridFsBucket,
+    refs: mongodb::Collection<Document>,
+}
+
+impl GridFsStore {
+    pub fn new(db: &mongodb::Database, bucket: &str) -> Self {
+        let options = GridFsBucketOptions::builder()
+            .bucket_name(bucket.to_string())
+            .build();
+
+        GridFsStore {
+            bucket: db.gridfs_bucket(options),
+            refs: db.collection(&format!("{}.refs", bucket)),
+        }
+    }
+
+    /// Adds to a blob's reference count, returning the new count.
+    async fn count(&self, hash: &str, by: i64) -> Result<i64, Error> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let counted = self
+            .refs
+            .find_one_and_update(
+                doc! { "_id": hash },
+                doc! { "$inc": { "count": by } },
+                options,
+            )
+            .await?;
+
+        Ok(counted
+            .and_then(|c| c.get("count").cloned())
+            .and_then(|c| c.as_i64().or_else(|| c.as_i32().map(i64::from)))
+            .unwrap_or(0))
+    }
+}
+
+#[async_trait]
+impl BlobStore for GridFsStore {
+    async fn add_reference(&self, hash: &str, data: Vec<u8>) -> Result<(), Error> {
+        if self.count(hash, 1).await? > 1 {
+            metrics::counter!(
+                "couchapi_attachment_dedup_saved_bytes_total",
+                data.len() as u64
+            );
+            return Ok(());
+        }
+
+        let id = Bson::String(hash.to_string());
+        match self
+            .bucket
+            .upload_from_futures_0_3_reader_with_id(id, hash, data.as_slice(), None)
+            .await
+        {
+            Ok(()) => {
+                metrics::increment_counter!("couchapi_attachment_blobs_stored_total");
+                Ok(())
+            }
+            // Still stored from a reference released while this one was being counted.
+            Err(e) if is_duplicate_key(&e) => Ok(()),
+            Err(e) => {
+                self.count(hash, -1).await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn release(&self, hash: &str) -> Result<(), Error> {
+        if self.count(hash, -1).await? > 0 {
+            return Ok(());
+        }
+
+        // Only the release that removes the count deletes the blob, in case another reference
+        // was added in the meantime.
+        let removed = self
+            .refs
+            .delete_one(doc! { "_id": hash, "count": { "$lte": 0 } }, None)
+            .await?;
+        if removed.deleted_count == 0 {
+            return Ok(());
+        }
+
+        match self.bucket.delete(Bson::String(hash.to_string())).await {
+            Err(e) if is_file_not_found(&e) => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>, Error> {
+        let mut data = Vec::new();
+        match self
+            .bucket
+            .download_to_futures_0_3_writer(Bson::String(hash.to_string()), &mut data)
+            .await
+        {
+            Ok(()) => Ok(Some(data)),
+            Err(e) if is_file_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn is_file_not_found(e: &Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::GridFs {
+            0: GridFsErrorKind::FileNotFound { .. },
+            ..
+        }
+    )
+}
+
+/// The blobs a stored document refers to.
+fn blob_hashes(document: &Document) -> Vec<String> {
+    let Ok(attachments) = document.get_document("_attachments") else {
+        return vec![];
+    };
+
+    attachments
+        .values()
+        .filter_map(Bson::as_document)
+        .filter_map(|a| a.get_str(BLOB_FIELD).ok())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Puts the data of each attachment back from its blob.
+async fn inline(
+    blobs: &(dyn BlobStore + Send + Sync),
+    mut document: Document,
+) -> Result<Document, Error> {
+    let Ok(attachments) = document.get_document_mut("_attachments") else {
+        return Ok(document);
+    };
+
+    for (name, attachment) in attachments.iter_mut() {
+        let Some(attachment) = attachment.as_document_mut() else {
+            continue;
+        };
+        let Some(hash) = attachment.get_str(BLOB_FIELD).ok().map(str::to_string) else {
+            continue;
+        };
+
+        match blobs.get(&hash).await? {
+            Some(data) => {
+                attachment.remove(BLOB_FIELD);
+                attachment.insert(
+                    "data",
+                    base64::engine::general_purpose::STANDARD.encode(data),
+                );
+            }
+            None => warn!(attachment = name, hash, "attachment blob is missing"),
+        }
+    }
+
+    Ok(document)
+}
+
+async fn inline_raw(
+    blobs: &(dyn BlobStore + Send + Sync),
+    raw: RawDocumentBuf,
+) -> Result<RawDocumentBuf, Error> {
+    if !matches!(raw.get("_attachments"), Ok(Some(_))) {
+        return Ok(raw);
+    }
+
+    Ok(RawDocumentBuf::from_document(
+        &inline(blobs, raw.to_document()?).await?,
+    )?)
+}
+
+/// Moves the contents of large attachments to a blob store, so identical attachments across
+/// documents are stored once. Documents are handed back with their attachments whole.
+pub struct DedupedDatabase {
+    inner: Box<dyn Database + Send + Sync>,
+    blobs: Arc<dyn BlobStore + Send + Sync>,
+    settings: AttachmentDedupSettings,
+}
+
+impl DedupedDatabase {
+    pub fn new(
+        inner: Box<dyn Database + Send + Sync>,
+        blobs: Box<dyn BlobStore + Send + Sync>,
+        settings: AttachmentDedupSettings,
+    ) -> Self {
+        DedupedDatabase {
+            inner,
+            blobs: Arc::from(blobs),
+            settings,
+        }
+    }
+
+    fn applies_to(&self, coll: &str) -> bool {
+        self.settings.databases.is_empty() || self.settings.databases.iter().any(|d| d == coll)
+    }
+
+    /// Replaces the data of each large attachment with its hash, returning the blobs referenced.
+    async fn extract(&self, document: &mut Document) -> Result<Vec<String>, Error> {
+        let mut hashes = vec![];
+        let Ok(attachments) = document.get_document_mut("_attachments") else {
+            return Ok(hashes);
+        };
+
+        for attachment in attachments
+            .iter_mut()
+            .filter_map(|(_, a)| a.as_document_mut())
+        {
+            let data = attachment
+                .get_str("data")
+                .ok()
+                .and_then(|data| base64::engine::general_purpose::STANDARD.decode(data).ok());
+            let Some(data) = data.filter(|d| d.len() >= self.settings.threshold_bytes) else {
+                continue;
+            };
+
+            let hash = hex::encode(Sha256::digest(&data));
+            if let Err(e) = self.blobs.add_reference(&hash, data).await {
+                self.release(&hashes).await;
+                return Err(e);
+            }
+
+            attachment.remove("data");
+            attachment.insert(BLOB_FIELD, &hash);
+            hashes.push(hash);
+        }
+
+        Ok(hashes)
+    }
+
+    /// Drops references to blobs. A failure is logged rather than failing the write it followed,
+    /// and leaves the blob stored.
+    async fn release(&self, hashes: &[String]) {
+        for hash in hashes {
+            if let Err(e) = self.blobs.release(hash).await {
+                warn!(
+                    hash,
+                    error = e.to_string(),
+                    "unable to release attachment blob"
+                );
+            }
+        }
+    }
+
+    /// The blobs referred to by the document a write will replace or delete.
+    async fn previous_hashes(&self, coll: &str, id: Option<&str>) -> Result<Vec<String>, Error> {
+        let Some(id) = id else {
+            return Ok(vec![]);
+        };
+
+        let previous = self
+            .inner
+            .find_one(coll, id, FindOneOptions::default())
+            .await?;
+        Ok(previous.as_ref().map(blob_hashes).unwrap_or_default())
+    }
+}
+
+/// The references a replacement gives up once it's done: the previous document's when it was
+/// replaced, the new document's when nothing was stored, and none when it was inserted.
+fn released_by<'a>(
+    matched: u64,
+    upserted: bool,
+    previous: &'a [String],
+    added: &'a [String],
+) -> &'a [String] {
+    match (matched, upserted) {
+        (0, false) => added,
+        (0, true) => &[],
+        _ => previous,
+    }
+}
+
+#[async_trait]
+impl Database for DedupedDatabase {
+    async fn get_version(&self) -> Result<Document, Error> {
+        self.inner.get_version().await
+    }
+
+    async fn find_one(
+        &self,
+        coll: &str,
+        id: &str,
+        options: FindOneOptions,
+    ) -> Result<Option<Document>, Error> {
+        match self.inner.find_one(coll, id, options).await? {
+            Some(document) => Ok(Some(inline(self.blobs.as_ref(), document).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn replace_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        mut replacement: Document,
+        options: ReplaceOptions,
+    ) -> Result<UpdateResult, Error> {
+        if !self.applies_to(coll) {
+            return self
+                .inner
+                .replace_one(coll, filter, replacement, options)
+                .await;
+        }
+
+        let added = self.extract(&mut replacement).await?;
+        let id = filter.get_str("_id").ok();
+        let previous = match self.previous_hashes(coll, id).await {
+            Ok(previous) => previous,
+            Err(e) => {
+                self.release(&added).await;
+                return Err(e);
+            }
+        };
+
+        let result = self
+            .inner
+            .replace_one(coll, filter, replacement, options)
+            .await;
+        let released = match &result {
+            Ok(r) => released_by(r.matched_count, r.upserted_id.is_some(), &previous, &added),
+            Err(_) => &added,
+        };
+        self.release(released).await;
+        result
+    }
+
+    async fn delete_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        options: DeleteOptions,
+    ) -> Result<u64, Error> {
+        if !self.applies_to(coll) {
+            return self.inner.delete_one(coll, filter, options).await;
+        }
+
+        let previous = self
+            .previous_hashes(coll, filter.get_str("_id").ok())
+            .await?;
+        let deleted = self.inner.delete_one(coll, filter, options).await?;
+        if deleted > 0 {
+            self.release(&previous).await;
+        }
+        Ok(deleted)
+    }
+
+    async fn aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+        options: AggregateOptions,
+    ) -> Result<Vec<RawDocumentBuf>, Error> {
+        let rows = self.inner.aggregate(coll, pipeline, options).await?;
+
+        let mut inlined = Vec::with_capacity(rows.len());
+        for row in rows {
+            inlined.push(inline_raw(self.blobs.as_ref(), row).await?);
+        }
+        Ok(inlined)
+    }
+
+    async fn explain_aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Document, Error> {
+        self.inner.explain_aggregate(coll, pipeline).await
+    }
+
+    async fn count(&self, coll: &str) -> Result<u64, Error> {
+        self.inner.count(coll).await
+    }
+
+    async fn collection_stats(&self, coll: &str) -> Result<Option<Document>, Error> {
+        self.inner.collection_stats(coll).await
+    }
+
+    async fn watch(
+        &self,
+        coll: &str,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeStream, Error> {
+        let changes = self.inner.watch(coll, resume_after).await?;
+        let blobs = self.blobs.clone();
+        Ok(changes
+            .then(move |event| {
+                let blobs = blobs.clone();
+                async move {
+                    let mut event = event?;
+                    if let Some(Bson::Document(document)) = event.remove("fullDocument") {
+                        event.insert("fullDocument", inline(blobs.as_ref(), document).await?);
+                    }
+                    Ok(event)
+                }
+            })
+            .boxed())
+    }
+
+    async fn watch_database(&self, resume_after: Option<Document>) -> Result<ChangeStream, Error> {
+        self.inner.watch_database(resume_after).await
+    }
+
+    async fn create_ttl_index(&self, coll: &str, field: &str) -> Result<(), Error> {
+        self.inner.create_ttl_index(coll, field).await
+    }
+
+    async fn update_many(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<u64, Error> {
+        self.inner.update_many(coll, filter, update).await
+    }
+
+    async fn delete_many(&self, coll: &str, filter: Document) -> Result<u64, Error> {
+        if !self.applies_to(coll) {
+            return self.inner.delete_many(coll, filter).await;
+        }
+
+        let pipeline = vec![
+            doc! { "$match": filter.clone() },
+            doc! { "$project": { "_attachments": 1 } },
+        ];
+        let previous = self
+            .inner
+            .aggregate(coll, pipeline, AggregateOptions::default())
+            .await?
+            .iter()
+            .filter_map(|raw| raw.to_document().ok())
+            .flat_map(|document| blob_hashes(&document))
+            .collect::<Vec<_>>();
+
+        let deleted = self.inner.delete_many(coll, filter).await?;
+        self.release(&previous).await;
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use std::sync::Mutex;
+
+    fn settings() -> AttachmentDedupSettings {
+        AttachmentDedupSettings {
+            threshold_bytes: 16,
+            bucket: "attachments".to_string(),
+            databases: vec!["stores".to_string()],
+        }
+    }
+
+    fn image() -> Vec<u8> {
+        b"a store front image".repeat(4)
+    }
+
+    fn hash() -> String {
+        hex::encode(Sha256::digest(image()))
+    }
+
+    fn document() -> Document {
+        doc! {
+            "_id": "store1",
+            "_rev": "1-a",
+            "_attachments": {
+                "front.png": {
+                    "content_type": "image/png",
+                    "data": base64::engine::general_purpose::STANDARD.encode(image()),
+                },
+                "note.txt": {
+                    "content_type": "text/plain",
+                    "data": base64::engine::general_purpose::STANDARD.encode("hi"),
+                },
+            },
+        }
+    }
+
+    fn stored_document() -> Document {
+        doc! {
+            "_id": "store1",
+            "_rev": "1-a",
+            "_attachments": {
+                "front.png": { "content_type": "image/png", BLOB_FIELD: hash() },
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stores_large_attachments_once() {
+        let written = Arc::new(Mutex::new(None::<Document>));
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one()
+            .returning(|_, _, _| Box::pin(async { Ok(None) }));
+        let w = written.clone();
+        mock.expect_replace_one()
+            .returning(move |_, _, replacement, _| {
+                *w.lock().unwrap() = Some(replacement);
+                // UpdateResult can't be built outside of the driver, so the write fails and its
+                // reference is handed back
+                Box::pin(async { Err(Error::custom("written")) })
+            });
+
+        let mut blobs = MockBlobStore::new();
+        blobs
+            .expect_add_reference()
+            .withf(|h, data| h == hash() && data == &image())
+            .times(1)
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+        blobs
+            .expect_release()
+            .withf(|h| h == hash())
+            .times(1)
+            .returning(|_| Box::pin(async { Ok(()) }));
+
+        let db = DedupedDatabase::new(Box::new(mock), Box::new(blobs), settings());
+        db.replace_one(
+            "stores",
+            doc! { "_id": "store1" },
+            document(),
+            ReplaceOptions::default(),
+        )
+        .await
+        .unwrap_err();
+
+        let written = written.lock().unwrap().clone().unwrap();
+        let attachments = written.get_document("_attachments").unwrap();
+        let front = attachments.get_document("front.png").unwrap();
+        assert_eq!(front.get_str(BLOB_FIELD).unwrap(), hash());
+        assert!(front.get("data").is_none());
+
+        // Small attachments stay inline.
+        let note = attachments.get_document("note.txt").unwrap();
+        assert!(note.get_str("data").is_ok());
+    }
+
+    #[test]
+    fn test_released_by() {
+        let previous = ["old".to_string()];
+        let added = ["new".to_string()];
+
+        assert_eq!(released_by(1, false, &previous, &added), previous);
+        assert!(released_by(0, true, &previous, &added).is_empty());
+        // Nothing matched, say for a stale `_rev`, so the previous document still holds its blobs.
+        assert_eq!(released_by(0, false, &previous, &added), added);
+    }
+
+    #[tokio::test]
+    async fn test_other_databases_are_untouched() {
+        let mut mock = MockDatabase::new();
+        mock.expect_replace_one()
+            .withf(|_, _, replacement, _| replacement == &document())
+            .returning(|_, _, _, _| Box::pin(async { Err(Error::custom("written")) }));
+
+        let db = DedupedDatabase::new(Box::new(mock), Box::new(MockBlobStore::new()), settings());
+        db.replace_one(
+            "orders",
+            doc! { "_id": "store1" },
+            document(),
+            ReplaceOptions::default(),
+        )
+        .await
+        .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_reads_inline_blobs() {
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one()
+            .returning(|_, _, _| Box::pin(async { Ok(Some(stored_document())) }));
+
+        let mut blobs = MockBlobStore::new();
+        blobs
+            .expect_get()
+            .withf(|h| h == hash())
+            .returning(|_| Box::pin(async { Ok(Some(image())) }));
+
+        let db = DedupedDatabase::new(Box::new(mock), Box::new(blobs), settings());
+        let found = db
+            .find_one("stores", "store1", FindOneOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let front = found
+            .get_document("_attachments")
+            .unwrap()
+            .get_document("front.png")
+            .unwrap();
+        assert_eq!(
+            front.get_str("data").unwrap(),
+            base64::engine::general_purpose::STANDARD.encode(image())
+        );
+        assert!(front.get(BLOB_FIELD).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_deletes_release_references() {
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one()
+            .returning(|_, _, _| Box::pin(async { Ok(Some(stored_document())) }));
+        mock.expect_delete_one()
+            .returning(|_, _, _| Box::pin(async { Ok(1) }));
+        mock.expect_aggregate().returning(|_, _, _| {
+            let row = RawDocumentBuf::from_document(&stored_document()).unwrap();
+            Box::pin(async move { Ok(vec![row.clone(), row]) })
+        });
+        mock.expect_delete_many()
+            .returning(|_, _| Box::pin(async { Ok(2) }));
+
+        let mut blobs = MockBlobStore::new();
+        blobs
+            .expect_release()
+            .withf(|h| h == hash())
+            .times(3)
+            .returning(|_| Box::pin(async { Ok(()) }));
+
+        let db = DedupedDatabase::new(Box::new(mock), Box::new(blobs), settings());
+        db.delete_one("stores", doc! { "_id": "store1" }, DeleteOptions::default())
+            .await
+            .unwrap();
+        db.delete_many("stores", doc! {}).await.unwrap();
+    }
+}
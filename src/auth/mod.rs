@@ -0,0 +1,897 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod session;
+pub mod signing;
+
+use crate::config::{ApiKey, SecurityObject};
+use crate::ops::security::security_for_db;
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use crate::tenancy::Tenant;
+use axum::body::Body;
+use axum::extract::{MatchedPath, Path, State};
+use axum::http::{header, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Json;
+use serde_derive::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::warn;
+
+tokio::task_local! {
+    /// Whether the user behind the request being handled administers its database.
+    static DB_ADMIN: bool;
+}
+
+/// The user behind a request, in the same shape as CouchDB's `userCtx`.
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct UserCtx {
+    pub name: Option<String>,
+    pub roles: Vec<String>,
+}
+
+impl From<&ApiKey> for UserCtx {
+    fn from(api_key: &ApiKey) -> Self {
+        UserCtx {
+            name: api_key.name.clone(),
+            roles: api_key.roles.clone(),
+        }
+    }
+}
+
+/// The level of access a route needs on a database.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Reader,
+    Writer,
+    Admin,
+}
+
+/// Returns the API key from the `X-Api-Key` header or, failing that, a bearer token in the
+/// `Authorization` header.
+fn api_key_from_request(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get("X-Api-Key")
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| {
+            req.headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.strip_prefix("Bearer "))
+        })
+}
+
+/// Compares two strings without returning early, so the time taken doesn't leak how much of a
+/// key was guessed correctly.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+pub fn unauthorized(reason: &str) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(json!({"error": "unauthorized", "reason": reason})),
+    )
+}
+
+pub fn forbidden(reason: &str) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({"error": "forbidden", "reason": reason})),
+    )
+}
+
+/// Returns `true` for names that can't be a database: those starting with `_`, which are kept for
+/// this server's own collections such as the security objects, and those with a `.`, which would
+/// reach into MongoDB's namespaces such as the attachment buckets.
+pub fn is_reserved_db_name(db: &str) -> bool {
+    db.starts_with('_') || db.contains('.')
+}
+
+/// Turns away requests to a reserved database name before anything else sees them, as a writer
+/// could otherwise replace another database's security object or poison the internal caches.
+pub async fn check_db_name(
+    Path((db,)): Path<(String,)>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    if is_reserved_db_name(&db) {
+        warn!(db = db.as_str(), "reserved database name refused");
+
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "illegal_database_name",
+                "reason": format!(
+                    "Name: '{}'. Names starting with _ or containing . are reserved.",
+                    db
+                ),
+            })),
+        ));
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Enforce the configured API keys on database routes. When no keys are configured every request
+/// is let through. The matched key is stored in the request extensions for later handlers.
+/// Requests that arrived with a verified client certificate, a valid session cookie or a request
+/// signature already carry a `UserCtx` and don't need a key.
+pub async fn check_api_key(
+    State(state): State<Arc<AppState>>,
+    Path((db,)): Path<(String,)>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let api_keys = match &state.api_keys {
+        Some(api_keys) => api_keys,
+        None => return Ok(next.run(req).await),
+    };
+
+    // A verified client certificate, session or signature stands in for a key, leaving the security
+    // object to decide what its user may do.
+    let presented = match api_key_from_request(&req) {
+        Some(presented) => presented,
+        None if req.extensions().get::<UserCtx>().is_some() => return Ok(next.run(req).await),
+        None => return Err(unauthorized("You are not authorized to access this db.")),
+    };
+
+    let api_key: &ApiKey = api_keys
+        .iter()
+        .find(|k| constant_time_eq(&k.key, presented))
+        .ok_or_else(|| unauthorized("Name or password is incorrect."))?;
+
+    if !api_key.allows(&db, req.method().as_str()) {
+        warn!(
+            name = api_key.name.as_deref().unwrap_or("unnamed"),
+            db = db.as_str(),
+            method = req.method().as_str(),
+            "api key not permitted"
+        );
+
+        return Err(forbidden("You are not allowed to access this db."));
+    }
+
+    req.extensions_mut().insert(UserCtx::from(api_key));
+    req.extensions_mut().insert(api_key.clone());
+    if let Some(tenant) = &api_key.tenant {
+        req.extensions_mut().insert(Tenant(tenant.clone()));
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Lets only server admins, users with the `_admin` role, reach server wide admin routes such as
+/// `/_couchapi/views`. These have no database, so an API key is matched here rather than by
+/// `check_api_key`. With `open_admin_routes` set any known user may use them.
+pub async fn require_server_admin(
+    State(state): State<Arc<AppState>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    if !authentication_configured(&state) {
+        return Ok(next.run(req).await);
+    }
+
+    let api_key = match api_key_from_request(&req).zip(state.api_keys.as_ref()) {
+        Some((presented, api_keys)) => Some(
+            api_keys
+                .iter()
+                .find(|k| constant_time_eq(&k.key, presented))
+                .cloned()
+                .ok_or_else(|| unauthorized("Name or password is incorrect."))?,
+        ),
+        None => None,
+    };
+
+    let user_ctx = match &api_key {
+        Some(api_key) => UserCtx::from(api_key),
+        None => req
+            .extensions()
+            .get::<UserCtx>()
+            .cloned()
+            .unwrap_or_default(),
+    };
+
+    if user_ctx.name.is_none() && user_ctx.roles.is_empty() {
+        return Err(unauthorized("You are not a server admin."));
+    }
+
+    if !state.open_admin_routes && !user_ctx.roles.iter().any(|r| r == "_admin") {
+        warn!(
+            name = user_ctx.name.as_deref().unwrap_or("anonymous"),
+            "server admin role not held"
+        );
+        return Err(forbidden("You are not a server admin."));
+    }
+
+    // Left for `scope_tenant`, as `check_api_key` leaves it on database routes.
+    if let Some(tenant) = api_key.and_then(|k| k.tenant) {
+        req.extensions_mut().insert(Tenant(tenant));
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Returns `true` for routes that change how a database behaves rather than just its documents:
+/// running update handlers, writing design documents, purging, dry running views, exporting,
+/// importing and creating or deleting databases.
+fn is_admin_route(matched_path: &str, uri_path: &str, method: &Method) -> bool {
+    let is_read = method == Method::GET || method == Method::HEAD;
+
+    match matched_path {
+        p if p.contains("/_update/") || p.ends_with("/_purge") => true,
+        "/:db/_view_dry_run" | "/:db/_couchapi/export" | "/:db/_couchapi/import" => true,
+        "/:db" => method == Method::PUT || method == Method::DELETE,
+        "/:db/:item" if !is_read => uri_path
+            .trim_start_matches('/')
+            .split('/')
+            .nth(1)
+            .is_some_and(|item| item.starts_with("_design")),
+        _ => false,
+    }
+}
+
+/// Works out the role a request needs from the route it matched and its method. Views, searches,
+/// `_all_docs` and `_revs_diff` are reads even when POSTed, while update handlers and `_bulk_docs`
+/// are writes. Admin routes only need a writer when `open_admin_routes` is set.
+pub fn required_role(
+    matched_path: &str,
+    uri_path: &str,
+    method: &Method,
+    open_admin_routes: bool,
+) -> Role {
+    let is_read = method == Method::GET || method == Method::HEAD;
+
+    if !open_admin_routes && is_admin_route(matched_path, uri_path, method) {
+        return Role::Admin;
+    }
+
+    match matched_path {
+        "/:db/_security" if !is_read => Role::Admin,
+        p if p.contains("/_view/") || p.contains("/_search/") || p.ends_with("/_all_docs") => {
+            Role::Reader
+        }
+        "/:db/_revs_diff" => Role::Reader,
+        p if p.contains("/_geo/") || p.contains("/_spatial/") => Role::Reader,
+        p if p.contains("/_update/") || p.ends_with("/_bulk_docs") => Role::Writer,
+        _ if is_read => Role::Reader,
+        _ => Role::Writer,
+    }
+}
+
+/// Returns `true` if the security object grants the user the given role.
+pub fn has_role(security: &SecurityObject, user_ctx: &UserCtx, role: Role) -> bool {
+    let name = user_ctx.name.as_deref();
+
+    match role {
+        Role::Reader => security.can_read(name, &user_ctx.roles),
+        Role::Writer => security.can_write(name, &user_ctx.roles),
+        Role::Admin => security.is_admin(name, &user_ctx.roles),
+    }
+}
+
+/// Returns `true` if any way of identifying users is configured. Without one every request is
+/// anonymous, so security objects aren't enforced.
+fn authentication_configured(state: &AppState) -> bool {
+    state.api_keys.is_some()
+        || state.couch_httpd_auth.is_some()
+        || state.request_signing.is_some()
+        || state.client_cert_auth
+}
+
+/// Enforce the database security object against the user found by `check_api_key`, a session
+/// cookie, a request signature or a client certificate. This covers every database route, including
+/// views, update handlers and `_all_docs`. Anonymous users are told they are unauthorized while
+/// known users without the role are forbidden, as CouchDB does.
+pub async fn check_security(
+    State(state): State<Arc<AppState>>,
+    Path((db,)): Path<(String,)>,
+    matched_path: MatchedPath,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    if !authentication_configured(&state) {
+        return Ok(next.run(req).await);
+    }
+
+    let role = required_role(
+        matched_path.as_str(),
+        req.uri().path(),
+        req.method(),
+        state.open_admin_routes,
+    );
+
+    let user_ctx = req
+        .extensions()
+        .get::<UserCtx>()
+        .cloned()
+        .unwrap_or_default();
+    let security = security_for_db(&state, &db).await?;
+
+    // Design documents can also be written through routes that don't name them, such as
+    // `_bulk_docs`, so the write path checks this again for each document.
+    let db_admin = state.open_admin_routes
+        || has_role(
+            &security.clone().unwrap_or_default(),
+            &user_ctx,
+            Role::Admin,
+        );
+
+    // Without a security object the database is open, apart from admin routes which still need a
+    // server admin.
+    let security = match security {
+        Some(security) => security,
+        None if role == Role::Admin => SecurityObject::default(),
+        None => return Ok(DB_ADMIN.scope(db_admin, next.run(req)).await),
+    };
+
+    if !has_role(&security, &user_ctx, role) {
+        warn!(
+            name = user_ctx.name.as_deref().unwrap_or("anonymous"),
+            db = db.as_str(),
+            role = format!("{:?}", role),
+            "role not held"
+        );
+
+        return Err(match user_ctx.name {
+            None if user_ctx.roles.is_empty() => {
+                unauthorized("You are not authorized to access this db.")
+            }
+            _ => forbidden("You are not allowed to access this db."),
+        });
+    }
+
+    Ok(DB_ADMIN.scope(db_admin, next.run(req)).await)
+}
+
+/// Fails with a `403` when a design document is written or deleted by a user who doesn't
+/// administer the database, whichever route the document came through. Requests that
+/// `check_security` didn't see, and work done outside of a request, aren't checked.
+pub fn check_design_write(id: &str) -> Result<(), JsonWithStatusCodeResponse> {
+    let db_admin = DB_ADMIN.try_with(|db_admin| *db_admin).unwrap_or(true);
+    if id.starts_with("_design") && !db_admin {
+        return Err(forbidden("Only admins may write design documents."));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SecurityGroup;
+    use crate::db::MockDatabase;
+    use axum::routing::{get, put};
+    use axum::{middleware, Router};
+    use maplit::hashmap;
+    use tokio::net::TcpListener;
+
+    async fn handler() -> &'static str {
+        "OK"
+    }
+
+    #[tokio::test]
+    async fn test_check_db_name() {
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route("/:db/:item", get(handler).put(handler))
+            .layer(middleware::from_fn(check_db_name));
+        let send = |uri: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        for uri in [
+            "/_couchapi_security/other_db",
+            "/_couchapi_idempotency/x",
+            "/attachments.files/x",
+        ] {
+            let response = send(uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+
+        let response = send("/test_db/x").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    async fn serve(api_keys: Option<Vec<ApiKey>>) -> String {
+        let state = Arc::new(AppState {
+            api_keys,
+            ..AppState::for_tests(MockDatabase::new())
+        });
+
+        let admin_routes = Router::new()
+            .route("/_couchapi/views", get(handler))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_server_admin,
+            ));
+        let app = Router::new()
+            .route("/:db/:item", get(handler).put(handler))
+            .layer(middleware::from_fn_with_state(state.clone(), check_api_key))
+            .merge(admin_routes)
+            .with_state(state);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        format!("http://{}", addr)
+    }
+
+    fn test_keys() -> Option<Vec<ApiKey>> {
+        Some(vec![ApiKey {
+            key: "reader-key".to_string(),
+            name: Some("reader".to_string()),
+            databases: vec!["test_db".to_string()],
+            methods: Some(vec!["GET".to_string()]),
+            roles: vec![],
+            tenant: None,
+        }])
+    }
+
+    async fn serve_with_security(security: SecurityObject) -> String {
+        let state = Arc::new(AppState {
+            api_keys: Some(vec![
+                ApiKey {
+                    key: "reader-key".to_string(),
+                    name: Some("reader".to_string()),
+                    databases: vec!["*".to_string()],
+                    methods: None,
+                    roles: vec!["readers".to_string()],
+                    tenant: None,
+                },
+                ApiKey {
+                    key: "other-key".to_string(),
+                    name: Some("other".to_string()),
+                    databases: vec!["*".to_string()],
+                    methods: None,
+                    roles: vec![],
+                    tenant: None,
+                },
+            ]),
+            security: Some(hashmap! { "test_db".to_string() => security }),
+            ..AppState::for_tests(MockDatabase::new())
+        });
+
+        let app = Router::new()
+            .route("/:db/:item", get(handler).put(handler))
+            .route("/:db/_all_docs", get(handler).post(handler))
+            .route(
+                "/:db/_design/:design/_view/:view",
+                get(handler).post(handler),
+            )
+            .route("/:db/_design/:design/_update/:function", put(handler))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                check_security,
+            ))
+            .layer(middleware::from_fn_with_state(state.clone(), check_api_key))
+            .with_state(state);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_design_documents_need_an_admin() {
+        use crate::ops::bulk::bulk_docs;
+        use crate::ops::create_update::inner_new_item;
+        use axum::routing::post;
+        use serde_json::Value;
+        use std::collections::HashMap;
+        use tower::ServiceExt;
+
+        // The mock has no expectations, so reaching MongoDB would fail the test.
+        let state = Arc::new(AppState {
+            api_keys: Some(vec![ApiKey {
+                key: "writer-key".to_string(),
+                name: Some("writer".to_string()),
+                databases: vec!["*".to_string()],
+                methods: None,
+                roles: vec![],
+                tenant: None,
+            }]),
+            security: Some(hashmap! { "test_db".to_string() => SecurityObject::default() }),
+            ..AppState::for_tests(MockDatabase::new())
+        });
+
+        let app = Router::new()
+            .route(
+                "/:db",
+                post(
+                    |State(state): State<Arc<AppState>>,
+                     Path(db): Path<String>,
+                     Json(doc): Json<Value>| async move {
+                        inner_new_item(db, None, state, HashMap::new(), doc, None).await
+                    },
+                ),
+            )
+            .route("/:db/_bulk_docs", post(bulk_docs))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                check_security,
+            ))
+            .layer(middleware::from_fn_with_state(state.clone(), check_api_key))
+            .with_state(state);
+        let send = |uri: &str, body: Value| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .header("X-Api-Key", "writer-key")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+        };
+
+        let response = send("/test_db", json!({"_id": "_design/x"})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = send(
+            "/test_db/_bulk_docs",
+            json!({"docs": [{"_id": "_design/x", "views": {}}]}),
+        )
+        .await
+        .unwrap();
+        let body = http_body_util::BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let results: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(results[0]["error"], "forbidden");
+
+        let admin = DB_ADMIN.scope(true, async { check_design_write("_design/x") });
+        assert!(admin.await.is_ok());
+        assert!(check_design_write("_design/x").is_ok());
+    }
+
+    #[test]
+    fn test_required_role() {
+        let role = |path: &str, uri: &str, method: Method| required_role(path, uri, &method, false);
+
+        assert_eq!(role("/:db/:item", "/db/doc", Method::GET), Role::Reader);
+        assert_eq!(role("/:db/:item", "/db/doc", Method::PUT), Role::Writer);
+        assert_eq!(
+            role("/:db/_security", "/db/_security", Method::GET),
+            Role::Reader
+        );
+        assert_eq!(
+            role("/:db/_security", "/db/_security", Method::PUT),
+            Role::Admin
+        );
+        assert_eq!(
+            role("/:db/_all_docs", "/db/_all_docs", Method::POST),
+            Role::Reader
+        );
+        assert_eq!(
+            role("/:db/_revs_diff", "/db/_revs_diff", Method::POST),
+            Role::Reader
+        );
+        assert_eq!(
+            role(
+                "/:db/_design/:design/_view/:view",
+                "/db/_design/d/_view/v",
+                Method::POST
+            ),
+            Role::Reader
+        );
+        assert_eq!(
+            role(
+                "/:db/_design/:design/_search/:index",
+                "/db/_design/d/_search/i",
+                Method::POST
+            ),
+            Role::Reader
+        );
+        assert_eq!(
+            role(
+                "/:db/_design/:design/_spatial/:index",
+                "/db/_design/d/_spatial/i",
+                Method::GET
+            ),
+            Role::Reader
+        );
+    }
+
+    #[test]
+    fn test_required_role_admin_routes() {
+        let update = "/:db/_design/:design/_update/:function";
+
+        assert_eq!(
+            required_role(update, "/db/_design/d/_update/f", &Method::PUT, false),
+            Role::Admin
+        );
+        assert_eq!(
+            required_role("/:db/:item", "/db/_design%2Fd", &Method::PUT, false),
+            Role::Admin
+        );
+        assert_eq!(
+            required_role("/:db/:item", "/db/_design%2Fd", &Method::GET, false),
+            Role::Reader
+        );
+        assert_eq!(
+            required_role("/:db", "/db", &Method::DELETE, false),
+            Role::Admin
+        );
+        assert_eq!(
+            required_role("/:db", "/db", &Method::POST, false),
+            Role::Writer
+        );
+        assert_eq!(
+            required_role(
+                "/:db/_view_dry_run",
+                "/db/_view_dry_run",
+                &Method::POST,
+                false
+            ),
+            Role::Admin
+        );
+        assert_eq!(
+            required_role(
+                "/:db/_couchapi/export",
+                "/db/_couchapi/export",
+                &Method::GET,
+                false
+            ),
+            Role::Admin
+        );
+
+        // Open admin routes fall back to the previous behaviour, apart from `_security`.
+        assert_eq!(
+            required_role(update, "/db/_design/d/_update/f", &Method::PUT, true),
+            Role::Writer
+        );
+        assert_eq!(
+            required_role("/:db/_security", "/db/_security", &Method::PUT, true),
+            Role::Admin
+        );
+    }
+
+    #[tokio::test]
+    async fn test_security_object_enforced() {
+        let url = serve_with_security(SecurityObject {
+            members: SecurityGroup {
+                names: vec![],
+                roles: vec!["readers".to_string()],
+            },
+            writers: Some(SecurityGroup {
+                names: vec!["writer".to_string()],
+                roles: vec![],
+            }),
+            ..SecurityObject::default()
+        })
+        .await;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(format!("{}/test_db/doc", url))
+            .header("X-Api-Key", "reader-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::OK.as_u16());
+
+        let res = client
+            .put(format!("{}/test_db/doc", url))
+            .header("X-Api-Key", "reader-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::FORBIDDEN.as_u16());
+
+        let res = client
+            .get(format!("{}/test_db/doc", url))
+            .header("X-Api-Key", "other-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::FORBIDDEN.as_u16());
+    }
+
+    #[tokio::test]
+    async fn test_security_object_enforced_on_design_routes() {
+        let url = serve_with_security(SecurityObject {
+            members: SecurityGroup {
+                names: vec![],
+                roles: vec!["readers".to_string()],
+            },
+            ..SecurityObject::default()
+        })
+        .await;
+        let client = reqwest::Client::new();
+
+        for path in ["_design/d/_view/v", "_all_docs"] {
+            let res = client
+                .post(format!("{}/test_db/{}", url, path))
+                .header("X-Api-Key", "reader-key")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status().as_u16(), StatusCode::OK.as_u16(), "{}", path);
+
+            let res = client
+                .get(format!("{}/test_db/{}", url, path))
+                .header("X-Api-Key", "other-key")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(
+                res.status().as_u16(),
+                StatusCode::FORBIDDEN.as_u16(),
+                "{}",
+                path
+            );
+        }
+
+        let res = client
+            .put(format!("{}/test_db/_design/d/_update/f", url))
+            .header("X-Api-Key", "reader-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::FORBIDDEN.as_u16());
+    }
+
+    #[test]
+    fn test_authentication_configured() {
+        let state = AppState::for_tests(MockDatabase::new());
+        assert!(!authentication_configured(&state));
+
+        let state = AppState {
+            client_cert_auth: true,
+            ..state
+        };
+        assert!(authentication_configured(&state));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("abc", "abc"));
+        assert!(!constant_time_eq("abc", "abd"));
+        assert!(!constant_time_eq("abc", "abcd"));
+    }
+
+    #[tokio::test]
+    async fn test_server_admin_routes() {
+        let mut keys = test_keys().unwrap();
+        keys.push(ApiKey {
+            key: "admin-key".to_string(),
+            name: Some("admin".to_string()),
+            databases: vec![],
+            methods: None,
+            roles: vec!["_admin".to_string()],
+            tenant: None,
+        });
+        let url = serve(Some(keys)).await;
+        let client = reqwest::Client::new();
+        let status = |key: Option<&'static str>| {
+            let mut req = client.get(format!("{}/_couchapi/views", url));
+            if let Some(key) = key {
+                req = req.header("X-Api-Key", key);
+            }
+            async move { req.send().await.unwrap().status().as_u16() }
+        };
+
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED.as_u16());
+        assert_eq!(
+            status(Some("wrong-key")).await,
+            StatusCode::UNAUTHORIZED.as_u16()
+        );
+        assert_eq!(
+            status(Some("reader-key")).await,
+            StatusCode::FORBIDDEN.as_u16()
+        );
+        assert_eq!(status(Some("admin-key")).await, StatusCode::OK.as_u16());
+    }
+
+    #[tokio::test]
+    async fn test_no_api_keys_configured() {
+        let url = serve(None).await;
+
+        let res = reqwest::get(format!("{}/test_db/doc", url)).await.unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::OK.as_u16());
+    }
+
+    #[tokio::test]
+    async fn test_missing_and_unknown_key() {
+        let url = serve(test_keys()).await;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(format!("{}/test_db/doc", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::UNAUTHORIZED.as_u16());
+
+        let res = client
+            .get(format!("{}/test_db/doc", url))
+            .header("X-Api-Key", "wrong-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::UNAUTHORIZED.as_u16());
+
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(body["error"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn test_key_scoped_to_database_and_method() {
+        let url = serve(test_keys()).await;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(format!("{}/test_db/doc", url))
+            .header("X-Api-Key", "reader-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::OK.as_u16());
+
+        let res = client
+            .get(format!("{}/test_db/doc", url))
+            .bearer_auth("reader-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::OK.as_u16());
+
+        let res = client
+            .get(format!("{}/other_db/doc", url))
+            .header("X-Api-Key", "reader-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::FORBIDDEN.as_u16());
+
+        let res = client
+            .put(format!("{}/test_db/doc", url))
+            .header("X-Api-Key", "reader-key")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::FORBIDDEN.as_u16());
+
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(body["error"], "forbidden");
+    }
+}
@@ -0,0 +1,438 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `AuthSession` cookies, signed the same way as CouchDB's `couch_httpd_auth` so sessions carry
+//! over in either direction during a migration.
+
+use crate::auth::{unauthorized, UserCtx};
+use crate::config::{CouchHttpdAuth, SessionUser};
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use crate::tenancy::Tenant;
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha1::Sha1;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const COOKIE_NAME: &str = "AuthSession";
+
+/// CouchDB's default number of PBKDF2 iterations for user documents without one.
+const DEFAULT_ITERATIONS: u32 = 10;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Cookies are signed with the server secret followed by the user's salt, so changing either
+/// invalidates the user's sessions.
+fn signing_key(auth: &CouchHttpdAuth, user: &SessionUser) -> Vec<u8> {
+    [auth.secret.as_bytes(), user.salt.as_bytes()].concat()
+}
+
+/// Builds the `AuthSession` cookie value: `name:TIMESTAMP:hmac`, base64url encoded, where the
+/// timestamp is in upper case hex and the HMAC is SHA-256 as in CouchDB 3.
+pub fn make_cookie(auth: &CouchHttpdAuth, user: &SessionUser, timestamp: u64) -> String {
+    let message = format!("{}:{:X}", user.name, timestamp);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&signing_key(auth, user))
+        .expect("HMAC accepts keys of any size");
+    mac.update(message.as_bytes());
+
+    let mut cookie = message.into_bytes();
+    cookie.push(b':');
+    cookie.extend(mac.finalize().into_bytes());
+
+    URL_SAFE_NO_PAD.encode(cookie)
+}
+
+/// Checks an `AuthSession` cookie, returning the user and the time it was issued if it is valid
+/// and hasn't expired. Cookies signed with SHA-1, as older CouchDB releases do, are accepted too.
+pub fn verify_cookie<'a>(
+    auth: &'a CouchHttpdAuth,
+    cookie: &str,
+    now: u64,
+) -> Option<(&'a SessionUser, u64)> {
+    let cookie = URL_SAFE_NO_PAD.decode(cookie.trim_end_matches('=')).ok()?;
+    let mut parts = cookie.splitn(3, |b| *b == b':');
+
+    let name = std::str::from_utf8(parts.next()?).ok()?;
+    let time = std::str::from_utf8(parts.next()?).ok()?;
+    let hash = parts.next()?;
+
+    let timestamp = u64::from_str_radix(time, 16).ok()?;
+    if timestamp.saturating_add(auth.timeout) <= now {
+        return None;
+    }
+
+    let user = auth.users.iter().find(|u| u.name == name)?;
+    let key = signing_key(auth, user);
+    let message = format!("{}:{}", name, time);
+
+    let sha256 = Hmac::<Sha256>::new_from_slice(&key).map(|mut mac| {
+        mac.update(message.as_bytes());
+        mac.verify_slice(hash).is_ok()
+    });
+    let sha1 = Hmac::<Sha1>::new_from_slice(&key).map(|mut mac| {
+        mac.update(message.as_bytes());
+        mac.verify_slice(hash).is_ok()
+    });
+
+    if sha256.unwrap_or(false) || sha1.unwrap_or(false) {
+        Some((user, timestamp))
+    } else {
+        None
+    }
+}
+
+/// Checks a password against the PBKDF2 derived key copied from the user's CouchDB document.
+pub fn verify_password(user: &SessionUser, password: &str) -> bool {
+    let expected = match user.derived_key.as_deref().map(hex::decode) {
+        Some(Ok(expected)) => expected,
+        _ => return false,
+    };
+
+    let iterations = user.iterations.unwrap_or(DEFAULT_ITERATIONS);
+    let mut derived = vec![0u8; expected.len()];
+
+    match user.pbkdf2_prf.as_deref().unwrap_or("sha") {
+        "sha" => pbkdf2::pbkdf2::<Hmac<Sha1>>(
+            password.as_bytes(),
+            user.salt.as_bytes(),
+            iterations,
+            &mut derived,
+        ),
+        "sha256" => pbkdf2::pbkdf2::<Hmac<Sha256>>(
+            password.as_bytes(),
+            user.salt.as_bytes(),
+            iterations,
+            &mut derived,
+        ),
+        _ => return false,
+    }
+
+    derived
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+fn cookie_from_headers(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .map(|(_, value)| value)
+}
+
+fn set_cookie(auth: &CouchHttpdAuth, user: &SessionUser, timestamp: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{}={}; Version=1; Path=/; Max-Age={}; HttpOnly",
+        COOKIE_NAME,
+        make_cookie(auth, user, timestamp),
+        auth.timeout
+    ))
+    .expect("cookie values are base64")
+}
+
+/// Adds the user from a valid `AuthSession` cookie to the request, where `check_api_key` and
+/// `check_security` pick it up. Like CouchDB, a fresh cookie is sent once a tenth of the session
+/// has passed so active users stay logged in.
+pub async fn add_session_user(
+    State(state): State<Arc<AppState>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    let auth = match &state.couch_httpd_auth {
+        Some(auth) => auth,
+        None => return next.run(req).await,
+    };
+
+    let now = now();
+    let session = cookie_from_headers(req.headers()).and_then(|c| verify_cookie(auth, c, now));
+
+    let (user, timestamp) = match session {
+        Some(session) => session,
+        None => return next.run(req).await,
+    };
+
+    req.extensions_mut().insert(UserCtx {
+        name: Some(user.name.clone()),
+        roles: user.roles.clone(),
+    });
+    if let Some(tenant) = &user.tenant {
+        req.extensions_mut().insert(Tenant(tenant.clone()));
+    }
+
+    let mut res = next.run(req).await;
+
+    let time_left = (timestamp + auth.timeout).saturating_sub(now);
+    if (time_left as f64) < auth.timeout as f64 * 0.9 {
+        res.headers_mut()
+            .append(header::SET_COOKIE, set_cookie(auth, user, now));
+    }
+
+    res
+}
+
+fn session_not_configured() -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({"error": "not_found", "reason": "Session authentication is not configured."})),
+    )
+}
+
+/// `GET /_session` returns the user behind the request, as CouchDB does.
+pub async fn get_session(req: Request<Body>) -> Json<Value> {
+    let user_ctx = req.extensions().get::<UserCtx>().cloned();
+    let authenticated = user_ctx.is_some();
+
+    let mut body = json!({
+        "ok": true,
+        "userCtx": user_ctx.unwrap_or_default(),
+        "info": {
+            "authentication_handlers": ["cookie", "default"],
+        },
+    });
+
+    if authenticated {
+        body["info"]["authenticated"] = json!("cookie");
+    }
+
+    Json(body)
+}
+
+/// `POST /_session` logs in with a name and password, sent as JSON or a form, and sets the
+/// `AuthSession` cookie.
+pub async fn post_session(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let auth = state
+        .couch_httpd_auth
+        .as_ref()
+        .ok_or_else(session_not_configured)?;
+
+    let is_form = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.starts_with("application/x-www-form-urlencoded"));
+
+    let credentials: Value = if is_form {
+        url::form_urlencoded::parse(&body)
+            .map(|(k, v)| (k.to_string(), Value::String(v.to_string())))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "bad_request", "reason": e.to_string()})),
+            )
+        })?
+    };
+
+    let name = credentials["name"].as_str().unwrap_or_default();
+    let password = credentials["password"].as_str().unwrap_or_default();
+
+    let user = auth
+        .users
+        .iter()
+        .find(|u| u.name == name && verify_password(u, password))
+        .ok_or_else(|| unauthorized("Name or password is incorrect."))?;
+
+    let mut res = Json(json!({
+        "ok": true,
+        "name": user.name,
+        "roles": user.roles,
+    }))
+    .into_response();
+
+    res.headers_mut()
+        .insert(header::SET_COOKIE, set_cookie(auth, user, now()));
+
+    Ok(res)
+}
+
+/// `DELETE /_session` logs out by clearing the cookie.
+pub async fn delete_session(
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    state
+        .couch_httpd_auth
+        .as_ref()
+        .ok_or_else(session_not_configured)?;
+
+    let mut res = Json(json!({"ok": true})).into_response();
+    res.headers_mut().insert(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&format!(
+            "{}=; Version=1; Path=/; Max-Age=0; HttpOnly",
+            COOKIE_NAME
+        ))
+        .unwrap(),
+    );
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generated with the same scheme CouchDB uses, from Python's hmac and hashlib.
+    const SHA256_COOKIE: &str = "YWxpY2U6NjU1M0YxMDA61L4Pv8IY9osdrR3LlSpXvEYWMl_bJt0NJHXT80U44T8";
+    const SHA1_COOKIE: &str = "YWxpY2U6NjU1M0YxMDA6KO9f7xaqiAOC-vjeEgCkpWwIhbM";
+    const ISSUED: u64 = 1700000000;
+
+    fn auth() -> CouchHttpdAuth {
+        CouchHttpdAuth {
+            secret: "92de07df7e7a3fe14808cef90a7cc0d91".to_string(),
+            timeout: 600,
+            users: vec![SessionUser {
+                name: "alice".to_string(),
+                salt: "e7b6e3b1a5c44f4b0a0c33a1c6a1d6c4".to_string(),
+                roles: vec!["readers".to_string()],
+                derived_key: Some("f92b6432f86879b37a7eacc3873e586eeb93f2ad".to_string()),
+                iterations: Some(10),
+                pbkdf2_prf: None,
+                tenant: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_make_cookie_matches_couchdb() {
+        let auth = auth();
+        assert_eq!(make_cookie(&auth, &auth.users[0], ISSUED), SHA256_COOKIE);
+    }
+
+    #[test]
+    fn test_verify_cookie() {
+        let auth = auth();
+
+        let (user, timestamp) = verify_cookie(&auth, SHA256_COOKIE, ISSUED + 10).unwrap();
+        assert_eq!(user.name, "alice");
+        assert_eq!(timestamp, ISSUED);
+
+        assert!(verify_cookie(&auth, SHA1_COOKIE, ISSUED + 10).is_some());
+
+        // Expired
+        assert!(verify_cookie(&auth, SHA256_COOKIE, ISSUED + 600).is_none());
+
+        // Different secret
+        let other = CouchHttpdAuth {
+            secret: "something else".to_string(),
+            ..auth.clone()
+        };
+        assert!(verify_cookie(&other, SHA256_COOKIE, ISSUED + 10).is_none());
+
+        assert!(verify_cookie(&auth, "not a cookie", ISSUED).is_none());
+    }
+
+    #[test]
+    fn test_verify_password() {
+        let auth = auth();
+
+        assert!(verify_password(&auth.users[0], "wonderland"));
+        assert!(!verify_password(&auth.users[0], "looking-glass"));
+
+        let user = SessionUser {
+            derived_key: None,
+            ..auth.users[0].clone()
+        };
+        assert!(!verify_password(&user, "wonderland"));
+    }
+
+    #[test]
+    fn test_cookie_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; AuthSession=abc123; other=1"),
+        );
+
+        assert_eq!(cookie_from_headers(&headers), Some("abc123"));
+        assert_eq!(cookie_from_headers(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_session_users_stay_in_their_tenant() {
+        use crate::config::TenancySettings;
+        use crate::db::MockDatabase;
+        use crate::tenancy::{current_tenant, scope_tenant};
+        use axum::routing::get;
+        use axum::{middleware, Router};
+        use tower::ServiceExt;
+
+        let settings = Some(Arc::new(TenancySettings {
+            separator: "_".to_string(),
+            databases: Default::default(),
+        }));
+        let status = |tenant: Option<&str>, uri: &'static str| {
+            let mut auth = auth();
+            auth.users[0].tenant = tenant.map(str::to_string);
+            let cookie = format!("AuthSession={}", make_cookie(&auth, &auth.users[0], now()));
+
+            let state = Arc::new(AppState {
+                couch_httpd_auth: Some(auth),
+                ..AppState::for_tests(MockDatabase::new())
+            });
+            let app = Router::new()
+                .route(
+                    "/:db",
+                    get(|| async { current_tenant().unwrap_or_default() }),
+                )
+                .layer(middleware::from_fn_with_state(
+                    settings.clone(),
+                    scope_tenant,
+                ))
+                .layer(middleware::from_fn_with_state(state, add_session_user));
+
+            async move {
+                let req = Request::builder()
+                    .uri(uri)
+                    .header(header::COOKIE, cookie)
+                    .body(Body::empty())
+                    .unwrap();
+                app.oneshot(req).await.unwrap().status()
+            }
+        };
+
+        // Without a tenant, `globex_orders` would be globex's collection by its full name.
+        assert_eq!(status(None, "/globex_orders").await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            status(Some("acme"), "/globex_orders").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(status(Some("acme"), "/orders").await, StatusCode::OK);
+    }
+}
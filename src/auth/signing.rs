@@ -0,0 +1,289 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HMAC request signing for internal services. A signed request carries three headers:
+//!
+//! * `X-Couchapi-Service`: the name of the calling service.
+//! * `X-Couchapi-Timestamp`: the time the request was signed, in seconds since the epoch.
+//! * `X-Couchapi-Signature`: the hex encoded HMAC-SHA256, keyed by the service's secret, of the
+//!   method, path and query, and timestamp, each followed by a newline, then the body.
+
+use crate::auth::{unauthorized, UserCtx};
+use crate::config::{RequestSigning, SigningService};
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use crate::tenancy::Tenant;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Json;
+use hmac::{Hmac, Mac};
+use http_body_util::BodyExt;
+use serde_json::json;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+pub const SERVICE_HEADER: &str = "X-Couchapi-Service";
+pub const TIMESTAMP_HEADER: &str = "X-Couchapi-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Couchapi-Signature";
+
+/// The parts of a request covered by its signature.
+pub struct SignedContent<'a> {
+    pub method: &'a Method,
+    pub path_and_query: &'a str,
+    pub timestamp: &'a str,
+    pub body: &'a [u8],
+}
+
+fn mac_for(service: &SigningService, content: &SignedContent) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(service.secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(
+        format!(
+            "{}\n{}\n{}\n",
+            content.method, content.path_and_query, content.timestamp
+        )
+        .as_bytes(),
+    );
+    mac.update(content.body);
+    mac
+}
+
+/// Checks a signature, returning the service that made it if it is valid and recent.
+pub fn verify<'a>(
+    signing: &'a RequestSigning,
+    service_name: &str,
+    signature: &str,
+    content: &SignedContent,
+    now: u64,
+) -> Option<&'a SigningService> {
+    let signed_at = content.timestamp.parse::<u64>().ok()?;
+    if now.abs_diff(signed_at) > signing.max_clock_skew {
+        return None;
+    }
+
+    let service = signing.services.iter().find(|s| s.name == service_name)?;
+    let signature = hex::decode(signature).ok()?;
+
+    mac_for(service, content)
+        .verify_slice(&signature)
+        .ok()
+        .map(|_| service)
+}
+
+/// Validates signed requests and adds the service to the request as its user. Unsigned requests
+/// are passed through for the other authentication methods to deal with, while a bad signature is
+/// rejected outright.
+pub async fn check_request_signature(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let signing = match &state.request_signing {
+        Some(signing) => signing,
+        None => return Ok(next.run(req).await),
+    };
+
+    let headers = [SERVICE_HEADER, TIMESTAMP_HEADER, SIGNATURE_HEADER].map(|name| {
+        req.headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string())
+    });
+
+    let (service_name, timestamp, signature) = match headers {
+        [Some(service), Some(timestamp), Some(signature)] => (service, timestamp, signature),
+        [None, None, None] => return Ok(next.run(req).await),
+        _ => return Err(unauthorized("Incomplete request signature.")),
+    };
+
+    let (mut parts, body) = req.into_parts();
+    let body = body
+        .collect()
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "bad_request", "reason": e.to_string()})),
+            )
+        })?
+        .to_bytes();
+
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let content = SignedContent {
+        method: &parts.method,
+        path_and_query,
+        timestamp: &timestamp,
+        body: &body,
+    };
+
+    let service = verify(signing, &service_name, &signature, &content, now).ok_or_else(|| {
+        warn!(service = service_name.as_str(), "invalid request signature");
+        unauthorized("Invalid request signature.")
+    })?;
+
+    parts.extensions.insert(UserCtx {
+        name: Some(service.name.clone()),
+        roles: service.roles.clone(),
+    });
+    if let Some(tenant) = &service.tenant {
+        parts.extensions.insert(Tenant(tenant.clone()));
+    }
+
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use axum::routing::put;
+    use axum::{middleware, Extension, Router};
+    use tokio::net::TcpListener;
+
+    /// Signs a request the way callers are expected to, returning the hex encoded signature.
+    fn sign(service: &SigningService, content: &SignedContent) -> String {
+        hex::encode(mac_for(service, content).finalize().into_bytes())
+    }
+
+    fn signing() -> RequestSigning {
+        RequestSigning {
+            max_clock_skew: 300,
+            services: vec![SigningService {
+                name: "billing".to_string(),
+                secret: "shared-secret".to_string(),
+                roles: vec!["writers".to_string()],
+                tenant: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let signing = signing();
+        let content = |method, path_and_query, body| SignedContent {
+            method,
+            path_and_query,
+            timestamp: "1000",
+            body,
+        };
+        let signature = sign(
+            &signing.services[0],
+            &content(&Method::PUT, "/db/doc?w=2", b"{}"),
+        );
+
+        let check = |content: SignedContent, now: u64| {
+            verify(&signing, "billing", &signature, &content, now).map(|s| s.name.as_str())
+        };
+
+        assert_eq!(
+            check(content(&Method::PUT, "/db/doc?w=2", b"{}"), 1100),
+            Some("billing")
+        );
+
+        // Tampered body, path and method
+        assert!(check(content(&Method::PUT, "/db/doc?w=2", b"{\"a\":1}"), 1100).is_none());
+        assert!(check(content(&Method::PUT, "/db/other?w=2", b"{}"), 1100).is_none());
+        assert!(check(content(&Method::DELETE, "/db/doc?w=2", b"{}"), 1100).is_none());
+
+        // Too old
+        assert!(check(content(&Method::PUT, "/db/doc?w=2", b"{}"), 1301).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_check_request_signature() {
+        async fn handler(user_ctx: Option<Extension<UserCtx>>) -> String {
+            user_ctx
+                .and_then(|Extension(u)| u.name)
+                .unwrap_or_else(|| "anonymous".to_string())
+        }
+
+        let state = Arc::new(AppState {
+            request_signing: Some(signing()),
+            ..AppState::for_tests(MockDatabase::new())
+        });
+
+        let app = Router::new()
+            .route("/:db/:item", put(handler))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                check_request_signature,
+            ))
+            .with_state(state);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/db/doc", addr);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        let signature = sign(
+            &signing().services[0],
+            &SignedContent {
+                method: &Method::PUT,
+                path_and_query: "/db/doc",
+                timestamp: &timestamp,
+                body: b"{}",
+            },
+        );
+
+        let res = client
+            .put(&url)
+            .header(SERVICE_HEADER, "billing")
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .header(SIGNATURE_HEADER, &signature)
+            .body("{}")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::OK.as_u16());
+        assert_eq!(res.text().await.unwrap(), "billing");
+
+        let res = client
+            .put(&url)
+            .header(SERVICE_HEADER, "billing")
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .header(SIGNATURE_HEADER, &signature)
+            .body("{\"changed\": true}")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::UNAUTHORIZED.as_u16());
+
+        let res = client.put(&url).body("{}").send().await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "anonymous");
+    }
+}
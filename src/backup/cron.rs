@@ -0,0 +1,200 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, Datelike, Duration, DurationRound, NaiveDate, TimeZone, Timelike, Utc};
+use std::str::FromStr;
+
+/// A five field cron expression, `minute hour day-of-month month day-of-week`, in UTC. Each field
+/// is `*`, a number, a range such as `1-5` or a list of them, and may have a step such as `*/15`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+/// Parses one field into the values it allows, indexed by value.
+fn field(spec: &str, name: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut allowed = vec![false; max as usize + 1];
+    let number = |s: &str| {
+        s.parse::<u32>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .ok_or_else(|| format!("{} must be from {} to {}, not {}", name, min, max, s))
+    };
+
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("{} has a bad step: {}", name, part)),
+            },
+            None => (part, 1),
+        };
+
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (number(first)?, number(last)?),
+            // A single value with a step runs to the end, as `5/15` does in most crons.
+            None if step > 1 => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if first > last {
+            return Err(format!("{} has a backwards range: {}", name, part));
+        }
+
+        for value in (first..=last).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+
+    Ok(allowed)
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            return Err(format!(
+                "expected five fields in the schedule, found {}",
+                fields.len()
+            ));
+        };
+
+        // Sunday is both 0 and 7.
+        let mut days_of_week = field(day_of_week, "day of week", 0, 7)?;
+        days_of_week[0] |= days_of_week[7];
+        days_of_week.truncate(7);
+
+        Ok(Schedule {
+            minutes: field(minute, "minute", 0, 59)?,
+            hours: field(hour, "hour", 0, 23)?,
+            days_of_month: field(day_of_month, "day of month", 1, 31)?,
+            months: field(month, "month", 1, 12)?,
+            days_of_week,
+            any_day_of_month: day_of_month == "*",
+            any_day_of_week: day_of_week == "*",
+        })
+    }
+}
+
+impl Schedule {
+    /// As in cron, when both day fields are restricted a day matching either will do.
+    fn runs_on(&self, date: NaiveDate) -> bool {
+        let day_of_month = self.days_of_month[date.day() as usize];
+        let day_of_week = self.days_of_week[date.weekday().num_days_from_sunday() as usize];
+
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (false, true) => day_of_month,
+            (true, false) => day_of_week,
+            (false, false) => day_of_month || day_of_week,
+        }
+    }
+
+    /// The first time the schedule runs after `after`, or `None` if it never does, as with
+    /// `0 0 31 2 *`.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut time = after.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+
+        // Whole months, days and hours that can't match are skipped, so a few years of searching
+        // takes a few thousand steps at most.
+        let end = time + Duration::days(5 * 366);
+        while time < end {
+            if !self.months[time.month() as usize] {
+                let (year, month) = match time.month() {
+                    12 => (time.year() + 1, 1),
+                    month => (time.year(), month + 1),
+                };
+                time = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+            } else if !self.runs_on(time.date_naive()) {
+                time = Utc.from_utc_datetime(&time.date_naive().succ_opt()?.and_hms_opt(0, 0, 0)?);
+            } else if !self.hours[time.hour() as usize] {
+                time = time.duration_trunc(Duration::hours(1)).ok()? + Duration::hours(1);
+            } else if !self.minutes[time.minute() as usize] {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn next(expression: &str, after: &str) -> Option<DateTime<Utc>> {
+        expression
+            .parse::<Schedule>()
+            .unwrap()
+            .next_after(at(after))
+    }
+
+    #[test]
+    fn test_next_after() {
+        assert_eq!(
+            next("0 3 * * *", "2024-05-01T03:00:00Z"),
+            Some(at("2024-05-02T03:00:00Z"))
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2024-05-01T10:07:30Z"),
+            Some(at("2024-05-01T10:15:00Z"))
+        );
+        assert_eq!(
+            next("30 1 1 * *", "2024-12-15T00:00:00Z"),
+            Some(at("2025-01-01T01:30:00Z"))
+        );
+        // 2024-05-04 is a Saturday, so the next weekday is the Monday.
+        assert_eq!(
+            next("0 9 * * 1-5", "2024-05-04T08:00:00Z"),
+            Some(at("2024-05-06T09:00:00Z"))
+        );
+        // Either day field will do when both are restricted: the 10th or a Sunday.
+        assert_eq!(
+            next("0 0 10 * 7", "2024-05-01T00:00:00Z"),
+            Some(at("2024-05-05T00:00:00Z"))
+        );
+        assert_eq!(next("0 0 31 2 *", "2024-01-01T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn test_bad_schedules() {
+        for expression in [
+            "0 3 * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "x * * * *",
+        ] {
+            assert!(
+                expression.parse::<Schedule>().is_err(),
+                "{} should not parse",
+                expression
+            );
+        }
+    }
+}
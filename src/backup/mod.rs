@@ -0,0 +1,248 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod cron;
+mod s3;
+
+use crate::config::BackupSettings;
+use crate::ops::snapshot::ExportCursor;
+use crate::state::AppState;
+use chrono::Utc;
+use cron::Schedule;
+use s3::S3Client;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// The zstd level snapshots are compressed at.
+const SNAPSHOT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Where one database's snapshots go.
+struct Destination {
+    bucket: String,
+    prefix: String,
+    retention: usize,
+}
+
+/// Exports a database with its attachments, compresses it and uploads it, then deletes the oldest
+/// snapshots beyond the retention. Returns the size of the snapshot uploaded.
+async fn back_up(
+    state: &Arc<AppState>,
+    client: &S3Client,
+    db: &str,
+    destination: &Destination,
+) -> Result<usize, String> {
+    let task = state.active_tasks.start("backup", db);
+
+    let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), SNAPSHOT_COMPRESSION_LEVEL)
+        .map_err(|e| e.to_string())?;
+    let mut cursor = ExportCursor::new(state.clone(), db, true);
+    let mut exported = 0;
+    while let Some(batch) = cursor.next_batch().await {
+        let batch = batch.map_err(|e| e.to_string())?;
+        encoder.write_all(&batch).map_err(|e| e.to_string())?;
+        exported += batch.len();
+        task.update(serde_json::json!({ "bytes_exported": exported }));
+    }
+    let snapshot = encoder.finish().map_err(|e| e.to_string())?;
+    let size = snapshot.len();
+
+    // Snapshots are named after when they were taken, so their keys sort oldest first.
+    let folder = format!("{}{}/", destination.prefix, db);
+    let key = format!(
+        "{}{}.ndjson.zst",
+        folder,
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    );
+    client
+        .put_object(&destination.bucket, &key, snapshot)
+        .await?;
+
+    let mut keys = client.list_keys(&destination.bucket, &folder).await?;
+    keys.sort();
+    let expired = keys.len().saturating_sub(destination.retention);
+    for key in &keys[..expired] {
+        client.delete_object(&destination.bucket, key).await?;
+    }
+
+    info!(database = db, key, bytes = size, "backed up database");
+    Ok(size)
+}
+
+/// Backs each configured database up on its schedule, recording how each backup went.
+pub fn spawn_backups(state: Arc<AppState>, settings: BackupSettings) -> Result<(), String> {
+    settings.validate()?;
+    let client = Arc::new(S3Client::new(&settings)?);
+
+    for (db, backup) in settings.databases {
+        let schedule = backup.schedule.parse::<Schedule>()?;
+        let destination = Destination {
+            bucket: backup.bucket.or(settings.bucket.clone()).unwrap(),
+            prefix: settings.prefix.clone(),
+            retention: backup.retention,
+        };
+        let state = state.clone();
+        let client = client.clone();
+
+        tokio::spawn(async move {
+            while let Some(next) = schedule.next_after(Utc::now()) {
+                let wait = (next - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+
+                let start = Instant::now();
+                let result = back_up(&state, &client, &db, &destination).await;
+                let status = if result.is_ok() { "ok" } else { "error" };
+                let labels = [("database", db.clone()), ("status", status.to_string())];
+
+                metrics::increment_counter!("couchapi_backups_total", &labels);
+                metrics::histogram!(
+                    "couchapi_backup_duration_seconds",
+                    start.elapsed().as_secs_f64(),
+                    &labels
+                );
+
+                match result {
+                    Ok(size) => {
+                        let labels = [("database", db.clone())];
+                        metrics::gauge!("couchapi_backup_size_bytes", size as f64, &labels);
+                        metrics::gauge!(
+                            "couchapi_backup_last_success_timestamp_seconds",
+                            Utc::now().timestamp() as f64,
+                            &labels
+                        );
+                    }
+                    Err(e) => warn!(database = db, error = e, "unable to back up database"),
+                }
+            }
+
+            warn!(database = db, "backup schedule never runs again");
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseBackup;
+    use crate::db::MockDatabase;
+    use bson::{doc, RawDocumentBuf};
+    use httpmock::MockServer;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_back_up() {
+        let server = MockServer::start_async().await;
+
+        let upload = server
+            .mock_async(|when, then| {
+                when.method(httpmock::Method::PUT)
+                    .path_contains("/backups/prod/orders/")
+                    .header_exists("authorization")
+                    .header_exists("x-amz-date");
+                then.status(200);
+            })
+            .await;
+        let list = server
+            .mock_async(|when, then| {
+                when.method(httpmock::Method::GET)
+                    .path("/backups")
+                    .query_param("list-type", "2")
+                    .query_param("prefix", "prod/orders/");
+                then.status(200).body(
+                    "<ListBucketResult><IsTruncated>false</IsTruncated><Contents><Key>prod/orders/\
+                     20240503T030000Z.ndjson.zst</Key></Contents><Contents><Key>prod/orders/\
+                     20240501T030000Z.ndjson.zst</Key></Contents><Contents><Key>prod/orders/\
+                     20240502T030000Z.ndjson.zst</Key></Contents></ListBucketResult>",
+                );
+            })
+            .await;
+        let delete = server
+            .mock_async(|when, then| {
+                when.method(httpmock::Method::DELETE)
+                    .path("/backups/prod/orders/20240501T030000Z.ndjson.zst");
+                then.status(204);
+            })
+            .await;
+
+        let mut mock = MockDatabase::new();
+        mock.expect_aggregate().returning(|_, _, _| {
+            let row = RawDocumentBuf::from_document(&doc! { "_id": "a", "_rev": "1-a" }).unwrap();
+            Box::pin(async move { Ok(vec![row]) })
+        });
+        let state = Arc::new(AppState::for_tests(mock));
+
+        let settings = BackupSettings {
+            endpoint: server.base_url(),
+            region: "eu-west-1".to_string(),
+            bucket: Some("backups".to_string()),
+            prefix: "prod/".to_string(),
+            access_key_id: "key".to_string(),
+            secret_access_key: "secret".to_string(),
+            databases: HashMap::new(),
+        };
+        let destination = Destination {
+            bucket: "backups".to_string(),
+            prefix: "prod/".to_string(),
+            retention: 2,
+        };
+
+        let size = back_up(
+            &state,
+            &S3Client::new(&settings).unwrap(),
+            "orders",
+            &destination,
+        )
+        .await
+        .unwrap();
+        assert!(size > 0);
+
+        upload.assert_async().await;
+        list.assert_async().await;
+        delete.assert_async().await;
+        assert!(state.active_tasks.list().is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut settings = BackupSettings {
+            endpoint: "https://s3.eu-west-1.amazonaws.com".to_string(),
+            region: "eu-west-1".to_string(),
+            bucket: None,
+            prefix: String::new(),
+            access_key_id: "key".to_string(),
+            secret_access_key: "secret".to_string(),
+            databases: HashMap::from([(
+                "orders".to_string(),
+                DatabaseBackup {
+                    schedule: "0 3 * * *".to_string(),
+                    bucket: None,
+                    retention: 7,
+                },
+            )]),
+        };
+        assert_eq!(
+            settings.validate().unwrap_err(),
+            "orders: no bucket to back up to"
+        );
+
+        settings.bucket = Some("backups".to_string());
+        assert!(settings.validate().is_ok());
+
+        settings.databases.get_mut("orders").unwrap().schedule = "0 3 * *".to_string();
+        assert!(settings.validate().is_err());
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::BackupSettings;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::Method;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// Just enough of the S3 API for backups, signed with AWS Signature Version 4. Buckets are
+/// addressed by path, which every S3 compatible store accepts.
+pub struct S3Client {
+    client: reqwest::Client,
+    endpoint: Url,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+/// Percent encodes as SigV4 expects: everything but the unreserved characters, and `/` too
+/// unless it's a path.
+fn uri_encode(s: &str, path: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if path => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The key requests are signed with, derived from the secret for the day, region and service.
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    hmac(&key, "aws4_request")
+}
+
+/// Returns the text of each `<name>` element, for the small XML responses S3 sends.
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|rest| rest.split_once(close.as_str()).map(|(text, _)| text))
+        .collect()
+}
+
+/// Undoes the escaping XML gives text, for keys holding `&` and the like.
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+impl S3Client {
+    pub fn new(settings: &BackupSettings) -> Result<Self, String> {
+        let endpoint = Url::parse(&settings.endpoint)
+            .map_err(|e| format!("invalid endpoint {}: {}", settings.endpoint, e))?;
+        if endpoint.host_str().is_none() {
+            return Err(format!("endpoint {} has no host", settings.endpoint));
+        }
+
+        Ok(S3Client {
+            client: reqwest::Client::new(),
+            endpoint,
+            region: settings.region.clone(),
+            access_key_id: settings.access_key_id.clone(),
+            secret_access_key: settings.secret_access_key.clone(),
+        })
+    }
+
+    pub async fn put_object(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<(), String> {
+        self.send(Method::PUT, bucket, key, &[], body).await?;
+        Ok(())
+    }
+
+    pub async fn delete_object(&self, bucket: &str, key: &str) -> Result<(), String> {
+        self.send(Method::DELETE, bucket, key, &[], vec![]).await?;
+        Ok(())
+    }
+
+    /// Every key in the bucket starting with `prefix`, following continuation tokens.
+    pub async fn list_keys(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, String> {
+        let mut keys = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let mut query = vec![
+                ("list-type", "2".to_string()),
+                ("prefix", prefix.to_string()),
+            ];
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token", token.clone()));
+            }
+            let query = query
+                .iter()
+                .map(|(k, v)| (*k, v.as_str()))
+                .collect::<Vec<_>>();
+
+            let xml = self.send(Method::GET, bucket, "", &query, vec![]).await?;
+            keys.extend(elements(&xml, "Key").into_iter().map(unescape));
+
+            continuation_token = elements(&xml, "NextContinuationToken")
+                .first()
+                .map(|token| unescape(token));
+            if elements(&xml, "IsTruncated").first() != Some(&"true")
+                || continuation_token.is_none()
+            {
+                return Ok(keys);
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        bucket: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<String, String> {
+        let base = self.endpoint.path().trim_end_matches('/');
+        let path = match key {
+            "" => format!("{}/{}", base, uri_encode(bucket, true)),
+            key => format!(
+                "{}/{}/{}",
+                base,
+                uri_encode(bucket, true),
+                uri_encode(key, true)
+            ),
+        };
+
+        let mut pairs = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k, false), uri_encode(v, false)))
+            .collect::<Vec<_>>();
+        pairs.sort();
+        let query_string = pairs.join("&");
+
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap(), port),
+            None => self.endpoint.host_str().unwrap().to_string(),
+        };
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let authorization = self.authorization(
+            &method,
+            &path,
+            &query_string,
+            &host,
+            &payload_hash,
+            Utc::now(),
+        );
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        url.set_query((!query_string.is_empty()).then_some(query_string.as_str()));
+
+        let response = self
+            .client
+            .request(method.clone(), url)
+            .header("x-amz-content-sha256", &payload_hash)
+            .header("x-amz-date", authorization.1)
+            .header("authorization", authorization.0)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| format!("{} {}/{}: {}", method, bucket, key, e))?;
+
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            let code = elements(&text, "Code").first().copied().unwrap_or("");
+            return Err(format!(
+                "{} {}/{}: {} {}",
+                method, bucket, key, status, code
+            ));
+        }
+
+        Ok(text)
+    }
+
+    /// The `Authorization` header for a request and the `x-amz-date` it was signed for.
+    fn authorization(
+        &self,
+        method: &Method,
+        path: &str,
+        query_string: &str,
+        host: &str,
+        payload_hash: &str,
+        now: DateTime<Utc>,
+    ) -> (String, String) {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query_string, host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.secret_access_key, &date, &self.region, "s3");
+        let signature = hex::encode(hmac(&key, &string_to_sign));
+
+        (
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key_id, scope, signed_headers, signature
+            ),
+            amz_date,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing_key() {
+        // The example from AWS's documentation on deriving a signing key.
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(
+            uri_encode("prod/orders/a b+c.zst", true),
+            "prod/orders/a%20b%2Bc.zst"
+        );
+        assert_eq!(uri_encode("prod/orders/", false), "prod%2Forders%2F");
+    }
+
+    #[test]
+    fn test_elements() {
+        let xml = "<ListBucketResult><IsTruncated>false</IsTruncated><Contents><Key>a/1</Key></\
+                   Contents><Contents><Key>a/&amp;2</Key></Contents></ListBucketResult>";
+
+        assert_eq!(elements(xml, "Key"), vec!["a/1", "a/&amp;2"]);
+        assert_eq!(unescape(elements(xml, "Key")[1]), "a/&2");
+        assert_eq!(elements(xml, "IsTruncated"), vec!["false"]);
+        assert!(elements(xml, "NextContinuationToken").is_empty());
+    }
+}
@@ -0,0 +1,466 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use clap::ValueEnum;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, RequestBuilder};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Prefix of the ids of documents the bench writes, so they're easy to find and clear up.
+const ID_PREFIX: &str = "couchapi-bench-";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Workload {
+    /// Read documents written before the run starts.
+    Read,
+
+    /// Write a new document per request.
+    Write,
+
+    /// Write `bulk_size` new documents per request through `_bulk_docs`.
+    Bulk,
+
+    /// Query `view`, picking its parameters from `param` for each request.
+    View,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    /// Where the server is listening.
+    #[arg(long, default_value = "http://localhost:5984")]
+    url: String,
+
+    /// The database to run against.
+    #[arg(long)]
+    db: String,
+
+    #[arg(long, value_enum, default_value = "read")]
+    workload: Workload,
+
+    /// How many requests to make.
+    #[arg(long, default_value_t = 1000)]
+    requests: usize,
+
+    /// How many requests to have in flight at once.
+    #[arg(long, default_value_t = 10)]
+    concurrency: usize,
+
+    /// How many documents to write for the read workload to read back.
+    #[arg(long, default_value_t = 100)]
+    docs: usize,
+
+    /// Documents in each `_bulk_docs` request.
+    #[arg(long, default_value_t = 100)]
+    bulk_size: usize,
+
+    /// Bytes of filler in each document written.
+    #[arg(long, default_value_t = 256)]
+    doc_size: usize,
+
+    /// The view to query, as `design/view`.
+    #[arg(long)]
+    view: Option<String>,
+
+    /// A view parameter, picked afresh for each request. `name=a,b,c` picks one of the values and
+    /// `name=1..100` an integer from the range. Values are sent as given, so JSON strings need
+    /// their quotes, e.g. `key="order"`.
+    #[arg(long = "param")]
+    params: Vec<String>,
+
+    /// A header for every request, as `Name: value`, e.g. for an API key.
+    #[arg(long = "header")]
+    headers: Vec<String>,
+}
+
+/// How a view parameter's value is picked.
+#[derive(Debug, PartialEq)]
+enum Distribution {
+    OneOf(Vec<String>),
+
+    /// An integer from `start` up to, but not including, `end`.
+    Range(i64, i64),
+}
+
+impl Distribution {
+    fn pick(&self) -> String {
+        match self {
+            Distribution::OneOf(values) => {
+                values[random_below(values.len() as u64) as usize].clone()
+            }
+            Distribution::Range(start, end) => {
+                (start + random_below((end - start) as u64) as i64).to_string()
+            }
+        }
+    }
+}
+
+fn parse_param(param: &str) -> Result<(String, Distribution), String> {
+    let (name, spec) = param
+        .split_once('=')
+        .ok_or_else(|| format!("{}: expected name=values", param))?;
+
+    if let Some((start, end)) = spec.split_once("..") {
+        if let (Ok(start), Ok(end)) = (start.parse::<i64>(), end.parse::<i64>()) {
+            if start >= end {
+                return Err(format!("{}: the range is empty", param));
+            }
+            return Ok((name.to_string(), Distribution::Range(start, end)));
+        }
+    }
+
+    let values: Vec<String> = spec.split(',').map(str::to_string).collect();
+    Ok((name.to_string(), Distribution::OneOf(values)))
+}
+
+pub fn parse_headers(headers: &[String]) -> Result<HeaderMap, String> {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| format!("{}: expected Name: value", header))?;
+        let name = HeaderName::try_from(name.trim()).map_err(|e| e.to_string())?;
+        let value = HeaderValue::try_from(value.trim()).map_err(|e| e.to_string())?;
+        map.append(name, value);
+    }
+    Ok(map)
+}
+
+/// A random number below `n`, which must be more than zero.
+fn random_below(n: u64) -> u64 {
+    (Uuid::new_v4().as_u128() % u128::from(n)) as u64
+}
+
+/// What one request did: how long it took and the status, or `None` when it never got one.
+struct Sample {
+    latency: Duration,
+    status: Option<u16>,
+}
+
+/// Everything a request needs, shared by the workers.
+struct Bench {
+    client: Client,
+    base: String,
+    workload: Workload,
+    docs: usize,
+    bulk_size: usize,
+    filler: String,
+    view_path: Option<String>,
+    params: Vec<(String, Distribution)>,
+}
+
+impl Bench {
+    fn new_doc(&self) -> Value {
+        json!({ "_id": format!("{}{}", ID_PREFIX, Uuid::new_v4().simple()), "filler": self.filler })
+    }
+
+    fn request(&self) -> RequestBuilder {
+        match self.workload {
+            Workload::Read => {
+                let n = random_below(self.docs as u64);
+                self.client.get(format!("{}/{}{}", self.base, ID_PREFIX, n))
+            }
+            Workload::Write => {
+                let doc = self.new_doc();
+                self.client
+                    .put(format!("{}/{}", self.base, doc["_id"].as_str().unwrap()))
+                    .json(&doc)
+            }
+            Workload::Bulk => {
+                let docs: Vec<Value> = (0..self.bulk_size).map(|_| self.new_doc()).collect();
+                self.client
+                    .post(format!("{}/_bulk_docs", self.base))
+                    .json(&json!({ "docs": docs }))
+            }
+            Workload::View => {
+                let query: Vec<(&str, String)> = self
+                    .params
+                    .iter()
+                    .map(|(name, distribution)| (name.as_str(), distribution.pick()))
+                    .collect();
+                self.client
+                    .get(self.view_path.as_deref().unwrap_or_default())
+                    .query(&query)
+            }
+        }
+    }
+
+    /// Writes the documents the read workload reads. Ones left by an earlier run are reused.
+    async fn seed(&self) -> Result<(), String> {
+        for n in 0..self.docs {
+            let res = self
+                .client
+                .put(format!("{}/{}{}", self.base, ID_PREFIX, n))
+                .json(&json!({ "filler": self.filler }))
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let status = res.status();
+            if !status.is_success() && status != reqwest::StatusCode::CONFLICT {
+                return Err(format!("unable to write {}{}: {}", ID_PREFIX, n, status));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the `bench` subcommand against a running server and returns the process exit code.
+pub fn run_bench(args: &BenchArgs) -> i32 {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("unable to start the runtime");
+
+    match runtime.block_on(bench(args)) {
+        Ok(report) => {
+            print!("{}", report);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+async fn bench(args: &BenchArgs) -> Result<String, String> {
+    let base = format!("{}/{}", args.url.trim_end_matches('/'), args.db);
+
+    let view_path = match (args.workload, &args.view) {
+        (Workload::View, Some(view)) => {
+            let (design, view) = view
+                .split_once('/')
+                .ok_or_else(|| format!("{}: expected design/view", view))?;
+            Some(format!("{}/_design/{}/_view/{}", base, design, view))
+        }
+        (Workload::View, None) => return Err("the view workload needs --view".to_string()),
+        _ => None,
+    };
+
+    if args.workload == Workload::Read && args.docs == 0 {
+        return Err("the read workload needs --docs of at least 1".to_string());
+    }
+
+    let params = args
+        .params
+        .iter()
+        .map(|p| parse_param(p))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let client = Client::builder()
+        .default_headers(parse_headers(&args.headers)?)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let bench = Arc::new(Bench {
+        client,
+        base,
+        workload: args.workload,
+        docs: args.docs,
+        bulk_size: args.bulk_size,
+        filler: "x".repeat(args.doc_size),
+        view_path,
+        params,
+    });
+
+    if args.workload == Workload::Read {
+        bench.seed().await?;
+    }
+
+    let next = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+
+    let workers: Vec<_> = (0..args.concurrency.max(1))
+        .map(|_| {
+            let bench = bench.clone();
+            let next = next.clone();
+            let requests = args.requests;
+
+            tokio::spawn(async move {
+                let mut samples = Vec::new();
+                while next.fetch_add(1, Ordering::Relaxed) < requests {
+                    let start = Instant::now();
+                    let status = match bench.request().send().await {
+                        // Read the body too, as serializing it is part of what's measured
+                        Ok(res) => {
+                            let status = res.status().as_u16();
+                            res.bytes().await.ok().map(|_| status)
+                        }
+                        Err(_) => None,
+                    };
+                    samples.push(Sample {
+                        latency: start.elapsed(),
+                        status,
+                    });
+                }
+                samples
+            })
+        })
+        .collect();
+
+    let mut samples = Vec::with_capacity(args.requests);
+    for worker in workers {
+        samples.extend(worker.await.map_err(|e| e.to_string())?);
+    }
+
+    Ok(report(args, &samples, started.elapsed()))
+}
+
+/// The latency at or below which `p` percent of requests finished, from sorted latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn report(args: &BenchArgs, samples: &[Sample], elapsed: Duration) -> String {
+    let mut latencies: Vec<Duration> = samples.iter().map(|s| s.latency).collect();
+    latencies.sort();
+
+    let mut statuses = BTreeMap::new();
+    for sample in samples {
+        let status = match sample.status {
+            Some(status) => status.to_string(),
+            None => "failed".to_string(),
+        };
+        *statuses.entry(status).or_insert(0) += 1;
+    }
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let mut out = format!(
+        "workload: {:?}, requests: {}, concurrency: {}\n",
+        args.workload,
+        samples.len(),
+        args.concurrency
+    )
+    .to_lowercase();
+
+    out += &format!(
+        "elapsed: {:.2}s, {:.1} requests/s\n",
+        elapsed.as_secs_f64(),
+        samples.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    out += &format!(
+        "latency ms: p50 {:.2}, p90 {:.2}, p99 {:.2}, p99.9 {:.2}, max {:.2}\n",
+        ms(percentile(&latencies, 50.0)),
+        ms(percentile(&latencies, 90.0)),
+        ms(percentile(&latencies, 99.0)),
+        ms(percentile(&latencies, 99.9)),
+        ms(latencies.last().copied().unwrap_or_default()),
+    );
+    for (status, count) in statuses {
+        out += &format!("status {}: {}\n", status, count);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        bench: BenchArgs,
+    }
+
+    #[test]
+    fn test_parse_param() {
+        assert_eq!(
+            parse_param("key=1..10"),
+            Ok(("key".to_string(), Distribution::Range(1, 10)))
+        );
+        assert_eq!(
+            parse_param("key=\"a\",\"b\""),
+            Ok((
+                "key".to_string(),
+                Distribution::OneOf(vec!["\"a\"".to_string(), "\"b\"".to_string()])
+            ))
+        );
+        // Not a range of integers, so it's taken as a value.
+        assert_eq!(
+            parse_param("startkey=a..b"),
+            Ok((
+                "startkey".to_string(),
+                Distribution::OneOf(vec!["a..b".to_string()])
+            ))
+        );
+        assert!(parse_param("key=5..5").is_err());
+        assert!(parse_param("key").is_err());
+    }
+
+    #[test]
+    fn test_pick() {
+        for _ in 0..100 {
+            let n: i64 = Distribution::Range(-2, 3).pick().parse().unwrap();
+            assert!((-2..3).contains(&n));
+        }
+
+        let one_of = Distribution::OneOf(vec!["a".to_string(), "b".to_string()]);
+        assert!(["a", "b"].contains(&one_of.pick().as_str()));
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        let headers = parse_headers(&["X-Api-Key: abc".to_string()]).unwrap();
+        assert_eq!(headers["x-api-key"], "abc");
+        assert!(parse_headers(&["no colon".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_percentile() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 99.9), Duration::from_millis(100));
+        assert_eq!(percentile(&latencies, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_report() {
+        let args = Cli::parse_from(["bench", "--db", "orders", "--concurrency", "2"]).bench;
+        let samples = vec![
+            Sample {
+                latency: Duration::from_millis(2),
+                status: Some(200),
+            },
+            Sample {
+                latency: Duration::from_millis(4),
+                status: Some(404),
+            },
+            Sample {
+                latency: Duration::from_millis(6),
+                status: None,
+            },
+        ];
+
+        let report = report(&args, &samples, Duration::from_secs(1));
+        assert_eq!(
+            report,
+            "workload: read, requests: 3, concurrency: 2\nelapsed: 1.00s, 3.0 requests/s\nlatency \
+             ms: p50 4.00, p90 6.00, p99 6.00, p99.9 6.00, max 6.00\nstatus 200: 1\nstatus 404: \
+             1\nstatus failed: 1\n"
+        );
+    }
+}
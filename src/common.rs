@@ -12,17 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::config::CorsSettings;
 use axum::body::Body;
 use axum::extract;
 use axum::http;
-use axum::http::{Request, StatusCode};
+use axum::http::uri::PathAndQuery;
+use axum::http::{HeaderName, HeaderValue, Method, Request, StatusCode, Uri};
 use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 use bytes::Bytes;
 use http_body_util::BodyExt;
-use tracing::warn;
+use std::collections::HashMap;
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{error, info_span, warn, Instrument};
+use uuid::Uuid;
 
-/// Common middleware for all requests.
+// Common middleware for all requests.
 
 /// not_implemented_handler is a placeholder for unimplemented routes.
 #[allow(dead_code)]
@@ -32,15 +38,37 @@ pub async fn not_implemented_handler<B>(_: Request<B>) -> Response {
     r
 }
 
-/// Add a `must-revalidate` Cache-Control header to every response.
+/// Add a `must-revalidate` Cache-Control header to every response that doesn't already have one,
+/// such as one set by an update handler.
 /// ref: https://docs.couchdb.org/en/stable/api/basics.html#response-headers
 pub async fn always_add_must_revalidate(req: Request<Body>, next: Next) -> Response {
     let mut res = next.run(req).await;
     res.headers_mut()
-        .insert("Cache-Control", "must-revalidate".parse().unwrap());
+        .entry("Cache-Control")
+        .or_insert("must-revalidate".parse().unwrap());
     res
 }
 
+/// Answers a request whose handler panicked with a JSON `500`, rather than dropping the
+/// connection. The panic's message is logged, not returned.
+pub fn panic_response(panic: Box<dyn std::any::Any + Send + 'static>) -> Response {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|m| m.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+    error!(message = message.as_str(), "request handler panicked");
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        axum::Json(serde_json::json!({
+            "error": "internal_server_error",
+            "reason": "The server hit an unexpected error handling the request."
+        })),
+    )
+        .into_response()
+}
+
 /// Add a `Server` header to every response.
 pub async fn add_server_header(req: Request<Body>, next: Next) -> Response {
     let mut res = next.run(req).await;
@@ -115,6 +143,25 @@ pub async fn add_if_none_match(mut req: Request<Body>, next: Next) -> Result<Res
 #[derive(Clone)]
 pub struct IfMatch(pub Option<String>);
 
+/// Returns the rev an `If-Match` value names. CouchDB's ETags are revs in double quotes, but a
+/// bare rev is accepted too.
+pub fn etag_rev(etag: &str) -> &str {
+    etag.trim().trim_matches('"')
+}
+
+/// Whether an `If-None-Match` value matches a document at `rev`, per RFC 7232: either `*`, or a
+/// comma separated list of ETags, weak or strong, one of which names the rev.
+pub fn if_none_match_matches(if_none_match: &str, rev: &str) -> bool {
+    if if_none_match.trim() == "*" {
+        return true;
+    }
+
+    if_none_match
+        .split(',')
+        .map(|etag| etag.trim().trim_start_matches("W/"))
+        .any(|etag| etag_rev(etag) == rev)
+}
+
 /// Extract the `If-Match` header from the request and store it in the request extensions.
 pub async fn add_if_match(mut req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
     // We deal with borrowing first as it's just easier - I promise you. It's because otherwise
@@ -131,10 +178,44 @@ pub async fn add_if_match(mut req: Request<Body>, next: Next) -> Result<Response
     Ok(next.run(req).await)
 }
 
+#[derive(Clone)]
+pub struct IdempotencyKey(pub Option<String>);
+
+/// Extract the `X-Idempotency-Key` header from the request and store it in the request
+/// extensions.
+pub async fn add_idempotency_key(
+    mut req: Request<Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let idempotency_key = req
+        .headers()
+        .get("X-Idempotency-Key")
+        .and_then(|h| h.to_str().ok())
+        .map(|s| s.to_string());
+
+    req.extensions_mut().insert(IdempotencyKey(idempotency_key));
+
+    Ok(next.run(req).await)
+}
+
+/// Routes whose handlers read the body as it was sent, so it keeps its own content type.
+fn keeps_content_type(matched_path: &str) -> bool {
+    match matched_path {
+        "/:db" | "/:db/:item" | "/:db/:item/:attachment" => true,
+        "/:db/_view_dry_run" | "/:db/_couchapi/import" => true,
+        p => p.contains("/_update/"),
+    }
+}
+
 pub async fn add_content_type_if_needed(
+    matched_path: Option<extract::MatchedPath>,
     mut req: Request<Body>,
     next: Next,
 ) -> Result<Response, StatusCode> {
+    if matched_path.is_some_and(|p| keeps_content_type(p.as_str())) {
+        return Ok(next.run(req).await);
+    }
+
     let headers = req.headers_mut();
     let empty_existing = http::HeaderValue::from_static("");
 
@@ -205,6 +286,156 @@ where
     Ok(bytes)
 }
 
+/// Returns the database a request path refers to, if any.
+pub fn db_from_path(path: &str) -> Option<String> {
+    path.trim_start_matches('/')
+        .split('/')
+        .next()
+        .filter(|db| !db.is_empty() && !db.starts_with('_') && *db != "metrics")
+        .map(|db| db.to_string())
+}
+
+/// Rewrites a request for an alias to be for the database it names, before it's routed, so every
+/// route, setting and read through sees the one name.
+pub fn resolve_alias<B>(aliases: &HashMap<String, String>, req: Request<B>) -> Request<B> {
+    let Some(path_and_query) = req.uri().path_and_query() else {
+        return req;
+    };
+    let path = path_and_query.path().trim_start_matches('/');
+    let (db, rest) = path.split_at(path.find('/').unwrap_or(path.len()));
+    let Some(target) = aliases.get(db) else {
+        return req;
+    };
+
+    let resolved = match path_and_query.query() {
+        Some(query) => format!("/{}{}?{}", target, rest, query),
+        None => format!("/{}{}", target, rest),
+    };
+    let Ok(resolved) = resolved.parse::<PathAndQuery>() else {
+        return req;
+    };
+
+    let (mut parts, body) = req.into_parts();
+    let mut uri = parts.uri.into_parts();
+    uri.path_and_query = Some(resolved);
+    parts.uri = Uri::from_parts(uri).expect("only the path changed");
+    Request::from_parts(parts, body)
+}
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-ID";
+
+tokio::task_local! {
+    /// The id of the request being handled, so it can be passed on to CouchDB.
+    pub static REQUEST_ID: String;
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 200 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Use the caller's `X-Request-ID`, or generate one, and return it on the response. Everything
+/// logged while handling the request is inside a span carrying the id, so our logs can be
+/// matched up with the caller's and CouchDB's.
+pub async fn add_request_id(mut req: Request<Body>, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    // Valid ids are visible ASCII, which is always a valid header value.
+    let header_value = HeaderValue::from_str(&id).unwrap();
+    req.headers_mut()
+        .insert(REQUEST_ID_HEADER, header_value.clone());
+
+    let span = info_span!("request_id", request_id = id.as_str());
+    let mut res = REQUEST_ID.scope(id, next.run(req)).instrument(span).await;
+
+    res.headers_mut().insert(REQUEST_ID_HEADER, header_value);
+    res
+}
+
+/// Methods allowed by CouchDB's default `[cors]` configuration.
+const DEFAULT_CORS_METHODS: [&str; 5] = ["GET", "PUT", "POST", "HEAD", "DELETE"];
+
+/// Request headers allowed by default, CouchDB's defaults plus the ones this proxy understands.
+const DEFAULT_CORS_HEADERS: [&str; 12] = [
+    "accept",
+    "authorization",
+    "content-type",
+    "origin",
+    "referer",
+    "if-match",
+    "if-none-match",
+    "x-api-key",
+    "x-couch-full-commit",
+    "x-idempotency-key",
+    "x-request-id",
+    "x-requested-with",
+];
+
+/// Response headers browsers are allowed to read.
+const CORS_EXPOSE_HEADERS: [&str; 6] = [
+    "cache-control",
+    "content-type",
+    "etag",
+    "server",
+    "x-idempotency-replayed",
+    "x-request-id",
+];
+
+/// Builds the CORS layer from the `[cors]` settings. A `*` origin with credentials enabled echoes
+/// the request's origin back, as browsers refuse a literal `*` alongside credentials.
+pub fn cors_layer(settings: &CorsSettings) -> Result<CorsLayer, String> {
+    let allow_origin = if settings.origins.iter().any(|o| o == "*") {
+        if settings.credentials {
+            AllowOrigin::mirror_request()
+        } else {
+            AllowOrigin::any()
+        }
+    } else {
+        let origins = settings
+            .origins
+            .iter()
+            .map(|o| HeaderValue::from_str(o).map_err(|e| format!("invalid origin {}: {}", o, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    let methods = match &settings.methods {
+        Some(methods) => methods.iter().map(|m| m.as_str()).collect::<Vec<_>>(),
+        None => DEFAULT_CORS_METHODS.to_vec(),
+    }
+    .into_iter()
+    .map(|m| Method::from_bytes(m.as_bytes()).map_err(|e| format!("invalid method {}: {}", m, e)))
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let headers = match &settings.headers {
+        Some(headers) => headers.iter().map(|h| h.as_str()).collect::<Vec<_>>(),
+        None => DEFAULT_CORS_HEADERS.to_vec(),
+    }
+    .into_iter()
+    .map(|h| {
+        HeaderName::from_bytes(h.as_bytes()).map_err(|e| format!("invalid header {}: {}", h, e))
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let mut layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(settings.credentials)
+        .expose_headers(CORS_EXPOSE_HEADERS.map(HeaderName::from_static));
+
+    if let Some(max_age) = settings.max_age {
+        layer = layer.max_age(Duration::from_secs(max_age));
+    }
+
+    Ok(layer)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -219,6 +450,97 @@ mod tests {
         "OK"
     }
 
+    #[test]
+    fn test_resolve_alias() {
+        let aliases = HashMap::from([("catalogue-v2".to_string(), "catalogue".to_string())]);
+        let resolve = |uri: &str| {
+            let req = Request::builder().uri(uri).body(()).unwrap();
+            resolve_alias(&aliases, req).uri().to_string()
+        };
+
+        assert_eq!(resolve("/catalogue-v2"), "/catalogue");
+        assert_eq!(
+            resolve("/catalogue-v2/_design/d/_view/v?limit=1"),
+            "/catalogue/_design/d/_view/v?limit=1"
+        );
+        assert_eq!(
+            resolve("http://localhost:5984/catalogue-v2/doc1"),
+            "http://localhost:5984/catalogue/doc1"
+        );
+        assert_eq!(resolve("/catalogue/doc1"), "/catalogue/doc1");
+        assert_eq!(resolve("/_uuids"), "/_uuids");
+    }
+
+    async fn content_type(headers: http::HeaderMap) -> String {
+        headers[http::header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_add_content_type_if_needed() {
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route("/:db/:item", axum::routing::put(content_type))
+            .route("/:db/_bulk_docs", axum::routing::post(content_type))
+            .route("/:db/_view_dry_run", axum::routing::post(content_type))
+            .layer(middleware::from_fn(add_content_type_if_needed));
+        let sent = |method: &str, uri: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(http::header::CONTENT_TYPE, "application/toml")
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let res = app.oneshot(req).await.unwrap();
+                let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        assert_eq!(sent("POST", "/db/_bulk_docs").await, "application/json");
+        assert_eq!(sent("PUT", "/db/doc").await, "application/toml");
+        assert_eq!(sent("POST", "/db/_view_dry_run").await, "application/toml");
+    }
+
+    async fn panicking_handler() -> &'static str {
+        panic!("handler failed")
+    }
+
+    #[tokio::test]
+    async fn test_panic_response() {
+        use tower::ServiceExt;
+
+        let app = Router::new().route("/", get(panicking_handler)).layer(
+            tower_http::catch_panic::CatchPanicLayer::custom(panic_response),
+        );
+
+        let res = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "internal_server_error");
+    }
+
+    #[test]
+    fn test_db_from_path() {
+        assert_eq!(db_from_path("/orders/abc"), Some("orders".to_string()));
+        assert_eq!(
+            db_from_path("/orders/_design/d/_view/v"),
+            Some("orders".to_string())
+        );
+        assert_eq!(db_from_path("/_session"), None);
+        assert_eq!(db_from_path("/"), None);
+    }
+
     #[tokio::test]
     async fn test_not_implemented_handler() {
         let req: Request<Body> = Request::default();
@@ -230,6 +552,10 @@ mod tests {
     async fn test_always_add_must_revalidate() {
         let app = Router::new()
             .route("/", get(handler))
+            .route(
+                "/cached",
+                get(|| async { ([("Cache-Control", "max-age=60")], "cached") }),
+            )
             .layer(middleware::from_fn(always_add_must_revalidate));
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
@@ -241,6 +567,13 @@ mod tests {
         let client = reqwest::Client::new();
         let res = client.get(format!("http://{}", addr)).send().await.unwrap();
         assert_eq!(res.headers()["Cache-Control"], "must-revalidate");
+
+        let res = client
+            .get(format!("http://{}/cached", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.headers()["Cache-Control"], "max-age=60");
     }
 
     // Test add_server_header
@@ -321,4 +654,161 @@ mod tests {
         let text = res.text().await.unwrap();
         assert_eq!(text, "\"12345\"");
     }
+
+    #[test]
+    fn test_etag_rev() {
+        assert_eq!(etag_rev("\"1-abc\""), "1-abc");
+        assert_eq!(etag_rev("1-abc"), "1-abc");
+    }
+
+    #[test]
+    fn test_if_none_match_matches() {
+        assert!(if_none_match_matches("\"1-abc\"", "1-abc"));
+        assert!(if_none_match_matches("1-abc", "1-abc"));
+        assert!(if_none_match_matches("*", "1-abc"));
+        assert!(if_none_match_matches("\"2-def\", W/\"1-abc\"", "1-abc"));
+        assert!(!if_none_match_matches("\"2-def\"", "1-abc"));
+    }
+
+    async fn idempotency_key_handler(
+        Extension(idempotency_key): Extension<IdempotencyKey>,
+    ) -> String {
+        idempotency_key.0.unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_add_idempotency_key() {
+        let app = Router::new()
+            .route("/", get(idempotency_key_handler))
+            .route_layer(middleware::from_fn(add_idempotency_key));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("http://{}", addr))
+            .header("X-Idempotency-Key", "abc-123")
+            .send()
+            .await
+            .unwrap();
+
+        let text = res.text().await.unwrap();
+        assert_eq!(text, "abc-123");
+    }
+
+    fn cors_settings(origins: Vec<&str>, credentials: bool) -> CorsSettings {
+        CorsSettings {
+            origins: origins.into_iter().map(|o| o.to_string()).collect(),
+            credentials,
+            methods: None,
+            headers: None,
+            max_age: Some(600),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cors_layer_preflight() {
+        let settings = cors_settings(vec!["https://app.example.com"], true);
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(cors_layer(&settings).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let client = reqwest::Client::new();
+        let res = client
+            .request(reqwest::Method::OPTIONS, format!("http://{}/", addr))
+            .header("Origin", "https://app.example.com")
+            .header("Access-Control-Request-Method", "PUT")
+            .header("Access-Control-Request-Headers", "x-api-key")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status().as_u16(), StatusCode::OK.as_u16());
+        let headers = res.headers();
+        assert_eq!(
+            headers.get("access-control-allow-origin"),
+            Some(&HeaderValue::from_static("https://app.example.com"))
+        );
+        assert_eq!(
+            headers.get("access-control-allow-credentials"),
+            Some(&HeaderValue::from_static("true"))
+        );
+        assert_eq!(
+            headers.get("access-control-max-age"),
+            Some(&HeaderValue::from_static("600"))
+        );
+
+        let res = client
+            .get(format!("http://{}/", addr))
+            .header("Origin", "https://elsewhere.example.com")
+            .send()
+            .await
+            .unwrap();
+
+        assert!(res.headers().get("access-control-allow-origin").is_none());
+    }
+
+    #[test]
+    fn test_cors_layer_invalid_settings() {
+        assert!(cors_layer(&cors_settings(vec!["*"], true)).is_ok());
+        assert!(cors_layer(&cors_settings(vec!["https://bad\norigin"], false)).is_err());
+
+        let settings = CorsSettings {
+            methods: Some(vec!["NOT A METHOD".to_string()]),
+            ..cors_settings(vec!["*"], false)
+        };
+        assert!(cors_layer(&settings).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_add_request_id() {
+        async fn request_id_handler() -> String {
+            REQUEST_ID.with(|id| id.clone())
+        }
+
+        let app = Router::new()
+            .route("/", get(request_id_handler))
+            .layer(middleware::from_fn(add_request_id));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(format!("http://{}/", addr))
+            .header(REQUEST_ID_HEADER, "abc-123")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], "abc-123");
+        assert_eq!(res.text().await.unwrap(), "abc-123");
+
+        let res = client
+            .get(format!("http://{}/", addr))
+            .header(REQUEST_ID_HEADER, "has spaces")
+            .send()
+            .await
+            .unwrap();
+        let generated = res.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(Uuid::parse_str(&generated).is_ok());
+        assert_eq!(res.text().await.unwrap(), generated);
+    }
 }
@@ -0,0 +1,314 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http_body_util::BodyExt;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// The CouchDB release whose responses clients expect, for clients that check fields which
+/// changed between releases.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Compatibility {
+    #[serde(rename = "1.6")]
+    V1_6,
+    #[serde(rename = "2.3")]
+    V2_3,
+    #[serde(rename = "3.3")]
+    V3_3,
+}
+
+impl Compatibility {
+    /// The version fields of the welcome response at `/`.
+    pub fn welcome(self) -> Value {
+        match self {
+            Compatibility::V1_6 => json!({
+                "version": "1.6.1",
+                "vendor": { "name": "Green Man Gaming", "version": "1.6.1" },
+            }),
+            Compatibility::V2_3 => json!({
+                "version": "2.3.1",
+                "git_sha": "c298091a4",
+                "vendor": { "name": "Green Man Gaming" },
+            }),
+            Compatibility::V3_3 => json!({
+                "version": "3.3.3",
+                "git_sha": "40afbcfc7",
+                "vendor": { "name": "Green Man Gaming" },
+            }),
+        }
+    }
+
+    /// `features` were added to the welcome response in 2.0.
+    pub fn reports_features(self) -> bool {
+        self != Compatibility::V1_6
+    }
+
+    /// From 2.0 a `_bulk_docs` request with a document rejected by validation gets a `417`
+    /// rather than a `201`.
+    pub fn bulk_docs_status(self, results: &[Value]) -> StatusCode {
+        let rejected = results.iter().any(|r| r["error"] == "forbidden");
+
+        match self {
+            Compatibility::V2_3 | Compatibility::V3_3 if rejected => StatusCode::EXPECTATION_FAILED,
+            _ => StatusCode::CREATED,
+        }
+    }
+
+    /// From 2.0 `_all_docs` has no `offset` when it's asked for `keys`, as a cluster can't work
+    /// one out.
+    pub fn all_docs_offset(self, keys: bool, skip: i64) -> Option<i64> {
+        match self {
+            Compatibility::V2_3 | Compatibility::V3_3 if keys => None,
+            _ => Some(skip),
+        }
+    }
+
+    fn invalid_json_reason(self) -> &'static str {
+        match self {
+            Compatibility::V1_6 => "invalid_json",
+            Compatibility::V2_3 | Compatibility::V3_3 => "invalid UTF-8 JSON",
+        }
+    }
+}
+
+/// CouchDB's name for an error with this status.
+fn error_for_status(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => "bad_request",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::METHOD_NOT_ALLOWED => "method_not_allowed",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::PRECONDITION_FAILED => "precondition_failed",
+        StatusCode::PAYLOAD_TOO_LARGE => "too_large",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "bad_content_type",
+        StatusCode::EXPECTATION_FAILED => "expectation_failed",
+        StatusCode::TOO_MANY_REQUESTS => "too_many_requests",
+        StatusCode::SERVICE_UNAVAILABLE => "service_unavailable",
+        _ => "unknown_error",
+    }
+}
+
+/// CouchDB names errors in `snake_case`. Anything else is a message that belongs in `reason`.
+fn is_error_name(error: &str) -> bool {
+    !error.is_empty()
+        && error
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Names the error and gives it a reason, as CouchDB's error bodies always have both.
+fn error_object(status: StatusCode, mut object: Map<String, Value>) -> Value {
+    let error = object
+        .get("error")
+        .and_then(Value::as_str)
+        .map(String::from);
+    let reason = object
+        .get("reason")
+        .and_then(Value::as_str)
+        .map(String::from);
+
+    let (error, reason) = match (error, reason) {
+        (Some(error), Some(reason)) if is_error_name(&error) => (error, reason),
+        (Some(error), None) if error == "not_found" => (error, "missing".to_string()),
+        (Some(error), None) if is_error_name(&error) => (error.clone(), error),
+        (Some(message), _) => (error_for_status(status).to_string(), message),
+        (None, reason) => (
+            error_for_status(status).to_string(),
+            reason.unwrap_or_else(|| error_for_status(status).to_string()),
+        ),
+    };
+
+    object.insert("error".to_string(), json!(error));
+    object.insert("reason".to_string(), json!(reason));
+    Value::Object(object)
+}
+
+/// The `{"error", "reason"}` body CouchDB would have sent for an error response.
+fn couchdb_error(
+    compatibility: Compatibility,
+    status: StatusCode,
+    body: &[u8],
+) -> (StatusCode, Value) {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(object)) => return (status, error_object(status, object)),
+        // Such as the results of a `_bulk_docs` that got a 417
+        Ok(other) => return (status, other),
+        Err(_) => {}
+    }
+
+    // Anything that isn't JSON is one of axum's rejections, which CouchDB sends as JSON. A request
+    // body CouchDB can't parse always gets a 400.
+    let text = String::from_utf8_lossy(body).trim().to_string();
+    if text.contains("JSON") && matches!(status.as_u16(), 400 | 422) {
+        return (
+            StatusCode::BAD_REQUEST,
+            json!({
+                "error": "bad_request",
+                "reason": compatibility.invalid_json_reason(),
+            }),
+        );
+    }
+
+    let reason = match status {
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "Content-Type must be application/json".to_string(),
+        _ if text.is_empty() => error_for_status(status).to_string(),
+        _ => text,
+    };
+    (
+        status,
+        json!({ "error": error_for_status(status), "reason": reason }),
+    )
+}
+
+/// Gives every error response the `{"error", "reason"}` body of the configured CouchDB release,
+/// including the plain text ones axum sends when it can't extract a request.
+pub async fn shape_errors(
+    State(compatibility): State<Compatibility>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let response = next.run(req).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return status.into_response(),
+    };
+
+    let (status, shaped) = couchdb_error(compatibility, status, &body);
+    parts.status = status;
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::CONTENT_TYPE);
+    (parts, Json(shaped)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shaped(compatibility: Compatibility, status: StatusCode, body: &str) -> (u16, Value) {
+        let (status, body) = couchdb_error(compatibility, status, body.as_bytes());
+        (status.as_u16(), body)
+    }
+
+    #[test]
+    fn test_couchdb_error() {
+        let not_found = shaped(
+            Compatibility::V3_3,
+            StatusCode::NOT_FOUND,
+            r#"{"error": "not_found"}"#,
+        );
+        assert_eq!(
+            not_found,
+            (404, json!({"error": "not_found", "reason": "missing"}))
+        );
+
+        let message = shaped(
+            Compatibility::V3_3,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            r#"{"error": "Kind: connection refused"}"#,
+        );
+        assert_eq!(
+            message,
+            (
+                500,
+                json!({"error": "unknown_error", "reason": "Kind: connection refused"})
+            )
+        );
+
+        let conflict = r#"{"error": "conflict", "reason": "Document update conflict."}"#;
+        assert_eq!(
+            shaped(Compatibility::V1_6, StatusCode::CONFLICT, conflict),
+            (409, serde_json::from_str(conflict).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_couchdb_error_for_rejections() {
+        let syntax = "Failed to parse the request body as JSON: key must be a string";
+        assert_eq!(
+            shaped(Compatibility::V1_6, StatusCode::BAD_REQUEST, syntax),
+            (
+                400,
+                json!({"error": "bad_request", "reason": "invalid_json"})
+            )
+        );
+        let data = "Failed to deserialize the JSON body into the target type: missing field `docs`";
+        assert_eq!(
+            shaped(Compatibility::V2_3, StatusCode::UNPROCESSABLE_ENTITY, data),
+            (
+                400,
+                json!({"error": "bad_request", "reason": "invalid UTF-8 JSON"})
+            )
+        );
+        assert_eq!(
+            shaped(
+                Compatibility::V3_3,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected request with `Content-Type: application/json`"
+            )
+            .1,
+            json!({"error": "bad_content_type", "reason": "Content-Type must be application/json"})
+        );
+    }
+
+    #[test]
+    fn test_couchdb_error_keeps_other_json() {
+        let results = r#"[{"id": "b", "error": "forbidden", "reason": "no"}]"#;
+        assert_eq!(
+            shaped(Compatibility::V3_3, StatusCode::EXPECTATION_FAILED, results),
+            (417, serde_json::from_str(results).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_bulk_docs_status() {
+        let results = vec![
+            json!({"id": "a", "rev": "1-a", "ok": true}),
+            json!({"id": "b", "error": "forbidden", "reason": "no"}),
+        ];
+
+        assert_eq!(
+            Compatibility::V1_6.bulk_docs_status(&results),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            Compatibility::V3_3.bulk_docs_status(&results),
+            StatusCode::EXPECTATION_FAILED
+        );
+        assert_eq!(
+            Compatibility::V3_3.bulk_docs_status(&results[..1]),
+            StatusCode::CREATED
+        );
+    }
+
+    #[test]
+    fn test_all_docs_offset() {
+        assert_eq!(Compatibility::V1_6.all_docs_offset(true, 2), Some(2));
+        assert_eq!(Compatibility::V2_3.all_docs_offset(true, 2), None);
+        assert_eq!(Compatibility::V3_3.all_docs_offset(false, 2), Some(2));
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::state::AppState;
+use axum::extract::State;
+use axum::Json;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// How much of a CouchDB endpoint is served.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Support {
+    Full,
+    Partial,
+    None,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Endpoint {
+    endpoint: &'static str,
+    support: Support,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<&'static str>,
+}
+
+fn endpoint(endpoint: &'static str, support: Support, note: Option<&'static str>) -> Endpoint {
+    Endpoint {
+        endpoint,
+        support,
+        note,
+    }
+}
+
+/// The CouchDB endpoints clients use, and how much of each this server serves as configured.
+pub fn endpoints(state: &AppState) -> Vec<Endpoint> {
+    let replication = if state.replication_target {
+        Support::Full
+    } else {
+        Support::None
+    };
+    let search = if state.search_indexes.is_some() {
+        Support::Partial
+    } else {
+        Support::None
+    };
+
+    vec![
+        endpoint("GET /", Support::Full, None),
+        endpoint("GET /_uuids", Support::Full, None),
+        endpoint("GET /_session", Support::Full, None),
+        endpoint(
+            "POST /_dbs_info",
+            Support::Partial,
+            Some("Counts and sizes come from MongoDB, and deleted documents aren't counted."),
+        ),
+        endpoint(
+            "GET /_db_updates",
+            Support::Partial,
+            Some("feed=longpoll and feed=continuous, without created rows."),
+        ),
+        endpoint("POST /_replicate", Support::None, None),
+        endpoint(
+            "PUT /{db}",
+            Support::None,
+            Some("Databases are MongoDB collections, created when first written to."),
+        ),
+        endpoint("DELETE /{db}", Support::None, None),
+        endpoint("GET /{db}", Support::Full, None),
+        endpoint("POST /{db}", Support::Full, None),
+        endpoint(
+            "GET /{db}/{docid}",
+            Support::Partial,
+            Some("Only the latest revision is kept, so there's no revision history."),
+        ),
+        endpoint("PUT /{db}/{docid}", Support::Full, None),
+        endpoint("DELETE /{db}/{docid}", Support::Full, None),
+        endpoint("PUT /{db}/{docid}/{attname}", Support::Full, None),
+        endpoint(
+            "GET /{db}/{docid}/{attname}",
+            Support::None,
+            Some("Attachments are read inline with their document."),
+        ),
+        endpoint("POST /{db}/_bulk_docs", Support::Full, None),
+        endpoint("POST /{db}/_bulk_get", Support::None, None),
+        endpoint("GET /{db}/_all_docs", Support::Full, None),
+        endpoint("POST /{db}/_all_docs", Support::Full, None),
+        endpoint(
+            "GET /{db}/_changes",
+            Support::Partial,
+            Some(
+                "feed=longpoll and feed=continuous need the changes_feed database feature, and \
+                 since has to be now or a seq from the feed.",
+            ),
+        ),
+        endpoint("POST /{db}/_find", Support::None, None),
+        endpoint("GET /{db}/_security", Support::Full, None),
+        endpoint("PUT /{db}/_security", Support::Full, None),
+        endpoint(
+            "GET /{db}/_design/{ddoc}",
+            Support::None,
+            Some("Design documents live in the configuration, not the database."),
+        ),
+        endpoint(
+            "GET /{db}/_design/{ddoc}/_view/{view}",
+            Support::Partial,
+            Some("Views are MongoDB aggregations set up in the configuration."),
+        ),
+        endpoint(
+            "POST /{db}/_design/{ddoc}/_update/{func}",
+            Support::Full,
+            None,
+        ),
+        endpoint("GET /{db}/_design/{ddoc}/_search/{index}", search, None),
+        endpoint("GET /{db}/_local/{docid}", replication, None),
+        endpoint("PUT /{db}/_local/{docid}", replication, None),
+        endpoint("POST /{db}/_revs_diff", replication, None),
+        endpoint("POST /{db}/_ensure_full_commit", replication, None),
+    ]
+}
+
+/// compat_report reports which CouchDB endpoints and features are served, for tools deciding
+/// whether a client will work against this server.
+pub async fn compat_report(State(state): State<Arc<AppState>>) -> Json<Value> {
+    Json(json!({
+        "compatibility": state.compatibility,
+        "features": state.server_features(),
+        "endpoints": endpoints(&state),
+    }))
+}
@@ -0,0 +1,321 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::CompressionSettings;
+use crate::db::{ChangeStream, Database};
+use crate::expiry::EXPIRES_FIELD;
+use async_trait::async_trait;
+use bson::spec::BinarySubtype;
+use bson::{Binary, Bson, Document, RawDocumentBuf};
+use futures_util::StreamExt;
+use mongodb::error::Error;
+use mongodb::options::{AggregateOptions, DeleteOptions, FindOneOptions, ReplaceOptions};
+use mongodb::results::UpdateResult;
+
+/// Holds the whole document, as zstd compressed BSON.
+const COMPRESSED_FIELD: &str = "_couchapi_zstd";
+
+/// Always left uncompressed, as writes, deletes and the TTL index need them.
+const KEPT_FIELDS: [&str; 4] = ["_id", "_rev", "_deleted", EXPIRES_FIELD];
+
+/// Stores documents over a size compressed, and hands them back whole. Only the kept fields can
+/// be matched on in MongoDB, but view rows are read from the decompressed documents.
+pub struct CompressedDatabase {
+    inner: Box<dyn Database + Send + Sync>,
+    settings: CompressionSettings,
+}
+
+impl CompressedDatabase {
+    pub fn new(inner: Box<dyn Database + Send + Sync>, settings: CompressionSettings) -> Self {
+        CompressedDatabase { inner, settings }
+    }
+
+    fn applies_to(&self, coll: &str) -> bool {
+        self.settings.databases.is_empty() || self.settings.databases.iter().any(|d| d == coll)
+    }
+
+    fn compress(&self, document: Document) -> Result<Document, Error> {
+        let bytes = bson::to_vec(&document)?;
+        if bytes.len() < self.settings.threshold_bytes {
+            return Ok(document);
+        }
+
+        let compressed = zstd::bulk::compress(&bytes, self.settings.level)?;
+        metrics::increment_counter!("couchapi_compressed_documents_total");
+        metrics::counter!(
+            "couchapi_compression_saved_bytes_total",
+            bytes.len().saturating_sub(compressed.len()) as u64
+        );
+
+        let mut stored: Document = document
+            .into_iter()
+            .filter(|(field, _)| {
+                KEPT_FIELDS.contains(&field.as_str()) || self.settings.keep_fields.contains(field)
+            })
+            .collect();
+        stored.insert(
+            COMPRESSED_FIELD,
+            Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: compressed,
+            },
+        );
+        Ok(stored)
+    }
+}
+
+/// The document as it was written, whether or not it was stored compressed.
+fn decompress(document: Document) -> Result<Document, Error> {
+    let Ok(compressed) = document.get_binary_generic(COMPRESSED_FIELD) else {
+        return Ok(document);
+    };
+
+    let bytes = zstd::stream::decode_all(compressed.as_slice())?;
+    Ok(Document::from_reader(bytes.as_slice())?)
+}
+
+fn decompress_raw(raw: RawDocumentBuf) -> Result<RawDocumentBuf, Error> {
+    if !matches!(raw.get(COMPRESSED_FIELD), Ok(Some(_))) {
+        return Ok(raw);
+    }
+
+    Ok(RawDocumentBuf::from_document(&decompress(
+        raw.to_document()?,
+    )?)?)
+}
+
+/// A change stream event with its `fullDocument` decompressed.
+fn decompress_event(mut event: Document) -> Result<Document, Error> {
+    if let Some(Bson::Document(document)) = event.remove("fullDocument") {
+        event.insert("fullDocument", decompress(document)?);
+    }
+    Ok(event)
+}
+
+#[async_trait]
+impl Database for CompressedDatabase {
+    async fn get_version(&self) -> Result<Document, Error> {
+        self.inner.get_version().await
+    }
+
+    async fn find_one(
+        &self,
+        coll: &str,
+        id: &str,
+        options: FindOneOptions,
+    ) -> Result<Option<Document>, Error> {
+        self.inner
+            .find_one(coll, id, options)
+            .await?
+            .map(decompress)
+            .transpose()
+    }
+
+    async fn replace_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        replacement: Document,
+        options: ReplaceOptions,
+    ) -> Result<UpdateResult, Error> {
+        let replacement = match self.applies_to(coll) {
+            true => self.compress(replacement)?,
+            false => replacement,
+        };
+        self.inner
+            .replace_one(coll, filter, replacement, options)
+            .await
+    }
+
+    async fn delete_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        options: DeleteOptions,
+    ) -> Result<u64, Error> {
+        self.inner.delete_one(coll, filter, options).await
+    }
+
+    async fn aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+        options: AggregateOptions,
+    ) -> Result<Vec<RawDocumentBuf>, Error> {
+        self.inner
+            .aggregate(coll, pipeline, options)
+            .await?
+            .into_iter()
+            .map(decompress_raw)
+            .collect()
+    }
+
+    async fn explain_aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Document, Error> {
+        self.inner.explain_aggregate(coll, pipeline).await
+    }
+
+    async fn count(&self, coll: &str) -> Result<u64, Error> {
+        self.inner.count(coll).await
+    }
+
+    async fn collection_stats(&self, coll: &str) -> Result<Option<Document>, Error> {
+        self.inner.collection_stats(coll).await
+    }
+
+    async fn watch(
+        &self,
+        coll: &str,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeStream, Error> {
+        let changes = self.inner.watch(coll, resume_after).await?;
+        Ok(changes
+            .map(|event| event.and_then(decompress_event))
+            .boxed())
+    }
+
+    async fn watch_database(&self, resume_after: Option<Document>) -> Result<ChangeStream, Error> {
+        self.inner.watch_database(resume_after).await
+    }
+
+    async fn create_ttl_index(&self, coll: &str, field: &str) -> Result<(), Error> {
+        self.inner.create_ttl_index(coll, field).await
+    }
+
+    async fn update_many(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<u64, Error> {
+        self.inner.update_many(coll, filter, update).await
+    }
+
+    async fn delete_many(&self, coll: &str, filter: Document) -> Result<u64, Error> {
+        self.inner.delete_many(coll, filter).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use bson::doc;
+    use mongodb::error::ErrorKind;
+    use std::sync::{Arc, Mutex};
+
+    fn settings() -> CompressionSettings {
+        CompressionSettings {
+            threshold_bytes: 1024,
+            level: 3,
+            databases: vec!["catalogue".to_string()],
+            keep_fields: vec!["type".to_string()],
+        }
+    }
+
+    fn large_document() -> Document {
+        doc! {
+            "_id": "sku1",
+            "_rev": "1-a",
+            "type": "product",
+            "description": "a very long description ".repeat(100),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compresses_large_documents() {
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let mut mock = MockDatabase::new();
+        let written = stored.clone();
+        mock.expect_replace_one()
+            .times(3)
+            .returning(move |_, _, replacement, _| {
+                written.lock().unwrap().push(replacement);
+                // UpdateResult can't be built outside of the driver, so the write is seen through
+                // what was passed on
+                Box::pin(async { Err(Error::from(ErrorKind::Custom(Arc::new("written")))) })
+            });
+
+        let db = CompressedDatabase::new(Box::new(mock), settings());
+        for (coll, document) in [
+            ("catalogue", large_document()),
+            ("catalogue", doc! { "_id": "small", "_rev": "1-b" }),
+            ("orders", large_document()),
+        ] {
+            let _ = db
+                .replace_one(coll, doc! {}, document, ReplaceOptions::default())
+                .await;
+        }
+
+        let stored = stored.lock().unwrap();
+        assert_eq!(
+            stored[0].keys().collect::<Vec<_>>(),
+            vec!["_id", "_rev", "type", COMPRESSED_FIELD]
+        );
+        assert_eq!(decompress(stored[0].clone()).unwrap(), large_document());
+        assert_eq!(stored[1], doc! { "_id": "small", "_rev": "1-b" });
+        assert_eq!(stored[2], large_document());
+    }
+
+    #[tokio::test]
+    async fn test_decompresses_reads() {
+        let compressor = CompressedDatabase::new(Box::new(MockDatabase::new()), settings());
+        let compressed = compressor.compress(large_document()).unwrap();
+
+        let mut mock = MockDatabase::new();
+        let found = compressed.clone();
+        mock.expect_find_one().returning(move |_, _, _| {
+            let found = found.clone();
+            Box::pin(async move { Ok(Some(found)) })
+        });
+        let rows = vec![
+            RawDocumentBuf::from_document(&compressed).unwrap(),
+            RawDocumentBuf::from_document(&doc! { "_id": "small" }).unwrap(),
+        ];
+        mock.expect_aggregate().returning(move |_, _, _| {
+            let rows = rows.clone();
+            Box::pin(async move { Ok(rows) })
+        });
+        let event = doc! { "operationType": "insert", "fullDocument": compressed };
+        mock.expect_watch().returning(move |_, _| {
+            let events = vec![Ok(event.clone())];
+            Box::pin(async move { Ok(futures_util::stream::iter(events).boxed()) })
+        });
+
+        let db = CompressedDatabase::new(Box::new(mock), settings());
+        assert_eq!(
+            db.find_one("catalogue", "sku1", FindOneOptions::default())
+                .await
+                .unwrap(),
+            Some(large_document())
+        );
+
+        let rows = db
+            .aggregate("catalogue", vec![], AggregateOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(rows[0].to_document().unwrap(), large_document());
+        assert_eq!(rows[1].to_document().unwrap(), doc! { "_id": "small" });
+
+        let mut changes = db.watch("catalogue", None).await.unwrap();
+        let event = changes.next().await.unwrap().unwrap();
+        assert_eq!(
+            event.get_document("fullDocument").unwrap(),
+            &large_document()
+        );
+    }
+}
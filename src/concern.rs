@@ -0,0 +1,168 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use axum::http::StatusCode;
+use axum::Json;
+use mongodb::options::{Acknowledgment, ReadConcern, WriteConcern};
+use serde_json::json;
+use std::collections::HashMap;
+
+/// Parses a CouchDB style `r` value into a MongoDB read concern. In CouchDB `r` is the number of
+/// replicas that must agree before a read is returned, so a single replica maps to `local` and
+/// anything greater maps to `majority`. The MongoDB level names are also accepted as-is.
+pub fn parse_read_concern(r: &str) -> Result<ReadConcern, String> {
+    match r {
+        "local" => Ok(ReadConcern::local()),
+        "available" => Ok(ReadConcern::available()),
+        "majority" => Ok(ReadConcern::majority()),
+        "linearizable" => Ok(ReadConcern::linearizable()),
+        _ => match r.parse::<u32>() {
+            Ok(1) => Ok(ReadConcern::local()),
+            Ok(n) if n > 1 => Ok(ReadConcern::majority()),
+            _ => Err(format!("invalid value for r: {}", r)),
+        },
+    }
+}
+
+/// Parses a CouchDB style `w` value into a MongoDB write concern. A number is the count of
+/// replicas that must acknowledge the write and `majority` is passed straight through.
+pub fn parse_write_concern(w: &str) -> Result<WriteConcern, String> {
+    let acknowledgment = match w {
+        "majority" => Acknowledgment::Majority,
+        _ => match w.parse::<u32>() {
+            Ok(n) if n > 0 => Acknowledgment::Nodes(n),
+            _ => return Err(format!("invalid value for w: {}", w)),
+        },
+    };
+
+    Ok(WriteConcern::builder().w(acknowledgment).build())
+}
+
+/// Returns the read concern for a request, taken from the `r` query parameter if present and
+/// otherwise from the configured default.
+pub fn read_concern_for_request(
+    state: &AppState,
+    params: &HashMap<String, String>,
+) -> Result<Option<ReadConcern>, JsonWithStatusCodeResponse> {
+    match params.get("r") {
+        Some(r) => parse_read_concern(r).map(Some).map_err(bad_request),
+        None => Ok(state.default_read_concern.clone()),
+    }
+}
+
+/// Returns the write concern for a request, taken from the `w` query parameter if present and
+/// otherwise from the configured default.
+pub fn write_concern_for_request(
+    state: &AppState,
+    params: &HashMap<String, String>,
+) -> Result<Option<WriteConcern>, JsonWithStatusCodeResponse> {
+    match params.get("w") {
+        Some(w) => parse_write_concern(w).map(Some).map_err(bad_request),
+        None => Ok(state.default_write_concern.clone()),
+    }
+}
+
+fn bad_request(reason: String) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "bad_request", "reason": reason})),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use maplit::hashmap;
+    use mongodb::options::ReadConcernLevel;
+
+    fn state_with_defaults(r: Option<ReadConcern>, w: Option<WriteConcern>) -> AppState {
+        AppState {
+            default_read_concern: r,
+            default_write_concern: w,
+            ..AppState::for_tests(MockDatabase::new())
+        }
+    }
+
+    #[test]
+    fn test_parse_read_concern() {
+        assert_eq!(
+            parse_read_concern("1").unwrap().level,
+            ReadConcernLevel::Local
+        );
+        assert_eq!(
+            parse_read_concern("2").unwrap().level,
+            ReadConcernLevel::Majority
+        );
+        assert_eq!(
+            parse_read_concern("majority").unwrap().level,
+            ReadConcernLevel::Majority
+        );
+        assert!(parse_read_concern("0").is_err());
+        assert!(parse_read_concern("lots").is_err());
+    }
+
+    #[test]
+    fn test_parse_write_concern() {
+        assert_eq!(
+            parse_write_concern("2").unwrap().w,
+            Some(Acknowledgment::Nodes(2))
+        );
+        assert_eq!(
+            parse_write_concern("majority").unwrap().w,
+            Some(Acknowledgment::Majority)
+        );
+        assert!(parse_write_concern("0").is_err());
+        assert!(parse_write_concern("everyone").is_err());
+    }
+
+    #[test]
+    fn test_concern_for_request_uses_defaults() {
+        let state =
+            state_with_defaults(Some(ReadConcern::majority()), Some(WriteConcern::MAJORITY));
+
+        let r = read_concern_for_request(&state, &hashmap! {}).unwrap();
+        assert_eq!(r, Some(ReadConcern::majority()));
+
+        let w = write_concern_for_request(&state, &hashmap! {}).unwrap();
+        assert_eq!(w, Some(WriteConcern::MAJORITY));
+    }
+
+    #[test]
+    fn test_concern_for_request_params_override_defaults() {
+        let state = state_with_defaults(Some(ReadConcern::majority()), None);
+        let params = hashmap! {
+            "r".to_string() => "1".to_string(),
+            "w".to_string() => "3".to_string(),
+        };
+
+        let r = read_concern_for_request(&state, &params).unwrap();
+        assert_eq!(r, Some(ReadConcern::local()));
+
+        let w = write_concern_for_request(&state, &params).unwrap();
+        assert_eq!(w.unwrap().w, Some(Acknowledgment::Nodes(3)));
+    }
+
+    #[test]
+    fn test_concern_for_request_invalid_param() {
+        let state = state_with_defaults(None, None);
+        let params = hashmap! { "w".to_string() => "nope".to_string() };
+
+        let (status, json) = write_concern_for_request(&state, &params).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json.0["error"], "bad_request");
+    }
+}
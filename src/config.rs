@@ -12,12 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::compat::Compatibility;
+use crate::concern::{parse_read_concern, parse_write_concern};
 use config::{Config, ConfigError, Environment};
 use maplit::hashmap;
-use serde_derive::Deserialize;
+use mongodb::options::{ReadConcern, WriteConcern};
+use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tracing::{error, info};
 use tracing_subscriber::fmt::format::FmtSpan;
 use walkdir::WalkDir;
@@ -34,13 +39,225 @@ fn default_listen_address() -> String {
     "0.0.0.0:3000".to_string()
 }
 
-#[derive(Debug, Deserialize)]
+/// Variables starting with this override nested settings, with `__` between each level, e.g.
+/// `COUCH_STREAM__COUCHDB_SETTINGS__URL`.
+const ENV_OVERRIDE_PREFIX: &str = "COUCH_STREAM__";
+
+/// Builds a tree of settings from the `COUCH_STREAM__` variables. Values that parse as JSON, such
+/// as numbers, booleans, lists and objects, are used as such and anything else is a string.
+fn env_overrides(env_vars: impl IntoIterator<Item = (String, String)>) -> serde_json::Value {
+    let mut overrides = serde_json::Map::new();
+
+    for (name, value) in env_vars {
+        let Some(path) = name.strip_prefix(ENV_OVERRIDE_PREFIX) else {
+            continue;
+        };
+
+        let keys = path
+            .split("__")
+            .map(|k| k.to_lowercase())
+            .collect::<Vec<_>>();
+        if keys.iter().any(|k| k.is_empty()) {
+            continue;
+        }
+
+        let value = serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value));
+
+        let (last, parents) = keys.split_last().unwrap();
+        let mut current = &mut overrides;
+        for key in parents {
+            let entry = current
+                .entry(key.clone())
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+            if !entry.is_object() {
+                *entry = serde_json::Value::Object(serde_json::Map::new());
+            }
+            current = entry.as_object_mut().unwrap();
+        }
+        current.insert(last.clone(), value);
+    }
+
+    serde_json::Value::Object(overrides)
+}
+
+/// Expands the `include` patterns of `config_file` into the files they match. Relative patterns
+/// are relative to the config file, and `*` and `?` are only supported in the file name. Each
+/// pattern's matches are sorted, so files merge in a predictable order. A pattern without
+/// wildcards must name a file that exists.
+fn resolve_includes(config_file: &str, patterns: &[String]) -> Result<Vec<PathBuf>, ConfigError> {
+    let base = Path::new(config_file).parent().unwrap_or(Path::new(""));
+    let mut files = Vec::new();
+
+    for pattern in patterns {
+        let pattern_path = base.join(pattern);
+        let file_pattern = pattern_path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .ok_or_else(|| ConfigError::Message(format!("include {}: no file name", pattern)))?;
+
+        if !file_pattern.contains(['*', '?']) {
+            if !pattern_path.is_file() {
+                return Err(ConfigError::Message(format!(
+                    "include {}: {} not found",
+                    pattern,
+                    pattern_path.display()
+                )));
+            }
+            files.push(pattern_path);
+            continue;
+        }
+
+        let dir = pattern_path.parent().unwrap_or(Path::new(""));
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+
+        let entries = fs::read_dir(dir)
+            .map_err(|e| ConfigError::Message(format!("include {}: {}", pattern, e)))?;
+
+        let mut matched = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|f| f.to_str())
+                    .is_some_and(|f| wildcard_match(file_pattern, f))
+            })
+            .collect::<Vec<_>>();
+        matched.sort();
+
+        files.extend(matched);
+    }
+
+    Ok(files)
+}
+
+/// Matches `name` against a pattern where `*` matches any run of characters and `?` matches
+/// exactly one.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// A view file found under the view folder, with the database, design and view it defines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ViewFile {
+    pub path: PathBuf,
+    pub db: String,
+    pub design: String,
+    pub view: String,
+}
+
+/// Finds every `.toml` file under `folder`, laid out as `db/design/view.toml`.
+pub fn find_view_files(folder: &str) -> Vec<ViewFile> {
+    WalkDir::new(folder)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let path = entry.path();
+            let view = path.file_name()?.to_str()?.strip_suffix(".toml")?;
+
+            // The view group is the parent folder and the database the one above it
+            let name = |p: Option<&Path>| {
+                p.and_then(|p| p.file_name())
+                    .and_then(|os_str| os_str.to_str())
+                    .map(|s| s.to_string())
+                    .unwrap_or_default()
+            };
+
+            Some(ViewFile {
+                path: path.to_path_buf(),
+                db: name(path.parent().and_then(|p| p.parent())),
+                design: name(path.parent()),
+                view: view.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Reads and parses a single view file. Errors name the file and include the line and column of
+/// parse errors.
+pub fn load_view_file(path: &Path) -> Result<DesignView, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Reads every `.toml` file under `folder` as a view, laid out as `db/design/view.toml`. Files that
+/// can't be read or parsed are skipped and described in the returned errors, which include the
+/// line and column of parse errors.
+pub fn load_views_from_folder(folder: &str) -> (HashMap<String, DesignMapping>, Vec<String>) {
+    let mut view_groups: HashMap<String, DesignMapping> = HashMap::new();
+    let mut errors = Vec::new();
+
+    for file in find_view_files(folder) {
+        // Read the contents of the file and parse it into a `DesignView` struct
+        let design_view = match load_view_file(&file.path) {
+            Ok(design_view) => design_view,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+
+        // Insert the view into the `view_groups` HashMap
+        info!(
+            db_name = file.db.as_str(),
+            view_group_name = file.design.as_str(),
+            view_name = file.view.as_str(),
+            "adding view"
+        );
+
+        // Create an empty view group IF we need one
+        let design_mapping = view_groups.entry(file.db).or_insert(DesignMapping {
+            view_groups: hashmap! {},
+        });
+
+        let db_mapping = design_mapping
+            .view_groups
+            .entry(file.design)
+            .or_insert(hashmap! {});
+        db_mapping.insert(file.view, design_view);
+    }
+
+    (view_groups, errors)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub enum LogFormat {
     Compact,
     Json,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub enum LogLevel {
     Debug,
     Info,
@@ -48,72 +265,1010 @@ pub enum LogLevel {
     Error,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
-pub struct ReduceView {
-    pub aggregation: Vec<String>,
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct ReduceView {
+    pub aggregation: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct DesignView {
+    pub match_fields: Vec<String>,
+    pub sort_fields: Option<Vec<String>>,
+    pub aggregation: Vec<String>,
+    pub key_fields: Vec<String>,
+    pub value_fields: Vec<String>,
+    pub filter_insert_index: usize,
+    pub reduce: Option<HashMap<String, ReduceView>>,
+
+    #[serde(default)]
+    pub single_item_key_is_list: bool,
+
+    #[serde(default)]
+    pub single_item_value_is_dict: bool,
+
+    pub break_glass_js_script: Option<String>,
+
+    #[serde(default)]
+    pub omit_null_keys_in_value: bool,
+
+    /// Fields of each row emitted as its `doc`, so a view can carry them without `include_docs`.
+    #[serde(default)]
+    pub doc_fields: Vec<String>,
+
+    /// Names for value fields in the value object, keyed by the field.
+    #[serde(default)]
+    pub value_field_names: HashMap<String, String>,
+
+    /// Leaves `id` out of each row.
+    #[serde(default)]
+    pub omit_id: bool,
+}
+
+/// A Cloudant style search index, served at `/{db}/_design/{design}/_search/{index}` from the
+/// collection's MongoDB text index or from an Atlas Search index.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct SearchIndex {
+    /// Fields returned in each row's `fields`.
+    #[serde(default)]
+    pub fields: Vec<String>,
+
+    /// The Atlas Search index to query. Without one, the collection's text index is used.
+    pub atlas_index: Option<String>,
+}
+
+/// A geo view, served GeoCouch and Cloudant style at `/{db}/_design/{design}/_geo/{index}` (and
+/// `_spatial`) from a MongoDB `2dsphere` index.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct GeoIndex {
+    /// The GeoJSON field the `2dsphere` index covers.
+    pub geometry_field: String,
+
+    /// Fields returned as each row's `value`, as a view's `value_fields`.
+    #[serde(default)]
+    pub value_fields: Vec<String>,
+}
+
+/// Geo views keyed by database, then design document, then index.
+pub type GeoIndexes = HashMap<String, HashMap<String, HashMap<String, GeoIndex>>>;
+
+/// Search indexes keyed by database, then design document, then index.
+pub type SearchIndexes = HashMap<String, HashMap<String, HashMap<String, SearchIndex>>>;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DesignMapping {
+    // Keyed by ViewGroup then by View
+    pub view_groups: HashMap<String, HashMap<String, DesignView>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CouchDb {
+    /// url defines the URL of the CouchDB server.
+    pub url: String,
+
+    /// username defines the username to use when connecting to the CouchDB server.
+    pub username: Option<String>,
+
+    /// password defines the password to use when connecting to the CouchDB server.
+    pub password: Option<String>,
+
+    /// When set to try, we will attempt to read a view from CouchDB if the view is not found in
+    /// the configuration file. This is useful for migrating from CouchDB to MongoDB.
+    #[serde(default)]
+    pub read_through: bool,
+
+    /// When set to true, we will not write to MongoDB but instead only to CouchDB
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// A list of databases that we will read views from CouchDB instead of MongoDB
+    /// if the view doesn't exist.
+    pub read_through_databases: Option<Vec<String>>,
+
+    /// A list of databases that we will only read from MongoDB and write to CouchDB
+    pub read_only_databases: Option<Vec<String>>,
+
+    /// When set to true, views read through to CouchDB are answered with the last response stored
+    /// in MongoDB, marked as stale, if CouchDB can't be reached or fails.
+    #[serde(default)]
+    pub stale_fallback: bool,
+
+    /// The percentage of each database's document reads to serve from MongoDB, the rest being read
+    /// through to CouchDB. A document is always read from the same side. Databases not listed are
+    /// read from MongoDB.
+    pub soft_launch: Option<HashMap<String, u8>>,
+
+    /// mappings defines which CouchDB database to use on read and write. The key is the MongoDB
+    /// Collection name and the value is the CouchDB database name.
+    pub mappings: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ApiKey {
+    /// The key itself, which clients send in the `X-Api-Key` header or as a bearer token.
+    pub key: String,
+
+    /// A friendly name for the key so logs don't have to contain the key.
+    pub name: Option<String>,
+
+    /// The databases this key may access. `*` allows access to every database.
+    pub databases: Vec<String>,
+
+    /// The HTTP methods this key may use. When unset, every method is allowed.
+    pub methods: Option<Vec<String>>,
+
+    /// The roles held by whoever uses this key, matched against database security objects.
+    /// `_admin` grants access to everything.
+    #[serde(default)]
+    pub roles: Vec<String>,
+
+    /// The tenant whose collections this key reaches, when `tenancy` is configured.
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct SecurityGroup {
+    #[serde(default)]
+    pub names: Vec<String>,
+
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+/// A CouchDB style security object. `admins` may do anything, `members` may read and write and,
+/// as an extension, `writers` narrows writes down to a smaller group than `members`. As with
+/// CouchDB, a database with no members is public.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct SecurityObject {
+    #[serde(default)]
+    pub admins: SecurityGroup,
+
+    #[serde(default)]
+    pub members: SecurityGroup,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub writers: Option<SecurityGroup>,
+}
+
+fn default_session_timeout() -> u64 {
+    600
+}
+
+/// Mirrors CouchDB's `[couch_httpd_auth]` section, so `AuthSession` cookies issued by CouchDB are
+/// accepted here and the other way round.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CouchHttpdAuth {
+    pub secret: String,
+
+    /// How long, in seconds, a session cookie is valid for.
+    #[serde(default = "default_session_timeout")]
+    pub timeout: u64,
+
+    #[serde(default)]
+    pub users: Vec<SessionUser>,
+}
+
+/// A user who can log in with `POST /_session`. `salt`, `derived_key`, `iterations` and
+/// `pbkdf2_prf` are copied from the user's document in CouchDB's `_users` database.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SessionUser {
+    pub name: String,
+    pub salt: String,
+
+    #[serde(default)]
+    pub roles: Vec<String>,
+
+    pub derived_key: Option<String>,
+    pub iterations: Option<u32>,
+    pub pbkdf2_prf: Option<String>,
+
+    /// The tenant whose collections this user reaches, when `tenancy` is configured.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+fn default_max_clock_skew() -> u64 {
+    300
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum AccessLogFormat {
+    Common,
+    Json,
+}
+
+fn default_access_log_format() -> AccessLogFormat {
+    AccessLogFormat::Common
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogField {
+    Timestamp,
+    RemoteAddr,
+    Method,
+    Path,
+    Status,
+    Bytes,
+    DurationMs,
+    Db,
+    Docs,
+    ReadThrough,
+    RequestId,
+    UserAgent,
+}
+
+impl AccessLogField {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccessLogField::Timestamp => "timestamp",
+            AccessLogField::RemoteAddr => "remote_addr",
+            AccessLogField::Method => "method",
+            AccessLogField::Path => "path",
+            AccessLogField::Status => "status",
+            AccessLogField::Bytes => "bytes",
+            AccessLogField::DurationMs => "duration_ms",
+            AccessLogField::Db => "db",
+            AccessLogField::Docs => "docs",
+            AccessLogField::ReadThrough => "read_through",
+            AccessLogField::RequestId => "request_id",
+            AccessLogField::UserAgent => "user_agent",
+        }
+    }
+
+    /// Whether Common Log Format already includes the field.
+    pub fn in_common_log_format(&self) -> bool {
+        matches!(
+            self,
+            AccessLogField::Timestamp
+                | AccessLogField::RemoteAddr
+                | AccessLogField::Method
+                | AccessLogField::Path
+                | AccessLogField::Status
+                | AccessLogField::Bytes
+        )
+    }
+}
+
+fn default_access_log_fields() -> Vec<AccessLogField> {
+    vec![
+        AccessLogField::Timestamp,
+        AccessLogField::RemoteAddr,
+        AccessLogField::Method,
+        AccessLogField::Path,
+        AccessLogField::Status,
+        AccessLogField::Bytes,
+        AccessLogField::DurationMs,
+        AccessLogField::Db,
+        AccessLogField::Docs,
+        AccessLogField::ReadThrough,
+        AccessLogField::RequestId,
+    ]
+}
+
+/// Writes one line per request, separate from the tracing output.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AccessLogSettings {
+    #[serde(default = "default_access_log_format")]
+    pub format: AccessLogFormat,
+
+    /// Append to this file rather than writing to stdout.
+    pub path: Option<String>,
+
+    /// The fields written as JSON, or appended as `name=value` after the Common Log Format ones.
+    #[serde(default = "default_access_log_fields")]
+    pub fields: Vec<AccessLogField>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Endpoints that can be switched off for a database. Everything is enabled unless turned off.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DatabaseFeatures {
+    #[serde(default = "default_true")]
+    pub all_docs: bool,
+
+    /// Documents marked `_deleted` in `_bulk_docs`.
+    #[serde(default = "default_true")]
+    pub bulk_deletes: bool,
+
+    /// Views that run a `break_glass_js_script`.
+    #[serde(default = "default_true")]
+    pub break_glass_scripts: bool,
+
+    /// Change notifications over a WebSocket at `/{db}/_changes/_ws`. Off unless enabled.
+    #[serde(default)]
+    pub changes_websocket: bool,
+
+    /// Longpoll and continuous feeds at `/{db}/_changes`. Off unless enabled.
+    #[serde(default)]
+    pub changes_feed: bool,
+}
+
+impl Default for DatabaseFeatures {
+    fn default() -> Self {
+        DatabaseFeatures {
+            all_docs: true,
+            bulk_deletes: true,
+            break_glass_scripts: true,
+            changes_websocket: false,
+            changes_feed: false,
+        }
+    }
+}
+
+/// Runtime and connection tuning. Anything left unset keeps the Tokio or hyper default.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ServerSettings {
+    /// Threads running requests. Tokio defaults to one per core.
+    pub worker_threads: Option<usize>,
+
+    /// Threads available for blocking work such as file reads. Tokio defaults to 512.
+    pub max_blocking_threads: Option<usize>,
+
+    /// Connections the kernel queues before they're accepted. Defaults to 1024.
+    pub tcp_backlog: Option<u32>,
+
+    /// Keep HTTP/1 connections open between requests.
+    #[serde(default = "default_true")]
+    pub keep_alive: bool,
+
+    /// The most an HTTP/1 connection buffers while reading a request's headers, which caps their
+    /// size. hyper requires at least 8192.
+    pub max_header_bytes: Option<usize>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            worker_threads: None,
+            max_blocking_threads: None,
+            tcp_backlog: None,
+            keep_alive: true,
+            max_header_bytes: None,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Returns a multi-threaded Tokio runtime builder with the configured thread counts.
+    pub fn runtime_builder(&self) -> tokio::runtime::Builder {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all();
+
+        if let Some(worker_threads) = self.worker_threads {
+            builder.worker_threads(worker_threads);
+        }
+
+        if let Some(max_blocking_threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(max_blocking_threads);
+        }
+
+        builder
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.worker_threads == Some(0) || self.max_blocking_threads == Some(0) {
+            return Err("thread counts must be greater than zero".to_string());
+        }
+
+        if self.max_header_bytes.is_some_and(|m| m < 8192) {
+            return Err("max_header_bytes must be at least 8192".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+fn default_script_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_script_loop_iteration_limit() -> u64 {
+    10_000_000
+}
+
+fn default_script_recursion_limit() -> usize {
+    400
+}
+
+fn default_script_stack_size_limit() -> usize {
+    1024
+}
+
+fn default_script_queue_size() -> usize {
+    256
+}
+
+fn default_document_cache_max_entries() -> usize {
+    10_000
+}
+
+fn default_document_cache_ttl_ms() -> u64 {
+    30_000
+}
+
+/// Keeps recently read documents in memory.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DocumentCacheSettings {
+    /// How many documents are kept. The least recently read go first.
+    #[serde(default = "default_document_cache_max_entries")]
+    pub max_entries: usize,
+
+    /// How long a document is served from memory. Writes through this server drop it sooner, but
+    /// this bounds how stale a document written elsewhere can be.
+    #[serde(default = "default_document_cache_ttl_ms")]
+    pub ttl_ms: u64,
+
+    /// How long an id MongoDB had no document for is answered with a `404` from memory. Unset
+    /// doesn't remember missing documents.
+    pub missing_ttl_ms: Option<u64>,
+}
+
+fn default_redis_cache_ttl_ms() -> u64 {
+    30_000
+}
+
+/// Caches documents and view results in Redis, shared between replicas.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RedisCacheSettings {
+    /// A `redis://` URL. May be a `file:` or `env:` secret.
+    pub url: String,
+
+    /// How long an entry is kept.
+    #[serde(default = "default_redis_cache_ttl_ms")]
+    pub ttl_ms: u64,
+
+    /// Cache view results as well as documents.
+    #[serde(default = "default_true")]
+    pub views: bool,
+}
+
+fn default_expiry_field() -> String {
+    "couchapi_expire_at".to_string()
+}
+
+/// Documents in a database that MongoDB deletes once they expire.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ExpirySettings {
+    /// The field holding when a document expires, as an RFC 3339 date or seconds since the epoch.
+    #[serde(default = "default_expiry_field")]
+    pub field: String,
+}
+
+fn default_tenancy_separator() -> String {
+    "_".to_string()
+}
+
+/// Gives each tenant collections of its own, found from the tenant of the request's API key.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TenancySettings {
+    /// Goes between the tenant and the database to name the collection, as in `acme_orders`.
+    #[serde(default = "default_tenancy_separator")]
+    pub separator: String,
+
+    /// Collections named outright, keyed by tenant and then database, instead of by prefix.
+    #[serde(default)]
+    pub databases: HashMap<String, HashMap<String, String>>,
+}
+
+fn default_tombstone_interval_ms() -> u64 {
+    3_600_000
+}
+
+/// Purges documents deleted longer ago than their database's retention.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TombstoneSettings {
+    /// How often the purge runs.
+    #[serde(default = "default_tombstone_interval_ms")]
+    pub interval_ms: u64,
+
+    /// How long tombstones are kept, keyed by database. Databases not listed keep theirs.
+    #[serde(default)]
+    pub retention_hours: HashMap<String, u64>,
+}
+
+fn default_backup_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// Uploads zstd compressed exports of databases to S3 compatible storage on a schedule.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct BackupSettings {
+    /// The storage's endpoint, such as `https://s3.eu-west-1.amazonaws.com`.
+    pub endpoint: String,
+
+    #[serde(default = "default_backup_region")]
+    pub region: String,
+
+    /// The bucket for databases that don't name one of their own.
+    pub bucket: Option<String>,
+
+    /// Goes before `<db>/<time>.ndjson.zst` in the key of each snapshot.
+    #[serde(default)]
+    pub prefix: String,
+
+    /// May be a `file:` or `env:` secret.
+    pub access_key_id: String,
+
+    /// May be a `file:` or `env:` secret.
+    pub secret_access_key: String,
+
+    /// The databases backed up, keyed by database.
+    #[serde(default)]
+    pub databases: HashMap<String, DatabaseBackup>,
+}
+
+fn default_backup_retention() -> usize {
+    7
+}
+
+/// When and where one database is backed up.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DatabaseBackup {
+    /// A five field cron expression, in UTC, such as `0 3 * * *`.
+    pub schedule: String,
+
+    /// Overrides the bucket of the backup settings.
+    pub bucket: Option<String>,
+
+    /// How many snapshots are kept. Older ones are deleted once a new one is uploaded.
+    #[serde(default = "default_backup_retention")]
+    pub retention: usize,
+}
+
+impl BackupSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        for (db, backup) in &self.databases {
+            backup
+                .schedule
+                .parse::<crate::backup::cron::Schedule>()
+                .map_err(|e| format!("{}: {}", db, e))?;
+
+            if backup.bucket.is_none() && self.bucket.is_none() {
+                return Err(format!("{}: no bucket to back up to", db));
+            }
+            if backup.retention == 0 {
+                return Err(format!("{}: retention must be at least 1", db));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn default_compression_threshold_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+/// Stores large documents zstd compressed.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CompressionSettings {
+    /// Documents at least this big, as BSON, are compressed.
+    #[serde(default = "default_compression_threshold_bytes")]
+    pub threshold_bytes: usize,
+
+    /// The zstd level, from 1 to 22.
+    #[serde(default = "default_compression_level")]
+    pub level: i32,
+
+    /// Databases whose documents are compressed. Empty covers every database.
+    #[serde(default)]
+    pub databases: Vec<String>,
+
+    /// Top level fields left uncompressed alongside `_id` and `_rev`, so views can still match and
+    /// sort on them.
+    #[serde(default)]
+    pub keep_fields: Vec<String>,
+}
+
+fn default_dedup_threshold_bytes() -> usize {
+    16 * 1024
+}
+
+fn default_dedup_bucket() -> String {
+    "attachments".to_string()
+}
+
+/// Stores each large attachment once in GridFS, however many documents hold it.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct AttachmentDedupSettings {
+    /// Attachments at least this big, decoded, are moved to GridFS.
+    #[serde(default = "default_dedup_threshold_bytes")]
+    pub threshold_bytes: usize,
+
+    /// The GridFS bucket. Reference counts are kept in `<bucket>.refs`.
+    #[serde(default = "default_dedup_bucket")]
+    pub bucket: String,
+
+    /// Databases whose attachments are deduplicated. Empty covers every database.
+    #[serde(default)]
+    pub databases: Vec<String>,
+}
+
+/// How many view aggregations may run at once on each database.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ViewConcurrencySettings {
+    /// The limit for databases without one in `databases`. Unset leaves them unlimited.
+    pub max_per_database: Option<usize>,
+
+    #[serde(default)]
+    pub databases: HashMap<String, usize>,
+
+    /// How long a view waits for a slot before it gets a `503`. Defaults to turning it away at
+    /// once.
+    #[serde(default)]
+    pub queue_ms: u64,
+}
+
+/// How long requests may take, in milliseconds. Routes are named as they're routed, such as
+/// `/:db/_design/:design/_view/:view`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RequestTimeoutSettings {
+    /// The timeout for routes without one of their own. Unset leaves them without a timeout.
+    pub default_ms: Option<u64>,
+
+    #[serde(default)]
+    pub routes: HashMap<String, u64>,
+}
+
+/// Work done before the server takes traffic, so the first requests after a deploy aren't the
+/// ones that open MongoDB connections and plan each view.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct WarmupSettings {
+    /// Run each configured view once with `limit=1`.
+    #[serde(default)]
+    pub views: bool,
+}
+
+fn default_uuids_max_count() -> usize {
+    1000
+}
+
+/// Field rules applied to each document written to a database, before its transform hook. Fields
+/// are named by dotted paths, such as `customer.email`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct WriteTransforms {
+    /// Fields set to the time of the write, as an RFC 3339 UTC string.
+    #[serde(default)]
+    pub timestamp_fields: Vec<String>,
+
+    #[serde(default)]
+    pub lowercase_fields: Vec<String>,
+
+    #[serde(default)]
+    pub uppercase_fields: Vec<String>,
+
+    /// Fields with whitespace trimmed from either end.
+    #[serde(default)]
+    pub trim_fields: Vec<String>,
+
+    /// Fields set to a copy of another, keyed by the field written to.
+    #[serde(default)]
+    pub copy_fields: HashMap<String, String>,
+}
+
+fn default_maintenance_retry_after_secs() -> u64 {
+    30
+}
+
+/// Turns writes away while MongoDB fails over or is migrated, serving reads as usual. Can be
+/// switched at runtime through `/_couchapi/maintenance`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct MaintenanceSettings {
+    /// Start in maintenance mode.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Sent as `Retry-After` with each rejected write.
+    #[serde(default = "default_maintenance_retry_after_secs")]
+    pub retry_after_secs: u64,
+}
+
+impl Default for MaintenanceSettings {
+    fn default() -> Self {
+        MaintenanceSettings {
+            enabled: false,
+            retry_after_secs: default_maintenance_retry_after_secs(),
+        }
+    }
+}
+
+fn default_idempotency_ttl_secs() -> u64 {
+    86_400
+}
+
+/// How long writes made with an `X-Idempotency-Key` can be retried for.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct IdempotencySettings {
+    /// Seconds to keep each write's response for, after which MongoDB deletes it and the key can
+    /// be used again.
+    #[serde(default = "default_idempotency_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+impl IdempotencySettings {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+impl Default for IdempotencySettings {
+    fn default() -> Self {
+        IdempotencySettings {
+            ttl_secs: default_idempotency_ttl_secs(),
+        }
+    }
+}
+
+fn default_max_decompressed_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// How large a compressed request body may grow as it's decompressed, in bytes. Routes are named
+/// as they're routed, such as `/:db/_bulk_docs_stream`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DecompressionSettings {
+    /// The limit for routes without one of their own.
+    #[serde(default = "default_max_decompressed_bytes")]
+    pub max_bytes: u64,
+
+    #[serde(default)]
+    pub routes: HashMap<String, u64>,
+}
+
+impl Default for DecompressionSettings {
+    fn default() -> Self {
+        DecompressionSettings {
+            max_bytes: default_max_decompressed_bytes(),
+            routes: HashMap::new(),
+        }
+    }
+}
+
+/// How generated document ids and `/_uuids` are made, as CouchDB's `[uuids]` section.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct UuidSettings {
+    #[serde(default)]
+    pub algorithm: UuidAlgorithm,
+
+    /// Appended to the time by `utc_id`.
+    #[serde(default)]
+    pub utc_id_suffix: String,
+
+    /// The most ids one request to `/_uuids` may ask for.
+    #[serde(default = "default_uuids_max_count")]
+    pub max_count: usize,
+}
+
+impl Default for UuidSettings {
+    fn default() -> Self {
+        UuidSettings {
+            algorithm: UuidAlgorithm::default(),
+            utc_id_suffix: String::new(),
+            max_count: default_uuids_max_count(),
+        }
+    }
+}
+
+/// CouchDB's id algorithms.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UuidAlgorithm {
+    /// 32 random hex digits.
+    #[default]
+    Random,
+
+    /// A random prefix followed by a counter that grows by a random step, so ids sort in the
+    /// order they were made and land close together in an index.
+    Sequential,
+
+    /// The time in microseconds followed by random hex digits.
+    UtcRandom,
+
+    /// The time in microseconds followed by `utc_id_suffix`.
+    UtcId,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
-pub struct DesignView {
-    pub match_fields: Vec<String>,
-    pub sort_fields: Option<Vec<String>>,
-    pub aggregation: Vec<String>,
-    pub key_fields: Vec<String>,
-    pub value_fields: Vec<String>,
-    pub filter_insert_index: usize,
-    pub reduce: Option<HashMap<String, ReduceView>>,
+/// Limits on update handlers and break glass scripts.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct ScriptSettings {
+    /// How long a request waits on a script before it gets a `script_timeout` error.
+    #[serde(default = "default_script_timeout_ms")]
+    pub timeout_ms: u64,
 
-    #[serde(default)]
-    pub single_item_key_is_list: bool,
+    /// The most iterations any one loop in a script may run. A script can't be interrupted once
+    /// it has started, so this is what stops a runaway loop after its request has timed out.
+    #[serde(default = "default_script_loop_iteration_limit")]
+    pub loop_iteration_limit: u64,
+
+    /// How deep a script's function calls may nest.
+    #[serde(default = "default_script_recursion_limit")]
+    pub recursion_limit: usize,
+
+    /// How many values a script's stack may hold.
+    #[serde(default = "default_script_stack_size_limit")]
+    pub stack_size_limit: usize,
+
+    /// Threads dedicated to running scripts. Defaults to one per core.
+    pub workers: Option<usize>,
 
+    /// How many scripts may wait for a free thread before new ones are turned away.
+    #[serde(default = "default_script_queue_size")]
+    pub queue_size: usize,
+
+    /// Which JavaScript engine runs scripts.
     #[serde(default)]
-    pub single_item_value_is_dict: bool,
+    pub engine: ScriptEngineKind,
+}
 
-    pub break_glass_js_script: Option<String>,
+/// The JavaScript engines scripts can run on.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptEngineKind {
+    /// Boa, built into the server.
+    #[default]
+    Boa,
+
+    /// V8, through deno_core, in builds with the `deno` feature.
+    Deno,
+}
+
+impl Default for ScriptSettings {
+    fn default() -> Self {
+        ScriptSettings {
+            timeout_ms: default_script_timeout_ms(),
+            loop_iteration_limit: default_script_loop_iteration_limit(),
+            recursion_limit: default_script_recursion_limit(),
+            stack_size_limit: default_script_stack_size_limit(),
+            workers: None,
+            queue_size: default_script_queue_size(),
+            engine: ScriptEngineKind::default(),
+        }
+    }
+}
+
+/// An external process that speaks CouchDB's line-based query server protocol, such as `couchjs`
+/// with CouchDB's `main.js`, for update handlers the built-in engine can't run.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct QueryServerSettings {
+    pub command: String,
 
     #[serde(default)]
-    pub omit_null_keys_in_value: bool,
+    pub args: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct DesignMapping {
-    // Keyed by ViewGroup then by View
-    pub view_groups: HashMap<String, HashMap<String, DesignView>>,
+fn default_error_reporting_timeout() -> u64 {
+    5
 }
 
-#[derive(Debug, Deserialize)]
-pub struct CouchDb {
-    /// url defines the URL of the CouchDB server.
+/// Posts a JSON event for every 5xx response and panic to `url`, such as an error tracker's
+/// webhook or a relay in front of Sentry.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ErrorReporting {
     pub url: String,
+    pub environment: Option<String>,
 
-    /// username defines the username to use when connecting to the CouchDB server.
-    pub username: Option<String>,
+    /// How long, in seconds, to wait for the error tracker to accept an event.
+    #[serde(default = "default_error_reporting_timeout")]
+    pub timeout: u64,
+}
 
-    /// password defines the password to use when connecting to the CouchDB server.
+/// Keeps `/metrics` away from application traffic, either on its own listener or behind HTTP basic
+/// authentication (or both).
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MetricsSettings {
+    /// Serve `/metrics` on this address rather than on the main listener.
+    pub listen_address: Option<String>,
+
+    /// Require these credentials. Both must be set for authentication to be enforced.
+    pub username: Option<String>,
     pub password: Option<String>,
+}
+
+/// Serves the gRPC admin service, when the server is built with the `grpc` feature.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GrpcSettings {
+    /// The address the service listens on, apart from the HTTP listener.
+    pub listen_address: String,
+
+    /// The bearer token callers have to send in the `authorization` metadata.
+    pub token: String,
+}
+
+/// Lets internal services authenticate by signing requests with a shared secret.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RequestSigning {
+    /// How far, in seconds, a request's timestamp may be from the server's clock.
+    #[serde(default = "default_max_clock_skew")]
+    pub max_clock_skew: u64,
+
+    pub services: Vec<SigningService>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SigningService {
+    pub name: String,
+    pub secret: String,
 
-    /// When set to try, we will attempt to read a view from CouchDB if the view is not found in
-    /// the configuration file. This is useful for migrating from CouchDB to MongoDB.
     #[serde(default)]
-    pub read_through: bool,
+    pub roles: Vec<String>,
 
-    /// When set to true, we will not write to MongoDB but instead only to CouchDB
+    /// The tenant whose collections this service reaches, when `tenancy` is configured.
     #[serde(default)]
-    pub read_only: bool,
+    pub tenant: Option<String>,
+}
 
-    /// A list of databases that we will read views from CouchDB instead of MongoDB
-    /// if the view doesn't exist.
-    pub read_through_databases: Option<Vec<String>>,
+/// A token bucket: `requests_per_second` refills it and `burst` is its size.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
 
-    /// A list of databases that we will only read from MongoDB and write to CouchDB
-    pub read_only_databases: Option<Vec<String>>,
+/// Limits how fast each client can make requests against each database. Clients are told apart
+/// by API key name or client certificate name, falling back to their IP address.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RateLimitSettings {
+    #[serde(flatten)]
+    pub default: RateLimit,
 
-    /// mappings defines which CouchDB database to use on read and write. The key is the MongoDB
-    /// Collection name and the value is the CouchDB database name.
-    pub mappings: Option<HashMap<String, String>>,
+    /// Limits for particular databases, overriding the default.
+    #[serde(default)]
+    pub databases: HashMap<String, RateLimit>,
+}
+
+fn default_cors_origins() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+/// Mirrors CouchDB's `[cors]` section. `origins` may contain `*` to allow any origin.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CorsSettings {
+    #[serde(default = "default_cors_origins")]
+    pub origins: Vec<String>,
+
+    #[serde(default)]
+    pub credentials: bool,
+
+    pub methods: Option<Vec<String>>,
+    pub headers: Option<Vec<String>>,
+
+    /// How long, in seconds, browsers may cache a preflight response.
+    pub max_age: Option<u64>,
+}
+
+/// Serve HTTPS rather than HTTP. When `client_ca_file` is set, client certificates signed by that
+/// CA are verified and the certificate's common name is used as the user's name.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TlsSettings {
+    pub cert_file: String,
+    pub key_file: String,
+    pub client_ca_file: Option<String>,
+
+    /// Reject connections that don't present a valid client certificate.
+    #[serde(default)]
+    pub require_client_cert: bool,
+
+    /// Roles granted to clients, matched against the certificate's common name or any of its DNS
+    /// subject alternative names.
+    #[serde(default)]
+    pub clients: Vec<TlsClient>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TlsClient {
+    pub name: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(unused)]
 pub struct Settings {
     #[serde(default)]
@@ -130,6 +1285,9 @@ pub struct Settings {
 
     pub views: Option<HashMap<String, DesignMapping>>,
     pub view_folder: Option<String>,
+
+    pub search_indexes: Option<SearchIndexes>,
+    pub geo_indexes: Option<GeoIndexes>,
     pub updates_folder: Option<String>,
 
     pub couchdb_settings: Option<CouchDb>,
@@ -139,6 +1297,155 @@ pub struct Settings {
 
     #[serde(default = "default_log_level")]
     pub log_level: LogLevel,
+
+    /// The default CouchDB style `r` value applied to reads when the request doesn't specify
+    /// one. Either a replica count or a MongoDB read concern level such as `majority`.
+    pub default_r: Option<String>,
+
+    /// The default CouchDB style `w` value applied to writes when the request doesn't specify
+    /// one. Either a replica count or `majority`.
+    pub default_w: Option<String>,
+
+    /// When set, every database request must carry one of these keys and is limited to the
+    /// databases and methods the key allows.
+    pub api_keys: Option<Vec<ApiKey>>,
+
+    /// Security objects keyed by database. These take precedence over any `_security` document
+    /// stored for the database and are only enforced when API keys, session cookies or client
+    /// certificates are configured.
+    pub security: Option<HashMap<String, SecurityObject>>,
+
+    /// Lets any writer run update handlers, write design documents, purge and create or delete
+    /// databases rather than requiring an admin. Meant for development environments.
+    #[serde(default)]
+    pub open_admin_routes: bool,
+
+    /// Accept replication from CouchDB: `_local` documents, `_revs_diff`, `new_edits=false` and
+    /// `_ensure_full_commit`.
+    #[serde(default)]
+    pub replication_target: bool,
+
+    pub tls: Option<TlsSettings>,
+
+    /// Answer CORS preflight requests and add CORS headers to responses.
+    pub cors: Option<CorsSettings>,
+
+    pub rate_limit: Option<RateLimitSettings>,
+
+    /// Enables `AuthSession` cookies using the same secret as the CouchDB being replaced.
+    pub couch_httpd_auth: Option<CouchHttpdAuth>,
+
+    /// Accept requests signed with a per-service secret in place of an API key.
+    pub request_signing: Option<RequestSigning>,
+
+    pub metrics: Option<MetricsSettings>,
+
+    /// Serve the gRPC admin service, for control planes, on a listener of its own.
+    pub grpc: Option<GrpcSettings>,
+
+    pub error_reporting: Option<ErrorReporting>,
+
+    pub access_log: Option<AccessLogSettings>,
+
+    /// Respond with a `503` rather than start on a request when this many are already in flight.
+    pub max_in_flight: Option<usize>,
+
+    /// JSON Schema files, by database, that documents written to the database have to match.
+    pub schemas: Option<HashMap<String, String>>,
+
+    /// Field rules applied to documents as they're written, by database.
+    pub write_transforms: Option<HashMap<String, WriteTransforms>>,
+
+    /// Reject writes with a `503` while keeping reads up.
+    #[serde(default)]
+    pub maintenance: MaintenanceSettings,
+
+    /// How long responses to writes with an `X-Idempotency-Key` are kept for retries.
+    #[serde(default)]
+    pub idempotency: IdempotencySettings,
+
+    /// Reject compressed request bodies that decompress to more than this with a `413`.
+    #[serde(default)]
+    pub decompression: DecompressionSettings,
+
+    /// Serve repeated reads of the same documents from memory.
+    pub document_cache: Option<DocumentCacheSettings>,
+
+    /// Share cached documents and view results between replicas through Redis.
+    pub redis_cache: Option<RedisCacheSettings>,
+
+    /// Databases whose documents expire, keyed by database.
+    pub expiry: Option<HashMap<String, ExpirySettings>>,
+
+    /// Other names for databases, keyed by the alias, such as an old name during a rename.
+    pub aliases: Option<HashMap<String, String>>,
+
+    /// Keep each API key tenant's data in collections of its own.
+    pub tenancy: Option<TenancySettings>,
+
+    /// Purge old tombstones in the background.
+    pub tombstones: Option<TombstoneSettings>,
+
+    /// Store documents above a size zstd compressed.
+    pub compression: Option<CompressionSettings>,
+
+    /// Store large attachments once each in GridFS, keyed by their content.
+    pub attachment_dedup: Option<AttachmentDedupSettings>,
+
+    /// Back databases up to S3 compatible storage on a schedule.
+    pub backups: Option<BackupSettings>,
+
+    /// Shape responses as this CouchDB release did, for clients that check its fields.
+    pub compatibility: Option<Compatibility>,
+
+    /// Limit how many views run at once on each database.
+    pub view_concurrency: Option<ViewConcurrencySettings>,
+
+    /// Abandon requests that take longer than this, per route.
+    pub request_timeouts: Option<RequestTimeoutSettings>,
+
+    /// Open the connection pool up to `minPoolSize`, and optionally run the views, at startup.
+    pub warmup: Option<WarmupSettings>,
+
+    /// The `limit` used for views and `_all_docs` when the request doesn't give one.
+    pub default_limit: Option<i64>,
+
+    /// The largest `limit` a request to a view or `_all_docs` can ask for.
+    pub max_limit: Option<i64>,
+
+    /// How many documents from one `_bulk_docs` request are written at once. Defaults to 16.
+    pub bulk_concurrency: Option<usize>,
+
+    /// Endpoints switched off per database. The `*` entry covers databases without one of their
+    /// own.
+    pub features: Option<HashMap<String, DatabaseFeatures>>,
+
+    #[serde(default)]
+    pub server: ServerSettings,
+
+    #[serde(default)]
+    pub scripts: ScriptSettings,
+
+    #[serde(default)]
+    pub uuids: UuidSettings,
+
+    /// Runs update handlers on an external CouchDB query server rather than the built-in engine.
+    pub query_server: Option<QueryServerSettings>,
+}
+
+/// Resolves a config value that may refer to a secret held elsewhere. `file:<path>` is replaced
+/// by the contents of the file, with surrounding whitespace trimmed, and `env:<name>` by the value
+/// of the environment variable. Anything else is returned as-is.
+pub fn resolve_secret(value: &str) -> Result<String, String> {
+    if let Some(path) = value.strip_prefix("file:") {
+        fs::read_to_string(path)
+            .map(|s| s.trim().to_string())
+            .map_err(|e| format!("unable to read secret from {}: {}", path, e))
+    } else if let Some(name) = value.strip_prefix("env:") {
+        std::env::var(name).map_err(|e| format!("unable to read secret from ${}: {}", name, e))
+    } else {
+        Ok(value.to_string())
+    }
 }
 
 impl Settings {
@@ -149,6 +1456,13 @@ impl Settings {
     /// returned. If an error occurs during the deserialization process, a `ConfigError` is
     /// returned.
     pub fn new(config_file: Option<String>) -> Result<Self, ConfigError> {
+        Self::from_sources(config_file, std::env::vars())
+    }
+
+    fn from_sources(
+        config_file: Option<String>,
+        env_vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
         let mut config_builder =
             Config::builder().add_source(Environment::with_prefix("couch_stream"));
 
@@ -156,9 +1470,31 @@ impl Settings {
             None => {}
             Some(file) => {
                 config_builder = config_builder.add_source(config::File::with_name(&file));
+
+                // Files pulled in by `include` are merged over the main file, in order.
+                let includes = match Config::builder()
+                    .add_source(config::File::with_name(&file))
+                    .build()?
+                    .get::<Vec<String>>("include")
+                {
+                    Ok(includes) => includes,
+                    Err(ConfigError::NotFound(_)) => vec![],
+                    Err(e) => return Err(e),
+                };
+
+                for path in resolve_includes(&file, &includes)? {
+                    config_builder = config_builder.add_source(config::File::from(path));
+                }
             }
         }
 
+        // Nested overrides go last so they win over both the file and the flat variables.
+        let overrides = env_overrides(env_vars);
+        config_builder = config_builder.add_source(config::File::from_str(
+            &overrides.to_string(),
+            config::FileFormat::Json,
+        ));
+
         config_builder.build()?.try_deserialize()
     }
 
@@ -180,85 +1516,9 @@ impl Settings {
             return;
         }
 
-        // Iterate over all files in the view folder with the extension ".toml"
-        let walker = WalkDir::new(self.view_folder.as_ref().unwrap()).into_iter();
-        let mut view_groups: HashMap<String, DesignMapping> = HashMap::new();
-
-        for entry in walker {
-            let entry = match entry {
-                Ok(entry) => entry,
-                Err(_) => continue,
-            };
-
-            let path = entry.path();
-
-            let file_name = match path.file_name() {
-                Some(file_name) => file_name,
-                None => continue,
-            };
-
-            let file_name_str = match file_name.to_str() {
-                Some(s) => s,
-                None => continue,
-            };
-
-            if !file_name_str.ends_with(".toml") {
-                continue;
-            }
-
-            // Extract the view group name, database name, and view name from the file path
-            let view_group_name = path
-                .parent()
-                .and_then(|p| p.file_name())
-                .and_then(|os_str| os_str.to_str())
-                .map(|s| s.to_string())
-                .unwrap_or_default();
-
-            let db_name = path
-                .parent()
-                .and_then(|p| p.parent())
-                .and_then(|p| p.file_name())
-                .and_then(|os_str| os_str.to_str())
-                .map(|s| s.to_string())
-                .unwrap_or_default();
-
-            let view_name = file_name_str.replace(".toml", "");
-
-            // Read the contents of the file and parse it into a `DesignView` struct
-            let contents = match fs::read_to_string(path) {
-                Ok(c) => c,
-                Err(_) => {
-                    println!("could not read file");
-                    continue;
-                }
-            };
-
-            let design_view: DesignView = match toml::from_str(&contents) {
-                Ok(design_view) => design_view,
-                Err(_) => {
-                    println!("could not parse file");
-                    continue;
-                }
-            };
-
-            // Insert the view into the `view_groups` HashMap
-            info!(
-                db_name = db_name.as_str(),
-                view_group_name = view_group_name.as_str(),
-                view_name = view_name.as_str(),
-                "adding view"
-            );
-
-            // Create an empty view group IF we need one
-            let design_mapping = view_groups.entry(db_name.clone()).or_insert(DesignMapping {
-                view_groups: hashmap! {},
-            });
-
-            let db_mapping = design_mapping
-                .view_groups
-                .entry(view_group_name.clone())
-                .or_insert(hashmap! {});
-            db_mapping.insert(view_name.clone(), design_view);
+        let (view_groups, errors) = load_views_from_folder(self.view_folder.as_ref().unwrap());
+        for e in errors {
+            error!(error = e.as_str(), "unable to load view");
         }
 
         // Insert the `view_groups` HashMap into the `views` field of the `Settings` struct
@@ -317,6 +1577,134 @@ impl Settings {
 
         Ok(db)
     }
+
+    /// Resolves any `file:` or `env:` references in the settings that hold secrets: the MongoDB
+    /// connection string, the CouchDB credentials, the API keys, the session and signing secrets,
+    /// the gRPC token and the backup credentials. TLS files are resolved when they are loaded.
+    pub fn resolve_secrets(&mut self) -> Result<(), String> {
+        self.mongodb_connect_string = resolve_secret(&self.mongodb_connect_string)?;
+
+        if let Some(couchdb) = self.couchdb_settings.as_mut() {
+            couchdb.username = couchdb
+                .username
+                .as_deref()
+                .map(resolve_secret)
+                .transpose()?;
+            couchdb.password = couchdb
+                .password
+                .as_deref()
+                .map(resolve_secret)
+                .transpose()?;
+        }
+
+        for api_key in self.api_keys.iter_mut().flatten() {
+            api_key.key = resolve_secret(&api_key.key)?;
+        }
+
+        if let Some(auth) = self.couch_httpd_auth.as_mut() {
+            auth.secret = resolve_secret(&auth.secret)?;
+        }
+
+        for service in self
+            .request_signing
+            .iter_mut()
+            .flat_map(|s| &mut s.services)
+        {
+            service.secret = resolve_secret(&service.secret)?;
+        }
+
+        if let Some(reporting) = self.error_reporting.as_mut() {
+            reporting.url = resolve_secret(&reporting.url)?;
+        }
+
+        if let Some(grpc) = self.grpc.as_mut() {
+            grpc.token = resolve_secret(&grpc.token)?;
+        }
+
+        if let Some(backups) = self.backups.as_mut() {
+            backups.access_key_id = resolve_secret(&backups.access_key_id)?;
+            backups.secret_access_key = resolve_secret(&backups.secret_access_key)?;
+        }
+
+        if let Some(metrics) = self.metrics.as_mut() {
+            metrics.password = metrics
+                .password
+                .as_deref()
+                .map(resolve_secret)
+                .transpose()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the MongoDB read concern for the configured `default_r`, if there is one.
+    pub fn get_default_read_concern(&self) -> Result<Option<ReadConcern>, String> {
+        self.default_r
+            .as_deref()
+            .map(parse_read_concern)
+            .transpose()
+    }
+
+    /// Returns the MongoDB write concern for the configured `default_w`, if there is one.
+    pub fn get_default_write_concern(&self) -> Result<Option<WriteConcern>, String> {
+        self.default_w
+            .as_deref()
+            .map(parse_write_concern)
+            .transpose()
+    }
+}
+
+impl ApiKey {
+    /// Returns `true` if this key may use the given method against the given database.
+    pub fn allows(&self, db: &str, method: &str) -> bool {
+        let db_allowed = self.databases.iter().any(|d| d == "*" || d == db);
+        let method_allowed = self
+            .methods
+            .as_ref()
+            .map(|m| m.iter().any(|m| m.eq_ignore_ascii_case(method)))
+            .unwrap_or(true);
+
+        db_allowed && method_allowed
+    }
+}
+
+impl SecurityGroup {
+    fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.roles.is_empty()
+    }
+
+    fn contains(&self, name: Option<&str>, roles: &[String]) -> bool {
+        name.is_some_and(|n| self.names.iter().any(|x| x == n))
+            || roles.iter().any(|r| self.roles.contains(r))
+    }
+}
+
+impl SecurityObject {
+    /// Returns `true` if the user is a server admin or listed in `admins`.
+    pub fn is_admin(&self, name: Option<&str>, roles: &[String]) -> bool {
+        roles.iter().any(|r| r == "_admin") || self.admins.contains(name, roles)
+    }
+
+    /// Returns `true` if the user may read from the database.
+    pub fn can_read(&self, name: Option<&str>, roles: &[String]) -> bool {
+        self.is_admin(name, roles)
+            || self.members.is_empty()
+            || self.members.contains(name, roles)
+            || self
+                .writers
+                .as_ref()
+                .is_some_and(|w| w.contains(name, roles))
+    }
+
+    /// Returns `true` if the user may write to the database.
+    pub fn can_write(&self, name: Option<&str>, roles: &[String]) -> bool {
+        match &self.writers {
+            Some(writers) if !writers.is_empty() => {
+                self.is_admin(name, roles) || writers.contains(name, roles)
+            }
+            _ => self.can_read(name, roles),
+        }
+    }
 }
 
 impl CouchDb {
@@ -346,6 +1734,18 @@ impl CouchDb {
                 .contains(&db.to_string())
     }
 
+    /// Returns `true` if document `id` of `db` is read from MongoDB rather than CouchDB, as its
+    /// database's `soft_launch` percentage decides.
+    pub fn reads_from_mongo(&self, db: &str, id: &str) -> bool {
+        let Some(percent) = self.soft_launch.as_ref().and_then(|s| s.get(db)) else {
+            return true;
+        };
+
+        let digest = md5::compute(id.as_bytes());
+        let bucket = u64::from_be_bytes(digest.0[..8].try_into().unwrap()) % 100;
+        bucket < u64::from(*percent)
+    }
+
     /// Returns `true` either if read_only is `true` or if the given database name is found
     /// in the `read_only_databases` vector. Otherwise, returns `false`.
     pub fn is_read_only(&self, db: &str) -> bool {
@@ -360,8 +1760,19 @@ impl CouchDb {
 
 #[cfg(test)]
 mod tests {
-    use super::CouchDb;
+    use super::{
+        env_overrides,
+        resolve_secret,
+        wildcard_match,
+        ApiKey,
+        CouchDb,
+        SecurityGroup,
+        SecurityObject,
+        Settings,
+    };
+    use serde_json::json;
     use std::collections::HashMap;
+    use std::fs;
 
     #[test]
     fn test_no_mappings() {
@@ -374,6 +1785,8 @@ mod tests {
             mappings: None,
             read_through_databases: None,
             read_only_databases: None,
+            stale_fallback: false,
+            soft_launch: None,
         };
         assert_eq!(couch.map_for_db("test_db"), "test_db".to_string());
     }
@@ -392,6 +1805,8 @@ mod tests {
             mappings: Some(map),
             read_through_databases: None,
             read_only_databases: None,
+            stale_fallback: false,
+            soft_launch: None,
         };
         assert_eq!(couch.map_for_db("test_db"), "test_db".to_string());
     }
@@ -410,6 +1825,8 @@ mod tests {
             mappings: Some(map),
             read_through_databases: None,
             read_only_databases: None,
+            stale_fallback: false,
+            soft_launch: None,
         };
         assert_eq!(couch.map_for_db("test_db"), "mapped_value".to_string());
     }
@@ -424,6 +1841,8 @@ mod tests {
             read_only: false,
             read_through_databases: None,
             read_only_databases: None,
+            stale_fallback: false,
+            soft_launch: None,
             mappings: None,
         };
 
@@ -449,6 +1868,45 @@ mod tests {
         assert!(!db.should_read_through("other_db"));
     }
 
+    #[test]
+    fn test_reads_from_mongo() {
+        let db = CouchDb {
+            url: "https://example.com".to_string(),
+            username: None,
+            password: None,
+            read_through: false,
+            read_only: false,
+            read_through_databases: None,
+            read_only_databases: None,
+            stale_fallback: false,
+            soft_launch: Some(HashMap::from([
+                ("none".to_string(), 0),
+                ("half".to_string(), 50),
+                ("most".to_string(), 75),
+                ("all".to_string(), 100),
+            ])),
+            mappings: None,
+        };
+
+        let ids = (0..1000).map(|i| format!("doc{}", i)).collect::<Vec<_>>();
+        let from_mongo = |name: &str| {
+            ids.iter()
+                .filter(|id| db.reads_from_mongo(name, id))
+                .count()
+        };
+
+        assert_eq!(from_mongo("none"), 0);
+        assert_eq!(from_mongo("all"), 1000);
+        assert_eq!(from_mongo("other"), 1000);
+        assert!((400..600).contains(&from_mongo("half")));
+
+        // Raising the percentage only moves documents over to MongoDB.
+        assert!(ids
+            .iter()
+            .filter(|id| db.reads_from_mongo("half", id))
+            .all(|id| db.reads_from_mongo("most", id)));
+    }
+
     #[test]
     fn test_is_read_only() {
         let db = CouchDb {
@@ -459,6 +1917,8 @@ mod tests {
             read_only: false,
             read_through_databases: None,
             read_only_databases: None,
+            stale_fallback: false,
+            soft_launch: None,
             mappings: None,
         };
 
@@ -483,4 +1943,257 @@ mod tests {
         // 4. Database NOT in read_only_databases
         assert!(!db.is_read_only("other_db"));
     }
+
+    #[test]
+    fn test_api_key_allows() {
+        let key = ApiKey {
+            key: "secret".to_string(),
+            name: None,
+            databases: vec!["test_db".to_string()],
+            methods: None,
+            roles: vec![],
+            tenant: None,
+        };
+
+        // 1. Any method on a listed database
+        assert!(key.allows("test_db", "GET"));
+        assert!(key.allows("test_db", "DELETE"));
+
+        // 2. Database not listed
+        assert!(!key.allows("other_db", "GET"));
+
+        // 3. Restricted methods
+        let key = ApiKey {
+            methods: Some(vec!["get".to_string(), "HEAD".to_string()]),
+            ..key
+        };
+        assert!(key.allows("test_db", "GET"));
+        assert!(!key.allows("test_db", "PUT"));
+
+        // 4. Wildcard database
+        let key = ApiKey {
+            databases: vec!["*".to_string()],
+            ..key
+        };
+        assert!(key.allows("anything", "HEAD"));
+    }
+
+    #[test]
+    fn test_security_object() {
+        let reader = vec!["reader".to_string()];
+        let writer = vec!["writer".to_string()];
+        let admin = vec!["admin".to_string()];
+        let server_admin = vec!["_admin".to_string()];
+
+        // 1. No members means the database is public
+        let security = SecurityObject::default();
+        assert!(security.can_read(None, &[]));
+        assert!(security.can_write(None, &[]));
+        assert!(!security.is_admin(None, &[]));
+        assert!(security.is_admin(None, &server_admin));
+
+        // 2. Members can read and write
+        let security = SecurityObject {
+            admins: SecurityGroup {
+                names: vec![],
+                roles: admin.clone(),
+            },
+            members: SecurityGroup {
+                names: vec!["alice".to_string()],
+                roles: reader.clone(),
+            },
+            writers: None,
+        };
+        assert!(security.can_read(Some("alice"), &[]));
+        assert!(security.can_write(None, &reader));
+        assert!(!security.can_read(Some("bob"), &[]));
+        assert!(security.can_write(None, &admin));
+        assert!(security.is_admin(None, &admin));
+
+        // 3. Writers narrows down who may write
+        let security = SecurityObject {
+            writers: Some(SecurityGroup {
+                names: vec![],
+                roles: writer.clone(),
+            }),
+            ..security
+        };
+        assert!(security.can_read(None, &reader));
+        assert!(!security.can_write(None, &reader));
+        assert!(security.can_read(None, &writer));
+        assert!(security.can_write(None, &writer));
+        assert!(security.can_write(None, &admin));
+    }
+
+    #[test]
+    fn test_resolve_secret() {
+        std::env::set_var("COUCHAPI_TEST_SECRET", "from-env");
+        let path = std::env::temp_dir().join(format!("couchapi-secret-{}", std::process::id()));
+        fs::write(&path, "from-file\n").unwrap();
+
+        assert_eq!(resolve_secret("plain").unwrap(), "plain");
+        assert_eq!(
+            resolve_secret("env:COUCHAPI_TEST_SECRET").unwrap(),
+            "from-env"
+        );
+        assert_eq!(
+            resolve_secret(&format!("file:{}", path.display())).unwrap(),
+            "from-file"
+        );
+        assert!(resolve_secret("env:COUCHAPI_TEST_MISSING_SECRET").is_err());
+        assert!(resolve_secret("file:/nonexistent/secret").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let overrides = env_overrides(vec![
+            (
+                "COUCH_STREAM__COUCHDB_SETTINGS__URL".to_string(),
+                "http://couchdb:5984".to_string(),
+            ),
+            (
+                "COUCH_STREAM__COUCHDB_SETTINGS__READ_ONLY".to_string(),
+                "true".to_string(),
+            ),
+            (
+                "COUCH_STREAM__VIEWS__ORDERS__VIEW_GROUPS__BY_DATE__BY_DAY".to_string(),
+                r#"{"match_fields": ["date"]}"#.to_string(),
+            ),
+            (
+                "COUCH_STREAM_MONGODB_DATABASE".to_string(),
+                "db".to_string(),
+            ),
+            ("COUCH_STREAM____BROKEN".to_string(), "x".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ]);
+
+        assert_eq!(
+            overrides,
+            json!({
+                "couchdb_settings": {
+                    "url": "http://couchdb:5984",
+                    "read_only": true,
+                },
+                "views": {
+                    "orders": {
+                        "view_groups": {
+                            "by_date": {
+                                "by_day": {"match_fields": ["date"]},
+                            },
+                        },
+                    },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.toml", "orders.toml"));
+        assert!(wildcard_match("orders-?.toml", "orders-1.toml"));
+        assert!(wildcard_match("*-views*.toml", "billing-views-v2.toml"));
+        assert!(!wildcard_match("*.toml", "orders.toml.bak"));
+        assert!(!wildcard_match("orders-?.toml", "orders-10.toml"));
+    }
+
+    #[test]
+    fn test_settings_includes() {
+        let dir = std::env::temp_dir().join(format!("couchapi-include-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("views")).unwrap();
+
+        let main = dir.join("config.toml");
+        fs::write(
+            &main,
+            r#"
+include = ["views/*.toml", "overrides.toml"]
+mongodb_connect_string = "mongodb://localhost:27017"
+mongodb_database = "test"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("views").join("orders.toml"),
+            r#"
+[views.orders.view_groups.orders.by_date]
+match_fields = ["type"]
+aggregation = []
+key_fields = ["date"]
+value_fields = ["total"]
+filter_insert_index = 0
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("views").join("users.toml"),
+            r#"
+[views.users.view_groups.users.by_email]
+match_fields = ["type"]
+aggregation = []
+key_fields = ["email"]
+value_fields = []
+filter_insert_index = 0
+"#,
+        )
+        .unwrap();
+        fs::write(dir.join("views").join("README.md"), "not config").unwrap();
+        fs::write(
+            dir.join("overrides.toml"),
+            r#"mongodb_database = "included""#,
+        )
+        .unwrap();
+
+        let settings = Settings::from_sources(Some(main.display().to_string()), vec![]).unwrap();
+
+        let missing = dir.join("missing.toml");
+        fs::write(&missing, r#"include = ["nope.toml"]"#).unwrap();
+        let error = Settings::from_sources(Some(missing.display().to_string()), vec![]);
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        let views = settings.views.unwrap();
+        assert!(views["orders"].view_groups["orders"].contains_key("by_date"));
+        assert!(views["users"].view_groups["users"].contains_key("by_email"));
+        assert_eq!(settings.mongodb_database, "included");
+        assert!(error.unwrap_err().to_string().contains("nope.toml"));
+    }
+
+    #[test]
+    fn test_settings_env_overrides_file() {
+        let path =
+            std::env::temp_dir().join(format!("couchapi-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+mongodb_connect_string = "mongodb://localhost:27017"
+mongodb_database = "test"
+
+[couchdb_settings]
+url = "http://localhost:5984"
+username = "admin"
+"#,
+        )
+        .unwrap();
+
+        let settings = Settings::from_sources(
+            Some(path.display().to_string()),
+            vec![
+                (
+                    "COUCH_STREAM__COUCHDB_SETTINGS__URL".to_string(),
+                    "http://couchdb:5984".to_string(),
+                ),
+                (
+                    "COUCH_STREAM__MONGODB_DATABASE".to_string(),
+                    "override".to_string(),
+                ),
+            ],
+        )
+        .unwrap();
+
+        let couchdb = settings.couchdb_settings.unwrap();
+        assert_eq!(couchdb.url, "http://couchdb:5984");
+        assert_eq!(couchdb.username, Some("admin".to_string()));
+        assert_eq!(settings.mongodb_database, "override");
+
+        fs::remove_file(&path).unwrap();
+    }
 }
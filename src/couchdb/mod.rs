@@ -12,8 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod stale;
+
+use crate::common::{REQUEST_ID, REQUEST_ID_HEADER};
 use crate::config::CouchDb;
 use crate::ops::JsonWithStatusCodeResponse;
+use axum::body::Bytes;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use reqwest::Method;
@@ -37,7 +41,7 @@ pub async fn read_through(
 
     inner_couch(
         method,
-        json_payload,
+        json_payload.map(CouchBody::Json),
         &url,
         params,
         maybe_auth(couchdb_details),
@@ -54,10 +58,17 @@ fn maybe_auth(couchdb_details: &CouchDb) -> Option<(&str, &str)> {
     None
 }
 
+/// A body to send to CouchDB, either JSON or passed on as it was sent to us.
+#[derive(Debug)]
+enum CouchBody<'a> {
+    Json(&'a Value),
+    Raw(Bytes, Option<&'a str>),
+}
+
 #[instrument]
 async fn inner_couch(
     method: Method,
-    json_payload: Option<&Value>,
+    body: Option<CouchBody<'_>>,
     url: &Url,
     params: &HashMap<String, String>,
     auth_details: Option<(&str, &str)>,
@@ -73,8 +84,19 @@ async fn inner_couch(
         req = req.basic_auth(username, Some(password));
     }
 
-    if let Some(json_payload) = json_payload {
-        req = req.json(json_payload);
+    match body {
+        Some(CouchBody::Json(json_payload)) => req = req.json(json_payload),
+        Some(CouchBody::Raw(body, content_type)) => {
+            if let Some(content_type) = content_type {
+                req = req.header(reqwest::header::CONTENT_TYPE, content_type);
+            }
+            req = req.body(body);
+        }
+        None => {}
+    }
+
+    if let Ok(request_id) = REQUEST_ID.try_with(|id| id.clone()) {
+        req = req.header(REQUEST_ID_HEADER, request_id);
     }
 
     // Try and send the request
@@ -111,7 +133,7 @@ async fn inner_couch(
     *r.status_mut() = status_code;
 
     header_map.iter().for_each(|(k, v)| {
-        if k.as_str().to_ascii_lowercase() == "transfer-encoding" {
+        if k.as_str().eq_ignore_ascii_case("transfer-encoding") {
             info!("Skipping transfer-encoding header");
             return;
         }
@@ -137,6 +159,47 @@ pub async fn maybe_write(
     json_payload: Option<&Value>,
     path: &str,
     params: &HashMap<String, String>,
+) -> Result<Option<Response>, JsonWithStatusCodeResponse> {
+    write_through(
+        couchdb_details,
+        mongodb_db,
+        method,
+        json_payload.map(CouchBody::Json),
+        path,
+        params,
+    )
+    .await
+}
+
+/// As `maybe_write`, for a body that is passed on as it was sent, whatever its content type.
+#[instrument(skip(body))]
+pub async fn maybe_write_raw(
+    couchdb_details: &Option<CouchDb>,
+    mongodb_db: &str,
+    method: Method,
+    body: Bytes,
+    content_type: Option<&str>,
+    path: &str,
+    params: &HashMap<String, String>,
+) -> Result<Option<Response>, JsonWithStatusCodeResponse> {
+    write_through(
+        couchdb_details,
+        mongodb_db,
+        method,
+        Some(CouchBody::Raw(body, content_type)),
+        path,
+        params,
+    )
+    .await
+}
+
+async fn write_through(
+    couchdb_details: &Option<CouchDb>,
+    mongodb_db: &str,
+    method: Method,
+    body: Option<CouchBody<'_>>,
+    path: &str,
+    params: &HashMap<String, String>,
 ) -> Result<Option<Response>, JsonWithStatusCodeResponse> {
     // Just return if there are no couchdb details
     if couchdb_details.is_none() {
@@ -156,15 +219,9 @@ pub async fn maybe_write(
     let mut url = Url::parse(&couchdb_details.url).unwrap();
     url.set_path(full_path.as_str());
 
-    inner_couch(
-        method,
-        json_payload,
-        &url,
-        params,
-        maybe_auth(couchdb_details),
-    )
-    .await
-    .map(Some)
+    inner_couch(method, body, &url, params, maybe_auth(couchdb_details))
+        .await
+        .map(Some)
 }
 
 #[cfg(test)]
@@ -207,4 +264,78 @@ mod tests {
 
         mock.assert_async().await;
     }
+
+    #[tokio::test]
+    async fn test_inner_couch_forwards_request_id() {
+        let server = MockServer::start_async().await;
+
+        let mock = server
+            .mock_async(|when, then| {
+                when.method(GET)
+                    .path("/test")
+                    .header(REQUEST_ID_HEADER, "abc-123");
+                then.status(200).body("success");
+            })
+            .await;
+
+        let url = Url::parse(&server.base_url())
+            .unwrap()
+            .join("/test")
+            .unwrap();
+
+        let params = HashMap::new();
+        let response = REQUEST_ID
+            .scope(
+                "abc-123".to_string(),
+                inner_couch(Method::GET, None, &url, &params, None),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_maybe_write_forwards_query() {
+        let server = MockServer::start_async().await;
+
+        let mock = server
+            .mock_async(|when, then| {
+                when.method(httpmock::Method::PUT)
+                    .path("/orders/_design/d/_update/f")
+                    .query_param("dry_run", "true");
+                then.status(201).body("{}");
+            })
+            .await;
+
+        let couchdb_details = Some(CouchDb {
+            url: server.base_url(),
+            username: None,
+            password: None,
+            read_through: false,
+            read_only: true,
+            read_through_databases: None,
+            read_only_databases: None,
+            stale_fallback: false,
+            soft_launch: None,
+            mappings: None,
+        });
+        let params = HashMap::from([("dry_run".to_string(), "true".to_string())]);
+
+        let response = maybe_write(
+            &couchdb_details,
+            "orders",
+            Method::PUT,
+            Some(&serde_json::json!({})),
+            "_design/d/_update/f",
+            &params,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        mock.assert_async().await;
+    }
 }
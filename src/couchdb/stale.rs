@@ -0,0 +1,299 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::CouchDb;
+use crate::couchdb::read_through;
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use axum::body::Body;
+use axum::http::header::{AGE, CONTENT_TYPE};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::Response;
+use bson::{doc, DateTime};
+use http_body_util::BodyExt;
+use mongodb::options::{FindOneOptions, ReplaceOptions};
+use reqwest::Method;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use tracing::warn;
+
+/// The collection holding the last response to each read-through view request.
+pub const STALE_COLLECTION: &str = "_couchapi_read_through";
+
+/// Marks a response served from MongoDB because CouchDB couldn't answer.
+pub const STALE_HEADER: &str = "X-Fake-CouchDb-Stale";
+
+/// Identifies a request by everything that shapes its response.
+fn request_id(
+    method: &Method,
+    payload: Option<&Value>,
+    path: &str,
+    params: &HashMap<String, String>,
+) -> String {
+    let params = params.iter().collect::<BTreeMap<_, _>>();
+    let request = format!(
+        "{} {} {:?} {}",
+        method,
+        path,
+        params,
+        payload.map(Value::to_string).unwrap_or_default()
+    );
+    format!("{:x}", md5::compute(request))
+}
+
+/// As `read_through`, for views. With `stale_fallback` set each successful response is stored,
+/// and when CouchDB can't be reached or answers with a server error the stored response is served
+/// instead, with the `Age` it has reached and the stale header.
+pub async fn read_through_view(
+    state: &AppState,
+    couchdb_details: &CouchDb,
+    method: Method,
+    payload: Option<&Value>,
+    path: &str,
+    params: &HashMap<String, String>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    if !couchdb_details.stale_fallback {
+        return read_through(couchdb_details, method, payload, path, params).await;
+    }
+
+    let id = request_id(&method, payload, path, params);
+    let failure = match read_through(couchdb_details, method, payload, path, params).await {
+        Ok(response) if !response.status().is_server_error() => {
+            return Ok(store(state, &id, path, response).await);
+        }
+        failure => failure,
+    };
+
+    match stored(state, &id).await {
+        Some(response) => {
+            warn!(
+                path = path,
+                "CouchDB unavailable, serving a stale read-through response"
+            );
+            Ok(response)
+        }
+        None => failure,
+    }
+}
+
+/// Stores a successful response, passing it on either way. A response that can't be stored is
+/// still served.
+async fn store(state: &AppState, id: &str, path: &str, response: Response) -> Response {
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let Ok(bytes) = BodyExt::collect(body).await.map(|b| b.to_bytes()) else {
+        return Response::from_parts(parts, Body::empty());
+    };
+
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .unwrap_or("application/json");
+    let record = doc! {
+        "_id": id,
+        "path": path,
+        "status": parts.status.as_u16() as i32,
+        "content_type": content_type,
+        "body": String::from_utf8_lossy(&bytes).into_owned(),
+        "stored_at": DateTime::now(),
+    };
+    let options = ReplaceOptions::builder().upsert(true).build();
+    if let Err(e) = state
+        .db
+        .replace_one(STALE_COLLECTION, doc! { "_id": id }, record, options)
+        .await
+    {
+        warn!(
+            path = path,
+            error = e.to_string(),
+            "unable to store read-through response"
+        );
+    }
+
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// The stored response to a request, if there is one.
+async fn stored(state: &AppState, id: &str) -> Option<Response> {
+    let record = state
+        .db
+        .find_one(STALE_COLLECTION, id, FindOneOptions::default())
+        .await
+        .ok()??;
+
+    let status = record
+        .get_i32("status")
+        .ok()
+        .and_then(|s| StatusCode::from_u16(s as u16).ok())
+        .unwrap_or(StatusCode::OK);
+    let age = record
+        .get_datetime("stored_at")
+        .map(|stored_at| {
+            (DateTime::now().timestamp_millis() - stored_at.timestamp_millis()).max(0) / 1000
+        })
+        .unwrap_or(0);
+
+    let mut response = Response::new(Body::from(record.get_str("body").ok()?.to_string()));
+    *response.status_mut() = status;
+
+    let headers = response.headers_mut();
+    if let Ok(content_type) = HeaderValue::from_str(record.get_str("content_type").unwrap_or("")) {
+        headers.insert(CONTENT_TYPE, content_type);
+    }
+    headers.insert(AGE, HeaderValue::from(age));
+    headers.insert(STALE_HEADER, HeaderValue::from_static("true"));
+
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use bson::Document;
+    use httpmock::MockServer;
+    use std::sync::{Arc, Mutex};
+
+    fn state(mock: MockDatabase) -> AppState {
+        AppState::for_tests(mock)
+    }
+
+    fn couchdb(url: String) -> CouchDb {
+        CouchDb {
+            url,
+            username: None,
+            password: None,
+            read_through: true,
+            read_only: false,
+            read_through_databases: None,
+            read_only_databases: None,
+            stale_fallback: true,
+            soft_launch: None,
+            mappings: None,
+        }
+    }
+
+    async fn text(response: Response) -> String {
+        let body = BodyExt::collect(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_bytes().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read_through_view_stores_responses() {
+        let server = MockServer::start_async().await;
+        server
+            .mock_async(|when, then| {
+                when.path("/orders/_design/d/_view/v");
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .body(r#"{"rows":[]}"#);
+            })
+            .await;
+
+        let stored = Arc::new(Mutex::new(None::<Document>));
+        let mut mock = MockDatabase::new();
+        let s = stored.clone();
+        mock.expect_replace_one()
+            .withf(|coll, _, _, _| coll == STALE_COLLECTION)
+            .returning(move |_, _, record, _| {
+                *s.lock().unwrap() = Some(record);
+                Box::pin(async { Err(mongodb::error::Error::custom("stored")) })
+            });
+
+        let response = read_through_view(
+            &state(mock),
+            &couchdb(server.base_url()),
+            Method::GET,
+            None,
+            "orders/_design/d/_view/v",
+            &HashMap::new(),
+        )
+        .await
+        .unwrap();
+
+        assert!(response.headers().get(STALE_HEADER).is_none());
+        assert_eq!(text(response).await, r#"{"rows":[]}"#);
+
+        let record = stored.lock().unwrap().clone().unwrap();
+        assert_eq!(record.get_str("body").unwrap(), r#"{"rows":[]}"#);
+        assert_eq!(record.get_str("content_type").unwrap(), "application/json");
+    }
+
+    #[tokio::test]
+    async fn test_read_through_view_falls_back_to_stored_response() {
+        let server = MockServer::start_async().await;
+        server
+            .mock_async(|when, then| {
+                when.path("/orders/_design/d/_view/v");
+                then.status(503);
+            })
+            .await;
+
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one()
+            .withf(|coll, _, _| coll == STALE_COLLECTION)
+            .returning(|_, id, _| {
+                let record = doc! {
+                    "_id": id,
+                    "status": 200,
+                    "content_type": "application/json",
+                    "body": r#"{"rows":[{"id":"a"}]}"#,
+                    "stored_at": DateTime::from_millis(DateTime::now().timestamp_millis() - 90_000),
+                };
+                Box::pin(async move { Ok(Some(record)) })
+            });
+        let response = read_through_view(
+            &state(mock),
+            &couchdb(server.base_url()),
+            Method::GET,
+            None,
+            "orders/_design/d/_view/v",
+            &HashMap::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[STALE_HEADER], "true");
+        assert!(
+            response.headers()[AGE]
+                .to_str()
+                .unwrap()
+                .parse::<i64>()
+                .unwrap()
+                >= 90
+        );
+        assert_eq!(text(response).await, r#"{"rows":[{"id":"a"}]}"#);
+
+        // Nothing stored and nothing listening, so the failure is passed on.
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one()
+            .returning(|_, _, _| Box::pin(async { Ok(None) }));
+        let (status, _) = read_through_view(
+            &state(mock),
+            &couchdb("http://127.0.0.1:1".to_string()),
+            Method::GET,
+            None,
+            "orders/_design/d/_view/v",
+            &HashMap::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -13,21 +13,43 @@
 // limitations under the License.
 
 use async_trait::async_trait;
-use bson::{doc, Document};
-use futures_util::StreamExt;
-use mongodb::error::Error;
-use mongodb::options::{DeleteOptions, ReplaceOptions};
+use bson::{doc, Document, RawDocumentBuf};
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use mongodb::change_stream::event::ResumeToken;
+use mongodb::error::{Error, ErrorKind, WriteFailure};
+use mongodb::options::{
+    AggregateOptions,
+    ChangeStreamOptions,
+    DeleteOptions,
+    FindOneOptions,
+    FullDocumentType,
+    IndexOptions,
+    ReplaceOptions,
+};
 use mongodb::results::UpdateResult;
+use mongodb::IndexModel;
+use std::future::Future;
+use std::time::{Duration, Instant};
 
 #[cfg(test)]
 use mockall::*;
-use tracing::debug;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Change stream events, as MongoDB sends them.
+pub type ChangeStream = BoxStream<'static, Result<Document, Error>>;
 
 #[async_trait]
 #[cfg_attr(test, automock)]
 pub trait Database {
     async fn get_version(&self) -> Result<Document, Error>;
-    async fn find_one(&self, coll: &str, id: &str) -> Result<Option<Document>, Error>;
+    async fn find_one(
+        &self,
+        coll: &str,
+        id: &str,
+        options: FindOneOptions,
+    ) -> Result<Option<Document>, Error>;
     async fn replace_one(
         &self,
         coll: &str,
@@ -41,13 +63,178 @@ pub trait Database {
         filter: Document,
         options: DeleteOptions,
     ) -> Result<u64, Error>;
-    async fn aggregate(&self, coll: &str, pipeline: Vec<Document>) -> Result<Vec<Document>, Error>;
+    async fn aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+        options: AggregateOptions,
+    ) -> Result<Vec<RawDocumentBuf>, Error>;
+    async fn explain_aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Document, Error>;
     async fn count(&self, coll: &str) -> Result<u64, Error>;
+
+    /// The collection's `storageStats` from `$collStats`, or `None` when there's no collection.
+    async fn collection_stats(&self, coll: &str) -> Result<Option<Document>, Error>;
+
+    /// Changes to the collection from now, or after the resume token `resume_after`.
+    async fn watch(
+        &self,
+        coll: &str,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeStream, Error>;
+
+    /// Streams changes to every collection, from after `resume_after` when it's given, otherwise
+    /// from now. Events say which collection changed, without the documents.
+    async fn watch_database(&self, resume_after: Option<Document>) -> Result<ChangeStream, Error>;
+
+    /// Has MongoDB delete documents once the date in `field` has passed.
+    async fn create_ttl_index(&self, coll: &str, field: &str) -> Result<(), Error>;
+
+    /// Applies `update` to every document `filter` matches, returning how many changed.
+    async fn update_many(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<u64, Error>;
+
+    /// Deletes every document `filter` matches, returning how many went.
+    async fn delete_many(&self, coll: &str, filter: Document) -> Result<u64, Error>;
+}
+
+/// Runs a MongoDB operation, recording how long it took against the collection and operation so
+/// time spent in MongoDB can be told apart from the rest of a request.
+async fn timed<T, F>(coll: &str, operation: &'static str, f: F) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    let start = Instant::now();
+    let result = f.await;
+
+    let latency = start.elapsed().as_secs_f64();
+    let status = if result.is_ok() { "ok" } else { "error" };
+    let labels = [
+        ("collection", coll.to_string()),
+        ("operation", operation.to_string()),
+        ("status", status.to_string()),
+    ];
+
+    metrics::increment_counter!("couchapi_mongodb_operations_total", &labels);
+    metrics::histogram!(
+        "couchapi_mongodb_operation_duration_seconds",
+        latency,
+        &labels,
+    );
+
+    result
+}
+
+/// MongoDB's codes for a write that would have given two documents the same unique key.
+const DUPLICATE_KEY_CODES: [i32; 2] = [11000, 11001];
+
+/// Whether a write failed because a document with the same `_id` (or other unique key) already
+/// exists, which for an upsert means another write got there first.
+pub fn is_duplicate_key(e: &Error) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => DUPLICATE_KEY_CODES.contains(&e.code),
+        ErrorKind::Command(e) => DUPLICATE_KEY_CODES.contains(&e.code),
+        ErrorKind::BulkWrite(e) => e
+            .write_errors
+            .iter()
+            .flatten()
+            .any(|e| DUPLICATE_KEY_CODES.contains(&e.code)),
+        _ => false,
+    }
+}
+
+/// Kills a tagged MongoDB operation if it's dropped before being disarmed. A request's future is
+/// dropped when the client disconnects or the request times out, but MongoDB carries on with the
+/// operation regardless unless told otherwise.
+struct KillOnDrop {
+    admin: mongodb::Database,
+    comment: Option<String>,
+}
+
+impl KillOnDrop {
+    fn new(admin: mongodb::Database, comment: String) -> Self {
+        KillOnDrop {
+            admin,
+            comment: Some(comment),
+        }
+    }
+
+    fn disarm(mut self) {
+        self.comment = None;
+    }
+}
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let Some(comment) = self.comment.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let admin = self.admin.clone();
+        runtime.spawn(async move {
+            if let Err(e) = kill_operations(&admin, &comment).await {
+                warn!(
+                    comment = comment.as_str(),
+                    error = e.to_string(),
+                    "unable to kill an abandoned operation"
+                );
+            }
+        });
+    }
+}
+
+/// Finds this user's operations tagged with `comment`.
+fn current_op_command(comment: &str) -> Document {
+    doc! {
+        "currentOp": 1,
+        "$ownOps": true,
+        "command.comment": comment,
+    }
+}
+
+async fn kill_operations(admin: &mongodb::Database, comment: &str) -> Result<(), Error> {
+    let current = admin.run_command(current_op_command(comment), None).await?;
+
+    for operation in current.get_array("inprog").into_iter().flatten() {
+        let Some(opid) = operation.as_document().and_then(|o| o.get("opid")) else {
+            continue;
+        };
+
+        admin
+            .run_command(doc! { "killOp": 1, "op": opid.clone() }, None)
+            .await?;
+        metrics::increment_counter!("couchapi_mongodb_operations_killed_total");
+        debug!(comment, "killed an abandoned operation");
+    }
+
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct MongoDB {
     pub db: mongodb::Database,
+
+    /// Where abandoned operations are found and killed.
+    admin: mongodb::Database,
+}
+
+impl MongoDB {
+    pub fn new(client: &mongodb::Client, database: &str) -> Self {
+        MongoDB {
+            db: client.database(database),
+            admin: client.database("admin"),
+        }
+    }
 }
 
 #[async_trait]
@@ -58,9 +245,14 @@ impl Database for MongoDB {
     }
 
     #[tracing::instrument(skip(self))]
-    async fn find_one(&self, coll: &str, id: &str) -> Result<Option<Document>, Error> {
+    async fn find_one(
+        &self,
+        coll: &str,
+        id: &str,
+        options: FindOneOptions,
+    ) -> Result<Option<Document>, Error> {
         let c = self.db.collection::<Document>(coll);
-        c.find_one(doc! { "_id": id }, None).await
+        timed(coll, "find_one", c.find_one(doc! { "_id": id }, options)).await
     }
 
     #[tracing::instrument(skip(self))]
@@ -72,7 +264,12 @@ impl Database for MongoDB {
         options: ReplaceOptions,
     ) -> Result<UpdateResult, Error> {
         let c = self.db.collection::<Document>(coll);
-        c.replace_one(filter, replacement, options).await
+        timed(
+            coll,
+            "replace_one",
+            c.replace_one(filter, replacement, options),
+        )
+        .await
     }
 
     #[tracing::instrument(skip(self))]
@@ -83,33 +280,199 @@ impl Database for MongoDB {
         options: DeleteOptions,
     ) -> Result<u64, Error> {
         let c = self.db.collection::<Document>(coll);
-        c.delete_one(filter, options).await.map(|r| r.deleted_count)
+        timed(coll, "delete_one", c.delete_one(filter, options))
+            .await
+            .map(|r| r.deleted_count)
     }
 
     #[tracing::instrument(skip(self))]
-    async fn aggregate(&self, coll: &str, pipeline: Vec<Document>) -> Result<Vec<Document>, Error> {
+    async fn aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+        mut options: AggregateOptions,
+    ) -> Result<Vec<RawDocumentBuf>, Error> {
         debug!(
             "aggregate: coll: {}, pipeline: {:?}",
             coll,
             serde_json::to_string(&pipeline).unwrap()
         );
 
+        // Views can be large, so allow MongoDB to spill to disk unless told otherwise
+        if options.allow_disk_use.is_none() {
+            options.allow_disk_use = Some(true);
+        }
+
+        // Tag the aggregation so it can be found and killed if the request is dropped
+        let guard = if options.comment.is_none() && options.comment_bson.is_none() {
+            let comment = format!("couchapi:{}", Uuid::new_v4());
+            options.comment = Some(comment.clone());
+            Some(KillOnDrop::new(self.admin.clone(), comment))
+        } else {
+            None
+        };
+
         let c = self.db.collection::<Document>(coll);
-        let options = mongodb::options::AggregateOptions::builder()
-            .allow_disk_use(Some(true))
-            .build();
-        let mut cursor = c.aggregate(pipeline, options).await?;
-        let mut results = Vec::new();
 
-        while let Some(doc) = cursor.next().await {
-            results.push(doc?);
+        // Time draining the cursor too, as that's where most of a large view's time goes
+        let result = timed(coll, "aggregate", async {
+            // Rows stay as raw BSON so views can be written out without building a `Document` each
+            let mut cursor = c
+                .aggregate(pipeline, options)
+                .await?
+                .with_type::<RawDocumentBuf>();
+            let mut results = Vec::new();
+
+            while let Some(doc) = cursor.next().await {
+                results.push(doc?);
+            }
+            Ok(results)
+        })
+        .await;
+
+        if let Some(guard) = guard {
+            guard.disarm();
         }
-        Ok(results)
+        result
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn explain_aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Document, Error> {
+        let command = doc! {
+            "explain": {
+                "aggregate": coll,
+                "pipeline": pipeline,
+                "cursor": {},
+            },
+            "verbosity": "queryPlanner",
+        };
+
+        timed(
+            coll,
+            "explain_aggregate",
+            self.db.run_command(command, None),
+        )
+        .await
     }
 
     #[tracing::instrument(skip(self))]
     async fn count(&self, coll: &str) -> Result<u64, Error> {
         let c = self.db.collection::<Document>(coll);
-        c.estimated_document_count(None).await
+        timed(coll, "count", c.estimated_document_count(None)).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn collection_stats(&self, coll: &str) -> Result<Option<Document>, Error> {
+        let names = self.db.list_collection_names(doc! { "name": coll });
+        if timed(coll, "list_collections", names).await?.is_empty() {
+            return Ok(None);
+        }
+
+        let c = self.db.collection::<Document>(coll);
+        let pipeline = [doc! { "$collStats": { "storageStats": {} } }];
+        let mut stats = timed(coll, "coll_stats", c.aggregate(pipeline, None)).await?;
+        let stats = stats.try_next().await?;
+        Ok(stats.and_then(|s| s.get_document("storageStats").ok().cloned()))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn watch(
+        &self,
+        coll: &str,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeStream, Error> {
+        let resume_after = resume_after
+            .map(bson::from_document::<ResumeToken>)
+            .transpose()?;
+
+        // Updates made outside of this server don't carry the document without a lookup
+        let options = ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .resume_after(resume_after)
+            .build();
+
+        let c = self.db.collection::<Document>(coll);
+        let changes = c.watch(None, options).await?.with_type::<Document>();
+        Ok(changes.boxed())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn watch_database(&self, resume_after: Option<Document>) -> Result<ChangeStream, Error> {
+        let resume_after = resume_after
+            .map(bson::from_document::<ResumeToken>)
+            .transpose()?;
+        let options = ChangeStreamOptions::builder()
+            .resume_after(resume_after)
+            .build();
+
+        let changes = self.db.watch(None, options).await?.with_type::<Document>();
+        Ok(changes.boxed())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn create_ttl_index(&self, coll: &str, field: &str) -> Result<(), Error> {
+        let index = IndexModel::builder()
+            .keys(doc! { field: 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build();
+
+        let c = self.db.collection::<Document>(coll);
+        timed(coll, "create_index", c.create_index(index, None)).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn update_many(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<u64, Error> {
+        let c = self.db.collection::<Document>(coll);
+        let result = timed(coll, "update_many", c.update_many(filter, update, None)).await?;
+        Ok(result.modified_count)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_many(&self, coll: &str, filter: Document) -> Result<u64, Error> {
+        let c = self.db.collection::<Document>(coll);
+        let result = timed(coll, "delete_many", c.delete_many(filter, None)).await?;
+        Ok(result.deleted_count)
+    }
+}
+
+/// A duplicate key error as the driver reports one, for tests.
+#[cfg(test)]
+pub fn duplicate_key_error() -> Error {
+    let write_error = serde_json::from_value(serde_json::json!({
+        "code": 11000,
+        "codeName": "DuplicateKey",
+        "errmsg": "E11000 duplicate key error collection: db.docs index: _id_ dup key",
+    }))
+    .unwrap();
+
+    Error::from(ErrorKind::Write(WriteFailure::WriteError(write_error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_op_command() {
+        assert_eq!(
+            current_op_command("couchapi:1"),
+            doc! { "currentOp": 1, "$ownOps": true, "command.comment": "couchapi:1" }
+        );
+    }
+
+    #[test]
+    fn test_is_duplicate_key() {
+        assert!(is_duplicate_key(&duplicate_key_error()));
+        assert!(!is_duplicate_key(&Error::custom("nothing")));
     }
 }
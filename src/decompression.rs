@@ -0,0 +1,186 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::DecompressionSettings;
+use crate::ops::JsonWithStatusCodeResponse;
+use axum::body::Body;
+use axum::extract::{MatchedPath, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Json;
+use http_body_util::{LengthLimitError, Limited};
+use serde_json::json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+/// How large each route's compressed request bodies may grow as they're decompressed.
+#[derive(Debug)]
+pub struct DecompressionLimits {
+    default: u64,
+    routes: HashMap<String, u64>,
+}
+
+impl DecompressionLimits {
+    pub fn new(settings: &DecompressionSettings) -> Self {
+        DecompressionLimits {
+            default: settings.max_bytes,
+            routes: settings.routes.clone(),
+        }
+    }
+
+    fn for_route(&self, route: Option<&str>) -> u64 {
+        route
+            .and_then(|route| self.routes.get(route))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Marks a request whose body was compressed, as the decompression layer removes the
+/// `Content-Encoding` that says so.
+#[derive(Clone, Copy)]
+struct Compressed;
+
+/// Goes outside the decompression layer, to note which requests it decompresses.
+pub async fn mark_compressed(mut req: Request<Body>, next: Next) -> Response {
+    let compressed = req
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding != "identity");
+    if compressed {
+        req.extensions_mut().insert(Compressed);
+    }
+
+    next.run(req).await
+}
+
+/// Goes inside the decompression layer, cutting a decompressed body off at its route's limit.
+/// Handlers that buffer the body respond with a `413` when it's reached, and those that stream it
+/// use `body_error` to.
+pub async fn limit_decompressed(
+    State(limits): State<Arc<DecompressionLimits>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if req.extensions().get::<Compressed>().is_none() {
+        return next.run(req).await;
+    }
+
+    let limit = limits.for_route(
+        req.extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str),
+    );
+    let req = req.map(|body| Body::new(Limited::new(body, limit as usize)));
+    next.run(req).await
+}
+
+/// The response for an error reading a streamed request body: a `413` when it decompressed to
+/// more than its limit, otherwise a `400`.
+pub fn body_error(e: axum::Error) -> JsonWithStatusCodeResponse {
+    let mut source: Option<&(dyn Error + 'static)> = Some(&e);
+    while let Some(error) = source {
+        if error.is::<LengthLimitError>() {
+            metrics::increment_counter!("couchapi_decompression_limit_exceeded_total");
+
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!({
+                    "error": "too_large",
+                    "reason": "The request body is too large once decompressed."
+                })),
+            );
+        }
+        source = error.source();
+    }
+
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "bad_request", "reason": e.to_string()})),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::routing::post;
+    use axum::{middleware, Router};
+    use http_body_util::BodyExt;
+    use std::io::Write;
+    use tower::ServiceExt;
+    use tower_http::decompression::RequestDecompressionLayer;
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_limit_decompressed() {
+        let limits = Arc::new(DecompressionLimits::new(&DecompressionSettings {
+            max_bytes: 1024,
+            routes: HashMap::from([("/stream".to_string(), 4096)]),
+        }));
+
+        let app = Router::new()
+            .route(
+                "/buffered",
+                post(|body: Bytes| async move { body.len().to_string() }),
+            )
+            .route(
+                "/stream",
+                post(|body: Body| async move {
+                    body.collect()
+                        .await
+                        .map(|b| b.to_bytes().len().to_string())
+                        .map_err(body_error)
+                }),
+            )
+            .layer(middleware::from_fn_with_state(limits, limit_decompressed))
+            .layer(RequestDecompressionLayer::new())
+            .layer(middleware::from_fn(mark_compressed));
+
+        let send = |uri: &str, body: Vec<u8>, gzipped: bool| {
+            let mut request = Request::builder().method("POST").uri(uri);
+            if gzipped {
+                request = request.header(header::CONTENT_ENCODING, "gzip");
+            }
+            app.clone().oneshot(request.body(Body::from(body)).unwrap())
+        };
+
+        // A couple of megabytes of zeros gzip down to a few kilobytes.
+        let bomb = gzip(&vec![0; 2 * 1024 * 1024]);
+        assert!(bomb.len() < 4096);
+
+        let res = send("/buffered", gzip(&[b'a'; 2048]), true).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let res = send("/stream", bomb, true).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "too_large");
+
+        let res = send("/stream", gzip(&[b'a'; 2048]), true).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Bodies that weren't compressed are left to the handlers' own limits.
+        let res = send("/buffered", vec![b'a'; 2048], false).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
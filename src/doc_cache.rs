@@ -0,0 +1,409 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::DocumentCacheSettings;
+use crate::db::{ChangeStream, Database};
+use async_trait::async_trait;
+use bson::{Document, RawDocumentBuf};
+use lru::LruCache;
+use mongodb::error::Error;
+use mongodb::options::{AggregateOptions, DeleteOptions, FindOneOptions, ReplaceOptions};
+use mongodb::results::UpdateResult;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A collection and document id.
+type Key = (String, String);
+
+enum Slot {
+    /// A read has gone to MongoDB and will fill the slot if nothing writes the document first.
+    Loading(u64),
+
+    Ready {
+        document: Document,
+        expires: Instant,
+    },
+
+    /// MongoDB had no such document.
+    Missing { expires: Instant },
+}
+
+/// Keeps the documents read most recently in memory. Writes and deletes through this server drop
+/// the document straight away; writes made elsewhere are seen once the entry expires.
+pub struct CachedDatabase {
+    inner: Box<dyn Database + Send + Sync>,
+    ttl: Duration,
+    missing_ttl: Option<Duration>,
+    entries: Arc<Mutex<LruCache<Key, Slot>>>,
+    next_load: AtomicU64,
+}
+
+/// Drops documents from a `CachedDatabase` from outside it, such as when another replica writes.
+#[derive(Clone)]
+pub struct CacheInvalidator(Arc<Mutex<LruCache<Key, Slot>>>);
+
+impl CacheInvalidator {
+    /// Drops the document a write's filter names, or the whole collection when it names no
+    /// single document.
+    pub fn invalidate(&self, coll: &str, filter: &Document) {
+        let mut entries = self.0.lock().unwrap();
+
+        match filter.get_str("_id") {
+            Ok(id) => {
+                entries.pop(&(coll.to_string(), id.to_string()));
+            }
+            Err(_) => {
+                let keys: Vec<Key> = entries
+                    .iter()
+                    .filter(|((c, _), _)| c == coll)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in keys {
+                    entries.pop(&key);
+                }
+            }
+        }
+    }
+}
+
+impl CachedDatabase {
+    pub fn new(inner: Box<dyn Database + Send + Sync>, settings: &DocumentCacheSettings) -> Self {
+        CachedDatabase {
+            inner,
+            ttl: Duration::from_millis(settings.ttl_ms),
+            missing_ttl: settings.missing_ttl_ms.map(Duration::from_millis),
+            entries: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(settings.max_entries).unwrap_or(NonZeroUsize::MIN),
+            ))),
+            next_load: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached document, or `None` for one known to be missing. Otherwise claims the
+    /// slot for a read from MongoDB.
+    fn lookup(&self, key: &Key) -> Result<Option<Document>, u64> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        match entries.get(key) {
+            Some(Slot::Ready { document, expires }) if *expires > now => {
+                return Ok(Some(document.clone()))
+            }
+            Some(Slot::Missing { expires }) if *expires > now => return Ok(None),
+            _ => {}
+        }
+
+        let load = self.next_load.fetch_add(1, Ordering::Relaxed);
+        entries.put(key.clone(), Slot::Loading(load));
+        Err(load)
+    }
+
+    /// Fills the slot claimed by `load`, unless a write or a later read has taken it since.
+    fn fill(&self, key: Key, load: u64, document: Option<&Document>) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if !matches!(entries.peek(&key), Some(Slot::Loading(l)) if *l == load) {
+            return;
+        }
+
+        let slot = match (document, self.missing_ttl) {
+            (Some(document), _) => Slot::Ready {
+                document: document.clone(),
+                expires: Instant::now() + self.ttl,
+            },
+            (None, Some(missing_ttl)) => Slot::Missing {
+                expires: Instant::now() + missing_ttl,
+            },
+            (None, None) => {
+                entries.pop(&key);
+                return;
+            }
+        };
+        entries.put(key, slot);
+    }
+
+    pub fn invalidator(&self) -> CacheInvalidator {
+        CacheInvalidator(self.entries.clone())
+    }
+
+    fn invalidate(&self, coll: &str, filter: &Document) {
+        self.invalidator().invalidate(coll, filter);
+    }
+}
+
+#[async_trait]
+impl Database for CachedDatabase {
+    async fn get_version(&self) -> Result<Document, Error> {
+        self.inner.get_version().await
+    }
+
+    async fn find_one(
+        &self,
+        coll: &str,
+        id: &str,
+        options: FindOneOptions,
+    ) -> Result<Option<Document>, Error> {
+        let key = (coll.to_string(), id.to_string());
+
+        let load = match self.lookup(&key) {
+            Ok(Some(document)) => {
+                metrics::increment_counter!("couchapi_document_cache_hits_total");
+                return Ok(Some(document));
+            }
+            Ok(None) => {
+                metrics::increment_counter!("couchapi_document_cache_missing_hits_total");
+                return Ok(None);
+            }
+            Err(load) => load,
+        };
+        metrics::increment_counter!("couchapi_document_cache_misses_total");
+
+        let result = self.inner.find_one(coll, id, options).await;
+        // A failed read leaves the slot loading, for the next read to claim.
+        if let Ok(document) = &result {
+            self.fill(key, load, document.as_ref());
+        }
+        result
+    }
+
+    async fn replace_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        replacement: Document,
+        options: ReplaceOptions,
+    ) -> Result<UpdateResult, Error> {
+        let result = self
+            .inner
+            .replace_one(coll, filter.clone(), replacement, options)
+            .await;
+
+        // Even a failed write may have been applied.
+        self.invalidate(coll, &filter);
+        result
+    }
+
+    async fn delete_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        options: DeleteOptions,
+    ) -> Result<u64, Error> {
+        let result = self.inner.delete_one(coll, filter.clone(), options).await;
+        self.invalidate(coll, &filter);
+        result
+    }
+
+    async fn aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+        options: AggregateOptions,
+    ) -> Result<Vec<RawDocumentBuf>, Error> {
+        self.inner.aggregate(coll, pipeline, options).await
+    }
+
+    async fn explain_aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Document, Error> {
+        self.inner.explain_aggregate(coll, pipeline).await
+    }
+
+    async fn count(&self, coll: &str) -> Result<u64, Error> {
+        self.inner.count(coll).await
+    }
+
+    async fn collection_stats(&self, coll: &str) -> Result<Option<Document>, Error> {
+        self.inner.collection_stats(coll).await
+    }
+
+    async fn watch(
+        &self,
+        coll: &str,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeStream, Error> {
+        self.inner.watch(coll, resume_after).await
+    }
+
+    async fn watch_database(&self, resume_after: Option<Document>) -> Result<ChangeStream, Error> {
+        self.inner.watch_database(resume_after).await
+    }
+
+    async fn create_ttl_index(&self, coll: &str, field: &str) -> Result<(), Error> {
+        self.inner.create_ttl_index(coll, field).await
+    }
+
+    async fn update_many(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<u64, Error> {
+        let result = self.inner.update_many(coll, filter.clone(), update).await;
+        self.invalidate(coll, &filter);
+        result
+    }
+
+    async fn delete_many(&self, coll: &str, filter: Document) -> Result<u64, Error> {
+        let result = self.inner.delete_many(coll, filter.clone()).await;
+        self.invalidate(coll, &filter);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use bson::doc;
+
+    fn settings() -> DocumentCacheSettings {
+        DocumentCacheSettings {
+            max_entries: 2,
+            ttl_ms: 60_000,
+            missing_ttl_ms: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_one_is_cached_until_written() {
+        let mut mock = MockDatabase::new();
+        let mut seq = mockall::Sequence::new();
+        mock.expect_find_one()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Box::pin(async { Ok(Some(doc! { "_id": "a", "_rev": "1-a" })) }));
+        mock.expect_delete_one()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Box::pin(async { Ok(1) }));
+        mock.expect_find_one()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Box::pin(async { Ok(None) }));
+
+        let db = CachedDatabase::new(Box::new(mock), &settings());
+
+        for _ in 0..3 {
+            let document = db.find_one("db", "a", FindOneOptions::default()).await;
+            assert_eq!(document.unwrap().unwrap().get_str("_rev"), Ok("1-a"));
+        }
+
+        db.delete_one(
+            "db",
+            doc! { "_id": "a", "_rev": "1-a" },
+            DeleteOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let document = db.find_one("db", "a", FindOneOptions::default()).await;
+        assert_eq!(document.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_entries_expire() {
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one()
+            .times(2)
+            .returning(|_, _, _| Box::pin(async { Ok(Some(doc! { "_id": "a" })) }));
+
+        let db = CachedDatabase::new(
+            Box::new(mock),
+            &DocumentCacheSettings {
+                max_entries: 2,
+                ttl_ms: 0,
+                missing_ttl_ms: None,
+            },
+        );
+
+        db.find_one("db", "a", FindOneOptions::default())
+            .await
+            .unwrap();
+        db.find_one("db", "a", FindOneOptions::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_missing_documents_are_remembered_until_written() {
+        let mut mock = MockDatabase::new();
+        let mut seq = mockall::Sequence::new();
+        mock.expect_find_one()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Box::pin(async { Ok(None) }));
+        mock.expect_delete_one()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Box::pin(async { Ok(0) }));
+        mock.expect_find_one()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Box::pin(async { Ok(Some(doc! { "_id": "a" })) }));
+
+        let db = CachedDatabase::new(
+            Box::new(mock),
+            &DocumentCacheSettings {
+                missing_ttl_ms: Some(60_000),
+                ..settings()
+            },
+        );
+
+        for _ in 0..3 {
+            let document = db.find_one("db", "a", FindOneOptions::default()).await;
+            assert_eq!(document.unwrap(), None);
+        }
+
+        db.delete_one("db", doc! { "_id": "a" }, DeleteOptions::default())
+            .await
+            .unwrap();
+
+        let document = db.find_one("db", "a", FindOneOptions::default()).await;
+        assert_eq!(document.unwrap(), Some(doc! { "_id": "a" }));
+    }
+
+    #[test]
+    fn test_write_during_read_wins() {
+        let db = CachedDatabase::new(Box::new(MockDatabase::new()), &settings());
+        let key = ("db".to_string(), "a".to_string());
+
+        // A read starts, a write lands, then the read comes back with what it saw before.
+        let load = db.lookup(&key).unwrap_err();
+        db.invalidate("db", &doc! { "_id": "a" });
+        db.fill(key.clone(), load, Some(&doc! { "_id": "a", "_rev": "1-a" }));
+
+        assert!(db.lookup(&key).is_err());
+    }
+
+    #[test]
+    fn test_invalidate_without_id_drops_collection() {
+        let db = CachedDatabase::new(Box::new(MockDatabase::new()), &settings());
+        let a = ("db".to_string(), "a".to_string());
+        let other = ("other".to_string(), "a".to_string());
+
+        for key in [&a, &other] {
+            let load = db.lookup(key).unwrap_err();
+            db.fill(key.clone(), load, Some(&doc! { "_id": "a" }));
+        }
+
+        db.invalidate("db", &doc! { "n": 1 });
+        assert!(db.lookup(&a).is_err());
+        assert!(db.lookup(&other).is_ok());
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::ExpirySettings;
+use crate::db::{ChangeStream, Database};
+use async_trait::async_trait;
+use bson::{Bson, DateTime, Document, RawDocumentBuf};
+use futures_util::StreamExt;
+use mongodb::error::Error;
+use mongodb::options::{AggregateOptions, DeleteOptions, FindOneOptions, ReplaceOptions};
+use mongodb::results::UpdateResult;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+/// When the document expires, as a BSON date for the TTL index. Documents keep their own expiry
+/// field as they wrote it.
+pub const EXPIRES_FIELD: &str = "_couchapi_expires";
+
+/// Has MongoDB delete documents once their expiry field has passed. MongoDB removes expired
+/// documents about once a minute, so until then reads of the document treat it as deleted.
+pub struct ExpiringDatabase {
+    inner: Box<dyn Database + Send + Sync>,
+    settings: HashMap<String, ExpirySettings>,
+}
+
+impl ExpiringDatabase {
+    pub fn new(
+        inner: Box<dyn Database + Send + Sync>,
+        settings: HashMap<String, ExpirySettings>,
+    ) -> Self {
+        ExpiringDatabase { inner, settings }
+    }
+}
+
+/// An RFC 3339 date or a number of seconds since the epoch, as CouchDB documents hold dates.
+fn expiry_date(value: &Bson) -> Option<DateTime> {
+    let millis = match value {
+        Bson::String(date) => chrono::DateTime::parse_from_rfc3339(date)
+            .ok()?
+            .timestamp_millis(),
+        Bson::Int32(seconds) => i64::from(*seconds) * 1000,
+        Bson::Int64(seconds) => seconds.checked_mul(1000)?,
+        Bson::Double(seconds) if seconds.is_finite() => (seconds * 1000.0) as i64,
+        Bson::DateTime(date) => return Some(*date),
+        _ => return None,
+    };
+    Some(DateTime::from_millis(millis))
+}
+
+/// The document as written, or `None` once it has expired.
+fn unexpired(mut document: Document) -> Option<Document> {
+    match document.remove(EXPIRES_FIELD) {
+        Some(Bson::DateTime(expires)) if expires <= DateTime::now() => None,
+        _ => Some(document),
+    }
+}
+
+fn strip_raw(raw: RawDocumentBuf) -> Result<RawDocumentBuf, Error> {
+    if !matches!(raw.get(EXPIRES_FIELD), Ok(Some(_))) {
+        return Ok(raw);
+    }
+
+    let mut document = raw.to_document()?;
+    document.remove(EXPIRES_FIELD);
+    Ok(RawDocumentBuf::from_document(&document)?)
+}
+
+fn strip_event(mut event: Document) -> Document {
+    if let Ok(document) = event.get_document_mut("fullDocument") {
+        document.remove(EXPIRES_FIELD);
+    }
+    event
+}
+
+/// Creates the TTL index for each database with expiring documents. A failure is logged, and
+/// leaves that database's documents to be treated as deleted without ever being removed.
+pub async fn create_ttl_indexes(
+    db: &(dyn Database + Send + Sync),
+    settings: &HashMap<String, ExpirySettings>,
+) {
+    for coll in settings.keys() {
+        match db.create_ttl_index(coll, EXPIRES_FIELD).await {
+            Ok(()) => info!(collection = coll, "ttl index ready"),
+            Err(e) => warn!(
+                collection = coll,
+                error = e.to_string(),
+                "unable to create ttl index"
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl Database for ExpiringDatabase {
+    async fn get_version(&self) -> Result<Document, Error> {
+        self.inner.get_version().await
+    }
+
+    async fn find_one(
+        &self,
+        coll: &str,
+        id: &str,
+        options: FindOneOptions,
+    ) -> Result<Option<Document>, Error> {
+        let document = self.inner.find_one(coll, id, options).await?;
+        Ok(document.and_then(unexpired))
+    }
+
+    async fn replace_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        mut replacement: Document,
+        options: ReplaceOptions,
+    ) -> Result<UpdateResult, Error> {
+        replacement.remove(EXPIRES_FIELD);
+        let expires = self
+            .settings
+            .get(coll)
+            .and_then(|settings| replacement.get(&settings.field))
+            .and_then(expiry_date);
+        if let Some(expires) = expires {
+            replacement.insert(EXPIRES_FIELD, expires);
+        }
+
+        self.inner
+            .replace_one(coll, filter, replacement, options)
+            .await
+    }
+
+    async fn delete_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        options: DeleteOptions,
+    ) -> Result<u64, Error> {
+        self.inner.delete_one(coll, filter, options).await
+    }
+
+    async fn aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+        options: AggregateOptions,
+    ) -> Result<Vec<RawDocumentBuf>, Error> {
+        self.inner
+            .aggregate(coll, pipeline, options)
+            .await?
+            .into_iter()
+            .map(strip_raw)
+            .collect()
+    }
+
+    async fn explain_aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Document, Error> {
+        self.inner.explain_aggregate(coll, pipeline).await
+    }
+
+    async fn count(&self, coll: &str) -> Result<u64, Error> {
+        self.inner.count(coll).await
+    }
+
+    async fn collection_stats(&self, coll: &str) -> Result<Option<Document>, Error> {
+        self.inner.collection_stats(coll).await
+    }
+
+    async fn watch(
+        &self,
+        coll: &str,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeStream, Error> {
+        let changes = self.inner.watch(coll, resume_after).await?;
+        Ok(changes.map(|event| event.map(strip_event)).boxed())
+    }
+
+    async fn watch_database(&self, resume_after: Option<Document>) -> Result<ChangeStream, Error> {
+        self.inner.watch_database(resume_after).await
+    }
+
+    async fn create_ttl_index(&self, coll: &str, field: &str) -> Result<(), Error> {
+        self.inner.create_ttl_index(coll, field).await
+    }
+
+    async fn update_many(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<u64, Error> {
+        self.inner.update_many(coll, filter, update).await
+    }
+
+    async fn delete_many(&self, coll: &str, filter: Document) -> Result<u64, Error> {
+        self.inner.delete_many(coll, filter).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use bson::doc;
+    use mongodb::error::ErrorKind;
+    use std::sync::{Arc, Mutex};
+
+    fn settings() -> HashMap<String, ExpirySettings> {
+        HashMap::from([(
+            "sessions".to_string(),
+            ExpirySettings {
+                field: "couchapi_expire_at".to_string(),
+            },
+        )])
+    }
+
+    #[test]
+    fn test_expiry_date() {
+        let expected = DateTime::from_millis(1_700_000_000_000);
+        assert_eq!(
+            expiry_date(&Bson::String("2023-11-14T22:13:20Z".to_string())),
+            Some(expected)
+        );
+        assert_eq!(expiry_date(&Bson::Int64(1_700_000_000)), Some(expected));
+        assert_eq!(expiry_date(&Bson::Double(1_700_000_000.0)), Some(expected));
+        assert_eq!(expiry_date(&Bson::String("tomorrow".to_string())), None);
+        assert_eq!(expiry_date(&Bson::Null), None);
+    }
+
+    #[tokio::test]
+    async fn test_writes_expiry() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut mock = MockDatabase::new();
+        let stored = written.clone();
+        mock.expect_replace_one()
+            .times(3)
+            .returning(move |_, _, replacement, _| {
+                stored.lock().unwrap().push(replacement);
+                // UpdateResult can't be built outside of the driver, so the write is seen through
+                // what was passed on
+                Box::pin(async { Err(Error::from(ErrorKind::Custom(Arc::new("written")))) })
+            });
+
+        let db = ExpiringDatabase::new(Box::new(mock), settings());
+        let session = doc! { "_id": "s1", "couchapi_expire_at": 1_700_000_000i64 };
+        for (coll, document) in [
+            ("sessions", session.clone()),
+            (
+                "sessions",
+                doc! { "_id": "s2", EXPIRES_FIELD: DateTime::now() },
+            ),
+            ("orders", session.clone()),
+        ] {
+            let _ = db
+                .replace_one(coll, doc! {}, document, ReplaceOptions::default())
+                .await;
+        }
+
+        let written = written.lock().unwrap();
+        assert_eq!(
+            written[0],
+            doc! {
+                "_id": "s1",
+                "couchapi_expire_at": 1_700_000_000i64,
+                EXPIRES_FIELD: DateTime::from_millis(1_700_000_000_000),
+            }
+        );
+        // Only the service sets the TTL field.
+        assert_eq!(written[1], doc! { "_id": "s2" });
+        assert_eq!(written[2], session);
+    }
+
+    #[tokio::test]
+    async fn test_expired_documents_are_not_found() {
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one().returning(|_, id, _| {
+            let expires = match id {
+                "expired" => DateTime::from_millis(0),
+                _ => DateTime::from_millis(i64::MAX),
+            };
+            let document = doc! { "_id": id, EXPIRES_FIELD: expires };
+            Box::pin(async move { Ok(Some(document)) })
+        });
+
+        let db = ExpiringDatabase::new(Box::new(mock), settings());
+        let find = |id| db.find_one("sessions", id, FindOneOptions::default());
+        assert_eq!(find("expired").await.unwrap(), None);
+        assert_eq!(find("live").await.unwrap(), Some(doc! { "_id": "live" }));
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The gRPC admin service, `couchapi.admin.v1.Admin`, for control planes that would rather not
+//! drive the HTTP admin routes. It offers the operations the server has: reporting its status and
+//! reloading a view.
+
+use crate::auth::constant_time_eq;
+use crate::config::GrpcSettings;
+use crate::ops::admin::reload_view;
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use std::sync::Arc;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::info;
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/couchapi.admin.v1.Admin.rs"));
+}
+
+use generated::admin_server::{Admin, AdminServer};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatusRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatusResponse {
+    /// This server's version.
+    #[prost(string, tag = "1")]
+    pub version: String,
+
+    /// Whether MongoDB answered, and the version it gave when it did.
+    #[prost(bool, tag = "2")]
+    pub mongodb_reachable: bool,
+    #[prost(string, tag = "3")]
+    pub mongodb_version: String,
+
+    /// Whether writes are being turned away for maintenance.
+    #[prost(bool, tag = "4")]
+    pub maintenance: bool,
+
+    /// How many tasks `/_active_tasks` would list.
+    #[prost(uint32, tag = "5")]
+    pub active_tasks: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ReloadViewRequest {
+    #[prost(string, tag = "1")]
+    pub db: String,
+    #[prost(string, tag = "2")]
+    pub design: String,
+    #[prost(string, tag = "3")]
+    pub view: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ReloadViewResponse {
+    /// The file the view was reloaded from.
+    #[prost(string, tag = "1")]
+    pub source: String,
+}
+
+struct AdminService {
+    state: Arc<AppState>,
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn status(
+        &self,
+        _request: Request<StatusRequest>,
+    ) -> Result<Response<StatusResponse>, Status> {
+        let mongodb_version = self.state.db.get_version().await.ok();
+
+        Ok(Response::new(StatusResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            mongodb_reachable: mongodb_version.is_some(),
+            mongodb_version: mongodb_version
+                .and_then(|v| v.get_str("version").ok().map(str::to_string))
+                .unwrap_or_default(),
+            maintenance: self.state.maintenance.is_enabled(),
+            active_tasks: self.state.active_tasks.list().len() as u32,
+        }))
+    }
+
+    async fn reload_view(
+        &self,
+        request: Request<ReloadViewRequest>,
+    ) -> Result<Response<ReloadViewResponse>, Status> {
+        let ReloadViewRequest { db, design, view } = request.into_inner();
+        let path = Path((db, design, view));
+
+        let reloaded = reload_view(State(self.state.clone()), path)
+            .await
+            .map_err(status_for)?;
+
+        Ok(Response::new(ReloadViewResponse {
+            source: reloaded["source"].as_str().unwrap_or_default().to_string(),
+        }))
+    }
+}
+
+/// The gRPC status for an error from the HTTP handler behind a method.
+fn status_for((status, body): JsonWithStatusCodeResponse) -> Status {
+    let message = body
+        .get("reason")
+        .or_else(|| body.get("error"))
+        .and_then(|m| m.as_str())
+        .unwrap_or_default()
+        .to_string();
+
+    match status {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::NOT_IMPLEMENTED => Status::failed_precondition("No view folder is configured."),
+        _ => Status::internal(message),
+    }
+}
+
+/// Lets through calls bearing the configured token in their `authorization` metadata.
+#[derive(Clone)]
+struct CheckToken {
+    token: String,
+}
+
+impl Interceptor for CheckToken {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let presented = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+
+        match presented {
+            Some(presented) if constant_time_eq(presented, &self.token) => Ok(request),
+            _ => Err(Status::unauthenticated("A valid bearer token is required.")),
+        }
+    }
+}
+
+fn admin_service(
+    state: Arc<AppState>,
+    settings: &GrpcSettings,
+) -> InterceptedService<AdminServer<AdminService>, CheckToken> {
+    let token = CheckToken {
+        token: settings.token.clone(),
+    };
+    AdminServer::with_interceptor(AdminService { state }, token)
+}
+
+/// Serves the admin service on its own listener for as long as the server runs.
+pub fn spawn_admin_service(state: Arc<AppState>, settings: &GrpcSettings) {
+    let address = settings
+        .listen_address
+        .parse()
+        .expect("invalid grpc listen_address");
+    let service = admin_service(state, settings);
+
+    info!(address = settings.listen_address, "serving grpc admin");
+    tokio::spawn(async move {
+        Server::builder()
+            .add_service(service)
+            .serve(address)
+            .await
+            .expect("unable to serve grpc admin");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use tonic::metadata::MetadataValue;
+
+    #[test]
+    fn test_check_token() {
+        let mut check = CheckToken {
+            token: "secret".to_string(),
+        };
+        let request = |authorization: Option<&'static str>| {
+            let mut request = Request::new(());
+            if let Some(authorization) = authorization {
+                let value = MetadataValue::from_static(authorization);
+                request.metadata_mut().insert("authorization", value);
+            }
+            request
+        };
+
+        assert!(check.call(request(Some("Bearer secret"))).is_ok());
+        let status = check.call(request(Some("Bearer guess"))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert!(check.call(request(None)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let mut mock = MockDatabase::new();
+        mock.expect_get_version()
+            .returning(|| Box::pin(async { Ok(bson::doc! { "version": "7.0.4" }) }));
+        let service = AdminService {
+            state: Arc::new(AppState::for_tests(mock)),
+        };
+
+        let status = service.status(Request::new(StatusRequest {})).await;
+        let status = status.unwrap().into_inner();
+
+        assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+        assert!(status.mongodb_reachable);
+        assert_eq!(status.mongodb_version, "7.0.4");
+        assert!(!status.maintenance);
+        assert_eq!(status.active_tasks, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reload_view_without_a_view_folder() {
+        let service = AdminService {
+            state: Arc::new(AppState::for_tests(MockDatabase::new())),
+        };
+        let request = ReloadViewRequest {
+            db: "orders".to_string(),
+            design: "sales".to_string(),
+            view: "by_customer".to_string(),
+        };
+
+        let status = service.reload_view(Request::new(request)).await;
+        assert_eq!(status.unwrap_err().code(), tonic::Code::FailedPrecondition);
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::ServerSettings;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::Response;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use std::convert::Infallible;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, UnixListener};
+use tower::{Service, ServiceExt};
+use tracing::{debug, warn};
+
+const DEFAULT_TCP_BACKLOG: u32 = 1024;
+
+/// Where the server listens, parsed from `listen_address`. Addresses starting with `unix:` are
+/// Unix domain socket paths, anything else is a TCP address.
+#[derive(Debug, PartialEq)]
+pub enum ListenAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    pub fn parse(address: &str) -> Self {
+        match address.strip_prefix("unix:") {
+            Some(path) => ListenAddress::Unix(PathBuf::from(path)),
+            None => ListenAddress::Tcp(address.to_string()),
+        }
+    }
+}
+
+/// Binds a TCP listener with the configured backlog.
+pub async fn bind_tcp(address: &str, settings: &ServerSettings) -> io::Result<TcpListener> {
+    let address = lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "address didn't resolve"))?;
+
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+
+    socket.listen(settings.tcp_backlog.unwrap_or(DEFAULT_TCP_BACKLOG))
+}
+
+/// Binds a Unix domain socket, removing a socket left behind by a previous run first.
+pub fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    UnixListener::bind(path)
+}
+
+/// Serves HTTP/1 or HTTP/2 on a single connection, using the connection limits from the
+/// settings. `prepare` can add extensions to each request before it reaches the app.
+pub async fn serve_connection<I, S, F>(io: I, app: S, settings: &ServerSettings, prepare: F)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    F: Fn(&mut Request<Incoming>) + Clone + Send + 'static,
+{
+    let service = hyper::service::service_fn(move |mut req: Request<Incoming>| {
+        prepare(&mut req);
+        app.clone().oneshot(req)
+    });
+
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder.http1().keep_alive(settings.keep_alive);
+    if let Some(max_header_bytes) = settings.max_header_bytes {
+        builder.http1().max_buf_size(max_header_bytes);
+    }
+
+    if let Err(e) = builder.serve_connection(TokioIo::new(io), service).await {
+        debug!(error = e.to_string(), "error serving connection");
+    }
+}
+
+/// Serves the app over plain TCP, adding the client's address to each request as `ConnectInfo`.
+pub async fn serve_tcp<S>(listener: TcpListener, app: S, settings: ServerSettings)
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!(error = e.to_string(), "unable to accept connection");
+                continue;
+            }
+        };
+
+        let app = app.clone();
+        let settings = settings.clone();
+
+        tokio::spawn(async move {
+            serve_connection(stream, app, &settings, move |req| {
+                req.extensions_mut().insert(ConnectInfo(remote_addr));
+            })
+            .await;
+        });
+    }
+}
+
+/// Serves the app over a Unix domain socket. Requests carry no `ConnectInfo`, as there is no
+/// remote address to give them.
+pub async fn serve_unix<S>(listener: UnixListener, app: S, settings: ServerSettings)
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!(error = e.to_string(), "unable to accept connection");
+                continue;
+            }
+        };
+
+        let app = app.clone();
+        let settings = settings.clone();
+
+        tokio::spawn(async move {
+            serve_connection(stream, app, &settings, |_| {}).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use axum::Router;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn test_listen_address_parse() {
+        assert_eq!(
+            ListenAddress::parse("0.0.0.0:3000"),
+            ListenAddress::Tcp("0.0.0.0:3000".to_string())
+        );
+        assert_eq!(
+            ListenAddress::parse("unix:/var/run/couchapi.sock"),
+            ListenAddress::Unix(PathBuf::from("/var/run/couchapi.sock"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_unix() {
+        let dir = std::env::temp_dir().join(format!("couchapi-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("couchapi.sock");
+
+        // A stale socket file shouldn't stop the server from starting.
+        std::fs::write(&path, "").unwrap();
+        let listener = bind_unix(&path).unwrap();
+
+        let app = Router::new().route("/", get(|| async { "hello" }));
+        tokio::spawn(serve_unix(listener, app, ServerSettings::default()));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("hello"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_tcp() {
+        let settings = ServerSettings {
+            tcp_backlog: Some(16),
+            keep_alive: false,
+            ..ServerSettings::default()
+        };
+        let listener = bind_tcp("127.0.0.1:0", &settings).await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let app = Router::new().route(
+            "/",
+            get(
+                |ConnectInfo(addr): ConnectInfo<std::net::SocketAddr>| async move {
+                    addr.ip().to_string()
+                },
+            ),
+        );
+        tokio::spawn(serve_tcp(listener, app, settings));
+
+        // Without keep-alive the server closes the connection after the response, so reading
+        // to the end finishes even though HTTP/1.1 keeps connections open by default.
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            stream.read_to_string(&mut response),
+        )
+        .await
+        .expect("connection wasn't closed")
+        .unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("127.0.0.1"));
+    }
+}
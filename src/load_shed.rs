@@ -0,0 +1,147 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Caps how many requests are handled at once, so that a burst is turned away early rather than
+/// queueing up behind MongoDB.
+#[derive(Debug)]
+pub struct InFlightLimit {
+    max: usize,
+    current: AtomicUsize,
+}
+
+/// Holds one of the limit's slots until dropped, which also covers requests that are cancelled.
+pub struct InFlightPermit<'a> {
+    limit: &'a InFlightLimit,
+}
+
+impl InFlightLimit {
+    pub fn new(max: usize) -> Self {
+        InFlightLimit {
+            max,
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn try_acquire(&self) -> Option<InFlightPermit<'_>> {
+        self.current
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+                (current < self.max).then_some(current + 1)
+            })
+            .ok()
+            .map(|_| InFlightPermit { limit: self })
+    }
+}
+
+impl Drop for InFlightPermit<'_> {
+    fn drop(&mut self) {
+        self.limit.current.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Responds with a `503` when `max_in_flight` requests are already being handled.
+pub async fn shed_load(
+    State(limit): State<Arc<InFlightLimit>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(_permit) = limit.try_acquire() else {
+        metrics::increment_counter!("couchapi_requests_shed_total");
+
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "1")],
+            Json(json!({
+                "error": "service_unavailable",
+                "reason": "Too many requests are in flight, try again shortly."
+            })),
+        )
+            .into_response();
+    };
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use tokio::sync::oneshot;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_try_acquire() {
+        let limit = InFlightLimit::new(2);
+
+        let first = limit.try_acquire();
+        let second = limit.try_acquire();
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(limit.try_acquire().is_none());
+
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_shed_load() {
+        let (started_tx, started_rx) = oneshot::channel::<()>();
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let channels = Arc::new(std::sync::Mutex::new(Some((started_tx, release_rx))));
+
+        let app = Router::new()
+            .route(
+                "/",
+                get(move || {
+                    let channels = channels.lock().unwrap().take();
+                    async move {
+                        if let Some((started, release)) = channels {
+                            started.send(()).unwrap();
+                            release.await.unwrap();
+                        }
+                        "ok"
+                    }
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                Arc::new(InFlightLimit::new(1)),
+                shed_load,
+            ));
+
+        let request = || Request::builder().uri("/").body(Body::empty()).unwrap();
+
+        let slow = tokio::spawn(app.clone().oneshot(request()));
+        started_rx.await.unwrap();
+
+        let res = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "1");
+
+        release_tx.send(()).unwrap();
+        assert_eq!(slow.await.unwrap().unwrap().status(), StatusCode::OK);
+
+        let res = app.oneshot(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -17,53 +17,126 @@
 #[cfg_attr(target_os = "macos", link(name = "CoreServices", kind = "framework"))]
 extern "C" {}
 
+mod access_log;
+mod active_tasks;
+mod attachments;
+mod auth;
+mod backup;
+mod bench;
+mod cli;
 mod common;
+mod compat;
+mod compat_report;
+mod compression;
+mod concern;
 mod config;
 mod couchdb;
 mod db;
+mod decompression;
+mod doc_cache;
+mod expiry;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod listener;
+mod load_shed;
+mod maintenance;
 mod metrics;
 mod ops;
+mod rate_limit;
+mod redis_cache;
+mod reporting;
+mod request_timeout;
+mod self_test;
+mod smoke;
 mod state;
-
+mod tenancy;
+mod tls;
+mod tombstones;
+mod view_limit;
+mod warmup;
+
+use crate::access_log::AccessLog;
+use crate::active_tasks::active_tasks;
+use crate::attachments::{DedupedDatabase, GridFsStore};
+use crate::auth::session::{delete_session, get_session, post_session};
+use crate::bench::BenchArgs;
+use crate::cli::ConfigCommand;
 use crate::common::{
     add_content_type_if_needed,
+    add_idempotency_key,
     add_if_match,
     add_if_none_match,
+    add_request_id,
     add_server_header,
     always_add_must_revalidate,
+    cors_layer,
     log_response_if_error,
+    panic_response,
     print_request_response,
+    resolve_alias,
 };
+use crate::compat_report::compat_report;
+use crate::compression::CompressedDatabase;
 use crate::config::Settings;
-use crate::db::MongoDB;
+use crate::db::{Database, MongoDB};
+use crate::decompression::DecompressionLimits;
+use crate::doc_cache::CachedDatabase;
+use crate::expiry::ExpiringDatabase;
+use crate::listener::ListenAddress;
+use crate::load_shed::InFlightLimit;
+use crate::maintenance::{get_maintenance, put_maintenance, Maintenance};
+use crate::ops::admin::{list_views, reload_view};
 use crate::ops::bulk::bulk_docs;
-use crate::ops::create_update::{new_item, new_item_with_id};
+use crate::ops::bulk_stream::bulk_docs_stream;
+use crate::ops::changes::{changes, changes_websocket, db_updates};
+use crate::ops::create_update::{new_item, new_item_with_id, put_attachment};
+use crate::ops::db_info::{db_info, dbs_info};
 use crate::ops::delete::delete_item;
+use crate::ops::geo::{geo, spatial};
 use crate::ops::get::{
     all_docs,
     get_item,
     get_view,
+    get_view_explain,
     post_all_docs,
     post_get_view,
     post_multi_query,
+    view_dry_run,
 };
+use crate::ops::idempotency;
+use crate::ops::query_server::QueryServer;
+use crate::ops::replication::{delete_local, ensure_full_commit, get_local, put_local, revs_diff};
+use crate::ops::schema::Schema;
+use crate::ops::script_cache::ScriptCache;
+use crate::ops::script_engine::new_script_engine;
+use crate::ops::search::{post_search, search};
+use crate::ops::security::{get_security, put_security};
+use crate::ops::snapshot::{export, import};
 use crate::ops::update::{execute_update_script, execute_update_script_with_doc};
+use crate::ops::uuids::{get_uuids, UuidGenerator};
 use crate::ops::JsonWithStatusCodeResponse;
+use crate::rate_limit::RateLimiter;
+use crate::redis_cache::RedisCachedDatabase;
+use crate::reporting::ErrorReporter;
+use crate::request_timeout::RequestTimeouts;
+use crate::smoke::SmokeArgs;
 use crate::state::AppState;
-use axum::body::Body;
-use axum::extract::{Json, Path, State};
+use crate::tenancy::TenantDatabase;
+use crate::view_limit::ViewLimits;
+use axum::extract::{Json, State};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post, put};
-use axum::ServiceExt;
 use axum::{middleware, Router};
-use clap::{command, Parser};
+use clap::{Parser, Subcommand};
 use serde_json::{json, Value};
 use std::error::Error;
 use std::sync::Arc;
 use tokio::net::TcpListener;
+use tower::util::MapRequestLayer;
+use tower_http::catch_panic::CatchPanicLayer;
 use tower_http::decompression::RequestDecompressionLayer;
-use tower_http::normalize_path::{NormalizePath, NormalizePathLayer};
+use tower_http::normalize_path::NormalizePathLayer;
 use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
 use tower_layer::Layer;
 use tracing::{instrument, warn, Level};
@@ -71,16 +144,44 @@ use tracing::{instrument, warn, Level};
 #[derive(Parser, Debug)]
 #[command(author = None, version = None, about = "CouchDB Emulation API for MongoDB", long_about = None)]
 struct Args {
-    #[arg(short, long, default_value = "config.toml")]
+    #[arg(short, long, default_value = "config.toml", global = true)]
     config: String,
+
+    /// Check the connections, views and scripts, report on them and exit.
+    #[arg(long)]
+    self_test: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[instrument]
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check or print the configuration without starting the server.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Load a running server with document, bulk or view requests and report the latencies.
+    Bench(BenchArgs),
+
+    /// Send a running server the requests pouchdb, pycouchdb and nano make and report which of
+    /// them work.
+    Smoke(SmokeArgs),
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
     let config_file = args.config;
 
+    match &args.command {
+        Some(Command::Config(command)) => {
+            std::process::exit(cli::run_config_command(command, &config_file));
+        }
+        Some(Command::Bench(bench_args)) => std::process::exit(bench::run_bench(bench_args)),
+        Some(Command::Smoke(smoke_args)) => std::process::exit(smoke::run_smoke(smoke_args)),
+        None => {}
+    }
+
     let settings = Settings::new(Some(config_file.to_string()));
     match settings {
         Ok(_) => {}
@@ -89,15 +190,42 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    let settings = settings.unwrap();
+    settings.server.validate().expect("invalid server settings");
+
+    if args.self_test {
+        let code = settings
+            .server
+            .runtime_builder()
+            .build()?
+            .block_on(self_test::run_self_test(settings));
+        std::process::exit(code);
+    }
+
+    // The runtime is built by hand, rather than with #[tokio::main], so it can be tuned from the
+    // settings.
+    settings
+        .server
+        .runtime_builder()
+        .build()?
+        .block_on(run(settings))
+}
+
+#[instrument(skip_all)]
+async fn run(settings: Settings) -> Result<(), Box<dyn Error>> {
     // TODO(lee) make this not mutable... it's just easier while it's late at night
-    let mut unwrapped_settings = settings.unwrap();
+    let mut unwrapped_settings = settings;
     unwrapped_settings.configure_logging();
+    unwrapped_settings
+        .resolve_secrets()
+        .expect("unable to resolve secrets");
     unwrapped_settings.maybe_add_views_from_files();
 
     if let Some(couchdb_present) = &unwrapped_settings.couchdb_settings {
         warn!(
             read_only = couchdb_present.read_only,
             read_through = couchdb_present.read_through,
+            stale_fallback = couchdb_present.stale_fallback,
             "CouchDB settings present, so some functionality will differ"
         );
 
@@ -115,6 +243,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
             );
         }
 
+        if let Some(soft_launch) = &couchdb_present.soft_launch {
+            for (database, percent) in soft_launch {
+                warn!(
+                    database,
+                    percent, "Soft launch: reading this percentage from MongoDB"
+                );
+            }
+        }
+
         if let Some(mappings) = &couchdb_present.mappings {
             for (k, v) in mappings {
                 warn!(couchdb = k, mongodb = v, "Mapping");
@@ -122,31 +259,208 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    let db = unwrapped_settings
-        .get_mongodb_database()
+    let default_read_concern = unwrapped_settings
+        .get_default_read_concern()
+        .expect("invalid default_r");
+    let default_write_concern = unwrapped_settings
+        .get_default_write_concern()
+        .expect("invalid default_w");
+
+    let client = unwrapped_settings
+        .get_mongodb_client()
         .await
         .expect("unable to connect to mongodb");
 
+    let mut db: Box<dyn Database + Send + Sync> =
+        Box::new(MongoDB::new(&client, &unwrapped_settings.mongodb_database));
+    if let Some(compression) = &unwrapped_settings.compression {
+        db = Box::new(CompressedDatabase::new(db, compression.clone()));
+    }
+    if let Some(dedup) = &unwrapped_settings.attachment_dedup {
+        let blobs = GridFsStore::new(
+            &client.database(&unwrapped_settings.mongodb_database),
+            &dedup.bucket,
+        );
+        db = Box::new(DedupedDatabase::new(db, Box::new(blobs), dedup.clone()));
+    }
+    if let Some(expiry) = &unwrapped_settings.expiry {
+        expiry::create_ttl_indexes(db.as_ref(), expiry).await;
+        db = Box::new(ExpiringDatabase::new(db, expiry.clone()));
+    }
+    if let Some(redis_cache) = &unwrapped_settings.redis_cache {
+        db = Box::new(
+            RedisCachedDatabase::connect(db, redis_cache)
+                .await
+                .expect("unable to connect to redis"),
+        );
+    }
+    if let Some(document_cache) = &unwrapped_settings.document_cache {
+        let cached = CachedDatabase::new(db, document_cache);
+        if let Some(redis_cache) = &unwrapped_settings.redis_cache {
+            redis_cache::subscribe_invalidations(redis_cache, cached.invalidator());
+        }
+        db = Box::new(cached);
+    }
+    if let Some(tenancy) = &unwrapped_settings.tenancy {
+        // Read through, soft launch and writes to CouchDB name the upstream database after the
+        // request's, so every tenant would share it.
+        if unwrapped_settings.couchdb_settings.is_some() {
+            panic!("tenancy can't be used with couchdb_settings");
+        }
+
+        let api_keys = unwrapped_settings.api_keys.iter().flatten();
+        let session_users = unwrapped_settings
+            .couch_httpd_auth
+            .iter()
+            .flat_map(|auth| &auth.users);
+        let signing_services = unwrapped_settings
+            .request_signing
+            .iter()
+            .flat_map(|signing| &signing.services);
+        let tenants = api_keys
+            .filter_map(|k| k.tenant.as_deref())
+            .chain(session_users.filter_map(|u| u.tenant.as_deref()))
+            .chain(signing_services.filter_map(|s| s.tenant.as_deref()));
+        tenancy::validate_tenants(tenancy, tenants).expect("invalid tenancy settings");
+        db = Box::new(TenantDatabase::new(db, tenancy.clone()));
+    }
+
+    let mut tenants: Vec<String> = match &unwrapped_settings.tenancy {
+        Some(_) => unwrapped_settings
+            .api_keys
+            .iter()
+            .flatten()
+            .filter_map(|k| k.tenant.clone())
+            .collect(),
+        None => vec![],
+    };
+    tenants.sort();
+    tenants.dedup();
+    let tenants = std::iter::once(None).chain(tenants.into_iter().map(Some));
+    idempotency::create_ttl_indexes(db.as_ref(), tenants.collect()).await;
+
+    let schemas = unwrapped_settings.schemas.as_ref().map(|schemas| {
+        schemas
+            .iter()
+            .map(|(db, path)| {
+                let schema = Schema::load(std::path::Path::new(path))
+                    .unwrap_or_else(|e| panic!("unable to load schema {}: {}", path, e));
+                (db.clone(), schema)
+            })
+            .collect()
+    });
+
     let state = Arc::new(AppState {
-        db: Box::new(MongoDB { db }),
+        db,
         views: unwrapped_settings.views,
+        view_folder: unwrapped_settings.view_folder,
         updates_folder: unwrapped_settings.updates_folder,
         couchdb_details: unwrapped_settings.couchdb_settings,
+        default_read_concern,
+        default_write_concern,
+        api_keys: unwrapped_settings.api_keys,
+        security: unwrapped_settings.security,
+        open_admin_routes: unwrapped_settings.open_admin_routes,
+        replication_target: unwrapped_settings.replication_target,
+        rate_limiter: unwrapped_settings.rate_limit.map(RateLimiter::new),
+        couch_httpd_auth: unwrapped_settings.couch_httpd_auth,
+        client_cert_auth: unwrapped_settings
+            .tls
+            .as_ref()
+            .is_some_and(|t| t.client_ca_file.is_some()),
+        request_signing: unwrapped_settings.request_signing,
+        features: unwrapped_settings.features,
+        default_limit: unwrapped_settings.default_limit,
+        max_limit: unwrapped_settings.max_limit,
+        script_cache: ScriptCache::default(),
+        script_engine: new_script_engine(&unwrapped_settings.scripts),
+        query_server: unwrapped_settings
+            .query_server
+            .map(|q| QueryServer::new(q, &unwrapped_settings.scripts)),
+        bulk_concurrency: unwrapped_settings.bulk_concurrency,
+        uuids: UuidGenerator::new(unwrapped_settings.uuids.clone()),
+        view_limits: unwrapped_settings.view_concurrency.map(ViewLimits::new),
+        search_indexes: unwrapped_settings.search_indexes,
+        geo_indexes: unwrapped_settings.geo_indexes,
+        reloaded_views: Default::default(),
+        compatibility: unwrapped_settings.compatibility,
+        active_tasks: Default::default(),
+        maintenance: Maintenance::new(&unwrapped_settings.maintenance),
+        idempotency: unwrapped_settings.idempotency,
+        schemas,
+        write_transforms: unwrapped_settings.write_transforms,
     });
 
     metrics_prometheus::install();
 
+    if let Some(warmup_settings) = &unwrapped_settings.warmup {
+        warmup::warm_up(&client, &state, warmup_settings).await;
+    }
+
+    if let Some(tombstones) = unwrapped_settings.tombstones {
+        tombstones::spawn_purge(state.clone(), tombstones);
+    }
+
+    if let Some(backups) = unwrapped_settings.backups.clone() {
+        backup::spawn_backups(state.clone(), backups).expect("invalid backup settings");
+    }
+
+    // Scrapers reach /metrics on the main listener unless it has a listener of its own.
+    let metrics_settings = unwrapped_settings.metrics.as_ref();
+    let main_listener_metrics = if metrics_settings.is_some_and(|m| m.listen_address.is_some()) {
+        Router::new()
+    } else {
+        metrics::metrics_router(metrics_settings)
+    };
+
+    // Server wide admin routes, which have no database for the security object to come from.
+    let admin_routes = Router::new()
+        .route("/_active_tasks", get(active_tasks))
+        .route("/_couchapi/views", get(list_views))
+        .route(
+            "/_couchapi/maintenance",
+            get(get_maintenance).put(put_maintenance),
+        )
+        .route(
+            "/_couchapi/views/reload/:db/:design/:view",
+            post(reload_view),
+        )
+        .route(
+            "/_db_updates",
+            get(db_updates).layer(middleware::from_fn_with_state(
+                unwrapped_settings.tenancy.clone().map(Arc::new),
+                tenancy::scope_tenant,
+            )),
+        )
+        .route(
+            "/_dbs_info",
+            post(dbs_info).layer(middleware::from_fn_with_state(
+                unwrapped_settings.tenancy.clone().map(Arc::new),
+                tenancy::scope_tenant,
+            )),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_server_admin,
+        ));
+
     let mut router = Router::new()
         .route("/:db/_design/:design/_view/:view",
                post(post_get_view)
                    .get(get_view)
                    .layer(middleware::from_fn(metrics::add_view_metrics))
         )
+        .route("/:db/_design/:design/_view/:view/_explain", get(get_view_explain))
+        .route("/:db/_view_dry_run", post(view_dry_run))
         .route("/:db/_design/:design/_view/:view/queries",
                post(post_multi_query)
                    .layer(middleware::from_fn(metrics::add_view_metrics))
         )
 
+        .route("/:db/_design/:design/_search/:index", post(post_search).get(search))
+        .route("/:db/_design/:design/_geo/:index", get(geo))
+        .route("/:db/_design/:design/_spatial/:index", get(spatial))
+
         .route("/:db/_design/:design/_update/:function",
                put(execute_update_script)
                    .post(execute_update_script)
@@ -159,7 +473,21 @@ async fn main() -> Result<(), Box<dyn Error>> {
         )
 
         .route("/:db/_bulk_docs", post(bulk_docs))
+        .route("/:db/_bulk_docs_stream", post(bulk_docs_stream))
         .route("/:db/_all_docs", post(post_all_docs).get(all_docs))
+        .route("/:db/_security", get(get_security).put(put_security))
+        .route("/:db/_changes", get(changes))
+        .route("/:db/_changes/_ws", get(changes_websocket))
+        .route("/:db/_couchapi/export", get(export))
+        .route("/:db/_couchapi/import", post(import))
+
+        // What CouchDB's replicator needs of a target, when replication_target is set
+        .route("/:db/_local/:id", get(get_local).put(put_local).delete(delete_local))
+        .route("/:db/_revs_diff", post(revs_diff))
+        .route("/:db/_ensure_full_commit", post(ensure_full_commit))
+
+        // Store a request body as an attachment of a document
+        .route("/:db/:item/:attachment", put(put_attachment))
 
         // Get a document
         .route("/:db/:item", get(get_item)
@@ -170,14 +498,41 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .route("/:db", post(new_item).get(db_info))
 
         .layer(middleware::from_fn(metrics::add_table_metrics))
-
-        .route("/metrics", get(metrics::collect_metrics))
+        .layer(middleware::from_fn(metrics::track_in_flight))
+        .layer(middleware::from_fn_with_state(state.clone(), maintenance::reject_writes))
+        .layer(middleware::from_fn_with_state(state.clone(), auth::check_security))
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit::check_rate_limit))
+        .layer(middleware::from_fn_with_state(
+            unwrapped_settings.tenancy.clone().map(Arc::new),
+            tenancy::scope_tenant,
+        ))
+        .layer(middleware::from_fn_with_state(state.clone(), auth::check_api_key))
+        .layer(middleware::from_fn(auth::check_db_name))
+
+        .merge(main_listener_metrics)
+        .merge(admin_routes)
         .route("/", get(server_info))
+        .route("/_uuids", get(get_uuids))
+        .route("/_couchapi/compat", get(compat_report))
+        .route("/_session", get(get_session).post(post_session).delete(delete_session))
+        .layer(middleware::from_fn_with_state(state.clone(), auth::session::add_session_user))
+        .layer(middleware::from_fn_with_state(state.clone(), auth::signing::check_request_signature))
 
         .route_layer(middleware::from_fn(add_if_none_match))
         .route_layer(middleware::from_fn(add_if_match))
+        .route_layer(middleware::from_fn(add_idempotency_key))
 
+        // Turn a panicking handler into a 500 the layers above can log and report.
+        .layer(CatchPanicLayer::custom(panic_response))
+
+        // Compressed bodies are cut off at a limit once decompressed, as partners send gzipped
+        // bulk writes and a small body can expand enormously.
+        .layer(middleware::from_fn_with_state(
+            Arc::new(DecompressionLimits::new(&unwrapped_settings.decompression)),
+            decompression::limit_decompressed,
+        ))
         .layer(RequestDecompressionLayer::new())
+        .layer(middleware::from_fn(decompression::mark_compressed))
 
         // This magic sets up logging to look like normal request logging.
         .layer(TraceLayer::new_for_http()
@@ -194,21 +549,109 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
         .layer(middleware::from_fn(log_response_if_error));
 
+    if let Some(max_in_flight) = unwrapped_settings.max_in_flight {
+        router = router.layer(middleware::from_fn_with_state(
+            Arc::new(InFlightLimit::new(max_in_flight)),
+            load_shed::shed_load,
+        ));
+    }
+
+    if let Some(request_timeouts) = &unwrapped_settings.request_timeouts {
+        router = router.layer(middleware::from_fn_with_state(
+            Arc::new(RequestTimeouts::new(request_timeouts)),
+            request_timeout::enforce_timeout,
+        ));
+    }
+
+    if let Some(access_log_settings) = &unwrapped_settings.access_log {
+        let access_log = AccessLog::new(access_log_settings).expect("unable to open access log");
+
+        router = router.layer(middleware::from_fn_with_state(
+            Arc::new(access_log),
+            access_log::log_access,
+        ));
+    }
+
+    if let Some(reporting) = unwrapped_settings.error_reporting.clone() {
+        let reporter = Arc::new(ErrorReporter::new(reporting));
+        reporting::install_panic_hook(reporter.clone());
+
+        router = router.layer(middleware::from_fn_with_state(
+            reporter,
+            reporting::report_server_errors,
+        ));
+    }
+
+    if let Some(compatibility) = unwrapped_settings.compatibility {
+        router = router.layer(middleware::from_fn_with_state(
+            compatibility,
+            compat::shape_errors,
+        ));
+    }
+
+    router = router.layer(middleware::from_fn(add_request_id));
+
+    if let Some(cors) = &unwrapped_settings.cors {
+        router = router.layer(cors_layer(cors).unwrap());
+    }
+
     if unwrapped_settings.debug_requests {
         router = router.layer(middleware::from_fn(print_request_response));
     }
 
+    if let Some(grpc_settings) = &unwrapped_settings.grpc {
+        #[cfg(feature = "grpc")]
+        grpc::spawn_admin_service(state.clone(), grpc_settings);
+
+        #[cfg(not(feature = "grpc"))]
+        panic!(
+            "grpc is configured for {}, but this build doesn't have the grpc feature",
+            grpc_settings.listen_address
+        );
+    }
+
     let app = NormalizePathLayer::trim_trailing_slash().layer(router.with_state(state));
+    let aliases = unwrapped_settings.aliases.clone().unwrap_or_default();
+    let app = MapRequestLayer::new(move |req| resolve_alias(&aliases, req)).layer(app);
+
+    if let Some(metrics_settings) = unwrapped_settings
+        .metrics
+        .as_ref()
+        .filter(|m| m.listen_address.is_some())
+    {
+        let metrics_listener = TcpListener::bind(metrics_settings.listen_address.as_ref().unwrap())
+            .await
+            .unwrap();
+        let metrics_app: Router = metrics::metrics_router(Some(metrics_settings));
+
+        tokio::spawn(async move { axum::serve(metrics_listener, metrics_app).await.unwrap() });
+    }
 
-    let listener = TcpListener::bind(&unwrapped_settings.listen_address)
-        .await
-        .unwrap();
-    axum::serve(
-        listener,
-        <NormalizePath<Router> as ServiceExt<hyper::Request<Body>>>::into_make_service(app),
-    )
-    .await
-    .unwrap();
+    let server_settings = unwrapped_settings.server.clone();
+
+    match ListenAddress::parse(&unwrapped_settings.listen_address) {
+        ListenAddress::Unix(path) => {
+            if unwrapped_settings.tls.is_some() {
+                panic!("tls can't be used with a unix socket listen_address");
+            }
+
+            let listener = listener::bind_unix(&path).unwrap();
+            listener::serve_unix(listener, app, server_settings).await;
+        }
+        ListenAddress::Tcp(address) => {
+            let listener = listener::bind_tcp(&address, &server_settings)
+                .await
+                .unwrap();
+
+            if let Some(tls_settings) = unwrapped_settings.tls {
+                tls::serve(listener, app, tls_settings, server_settings)
+                    .await
+                    .unwrap();
+            } else {
+                listener::serve_tcp(listener, app, server_settings).await;
+            }
+        }
+    }
 
     Ok(())
 }
@@ -229,36 +672,30 @@ async fn server_info(
         .map(|v| json!(v))?;
 
     // Return a fake amount of data so that libraries like pycouchdb can work
-    Ok(Json(json!({
+    let mut welcome = json!({
         "couchdb": "FakeCouchDB",
         "version": "3.1.1",
         "git_sha": "ce596c0ea",
         "uuid": "a7a9d4c9-6f4c-4f0c-8b1e-9c4e2d9e7e4a",
-        "features": [
-            "access-ready",
-            "partitioned",
-            "pluggable-storage-engines",
-            "reshard",
-            "scheduler"
-        ],
+        "features": state.server_features(),
         "vendor": {
             "name": "Green Man Gaming"
         },
         "mongo_details": version_info,
-    }))
-    .into_response())
-}
+    });
+
+    // Older releases had neither a git_sha nor features, so they're replaced rather than merged.
+    if let Some(compatibility) = state.compatibility {
+        let object = welcome.as_object_mut().unwrap();
+        object.remove("git_sha");
+        let features = object.remove("features");
+        if let Value::Object(fields) = compatibility.welcome() {
+            object.extend(fields);
+        }
+        if compatibility.reports_features() {
+            object.insert("features".to_string(), features.unwrap_or_default());
+        }
+    }
 
-async fn db_info(Path(db): Path<String>) -> Json<Value> {
-    Json(json!({
-        "db_name": db,
-        "doc_count": 0,
-        "doc_del_count": 0,
-        "update_seq": 0,
-        "purge_seq": 0,
-        "compact_running": false,
-        "disk_size": 0,
-        "data_size": 0,
-        "instance_start_time": "0"
-    }))
+    Ok(Json(welcome).into_response())
 }
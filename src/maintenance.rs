@@ -0,0 +1,189 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::auth::{required_role, Role};
+use crate::config::MaintenanceSettings;
+use crate::state::AppState;
+use axum::body::Body;
+use axum::extract::{MatchedPath, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Whether writes are being turned away, switchable while the server runs.
+#[derive(Debug, Default)]
+pub struct Maintenance {
+    enabled: AtomicBool,
+    retry_after_secs: AtomicU64,
+}
+
+impl Maintenance {
+    pub fn new(settings: &MaintenanceSettings) -> Self {
+        Maintenance {
+            enabled: AtomicBool::new(settings.enabled),
+            retry_after_secs: AtomicU64::new(settings.retry_after_secs),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn status(&self) -> Value {
+        json!({
+            "enabled": self.is_enabled(),
+            "retry_after_secs": self.retry_after_secs.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Responds to writes with a `503` and a `Retry-After` while in maintenance mode. Writes are told
+/// apart from reads as the security object does, so views and `_all_docs` are still served when
+/// POSTed.
+pub async fn reject_writes(
+    State(state): State<Arc<AppState>>,
+    matched_path: MatchedPath,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let maintenance = &state.maintenance;
+    if !maintenance.is_enabled()
+        || required_role(matched_path.as_str(), req.uri().path(), req.method(), true)
+            == Role::Reader
+    {
+        return next.run(req).await;
+    }
+
+    metrics::increment_counter!("couchapi_maintenance_rejected_writes_total");
+
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(
+            header::RETRY_AFTER,
+            maintenance
+                .retry_after_secs
+                .load(Ordering::Relaxed)
+                .to_string(),
+        )],
+        Json(json!({
+            "error": "service_unavailable",
+            "reason": "The server is in maintenance mode and isn't accepting writes."
+        })),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct MaintenanceUpdate {
+    enabled: bool,
+    retry_after_secs: Option<u64>,
+}
+
+/// get_maintenance says whether maintenance mode is on.
+pub async fn get_maintenance(State(state): State<Arc<AppState>>) -> Json<Value> {
+    Json(state.maintenance.status())
+}
+
+/// put_maintenance switches maintenance mode on or off, and optionally the `Retry-After` sent.
+pub async fn put_maintenance(
+    State(state): State<Arc<AppState>>,
+    Json(update): Json<MaintenanceUpdate>,
+) -> Json<Value> {
+    let maintenance = &state.maintenance;
+    if let Some(retry_after_secs) = update.retry_after_secs {
+        maintenance
+            .retry_after_secs
+            .store(retry_after_secs, Ordering::Relaxed);
+    }
+    maintenance.enabled.store(update.enabled, Ordering::Relaxed);
+    warn!(enabled = update.enabled, "maintenance mode switched");
+
+    Json(maintenance.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use axum::routing::{get, post};
+    use axum::{middleware, Router};
+    use tower::ServiceExt;
+
+    fn state(enabled: bool) -> Arc<AppState> {
+        Arc::new(AppState {
+            maintenance: Maintenance::new(&MaintenanceSettings {
+                enabled,
+                retry_after_secs: 60,
+            }),
+            ..AppState::for_tests(MockDatabase::new())
+        })
+    }
+
+    fn app(state: Arc<AppState>) -> Router {
+        Router::new()
+            .route(
+                "/:db/:item",
+                get(|| async { "read" }).put(|| async { "written" }),
+            )
+            .route("/:db/_all_docs", post(|| async { "read" }))
+            .layer(middleware::from_fn_with_state(state.clone(), reject_writes))
+            .with_state(state)
+    }
+
+    async fn send(app: Router, method: &str, uri: &str) -> Response {
+        app.oneshot(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reject_writes() {
+        let state = state(true);
+
+        let response = send(app(state.clone()), "PUT", "/orders/a").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+
+        for (method, uri) in [("GET", "/orders/a"), ("POST", "/orders/_all_docs")] {
+            let response = send(app(state.clone()), method, uri).await;
+            assert_eq!(response.status(), StatusCode::OK, "{} {}", method, uri);
+        }
+
+        let Json(status) = put_maintenance(
+            State(state.clone()),
+            Json(MaintenanceUpdate {
+                enabled: false,
+                retry_after_secs: None,
+            }),
+        )
+        .await;
+        assert_eq!(status, json!({"enabled": false, "retry_after_secs": 60}));
+        assert_eq!(get_maintenance(State(state.clone())).await.0, status);
+
+        let response = send(app(state), "PUT", "/orders/a").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
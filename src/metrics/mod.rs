@@ -12,12 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::auth::constant_time_eq;
+use crate::common::db_from_path;
+use crate::config::MetricsSettings;
 use axum::body::Body;
-use axum::extract::Path;
-use axum::http::Request;
+use axum::extract::{Path, State};
+use axum::http::{header, Request, StatusCode};
 use axum::middleware::Next;
-use axum::response::Response;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{middleware, Router};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use prometheus::{Encoder, TextEncoder};
+use std::sync::Arc;
 use std::time::Instant;
 
 pub async fn add_table_metrics(
@@ -30,6 +38,26 @@ pub async fn add_table_metrics(
     next.run(req).await
 }
 
+/// Decrements the in-flight gauge when the request finishes or is cancelled.
+struct InFlightGuard {
+    labels: [(&'static str, String); 1],
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        metrics::decrement_gauge!("couchapi_in_flight_requests", 1.0, &self.labels);
+    }
+}
+
+/// Tracks how many requests are being handled for each database.
+pub async fn track_in_flight(req: Request<Body>, next: Next) -> Response {
+    let labels = [("db", db_from_path(req.uri().path()).unwrap_or_default())];
+    metrics::increment_gauge!("couchapi_in_flight_requests", 1.0, &labels);
+    let _guard = InFlightGuard { labels };
+
+    next.run(req).await
+}
+
 pub async fn add_view_metrics(
     Path((db, design, view)): Path<(String, String, String)>,
     req: Request<Body>,
@@ -90,6 +118,45 @@ pub async fn add_update_metrics(
     res
 }
 
+/// Records a single run of a break glass view script or an update handler. `outcome` is "ok" when
+/// the script ran and returned something usable, or a short failure reason otherwise.
+pub fn record_script_execution(
+    kind: &str,
+    db: &str,
+    design: &str,
+    function: &str,
+    outcome: &str,
+    latency: f64,
+) {
+    let labels = [
+        ("kind", kind.to_string()),
+        ("db", db.to_string()),
+        ("design", design.to_string()),
+        ("function", function.to_string()),
+        ("outcome", outcome.to_string()),
+    ];
+
+    metrics::increment_counter!("couchapi_script_executions_total", &labels);
+    metrics::histogram!(
+        "couchapi_script_execution_duration_seconds",
+        latency,
+        &labels,
+    );
+}
+
+/// Records how many rows a view returned and how large the response body was, so the views
+/// producing very large responses stand out.
+pub fn record_view_result(db: &str, design: &str, view: &str, rows: usize, bytes: u64) {
+    let labels = [
+        ("db", db.to_string()),
+        ("design", design.to_string()),
+        ("view", view.to_string()),
+    ];
+
+    metrics::histogram!("couchapi_view_rows_returned", rows as f64, &labels);
+    metrics::histogram!("couchapi_view_response_bytes", bytes as f64, &labels);
+}
+
 pub async fn collect_metrics() -> String {
     let mut buffer = Vec::new();
     let encoder = TextEncoder::new();
@@ -99,3 +166,108 @@ pub async fn collect_metrics() -> String {
 
     String::from_utf8(buffer).unwrap()
 }
+
+/// Returns a router serving `/metrics`, behind HTTP basic authentication when credentials are
+/// configured.
+pub fn metrics_router<S>(settings: Option<&MetricsSettings>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let router = Router::new().route("/metrics", get(collect_metrics));
+
+    match settings.and_then(|s| s.username.clone().zip(s.password.clone())) {
+        Some(credentials) => router.layer(middleware::from_fn_with_state(
+            Arc::new(credentials),
+            check_basic_auth,
+        )),
+        None => router,
+    }
+}
+
+async fn check_basic_auth(
+    State(credentials): State<Arc<(String, String)>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|h| STANDARD.decode(h).ok())
+        .and_then(|h| String::from_utf8(h).ok());
+
+    let (username, password) = credentials.as_ref();
+    let authorized = presented
+        .as_deref()
+        .and_then(|p| p.split_once(':'))
+        .is_some_and(|(u, p)| constant_time_eq(u, username) & constant_time_eq(p, password));
+
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"metrics\"")],
+        )
+            .into_response();
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+
+    fn settings(username: Option<&str>, password: Option<&str>) -> MetricsSettings {
+        MetricsSettings {
+            listen_address: None,
+            username: username.map(str::to_string),
+            password: password.map(str::to_string),
+        }
+    }
+
+    async fn status_for(settings: &MetricsSettings, authorization: Option<&str>) -> StatusCode {
+        let mut req = Request::builder().uri("/metrics");
+        if let Some(authorization) = authorization {
+            req = req.header(header::AUTHORIZATION, authorization);
+        }
+
+        let app: Router = metrics_router(Some(settings));
+        app.oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_metrics_without_credentials_are_open() {
+        let settings = settings(None, None);
+        assert_eq!(status_for(&settings, None).await, StatusCode::OK);
+
+        // A username on its own isn't enough to turn authentication on.
+        let settings = MetricsSettings {
+            username: Some("prometheus".to_string()),
+            ..settings
+        };
+        assert_eq!(status_for(&settings, None).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_basic_auth() {
+        let settings = settings(Some("prometheus"), Some("scrape"));
+        let valid = format!("Basic {}", STANDARD.encode("prometheus:scrape"));
+        let invalid = format!("Basic {}", STANDARD.encode("prometheus:wrong"));
+
+        assert_eq!(status_for(&settings, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status_for(&settings, Some(&invalid)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status_for(&settings, Some("Bearer scrape")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status_for(&settings, Some(&valid)).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::{find_view_files, load_view_file, DesignView, ViewFile};
+use crate::not_found;
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+type ViewName = (String, String, String);
+
+/// Views reloaded from their files since startup. These are used in place of the views loaded
+/// at startup, which stay as they were.
+#[derive(Default)]
+pub struct ReloadedViews {
+    views: RwLock<HashMap<ViewName, ReloadedView>>,
+}
+
+struct ReloadedView {
+    view: DesignView,
+    source: PathBuf,
+    reloaded_at: DateTime<Utc>,
+}
+
+impl ReloadedViews {
+    /// Returns the reloaded definition of a view, if it has been reloaded.
+    pub fn get(&self, db: &str, design: &str, view: &str) -> Option<DesignView> {
+        let key = (db.to_string(), design.to_string(), view.to_string());
+        let views = self.views.read().unwrap();
+        views.get(&key).map(|reloaded| reloaded.view.clone())
+    }
+
+    fn insert(&self, file: ViewFile, view: DesignView) {
+        self.views.write().unwrap().insert(
+            (file.db, file.design, file.view),
+            ReloadedView {
+                view,
+                source: file.path,
+                reloaded_at: Utc::now(),
+            },
+        );
+    }
+}
+
+/// list_views describes every view being served, with the file it was loaded from, and every
+/// update script that has run. Views set in the configuration rather than loaded from a file
+/// have no source.
+pub async fn list_views(State(state): State<Arc<AppState>>) -> Json<Value> {
+    let sources = state
+        .view_folder
+        .as_deref()
+        .map(find_view_files)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|file| ((file.db, file.design, file.view), file.path))
+        .collect::<HashMap<_, _>>();
+
+    let mut views = BTreeMap::new();
+    for (db, mapping) in state.views.iter().flatten() {
+        for (design, group) in &mapping.view_groups {
+            for view in group.keys() {
+                let key = (db.clone(), design.clone(), view.clone());
+                let source = sources.get(&key).map(|p| p.display().to_string());
+                views.insert(key, json!({ "source": source, "reloaded_at": null }));
+            }
+        }
+    }
+
+    for (key, reloaded) in state.reloaded_views.views.read().unwrap().iter() {
+        views.insert(
+            key.clone(),
+            json!({
+                "source": reloaded.source.display().to_string(),
+                "reloaded_at": reloaded.reloaded_at.to_rfc3339(),
+            }),
+        );
+    }
+
+    let views = views
+        .into_iter()
+        .map(|((db, design, view), mut entry)| {
+            entry["db"] = json!(db);
+            entry["design"] = json!(design);
+            entry["view"] = json!(view);
+            entry
+        })
+        .collect::<Vec<_>>();
+
+    let update_scripts = state
+        .script_cache
+        .loaded()
+        .into_iter()
+        .map(|(path, modified)| {
+            json!({
+                "path": path.display().to_string(),
+                "modified": DateTime::<Utc>::from(modified).to_rfc3339(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Json(json!({ "views": views, "update_scripts": update_scripts }))
+}
+
+/// reload_view reads a view's file from the view folder again and serves the new definition
+/// from then on. A file that no longer parses leaves the view as it was.
+pub async fn reload_view(
+    State(state): State<Arc<AppState>>,
+    Path((db, design, view)): Path<(String, String, String)>,
+) -> Result<Json<Value>, JsonWithStatusCodeResponse> {
+    let folder = state.view_folder.as_deref().ok_or_else(|| {
+        (
+            StatusCode::NOT_IMPLEMENTED,
+            Json(json!({"error": "not implemented"})),
+        )
+    })?;
+
+    let file = find_view_files(folder)
+        .into_iter()
+        .find(|f| f.db == db && f.design == design && f.view == view)
+        .ok_or_else(|| not_found!())?;
+
+    let definition = load_view_file(&file.path).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "bad_request", "reason": e})),
+        )
+    })?;
+
+    let source = file.path.display().to_string();
+    info!(db, design, view, source, "reloaded view");
+    state.reloaded_views.insert(file, definition);
+
+    Ok(Json(json!({ "ok": true, "source": source })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+
+    const VIEW: &str = r#"
+        match_fields = ["field1"]
+        aggregation = ['{"$match": {}}']
+        key_fields = ["field1"]
+        value_fields = []
+        filter_insert_index = 0
+    "#;
+
+    #[tokio::test]
+    async fn test_reload_view() {
+        let folder = std::env::temp_dir().join(format!("couchapi-views-{}", uuid::Uuid::new_v4()));
+        let design = folder.join("orders").join("sales");
+        std::fs::create_dir_all(&design).unwrap();
+        std::fs::write(design.join("by_customer.toml"), VIEW).unwrap();
+
+        let state = Arc::new(AppState {
+            views: Some(crate::config::load_views_from_folder(folder.to_str().unwrap()).0),
+            view_folder: Some(folder.to_str().unwrap().to_string()),
+            ..AppState::for_tests(MockDatabase::new())
+        });
+        let path = |view: &str| Path(("orders".to_string(), "sales".to_string(), view.to_string()));
+
+        let Json(listed) = list_views(State(state.clone())).await;
+        assert_eq!(listed["views"][0]["view"], "by_customer");
+        assert_eq!(
+            listed["views"][0]["source"],
+            design.join("by_customer.toml").display().to_string()
+        );
+        assert!(listed["views"][0]["reloaded_at"].is_null());
+
+        std::fs::write(
+            design.join("by_customer.toml"),
+            VIEW.replace("[\"field1\"]", "[\"field2\"]"),
+        )
+        .unwrap();
+        let Json(response) = reload_view(State(state.clone()), path("by_customer"))
+            .await
+            .unwrap();
+        assert_eq!(response["ok"], true);
+        let reloaded = state.reloaded_views.get("orders", "sales", "by_customer");
+        assert_eq!(reloaded.unwrap().key_fields, vec!["field2".to_string()]);
+
+        let Json(listed) = list_views(State(state.clone())).await;
+        assert_eq!(listed["views"].as_array().unwrap().len(), 1);
+        assert!(listed["views"][0]["reloaded_at"].is_string());
+
+        // A file that no longer parses leaves the reloaded view in place.
+        std::fs::write(design.join("by_customer.toml"), "match_fields = [").unwrap();
+        let (status, _) = reload_view(State(state.clone()), path("by_customer"))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(state
+            .reloaded_views
+            .get("orders", "sales", "by_customer")
+            .is_some());
+
+        let (status, _) = reload_view(State(state.clone()), path("missing"))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use boa_engine::{Context, JsArgs, JsResult, JsValue, NativeFunction, Source};
+use tracing::info;
+
+/// The helpers CouchDB's query server defines in JavaScript, so scripts written for it run
+/// unmodified.
+pub(crate) const BUILTINS: &str = r#"
+function isArray(obj) {
+    return Array.isArray(obj);
+}
+
+function toJSON(obj) {
+    return JSON.stringify(obj);
+}
+
+function sum(values) {
+    var total = 0;
+    for (var i = 0; i < values.length; i++) {
+        total += values[i];
+    }
+    return total;
+}
+
+var log = (function (write) {
+    return function log(message) {
+        write(typeof message === "string" ? message : JSON.stringify(message));
+    };
+})(__couchapi_log);
+
+delete globalThis.__couchapi_log;
+"#;
+
+/// Registers CouchDB's `isArray`, `toJSON`, `sum` and `log` helpers.
+pub fn register_builtins(context: &mut Context<'_>) -> JsResult<()> {
+    context.register_global_callable(
+        "__couchapi_log",
+        1,
+        NativeFunction::from_fn_ptr(write_log),
+    )?;
+    context.eval(Source::from_bytes(BUILTINS))?;
+
+    Ok(())
+}
+
+/// Writes a message from `log` to the server log. Like CouchDB, anything other than a string has
+/// already been turned into JSON.
+fn write_log(_: &JsValue, args: &[JsValue], context: &mut Context<'_>) -> JsResult<JsValue> {
+    let message = args
+        .get_or_undefined(0)
+        .to_string(context)?
+        .to_std_string_escaped();
+
+    info!(message = message.as_str(), "script log");
+
+    Ok(JsValue::undefined())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_builtins() {
+        let mut context = Context::default();
+        register_builtins(&mut context).unwrap();
+
+        let result = context
+            .eval(Source::from_bytes(
+                r#"
+                log("hello");
+                log({ a: 1, b: undefined });
+                log(undefined);
+                [isArray([1]), isArray("no"), toJSON({ a: [1, 2] }), sum([1, 2, 3.5])]
+                "#,
+            ))
+            .unwrap()
+            .to_json(&mut context)
+            .unwrap();
+
+        assert_eq!(result, json!([true, false, r#"{"a":[1,2]}"#, 6.5]));
+    }
+}
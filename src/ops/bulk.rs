@@ -12,31 +12,55 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::auth::forbidden;
 use crate::couchdb::maybe_write;
 use crate::ops::create_update::inner_new_item;
 use crate::ops::delete::inner_delete_item;
+use crate::ops::replication::replicate_docs;
 use crate::ops::JsonWithStatusCodeResponse;
 use crate::state::AppState;
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
+use futures_util::{stream, StreamExt};
 use http_body_util::BodyExt;
-use maplit::hashmap;
 use reqwest::Method;
 use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::sync::Arc;
 
+const DEFAULT_BULK_CONCURRENCY: usize = 16;
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct Docs {
     docs: Vec<Value>,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    all_or_nothing: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_edits: Option<bool>,
+}
+
+pub(crate) fn is_deletion(doc: &Value) -> bool {
+    doc.get("_deleted")
+        .and_then(|d| d.as_bool())
+        .unwrap_or(false)
 }
 
 pub async fn bulk_docs(
     State(state): State<Arc<AppState>>,
     Path(db): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
     Json(payload): Json<Docs>,
 ) -> Result<Response, JsonWithStatusCodeResponse> {
+    if !state.features_for(&db).bulk_deletes && payload.docs.iter().any(is_deletion) {
+        return Err(forbidden(
+            "Deleting documents through _bulk_docs is disabled for this database.",
+        ));
+    }
+
     let p = json!(payload);
 
     let c = maybe_write(
@@ -45,7 +69,7 @@ pub async fn bulk_docs(
         Method::POST,
         Some(&p),
         "_bulk_docs",
-        &hashmap! {},
+        &params,
     )
     .await?;
 
@@ -53,77 +77,360 @@ pub async fn bulk_docs(
         return Ok(r);
     }
 
-    let mut collected_responses: Vec<Value> = vec![];
-
-    for doc in payload.docs {
-        let delete = doc
-            .get("_deleted")
-            .and_then(|d| d.as_bool())
-            .unwrap_or(false);
-
-        let id = doc
-            .get("_id")
-            .and_then(|id| id.as_str())
-            .map(|id| id.to_string());
-
-        let response = match delete {
-            true => {
-                let rev = doc.get("_rev").and_then(|r| r.as_str()).ok_or((
-                    StatusCode::PRECONDITION_FAILED,
-                    Json(json!({"error": "missing rev"})),
-                ));
-
-                match rev {
-                    Ok(r) => inner_delete_item(
-                        state.clone(),
-                        db.clone(),
-                        id.clone().unwrap(),
-                        hashmap! {
-                            "rev".to_string() => r.to_string()
-                        },
-                        None,
-                    )
-                    .await
-                    .map(|_| {
-                        Json(json!({"ok": true, "id": id.clone().unwrap(), "rev": r.to_string()}))
-                            .into_response()
-                    }),
-                    Err(e) => Err(e),
+    // Replicated documents keep the revs they were sent with.
+    if payload.new_edits == Some(false) {
+        let failures = replicate_docs(&state, &db, &params, payload.docs).await?;
+        return Ok((StatusCode::CREATED, Json(json!(failures))).into_response());
+    }
+
+    // As in CouchDB 2 and later, there's no way to write a batch atomically.
+    if payload.all_or_nothing {
+        return Err((
+            StatusCode::EXPECTATION_FAILED,
+            Json(json!({
+                "error": "not_implemented",
+                "reason": "all_or_nothing is not supported"
+            })),
+        ));
+    }
+
+    let collected_responses = write_docs(&state, &db, &params, payload.docs).await;
+    let status = match state.compatibility {
+        Some(compatibility) => compatibility.bulk_docs_status(&collected_responses),
+        None => StatusCode::CREATED,
+    };
+
+    let response = Json(json!(collected_responses));
+    let mut response = response.into_response();
+    *response.status_mut() = status;
+    Ok(response)
+}
+
+/// How many documents of a batch are written at once.
+pub(crate) fn bulk_concurrency(state: &AppState) -> usize {
+    state
+        .bulk_concurrency
+        .unwrap_or(DEFAULT_BULK_CONCURRENCY)
+        .max(1)
+}
+
+/// Writes a batch of documents, returning each one's entry in the `_bulk_docs` response.
+pub(crate) async fn write_docs(
+    state: &Arc<AppState>,
+    db: &str,
+    params: &HashMap<String, String>,
+    docs: Vec<Value>,
+) -> Vec<Value> {
+    // Documents sharing an id are written one after another, in the order they were sent, so the
+    // first one wins as in CouchDB. Different ids are written a few at a time.
+    let count = docs.len();
+    let mut groups: Vec<Vec<(usize, Value)>> = Vec::new();
+    let mut group_for_id: HashMap<String, usize> = HashMap::new();
+    for (position, doc) in docs.into_iter().enumerate() {
+        let id = doc.get("_id").and_then(Value::as_str).map(str::to_string);
+        match id.as_ref().and_then(|id| group_for_id.get(id)) {
+            Some(&group) => groups[group].push((position, doc)),
+            None => {
+                if let Some(id) = id {
+                    group_for_id.insert(id, groups.len());
                 }
+                groups.push(vec![(position, doc)]);
             }
-            false => {
-                inner_new_item(
-                    db.clone(),
-                    id.clone(),
-                    state.clone(),
-                    hashmap! {},
-                    doc.clone(),
-                    None,
-                )
-                .await
+        }
+    }
+
+    let written: Vec<Vec<(usize, Value)>> = stream::iter(groups)
+        .map(|group| async move {
+            let mut written = Vec::with_capacity(group.len());
+            for (position, doc) in group {
+                written.push((position, write_doc(state, db, params, doc).await));
             }
+            written
+        })
+        .buffer_unordered(bulk_concurrency(state))
+        .collect()
+        .await;
+
+    let mut results = vec![Value::Null; count];
+    for (position, result) in written.into_iter().flatten() {
+        results[position] = result;
+    }
+    results
+}
+
+/// Writes or deletes one document from a `_bulk_docs` batch, returning its entry in the response:
+/// `{"ok": true, "id", "rev"}`, or `{"id", "error", "reason"}` saying why it wasn't written.
+async fn write_doc(
+    state: &Arc<AppState>,
+    db: &str,
+    params: &HashMap<String, String>,
+    doc: Value,
+) -> Value {
+    let id = doc
+        .get("_id")
+        .and_then(|id| id.as_str())
+        .map(|id| id.to_string());
+
+    if !doc.is_object() {
+        return bulk_error(&id, "bad_request", "Document must be a JSON object");
+    }
+
+    if id
+        .as_deref()
+        .is_some_and(|id| id.starts_with('_') && !is_reserved_id(id))
+    {
+        return bulk_error(
+            &id,
+            "illegal_docid",
+            "Only reserved document ids may start with underscore.",
+        );
+    }
+
+    let response = if is_deletion(&doc) {
+        let Some(item) = id.clone() else {
+            return bulk_error(&id, "bad_request", "Document id is required to delete it.");
+        };
+        let Some(rev) = doc.get("_rev").and_then(|r| r.as_str()) else {
+            return bulk_error(&id, "bad_request", "Document rev is required to delete it.");
         };
 
-        match response {
-            Ok(r) => {
-                let body = BodyExt::collect(r.into_body()).await.unwrap().to_bytes();
-                let json: Value = serde_json::from_slice(&body).unwrap();
-                collected_responses.push(json);
-            }
-            Err((..)) => {
-                let j = json!({
-                    "id": id,
-                    "error": "conflict",
-                    "reason": "Document update conflict."
-                });
-
-                collected_responses.push(j)
-            }
+        let mut delete_params = params.clone();
+        delete_params.insert("rev".to_string(), rev.to_string());
+
+        inner_delete_item(state.clone(), db.to_string(), item, delete_params, None).await
+    } else {
+        inner_new_item(
+            db.to_string(),
+            id.clone(),
+            state.clone(),
+            params.clone(),
+            doc,
+            None,
+        )
+        .await
+    };
+
+    match response {
+        Ok(r) => {
+            let body = BodyExt::collect(r.into_body()).await.unwrap().to_bytes();
+            serde_json::from_slice(&body).unwrap()
+        }
+        Err(e) => bulk_failure(&id, e),
+    }
+}
+
+fn is_reserved_id(id: &str) -> bool {
+    id.starts_with("_design/") || id.starts_with("_local/")
+}
+
+pub(crate) fn bulk_error(id: &Option<String>, error: &str, reason: &str) -> Value {
+    json!({"id": id, "error": error, "reason": reason})
+}
+
+/// Turns a failed write into its `_bulk_docs` entry, using CouchDB's error names and reasons.
+pub(crate) fn bulk_failure(
+    id: &Option<String>,
+    (status, Json(body)): JsonWithStatusCodeResponse,
+) -> Value {
+    let field = |name: &str| body.get(name).and_then(|v| v.as_str());
+
+    match status {
+        StatusCode::CONFLICT => bulk_error(id, "conflict", "Document update conflict."),
+        StatusCode::NOT_FOUND => bulk_error(id, "not_found", "missing"),
+        _ => {
+            let error = field("error").unwrap_or("unknown_error");
+            let reason = field("reason").or(field("details")).unwrap_or(error);
+            bulk_error(id, error, reason)
         }
     }
+}
 
-    let response = Json(json!(collected_responses));
-    let mut response = response.into_response();
-    *response.status_mut() = StatusCode::CREATED;
-    Ok(response)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{duplicate_key_error, MockDatabase};
+
+    fn state(mock: MockDatabase) -> Arc<AppState> {
+        Arc::new(AppState::for_tests(mock))
+    }
+
+    async fn post(
+        state: Arc<AppState>,
+        payload: Value,
+    ) -> Result<Value, JsonWithStatusCodeResponse> {
+        let response = bulk_docs(
+            State(state),
+            Path("db".to_string()),
+            Query(HashMap::new()),
+            Json(serde_json::from_value(payload).unwrap()),
+        )
+        .await?;
+
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        Ok(serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_bulk_docs_reports_each_result() {
+        let mut mock = MockDatabase::new();
+        // UpdateResult can't be built outside of the driver, so a successful write is shown with
+        // a deletion.
+        mock.expect_delete_one()
+            .returning(|_, _, _| Box::pin(async { Ok(1) }));
+        mock.expect_replace_one()
+            .returning(|_, _, _, _| Box::pin(async { Err(duplicate_key_error()) }));
+
+        let result = post(
+            state(mock),
+            json!({"docs": [
+                {"_id": "old", "_rev": "1-a", "_deleted": true},
+                {"_id": "taken", "_rev": "1-a", "n": 2},
+                {"_id": "gone", "_deleted": true},
+                {"_id": "_secret"},
+                {"_id": "odd", "_rev": "one-a"},
+                [1, 2]
+            ]}),
+        )
+        .await
+        .unwrap();
+
+        let result = result.as_array().unwrap();
+        assert_eq!(result[0], json!({"ok": true, "id": "old", "rev": "1-a"}));
+        assert_eq!(
+            result[1],
+            json!({"id": "taken", "error": "conflict", "reason": "Document update conflict."})
+        );
+        assert_eq!(
+            result[2],
+            json!({"id": "gone", "error": "bad_request", "reason": "Document rev is required to delete it."})
+        );
+        assert_eq!(result[3]["error"], "illegal_docid");
+        assert_eq!(
+            result[4],
+            json!({"id": "odd", "error": "bad_request", "reason": "Invalid rev format"})
+        );
+        assert_eq!(
+            result[5],
+            json!({"id": null, "error": "bad_request", "reason": "Document must be a JSON object"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bulk_docs_writes_concurrently_in_order() {
+        let mut mock = MockDatabase::new();
+        // Earlier documents take longer, so they finish last.
+        mock.expect_delete_one().returning(|_, filter, _| {
+            let n: u64 = filter.get_str("_id").unwrap()[1..].parse().unwrap();
+            Box::pin(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(80 - n * 10)).await;
+                Ok(1)
+            })
+        });
+
+        let docs: Vec<Value> = (0..8)
+            .map(|n| json!({"_id": format!("d{}", n), "_rev": "1-a", "_deleted": true}))
+            .collect();
+
+        let start = std::time::Instant::now();
+        let result = post(state(mock), json!({ "docs": docs })).await.unwrap();
+
+        // One after another these would take 360ms.
+        assert!(start.elapsed() < std::time::Duration::from_millis(250));
+        let ids: Vec<&str> = result
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["d0", "d1", "d2", "d3", "d4", "d5", "d6", "d7"]);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_docs_writes_duplicate_ids_in_order() {
+        let writes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut mock = MockDatabase::new();
+        let log = writes.clone();
+        mock.expect_delete_one().returning(move |_, filter, _| {
+            let write = format!(
+                "{} {}",
+                filter.get_str("_id").unwrap(),
+                filter.get_str("_rev").unwrap()
+            );
+            let log = log.clone();
+            Box::pin(async move {
+                log.lock().unwrap().push(format!("start {}", write));
+                // The first write of the id is the slowest, so a concurrent second would overtake
+                // it.
+                if write == "dup 1-a" {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                }
+                log.lock().unwrap().push(format!("end {}", write));
+                Ok(1)
+            })
+        });
+
+        let result = post(
+            state(mock),
+            json!({"docs": [
+                {"_id": "dup", "_rev": "1-a", "_deleted": true},
+                {"_id": "other", "_rev": "1-a", "_deleted": true},
+                {"_id": "dup", "_rev": "2-b", "_deleted": true},
+            ]}),
+        )
+        .await
+        .unwrap();
+
+        let revs: Vec<(&str, &str)> = result
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| (r["id"].as_str().unwrap(), r["rev"].as_str().unwrap()))
+            .collect();
+        assert_eq!(revs, [("dup", "1-a"), ("other", "1-a"), ("dup", "2-b")]);
+
+        let writes = writes.lock().unwrap();
+        let position = |entry: &str| writes.iter().position(|w| w == entry).unwrap();
+        assert!(position("end dup 1-a") < position("start dup 2-b"));
+        // Other ids don't wait for it.
+        assert!(position("end other 1-a") < position("end dup 1-a"));
+    }
+
+    #[tokio::test]
+    async fn test_bulk_docs_rejects_all_or_nothing() {
+        let result = post(
+            state(MockDatabase::new()),
+            json!({"docs": [{"_id": "a"}], "all_or_nothing": true}),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(result.0, StatusCode::EXPECTATION_FAILED);
+        assert_eq!(result.1 .0["error"], "not_implemented");
+    }
+
+    #[test]
+    fn test_bulk_failure() {
+        let id = Some("a".to_string());
+
+        assert_eq!(
+            bulk_failure(
+                &id,
+                (StatusCode::NOT_FOUND, Json(json!({"error": "not_found"})))
+            ),
+            json!({"id": "a", "error": "not_found", "reason": "missing"})
+        );
+        assert_eq!(
+            bulk_failure(
+                &id,
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "internal server error", "details": "timed out"}))
+                )
+            ),
+            json!({"id": "a", "error": "internal server error", "reason": "timed out"})
+        );
+    }
 }
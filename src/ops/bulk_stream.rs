@@ -0,0 +1,291 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::couchdb::maybe_write;
+use crate::decompression::body_error;
+use crate::ops::bulk::{bulk_error, is_deletion, write_docs};
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::StreamExt;
+use http_body_util::BodyExt;
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// How many documents are gathered before a batch is written.
+const BATCH_SIZE: usize = 500;
+
+/// The longest line accepted, so a body without newlines can't be buffered without end.
+pub(crate) const MAX_LINE_BYTES: usize = 8 * 1024 * 1024;
+
+/// How many failed documents are listed in the response. The rest are only counted.
+pub(crate) const MAX_REPORTED_ERRORS: usize = 1000;
+
+/// Tallies an import as its batches are written.
+#[derive(Default)]
+struct ImportSummary {
+    docs: usize,
+    written: usize,
+    failed: usize,
+    errors: Vec<Value>,
+}
+
+impl ImportSummary {
+    fn record(&mut self, line: usize, result: Value) {
+        self.docs += 1;
+
+        if result.get("ok").and_then(|ok| ok.as_bool()) == Some(true) {
+            self.written += 1;
+            return;
+        }
+
+        self.failed += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            let mut error = result;
+            if let Some(e) = error.as_object_mut() {
+                e.insert("line".to_string(), json!(line));
+            }
+            self.errors.push(error);
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "ok": true,
+            "docs": self.docs,
+            "written": self.written,
+            "failed": self.failed,
+            "errors": self.errors,
+        })
+    }
+}
+
+/// Imports newline-delimited JSON documents, writing them in batches as the body arrives rather
+/// than holding the whole import in memory. Responds with counts and the documents that failed,
+/// each with the line it came from.
+pub async fn bulk_docs_stream(
+    State(state): State<Arc<AppState>>,
+    Path(db): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    body: Body,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let mut summary = ImportSummary::default();
+    let mut batch: Vec<(usize, Value)> = Vec::with_capacity(BATCH_SIZE);
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut line_number = 0;
+    let mut body = body.into_data_stream();
+
+    loop {
+        let chunk = body.next().await.transpose().map_err(body_error)?;
+        let finished = chunk.is_none();
+
+        if let Some(chunk) = chunk {
+            buffer.extend_from_slice(&chunk);
+        } else if !buffer.is_empty() {
+            // The last line doesn't need a newline.
+            buffer.push(b'\n');
+        }
+
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            line_number += 1;
+
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            match serde_json::from_slice::<Value>(&line) {
+                Ok(doc) => batch.push((line_number, doc)),
+                Err(e) => summary.record(
+                    line_number,
+                    bulk_error(&None, "bad_request", &format!("invalid JSON: {}", e)),
+                ),
+            }
+
+            if batch.len() >= BATCH_SIZE {
+                write_batch(
+                    &state,
+                    &db,
+                    &params,
+                    std::mem::take(&mut batch),
+                    &mut summary,
+                )
+                .await?;
+            }
+        }
+
+        if buffer.len() > MAX_LINE_BYTES {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!({
+                    "error": "document_too_large",
+                    "reason": format!(
+                        "Line {} is longer than {} bytes. {} documents were written before it.",
+                        line_number + 1,
+                        MAX_LINE_BYTES,
+                        summary.written
+                    )
+                })),
+            ));
+        }
+
+        if finished {
+            break;
+        }
+    }
+
+    if !batch.is_empty() {
+        write_batch(&state, &db, &params, batch, &mut summary).await?;
+    }
+
+    let mut response = Json(summary.to_json()).into_response();
+    *response.status_mut() = StatusCode::CREATED;
+    Ok(response)
+}
+
+/// Writes one batch, to CouchDB for a database that's still written there and to MongoDB
+/// otherwise, and records each document's result.
+async fn write_batch(
+    state: &Arc<AppState>,
+    db: &str,
+    params: &HashMap<String, String>,
+    batch: Vec<(usize, Value)>,
+    summary: &mut ImportSummary,
+) -> Result<(), JsonWithStatusCodeResponse> {
+    let (lines, docs): (Vec<usize>, Vec<Value>) = if state.features_for(db).bulk_deletes {
+        batch.into_iter().unzip()
+    } else {
+        let (deletions, writes): (Vec<_>, Vec<_>) =
+            batch.into_iter().partition(|(_, doc)| is_deletion(doc));
+
+        for (line, doc) in deletions {
+            let id = doc
+                .get("_id")
+                .and_then(|id| id.as_str())
+                .map(str::to_string);
+            summary.record(
+                line,
+                bulk_error(
+                    &id,
+                    "forbidden",
+                    "Deleting documents through _bulk_docs is disabled for this database.",
+                ),
+            );
+        }
+
+        writes.into_iter().unzip()
+    };
+
+    let couchdb_response = maybe_write(
+        &state.couchdb_details,
+        db,
+        Method::POST,
+        Some(&json!({ "docs": docs })),
+        "_bulk_docs",
+        params,
+    )
+    .await?;
+
+    let results = match couchdb_response {
+        Some(response) => {
+            let body = BodyExt::collect(response.into_body())
+                .await
+                .unwrap()
+                .to_bytes();
+
+            match serde_json::from_slice(&body) {
+                Ok(Value::Array(results)) => results,
+                _ => {
+                    return Err((
+                        StatusCode::BAD_GATEWAY,
+                        Json(json!({
+                            "error": "bad_gateway",
+                            "reason": format!(
+                                "CouchDB rejected a batch. {} documents were written before it.",
+                                summary.written
+                            )
+                        })),
+                    ))
+                }
+            }
+        }
+        None => write_docs(state, db, params, docs).await,
+    };
+
+    for (line, result) in lines.into_iter().zip(results) {
+        summary.record(line, result);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+
+    #[tokio::test]
+    async fn test_bulk_docs_stream() {
+        let mut mock = MockDatabase::new();
+        mock.expect_delete_one()
+            .returning(|_, _, _| Box::pin(async { Ok(1) }));
+
+        let state = Arc::new(AppState::for_tests(mock));
+
+        // Documents split across chunks, a blank line, a bad line and no final newline.
+        let chunks: Vec<Result<&str, std::io::Error>> = vec![
+            Ok("{\"_id\": \"a\", \"_rev\": \"1-a\", \"_del"),
+            Ok("eted\": true}\n\n{not json}\n"),
+            Ok("[1]\n{\"_id\": \"b\", \"_rev\": \"1-b\", \"_deleted\": true}"),
+        ];
+        let body = Body::from_stream(futures_util::stream::iter(chunks));
+
+        let response = bulk_docs_stream(
+            State(state),
+            Path("db".to_string()),
+            Query(HashMap::new()),
+            body,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let summary: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(summary["docs"], 4);
+        assert_eq!(summary["written"], 2);
+        assert_eq!(summary["failed"], 2);
+        assert_eq!(summary["errors"][0]["line"], 3);
+        assert_eq!(summary["errors"][0]["error"], "bad_request");
+        assert_eq!(
+            summary["errors"][1],
+            json!({
+                "id": null,
+                "error": "bad_request",
+                "reason": "Document must be a JSON object",
+                "line": 4
+            })
+        );
+    }
+}
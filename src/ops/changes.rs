@@ -0,0 +1,838 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::auth::{forbidden, is_reserved_db_name};
+use crate::couchdb::read_through;
+use crate::db::ChangeStream;
+use crate::ops::{stub_attachments, JsonWithStatusCodeResponse};
+use crate::state::AppState;
+use crate::tombstones;
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use bson::{doc, Bson, Document};
+use bytes::Bytes;
+use futures_util::{FutureExt, StreamExt};
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{Instant, Sleep};
+
+/// How often a ping is sent when the request doesn't give a `heartbeat`, to keep proxies and CDNs
+/// from closing a quiet connection.
+const DEFAULT_HEARTBEAT_MS: u64 = 30_000;
+
+/// CouchDB's `heartbeat` when it's given as `true`.
+const COUCHDB_HEARTBEAT_MS: u64 = 60_000;
+
+/// CouchDB's `timeout` for longpoll and continuous feeds without a heartbeat.
+const DEFAULT_TIMEOUT_MS: u64 = 60_000;
+
+/// What each row carries.
+#[derive(Debug, Clone, Copy, Default)]
+struct RowOptions {
+    include_docs: bool,
+    attachments: bool,
+}
+
+fn bad_request(reason: &str) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "bad_request", "reason": reason})),
+    )
+}
+
+fn row_options(params: &HashMap<String, String>) -> Result<RowOptions, JsonWithStatusCodeResponse> {
+    // Documents only ever have one leaf revision here, so both styles list the same rev
+    if !matches!(
+        params.get("style").map(String::as_str),
+        None | Some("main_only") | Some("all_docs")
+    ) {
+        return Err(bad_request("Invalid style parameter"));
+    }
+
+    Ok(RowOptions {
+        include_docs: params.get("include_docs").is_some_and(|i| i == "true"),
+        attachments: params.get("attachments").is_some_and(|a| a == "true"),
+    })
+}
+
+/// A duration in milliseconds, where `true` means `default`.
+fn millis_param(
+    params: &HashMap<String, String>,
+    name: &str,
+    default: u64,
+) -> Result<Option<Duration>, JsonWithStatusCodeResponse> {
+    let millis = match params.get(name).map(String::as_str) {
+        None => return Ok(None),
+        Some("true") => default,
+        Some(millis) => millis
+            .parse::<u64>()
+            .map_err(|_| bad_request(&format!("Invalid {} parameter", name)))?,
+    };
+    Ok(Some(Duration::from_millis(millis.max(1))))
+}
+
+/// Starts watching from `since`, so a bad `since` is an HTTP error the client can see.
+async fn watch_since(
+    state: &AppState,
+    db: &str,
+    params: &HashMap<String, String>,
+) -> Result<ChangeStream, JsonWithStatusCodeResponse> {
+    state
+        .db
+        .watch(db, resume_token(params.get("since"))?)
+        .await
+        .map_err(|e| bad_request(&e.to_string()))
+}
+
+/// Pushes the database's changes over a WebSocket, one JSON message per change in the shape of a
+/// CouchDB `_changes` row. `since` resumes after a row's `seq`, otherwise changes start from now.
+/// Needs MongoDB to be a replica set.
+pub async fn changes_websocket(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path(db): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    if !state.features_for(&db).changes_websocket {
+        return Err(forbidden("_changes/_ws is disabled for this database."));
+    }
+
+    let options = row_options(&params)?;
+    let heartbeat = millis_param(&params, "heartbeat", COUCHDB_HEARTBEAT_MS)?
+        .unwrap_or(Duration::from_millis(DEFAULT_HEARTBEAT_MS));
+    let changes = watch_since(&state, &db, &params).await?;
+
+    Ok(ws.on_upgrade(move |socket| send_changes(socket, changes, options, heartbeat)))
+}
+
+/// How a `_changes` feed is delivered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Feed {
+    /// Waits for changes, then answers with them all at once.
+    Longpoll,
+    /// Sends each change as a line of its own.
+    Continuous,
+}
+
+/// Serves `feed=longpoll` and `feed=continuous`. `heartbeat` sends a newline every so many
+/// milliseconds while nothing else is sent and keeps the feed open indefinitely; otherwise the feed
+/// ends after `timeout` milliseconds without a change. Other feeds, and a `since` from before the
+/// feed handed out a `seq`, need the database's history, so they're read through to CouchDB when
+/// that's set up.
+pub async fn changes(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path(db): Path<String>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let feed = feed_param(&params);
+    let enabled = state.features_for(&db).changes_feed;
+    let since = resume_token(params.get("since"));
+
+    let (Some(feed), true, Ok(_)) = (feed, enabled, &since) else {
+        if let Some(couchdb_details) = state
+            .couchdb_details
+            .as_ref()
+            .filter(|c| c.should_read_through(&db))
+        {
+            let path = format!("{}/_changes", couchdb_details.map_for_db(db.as_str()));
+            return read_through(couchdb_details, Method::GET, None, &path, &params).await;
+        }
+        if !enabled {
+            return Err(forbidden("_changes is disabled for this database."));
+        }
+        return Err(match (feed, since) {
+            (Some(_), Err(e)) => e,
+            _ => bad_request("Only feed=longpoll and feed=continuous are supported"),
+        });
+    };
+
+    let rows = row_options(&params)?;
+    let options = feed_options(feed, &params, Box::new(move |e| change_row(e, rows)))?;
+    let changes = watch_since(&state, &db, &params).await?;
+
+    Ok(stream_feed(changes, options))
+}
+
+/// Serves `_db_updates` as `feed=longpoll` or `feed=continuous`, with `heartbeat` and `timeout`
+/// as for `_changes`. Each change to a database's documents is an `updated` row, and dropping its
+/// collection a `deleted` one. Past updates aren't kept, so there are no other feeds, and databases
+/// are created by their first write, so there are no `created` rows. Needs MongoDB to be a replica
+/// set.
+pub async fn db_updates(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let Some(feed) = feed_param(&params) else {
+        return Err(bad_request(
+            "Only feed=longpoll and feed=continuous are supported",
+        ));
+    };
+
+    let options = feed_options(feed, &params, Box::new(db_update_row))?;
+    let changes = state
+        .db
+        .watch_database(resume_token(params.get("since"))?)
+        .await
+        .map_err(|e| bad_request(&e.to_string()))?;
+
+    Ok(stream_feed(changes, options))
+}
+
+fn feed_param(params: &HashMap<String, String>) -> Option<Feed> {
+    match params.get("feed").map(String::as_str) {
+        Some("longpoll") => Some(Feed::Longpoll),
+        Some("continuous") => Some(Feed::Continuous),
+        _ => None,
+    }
+}
+
+/// Turns each change stream event into a row of the feed, or leaves it out.
+type RowFn = Box<dyn Fn(&Document) -> Option<Value> + Send>;
+
+struct FeedOptions {
+    feed: Feed,
+    row: RowFn,
+    heartbeat: Option<Duration>,
+    timeout: Option<Duration>,
+    since: String,
+}
+
+fn feed_options(
+    feed: Feed,
+    params: &HashMap<String, String>,
+    row: RowFn,
+) -> Result<FeedOptions, JsonWithStatusCodeResponse> {
+    let heartbeat = millis_param(params, "heartbeat", COUCHDB_HEARTBEAT_MS)?;
+    let timeout = match heartbeat {
+        Some(_) => None,
+        None => Some(
+            millis_param(params, "timeout", DEFAULT_TIMEOUT_MS)?
+                .unwrap_or(Duration::from_millis(DEFAULT_TIMEOUT_MS)),
+        ),
+    };
+    let since = params
+        .get("since")
+        .cloned()
+        .unwrap_or_else(|| "now".to_string());
+
+    Ok(FeedOptions {
+        feed,
+        row,
+        heartbeat,
+        timeout,
+        since,
+    })
+}
+
+/// Answers with the feed, written as the changes arrive.
+fn stream_feed(changes: ChangeStream, options: FeedOptions) -> Response {
+    let content_type = match options.feed {
+        Feed::Longpoll => "application/json",
+        Feed::Continuous => "text/plain; charset=utf-8",
+    };
+
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Bytes>(16);
+    tokio::spawn(send_feed(sender, changes, options));
+
+    let body = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((Ok::<_, Infallible>(chunk), receiver))
+    });
+    ([(CONTENT_TYPE, content_type)], Body::from_stream(body)).into_response()
+}
+
+/// Ticks every `period`, or never when there isn't one.
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Fires at the deadline, or never when there isn't one.
+async fn expire(deadline: &mut Option<Pin<Box<Sleep>>>) {
+    match deadline {
+        Some(deadline) => deadline.await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Writes the feed until it's finished or the client goes away, which drops the receiver.
+async fn send_feed(sender: Sender<Bytes>, mut changes: ChangeStream, options: FeedOptions) {
+    metrics::increment_gauge!("couchapi_changes_feeds", 1.0);
+
+    let mut heartbeat = options
+        .heartbeat
+        .map(|period| tokio::time::interval_at(Instant::now() + period, period));
+    let mut deadline = options
+        .timeout
+        .map(|timeout| Box::pin(tokio::time::sleep(timeout)));
+
+    let mut last_seq = Value::String(options.since);
+    let mut results = Vec::new();
+
+    loop {
+        tokio::select! {
+            change = changes.next() => match change {
+                Some(Ok(event)) => {
+                    let Some(row) = (options.row)(&event) else {
+                        continue;
+                    };
+                    last_seq = row["seq"].clone();
+
+                    match options.feed {
+                        Feed::Longpoll => {
+                            results.push(row);
+                            // Take whatever else has already arrived, then answer
+                            while let Some(Some(Ok(event))) = changes.next().now_or_never() {
+                                if let Some(row) = (options.row)(&event) {
+                                    last_seq = row["seq"].clone();
+                                    results.push(row);
+                                }
+                            }
+                            break;
+                        }
+                        Feed::Continuous => {
+                            if sender.send(Bytes::from(format!("{}\n", row))).await.is_err() {
+                                break;
+                            }
+                            if let (Some(deadline), Some(timeout)) = (&mut deadline, options.timeout) {
+                                deadline.as_mut().reset(Instant::now() + timeout);
+                            }
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    let error = json!({"error": "changes_failed", "reason": e.to_string()});
+                    let _ = sender.send(Bytes::from(format!("{}\n", error))).await;
+                    metrics::decrement_gauge!("couchapi_changes_feeds", 1.0);
+                    return;
+                }
+                // The collection was dropped or renamed
+                None => break,
+            },
+            _ = tick(&mut heartbeat) => {
+                if sender.send(Bytes::from_static(b"\n")).await.is_err() {
+                    break;
+                }
+            }
+            _ = expire(&mut deadline) => break,
+            _ = sender.closed() => break,
+        }
+    }
+
+    let last = match options.feed {
+        Feed::Longpoll => json!({ "results": results, "last_seq": last_seq }),
+        Feed::Continuous => json!({ "last_seq": last_seq }),
+    };
+    let _ = sender.send(Bytes::from(format!("{}\n", last))).await;
+    metrics::decrement_gauge!("couchapi_changes_feeds", 1.0);
+}
+
+/// A change's `seq`: the operation's cluster time as a number, so sequences compare numerically
+/// like CouchDB's, then the change stream resume token. The resume token is a position in the
+/// oplog, so a `seq` stays valid across restarts for as long as the oplog still holds it.
+fn seq_token(event: &Document) -> Option<String> {
+    let data = event.get_document("_id").ok()?.get_str("_data").ok()?;
+    let number = match event.get_timestamp("clusterTime") {
+        Ok(time) => (u64::from(time.time) << 32) | u64::from(time.increment),
+        Err(_) => 0,
+    };
+    Some(format!("{}-{}", number, data))
+}
+
+/// The resume token for a `since`, which is the `seq` of the last row seen. A bare resume token,
+/// without the number in front, is also taken. A `seq` with no token, such as the `since=0`
+/// clients send to start from the beginning, asks for changes from before any token was handed
+/// out, which a change stream can't go back to, so it's refused rather than served from now.
+fn resume_token(since: Option<&String>) -> Result<Option<Document>, JsonWithStatusCodeResponse> {
+    let seq = since.map(String::as_str).unwrap_or("now");
+    if seq == "now" {
+        return Ok(None);
+    }
+
+    let data = match seq.split_once('-') {
+        Some((number, data)) if number.parse::<u64>().is_ok() => data,
+        _ => seq,
+    };
+    if data.is_empty() || data.parse::<u64>().is_ok() {
+        return Err(bad_request(
+            "since must be now or a seq from this feed, as past changes aren't kept",
+        ));
+    }
+    Ok(Some(doc! { "_data": data }))
+}
+
+async fn send_changes(
+    mut socket: WebSocket,
+    mut changes: ChangeStream,
+    options: RowOptions,
+    heartbeat: Duration,
+) {
+    metrics::increment_gauge!("couchapi_changes_websockets", 1.0);
+    let mut heartbeat = tokio::time::interval(heartbeat);
+
+    loop {
+        let message = tokio::select! {
+            change = changes.next() => match change {
+                Some(Ok(event)) => match change_row(&event, options) {
+                    Some(row) => Message::Text(row.to_string()),
+                    None => continue,
+                },
+                Some(Err(e)) => {
+                    let error = json!({"error": "changes_failed", "reason": e.to_string()});
+                    let _ = socket.send(Message::Text(error.to_string())).await;
+                    break;
+                }
+                // The collection was dropped or renamed
+                None => break,
+            },
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            _ = heartbeat.tick() => Message::Ping(vec![]),
+        };
+
+        if socket.send(message).await.is_err() {
+            break;
+        }
+    }
+
+    let _ = socket.close().await;
+    metrics::decrement_gauge!("couchapi_changes_websockets", 1.0);
+}
+
+/// Whether the event is only the tombstone purge noting when it first saw a deleted document.
+fn is_tombstone_stamp(event: &Document) -> bool {
+    event
+        .get_document("updateDescription")
+        .and_then(|description| description.get_document("updatedFields"))
+        .is_ok_and(|fields| {
+            fields
+                .keys()
+                .all(|field| field == tombstones::DELETED_AT_FIELD)
+        })
+}
+
+/// A database-wide change stream event as a `_db_updates` row, or `None` for events that don't
+/// change a database or are to the server's own collections.
+fn db_update_row(event: &Document) -> Option<Value> {
+    let kind = match event.get_str("operationType").ok()? {
+        "insert" | "update" | "replace" | "delete" => "updated",
+        "drop" => "deleted",
+        _ => return None,
+    };
+    if is_tombstone_stamp(event) {
+        return None;
+    }
+
+    let db_name = event.get_document("ns").ok()?.get_str("coll").ok()?;
+    if is_reserved_db_name(db_name) {
+        return None;
+    }
+
+    Some(json!({ "db_name": db_name, "type": kind, "seq": seq_token(event)? }))
+}
+
+/// A change stream event as a `_changes` row, or `None` for events that aren't a document changing.
+fn change_row(event: &Document, options: RowOptions) -> Option<Value> {
+    let operation = event.get_str("operationType").ok()?;
+    if !matches!(operation, "insert" | "update" | "replace" | "delete") {
+        return None;
+    }
+    if is_tombstone_stamp(event) {
+        return None;
+    }
+
+    let seq = seq_token(event)?;
+    let id = event.get_document("documentKey").ok()?.get("_id")?;
+
+    // A delete leaves nothing to look the rev up from
+    let document = match event.get("fullDocument") {
+        Some(Bson::Document(document)) => {
+            let mut document = document.clone();
+            tombstones::strip(&mut document);
+            Some(document)
+        }
+        _ => None,
+    };
+    let document = document.as_ref();
+    let changes: Vec<Value> = document
+        .and_then(|d| d.get_str("_rev").ok())
+        .map(|rev| json!({ "rev": rev }))
+        .into_iter()
+        .collect();
+    let deleted =
+        operation == "delete" || document.is_some_and(|d| d.get_bool("_deleted").unwrap_or(false));
+
+    let mut row = json!({ "seq": seq, "id": id, "changes": &changes });
+    if deleted {
+        row["deleted"] = json!(true);
+    }
+    if options.include_docs {
+        row["doc"] = match document {
+            Some(document) if !deleted => {
+                let mut doc = json!(document);
+                if !options.attachments {
+                    stub_attachments(&mut doc);
+                }
+                doc
+            }
+            _ => {
+                let mut stub = json!({ "_id": id, "_deleted": true });
+                if let Some(change) = changes.first() {
+                    stub["_rev"] = change["rev"].clone();
+                }
+                stub
+            }
+        };
+    }
+    Some(row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseFeatures;
+    use crate::db::MockDatabase;
+    use bson::Timestamp;
+
+    const SEQ: &str = "7301444403200000002-8265A1";
+
+    const WITH_DOCS: RowOptions = RowOptions {
+        include_docs: true,
+        attachments: false,
+    };
+
+    fn event(operation: &str, full_document: Option<Document>) -> Document {
+        let mut event = doc! {
+            "_id": { "_data": "8265A1" },
+            "operationType": operation,
+            "documentKey": { "_id": "a" },
+            "clusterTime": Timestamp { time: 1_700_000_000, increment: 2 },
+        };
+        if let Some(full_document) = full_document {
+            event.insert("fullDocument", full_document);
+        }
+        event
+    }
+
+    #[test]
+    fn test_change_row() {
+        let replaced = event("replace", Some(doc! { "_id": "a", "_rev": "2-b", "n": 1 }));
+        assert_eq!(
+            change_row(&replaced, RowOptions::default()),
+            Some(json!({"seq": SEQ, "id": "a", "changes": [{"rev": "2-b"}]}))
+        );
+        assert_eq!(
+            change_row(&replaced, WITH_DOCS),
+            Some(json!({
+                "seq": SEQ,
+                "id": "a",
+                "changes": [{"rev": "2-b"}],
+                "doc": {"_id": "a", "_rev": "2-b", "n": 1}
+            }))
+        );
+    }
+
+    #[test]
+    fn test_change_row_deleted() {
+        let soft = event(
+            "replace",
+            Some(doc! { "_id": "a", "_rev": "3-c", "_deleted": true }),
+        );
+        assert_eq!(
+            change_row(&soft, WITH_DOCS),
+            Some(json!({
+                "seq": SEQ,
+                "id": "a",
+                "changes": [{"rev": "3-c"}],
+                "deleted": true,
+                "doc": {"_id": "a", "_rev": "3-c", "_deleted": true}
+            }))
+        );
+
+        assert_eq!(
+            change_row(&event("delete", None), RowOptions::default()),
+            Some(json!({"seq": SEQ, "id": "a", "changes": [], "deleted": true}))
+        );
+    }
+
+    #[test]
+    fn test_change_row_attachments() {
+        let with_attachment = event(
+            "insert",
+            Some(doc! {
+                "_id": "a",
+                "_rev": "1-a",
+                "_attachments": { "note.txt": { "content_type": "text/plain", "data": "aGk=" } },
+            }),
+        );
+
+        let row = change_row(&with_attachment, WITH_DOCS).unwrap();
+        assert_eq!(
+            row["doc"]["_attachments"]["note.txt"],
+            json!({"content_type": "text/plain", "length": 2, "stub": true})
+        );
+
+        let options = RowOptions {
+            attachments: true,
+            ..WITH_DOCS
+        };
+        let row = change_row(&with_attachment, options).unwrap();
+        assert_eq!(row["doc"]["_attachments"]["note.txt"]["data"], "aGk=");
+    }
+
+    #[test]
+    fn test_change_row_ignores_tombstone_stamps() {
+        let mut stamped = event(
+            "update",
+            Some(
+                doc! { "_id": "a", "_rev": "2-b", "_deleted": true, tombstones::DELETED_AT_FIELD: 1 },
+            ),
+        );
+        stamped.insert(
+            "updateDescription",
+            doc! { "updatedFields": { tombstones::DELETED_AT_FIELD: 1 }, "removedFields": [] },
+        );
+        assert_eq!(change_row(&stamped, WITH_DOCS), None);
+
+        let mut updated = event(
+            "update",
+            Some(doc! { "_id": "a", "_rev": "2-b", tombstones::DELETED_AT_FIELD: 1 }),
+        );
+        updated.insert(
+            "updateDescription",
+            doc! { "updatedFields": { "_rev": "2-b" }, "removedFields": [] },
+        );
+        let row = change_row(&updated, WITH_DOCS).unwrap();
+        assert_eq!(row["doc"], json!({"_id": "a", "_rev": "2-b"}));
+    }
+
+    #[test]
+    fn test_change_row_ignores_other_events() {
+        assert_eq!(
+            change_row(&event("drop", None), RowOptions::default()),
+            None
+        );
+        assert_eq!(
+            change_row(&doc! { "operationType": "insert" }, RowOptions::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_db_update_row() {
+        let event = |operation: &str, coll: &str| {
+            let mut event = event(operation, None);
+            event.insert("ns", doc! { "db": "couchapi", "coll": coll });
+            event
+        };
+
+        assert_eq!(
+            db_update_row(&event("insert", "orders")),
+            Some(json!({"db_name": "orders", "type": "updated", "seq": SEQ}))
+        );
+        assert_eq!(
+            db_update_row(&event("drop", "orders")),
+            Some(json!({"db_name": "orders", "type": "deleted", "seq": SEQ}))
+        );
+        assert_eq!(db_update_row(&event("insert", "_couchapi_security")), None);
+        assert_eq!(db_update_row(&event("rename", "orders")), None);
+    }
+
+    #[test]
+    fn test_seq_token() {
+        let earlier = doc! {
+            "_id": { "_data": "8265A0" },
+            "clusterTime": Timestamp { time: 1_700_000_000, increment: 1 },
+        };
+        assert_eq!(seq_token(&event("insert", None)).as_deref(), Some(SEQ));
+
+        let number = |seq: String| seq.split_once('-').unwrap().0.parse::<u64>().unwrap();
+        assert!(
+            number(seq_token(&earlier).unwrap())
+                < number(seq_token(&event("insert", None)).unwrap())
+        );
+    }
+
+    fn options(feed: Feed, heartbeat: Option<u64>, timeout: Option<u64>) -> FeedOptions {
+        FeedOptions {
+            feed,
+            row: Box::new(|e| change_row(e, RowOptions::default())),
+            heartbeat: heartbeat.map(Duration::from_millis),
+            timeout: timeout.map(Duration::from_millis),
+            since: "now".to_string(),
+        }
+    }
+
+    /// Runs the feed to the end and returns what it wrote.
+    async fn feed_output(changes: ChangeStream, options: FeedOptions) -> String {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+        send_feed(sender, changes, options).await;
+
+        let mut output = String::new();
+        while let Some(chunk) = receiver.recv().await {
+            output.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        output
+    }
+
+    #[tokio::test]
+    async fn test_longpoll_feed() {
+        let events = vec![Ok(event("insert", None)), Ok(event("drop", None))];
+        let changes = futures_util::stream::iter(events)
+            .chain(futures_util::stream::pending())
+            .boxed();
+
+        let output = feed_output(changes, options(Feed::Longpoll, None, Some(1000))).await;
+        let body: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "results": [{"seq": SEQ, "id": "a", "changes": []}],
+                "last_seq": SEQ,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_longpoll_feed_timeout() {
+        let changes = futures_util::stream::pending().boxed();
+
+        let output = feed_output(changes, options(Feed::Longpoll, None, Some(10))).await;
+        let body: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(body, json!({"results": [], "last_seq": "now"}));
+    }
+
+    #[tokio::test]
+    async fn test_continuous_feed() {
+        let changes = futures_util::stream::iter(vec![Ok(event("insert", None))])
+            .chain(futures_util::stream::pending())
+            .boxed();
+
+        let output = feed_output(changes, options(Feed::Continuous, None, Some(20))).await;
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                json!({"seq": SEQ, "id": "a", "changes": []}),
+                json!({"last_seq": SEQ}),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_feed_heartbeat() {
+        let changes: ChangeStream = futures_util::stream::pending().boxed();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+        let feed = tokio::spawn(send_feed(
+            sender,
+            changes,
+            options(Feed::Continuous, Some(5), None),
+        ));
+
+        assert_eq!(receiver.recv().await.unwrap(), Bytes::from_static(b"\n"));
+        assert_eq!(receiver.recv().await.unwrap(), Bytes::from_static(b"\n"));
+
+        // The feed stops once the client has gone.
+        drop(receiver);
+        feed.await.unwrap();
+    }
+
+    #[test]
+    fn test_millis_param() {
+        let params = |value: &str| HashMap::from([("heartbeat".to_string(), value.to_string())]);
+
+        assert_eq!(
+            millis_param(&HashMap::new(), "heartbeat", 60_000).unwrap(),
+            None
+        );
+        assert_eq!(
+            millis_param(&params("true"), "heartbeat", 60_000).unwrap(),
+            Some(Duration::from_millis(60_000))
+        );
+        assert_eq!(
+            millis_param(&params("5000"), "heartbeat", 60_000).unwrap(),
+            Some(Duration::from_millis(5000))
+        );
+        let (status, _) = millis_param(&params("soon"), "heartbeat", 60_000).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_feeds_from_the_beginning_are_refused() {
+        let state = AppState {
+            features: Some(maplit::hashmap! {
+                "*".to_string() => DatabaseFeatures {
+                    changes_feed: true,
+                    ..Default::default()
+                },
+            }),
+            ..AppState::for_tests(MockDatabase::new())
+        };
+        let params = maplit::hashmap! {
+            "feed".to_string() => "longpoll".to_string(),
+            "since".to_string() => "0".to_string(),
+        };
+
+        let (status, _) = changes(
+            State(Arc::new(state)),
+            Query(params),
+            Path("orders".to_string()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_resume_token() {
+        assert_eq!(resume_token(None).unwrap(), None);
+        assert_eq!(resume_token(Some(&"now".to_string())).unwrap(), None);
+        // Clients start from the beginning with `since=0`, which has no token to resume from.
+        for since in ["0", "42", "42-", ""] {
+            let (status, _) = resume_token(Some(&since.to_string())).unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(
+            resume_token(Some(&SEQ.to_string())).unwrap(),
+            Some(doc! { "_data": "8265A1" })
+        );
+        let token = resume_token(Some(&"8265A1".to_string())).unwrap();
+        assert_eq!(token, Some(doc! { "_data": "8265A1" }));
+
+        // It has to be something the driver will take as a resume token.
+        let token =
+            bson::from_document::<mongodb::change_stream::event::ResumeToken>(token.unwrap());
+        assert!(token.is_ok());
+    }
+}
@@ -12,28 +12,49 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::common::IfMatch;
-use crate::couchdb::maybe_write;
-use crate::ops::{check_conflict, JsonWithStatusCodeResponse};
+use crate::auth::check_design_write;
+use crate::common::{etag_rev, IdempotencyKey, IfMatch};
+use crate::concern::read_concern_for_request;
+use crate::concern::write_concern_for_request;
+use crate::couchdb::{maybe_write, maybe_write_raw};
+use crate::db::is_duplicate_key;
+use crate::not_found;
+use crate::ops::idempotency::{
+    record_idempotent_write,
+    replay_idempotent_write,
+    request_fingerprint,
+};
+use crate::ops::merge::{merge_conflict, MAX_MERGES};
+use crate::ops::replication::replicate_item;
+use crate::ops::schema::check_schema;
+use crate::ops::transform::transform;
+use crate::ops::{get_item_from_db, validate_rev, JsonWithStatusCodeResponse};
 use crate::state::AppState;
+use crate::tombstones;
+use axum::body::Bytes;
 use axum::extract::{Path, Query, State};
-use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::{Extension, Json};
-use mongodb::options::ReplaceOptions;
+use base64::Engine;
+use mongodb::options::{FindOneOptions, ReplaceOptions};
 use reqwest::Method;
 use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::sync::Arc;
-use uuid::Uuid;
 
 pub async fn new_item(
     Extension(IfMatch(if_match)): Extension<IfMatch>,
+    Extension(IdempotencyKey(idempotency_key)): Extension<IdempotencyKey>,
     State(state): State<Arc<AppState>>,
     Query(params): Query<HashMap<String, String>>,
     Path(db): Path<String>,
-    Json(payload): Json<Value>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Result<Response, JsonWithStatusCodeResponse> {
+    let payload = document_body(&headers, &body, true)?;
+
     let c = maybe_write(
         &state.couchdb_details,
         &db,
@@ -48,16 +69,27 @@ pub async fn new_item(
         return Ok(r);
     }
 
-    inner_new_item(db, None, state, params, payload, if_match).await
+    let fingerprint = request_fingerprint("POST", &db, &params, &body);
+    if let Some(r) = replay_idempotent_write(&state, &db, &idempotency_key, &fingerprint).await? {
+        return Ok(r);
+    }
+
+    let response =
+        inner_new_item(db.clone(), None, state.clone(), params, payload, if_match).await?;
+    record_idempotent_write(&state, &db, &idempotency_key, &fingerprint, response).await
 }
 
 pub async fn new_item_with_id(
     Extension(IfMatch(if_match)): Extension<IfMatch>,
+    Extension(IdempotencyKey(idempotency_key)): Extension<IdempotencyKey>,
     State(state): State<Arc<AppState>>,
     Query(params): Query<HashMap<String, String>>,
     Path((db, item)): Path<(String, String)>,
-    Json(payload): Json<Value>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Result<Response, JsonWithStatusCodeResponse> {
+    let payload = document_body(&headers, &body, false)?;
+
     let c = maybe_write(
         &state.couchdb_details,
         &db,
@@ -72,31 +104,208 @@ pub async fn new_item_with_id(
         return Ok(r);
     }
 
-    inner_new_item(db, Some(item), state, params, payload, if_match).await
+    if params.get("new_edits").is_some_and(|n| n == "false") {
+        return replicate_item(&state, &db, item, &params, payload).await;
+    }
+
+    let path = format!("{}/{}", db, item);
+    let fingerprint = request_fingerprint("PUT", &path, &params, &body);
+    if let Some(r) = replay_idempotent_write(&state, &db, &idempotency_key, &fingerprint).await? {
+        return Ok(r);
+    }
+
+    let payload = with_query_rev(payload, params.get("rev"))?;
+
+    let response = inner_new_item(
+        db.clone(),
+        Some(item),
+        state.clone(),
+        params,
+        payload,
+        if_match,
+    )
+    .await?;
+    record_idempotent_write(&state, &db, &idempotency_key, &fingerprint, response).await
+}
+
+/// put_attachment stores a request body as an inline attachment of a document, creating the
+/// document when no rev is given. The body is kept as it was sent, whatever its content type.
+pub async fn put_attachment(
+    Extension(IfMatch(if_match)): Extension<IfMatch>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path((db, item, name)): Path<(String, String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    // Such as `_design/name`, which isn't an attachment
+    if item.starts_with('_') {
+        return Err(not_found!());
+    }
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    let c = maybe_write_raw(
+        &state.couchdb_details,
+        &db,
+        Method::PUT,
+        body.clone(),
+        Some(&content_type),
+        &format!("{}/{}", item, name),
+        &params,
+    )
+    .await?;
+
+    if let Some(r) = c {
+        return Ok(r);
+    }
+
+    let rev = params
+        .get("rev")
+        .map(String::as_str)
+        .or(if_match.as_deref().map(etag_rev))
+        .map(str::to_string);
+
+    // Without a rev this creates the document, which conflicts when it already exists. With one,
+    // the attachment is added to the document as it is at that rev.
+    let mut document = match &rev {
+        None => json!({ "_id": item }),
+        Some(rev) => {
+            validate_rev(rev)?;
+            let read_concern = read_concern_for_request(&state, &params)?;
+            let conflict = || {
+                (
+                    StatusCode::CONFLICT,
+                    Json(json!({"error": "conflict", "reason": "Document update conflict."})),
+                )
+            };
+
+            let mut current =
+                get_item_from_db(state.clone(), db.clone(), item.clone(), read_concern)
+                    .await
+                    .map_err(|_| conflict())?;
+            if current.get_str("_rev").ok() != Some(rev.as_str()) {
+                return Err(conflict());
+            }
+            tombstones::strip(&mut current);
+            current.remove("_deleted");
+            json!(current)
+        }
+    };
+
+    let revpos = rev
+        .as_deref()
+        .and_then(|rev| rev.split('-').next())
+        .and_then(|n| n.parse::<u64>().ok())
+        .unwrap_or(0)
+        + 1;
+
+    document["_attachments"][&name] = json!({
+        "content_type": content_type,
+        "revpos": revpos,
+        "digest": format!(
+            "md5-{}",
+            base64::engine::general_purpose::STANDARD.encode(md5::compute(&body).0)
+        ),
+        "length": body.len(),
+        "data": base64::engine::general_purpose::STANDARD.encode(&body),
+    });
+
+    inner_new_item(db, Some(item), state, params, document, None).await
 }
 
+/// Writes a document. A write that conflicts is offered to the database's merge hook, and the
+/// document it returns is written in its place.
 pub async fn inner_new_item(
     db: String,
     item: Option<String>,
     state: Arc<AppState>,
-    _params: HashMap<String, String>,
+    params: HashMap<String, String>,
+    mut payload: Value,
+    mut rev_if_match: Option<String>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let mut merges = 0;
+    loop {
+        let result = write_item(
+            &db,
+            item.clone(),
+            &state,
+            &params,
+            payload.clone(),
+            rev_if_match.clone(),
+        )
+        .await;
+
+        let id = item
+            .as_deref()
+            .or(payload.get("_id").and_then(|id| id.as_str()));
+        match (result, id) {
+            (Err((StatusCode::CONFLICT, e)), Some(id)) if merges < MAX_MERGES => {
+                match merge_conflict(&state, &db, id, &payload).await? {
+                    Some(merged) => {
+                        metrics::increment_counter!("couchapi_merged_conflicts_total");
+                        payload = merged;
+                        rev_if_match = None;
+                        merges += 1;
+                    }
+                    None => return Err((StatusCode::CONFLICT, e)),
+                }
+            }
+            (result, _) => return result,
+        }
+    }
+}
+
+async fn write_item(
+    db: &str,
+    item: Option<String>,
+    state: &Arc<AppState>,
+    params: &HashMap<String, String>,
     payload: Value,
     rev_if_match: Option<String>,
 ) -> Result<Response, JsonWithStatusCodeResponse> {
+    let payload = transform(state, db, payload).await?;
+    check_schema(state, db, &payload)?;
+
+    let write_concern = write_concern_for_request(state, params)?;
+
     // Generate an id if one wasn't provided through either the URL or the payload
     let id = item.unwrap_or_else(|| match payload.get("_id").and_then(|id| id.as_str()) {
         Some(id) => id.to_string(),
-        None => {
-            let mut id = Uuid::new_v4().to_string();
-            id.retain(|c| c != '-');
-            id
-        }
+        None => state.uuids.next(),
     });
+    check_design_write(&id)?;
 
-    let existing_rev = match payload.get("_rev").and_then(|rev| rev.as_str()) {
-        Some(rev) => Some(rev.to_string()),
-        None => rev_if_match,
-    };
+    let payload_rev = payload.get("_rev").and_then(|rev| rev.as_str());
+    let if_match = rev_if_match.as_deref().map(etag_rev);
+
+    for rev in payload_rev.iter().chain(if_match.iter()) {
+        validate_rev(rev)?;
+    }
+
+    if let (Some(payload_rev), Some(if_match)) = (payload_rev, if_match) {
+        if payload_rev != if_match {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "bad_request",
+                    "reason": "Document rev and etag have different values."
+                })),
+            ));
+        }
+    }
+
+    // If-Match is a precondition, so the document has to exist at that rev. Without this check
+    // the upsert below would create a document that was never there.
+    if let Some(if_match) = if_match {
+        check_current_rev(state, db, &id, if_match).await?;
+    }
+
+    let existing_rev = payload_rev.or(if_match).map(str::to_string);
 
     // Calculate the new 'rev' using the same formula as CouchDB - which the MD5 of the payload
     let digest = md5::compute(payload.to_string());
@@ -133,24 +342,31 @@ pub async fn inner_new_item(
     );
 
     // This allows for the insert if one doesn't exist
-    let options = ReplaceOptions::builder().upsert(true).build();
+    let options = ReplaceOptions::builder()
+        .upsert(true)
+        .write_concern(write_concern)
+        .build();
 
     // Try and get the document in
     match state
         .db
-        .replace_one(db.as_str(), filter, new_bson_document.clone(), options)
+        .replace_one(db, filter, new_bson_document.clone(), options)
         .await
     {
         Ok(_) => (),
-        Err(_) => {
-            // Check for the conflict to return the right error message
-            return match check_conflict(state, db.clone(), &id).await {
-                Ok((status, json)) => Err((status, json)),
-                Err(e) => Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({"error": "internal server error", "details": e.to_string()})),
-                )),
-            };
+        // The filter didn't match, so the upsert tried to insert and found the id taken: the
+        // document exists at another rev, or another write got there first.
+        Err(e) if is_duplicate_key(&e) => {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(json!({"error": "conflict", "reason": "Document update conflict."})),
+            ));
+        }
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal server error", "details": e.to_string()})),
+            ));
         }
     };
 
@@ -164,3 +380,423 @@ pub async fn inner_new_item(
 
     Ok(response)
 }
+
+/// Reads a document from a request body. As in CouchDB, a POST to the database has to say the body
+/// is JSON while a PUT only has to send JSON, and either way it has to be an object. A body without
+/// a content type is taken to be JSON.
+fn document_body(
+    headers: &HeaderMap,
+    body: &[u8],
+    check_content_type: bool,
+) -> Result<Value, JsonWithStatusCodeResponse> {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|c| c.to_str().ok());
+    if check_content_type && content_type.is_some_and(|c| !c.starts_with("application/json")) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(json!({
+                "error": "bad_content_type",
+                "reason": "Content-Type must be application/json"
+            })),
+        ));
+    }
+
+    let bad_request = |reason: &str| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "bad_request", "reason": reason})),
+        )
+    };
+
+    match serde_json::from_slice::<Value>(body) {
+        Ok(document) if document.is_object() => Ok(document),
+        Ok(_) => Err(bad_request("Document must be a JSON object")),
+        Err(_) => Err(bad_request("invalid UTF-8 JSON")),
+    }
+}
+
+/// Applies a `?rev=` from the query string to the document, as CouchDB does for a PUT. It has to
+/// agree with the body's `_rev` when both are given; an `If-Match` is then checked against either.
+fn with_query_rev(
+    mut payload: Value,
+    query_rev: Option<&String>,
+) -> Result<Value, JsonWithStatusCodeResponse> {
+    let Some(query_rev) = query_rev else {
+        return Ok(payload);
+    };
+    validate_rev(query_rev)?;
+
+    match payload.get("_rev").and_then(|rev| rev.as_str()) {
+        Some(body_rev) if body_rev != query_rev => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "bad_request",
+                "reason": "Document rev from request body and query string have different values"
+            })),
+        )),
+        Some(_) => Ok(payload),
+        None => {
+            if let Some(document) = payload.as_object_mut() {
+                document.insert("_rev".to_string(), json!(query_rev));
+            }
+            Ok(payload)
+        }
+    }
+}
+
+/// Fails with CouchDB's `409` unless document `id` exists and is at `rev`.
+async fn check_current_rev(
+    state: &AppState,
+    db: &str,
+    id: &str,
+    rev: &str,
+) -> Result<(), JsonWithStatusCodeResponse> {
+    let document = state
+        .db
+        .find_one(db, id, FindOneOptions::default())
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": e.to_string()})),
+            )
+        })?;
+
+    match document {
+        Some(d) if d.get_str("_rev") == Ok(rev) => Ok(()),
+        _ => Err((
+            StatusCode::CONFLICT,
+            Json(json!({"error": "conflict", "reason": "Document update conflict."})),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{duplicate_key_error, MockDatabase};
+    use mongodb::error::Error as MongoError;
+
+    fn state(mock: MockDatabase) -> Arc<AppState> {
+        Arc::new(AppState::for_tests(mock))
+    }
+
+    fn stored_at(rev: &'static str) -> MockDatabase {
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one().returning(move |_, _, _| {
+            Box::pin(async move { Ok(Some(bson::doc! { "_id": "a", "_rev": rev })) })
+        });
+        mock
+    }
+
+    async fn put(
+        mock: MockDatabase,
+        payload: Value,
+        if_match: &str,
+    ) -> Result<Response, JsonWithStatusCodeResponse> {
+        inner_new_item(
+            "db".to_string(),
+            Some("a".to_string()),
+            state(mock),
+            HashMap::new(),
+            payload,
+            Some(if_match.to_string()),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_if_match_must_name_the_current_rev() {
+        // No write is expected, so the mock fails the test if one is attempted.
+        let result = put(stored_at("2-b"), json!({"n": 1}), "\"1-a\"").await;
+        assert_eq!(result.unwrap_err().0, StatusCode::CONFLICT);
+
+        let mut missing = MockDatabase::new();
+        missing
+            .expect_find_one()
+            .returning(|_, _, _| Box::pin(async { Ok(None) }));
+        let result = put(missing, json!({"n": 1}), "\"1-a\"").await;
+        assert_eq!(result.unwrap_err().0, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_if_match_writes_against_the_unquoted_rev() {
+        let mut mock = stored_at("1-a");
+        // UpdateResult can't be built outside of the driver, so the write fails once it's shown
+        // the rev it was given.
+        mock.expect_replace_one()
+            .withf(|_, filter, _, _| {
+                filter.get_document("_rev").unwrap().get_str("$eq") == Ok("1-a")
+            })
+            .times(1)
+            .returning(|_, _, _, _| Box::pin(async { Err(duplicate_key_error()) }));
+
+        let result = put(mock, json!({"n": 1}), "\"1-a\"").await;
+        assert_eq!(result.unwrap_err().0, StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn test_with_query_rev() {
+        let rev = "1-a".to_string();
+
+        assert_eq!(
+            with_query_rev(json!({"n": 1}), Some(&rev)).unwrap(),
+            json!({"n": 1, "_rev": "1-a"})
+        );
+        assert_eq!(
+            with_query_rev(json!({"_rev": "1-a"}), Some(&rev)).unwrap(),
+            json!({"_rev": "1-a"})
+        );
+        assert_eq!(
+            with_query_rev(json!({"n": 1}), None).unwrap(),
+            json!({"n": 1})
+        );
+
+        let (status, Json(body)) = with_query_rev(json!({"_rev": "2-b"}), Some(&rev)).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["reason"],
+            "Document rev from request body and query string have different values"
+        );
+
+        let bad = "nope".to_string();
+        assert_eq!(
+            with_query_rev(json!({}), Some(&bad)).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_errors() {
+        let mut mock = MockDatabase::new();
+        mock.expect_replace_one()
+            .withf(|_, filter, _, _| filter.get_str("_id") == Ok("taken"))
+            .returning(|_, _, _, _| Box::pin(async { Err(duplicate_key_error()) }));
+        mock.expect_replace_one().returning(|_, _, _, _| {
+            Box::pin(async { Err(MongoError::custom("connection reset")) })
+        });
+        let state = state(mock);
+
+        for (id, status) in [
+            ("taken", StatusCode::CONFLICT),
+            ("other", StatusCode::INTERNAL_SERVER_ERROR),
+        ] {
+            let result = inner_new_item(
+                "db".to_string(),
+                Some(id.to_string()),
+                state.clone(),
+                HashMap::new(),
+                json!({"n": 1}),
+                None,
+            )
+            .await;
+            assert_eq!(result.unwrap_err().0, status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_if_match_and_body_rev_must_agree() {
+        let result = put(
+            MockDatabase::new(),
+            json!({"_rev": "2-b", "n": 1}),
+            "\"1-a\"",
+        )
+        .await;
+
+        let (status, Json(body)) = result.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["reason"],
+            "Document rev and etag have different values."
+        );
+    }
+
+    #[test]
+    fn test_document_body() {
+        let json = HeaderMap::from_iter([(CONTENT_TYPE, "application/json".parse().unwrap())]);
+        let text = HeaderMap::from_iter([(CONTENT_TYPE, "text/plain".parse().unwrap())]);
+
+        assert_eq!(
+            document_body(&json, br#"{"n": 1}"#, true).unwrap(),
+            json!({"n": 1})
+        );
+        assert_eq!(
+            document_body(&HeaderMap::new(), br#"{"n": 1}"#, true).unwrap(),
+            json!({"n": 1})
+        );
+        assert_eq!(
+            document_body(&text, br#"{"n": 1}"#, false).unwrap(),
+            json!({"n": 1})
+        );
+
+        let (status, Json(body)) = document_body(&text, br#"{"n": 1}"#, true).unwrap_err();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body["error"], "bad_content_type");
+
+        let (status, Json(body)) = document_body(&text, b"hello", false).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["reason"], "invalid UTF-8 JSON");
+
+        let (status, Json(body)) = document_body(&json, b"[1, 2]", false).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["reason"], "Document must be a JSON object");
+    }
+
+    #[tokio::test]
+    async fn test_put_attachment() {
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one().returning(|_, _, _| {
+            Box::pin(async { Ok(Some(bson::doc! { "_id": "a", "_rev": "1-a", "n": 1 })) })
+        });
+        mock.expect_replace_one()
+            .withf(|_, filter, replacement, _| {
+                let attachment = replacement
+                    .get_document("_attachments")
+                    .unwrap()
+                    .get_document("note.txt")
+                    .unwrap();
+                filter.get_document("_rev").unwrap().get_str("$eq") == Ok("1-a")
+                    && replacement.get_i64("n") == Ok(1)
+                    && attachment.get_str("content_type") == Ok("text/plain")
+                    && attachment.get_str("data") == Ok("aGk=")
+                    && attachment.get_i64("revpos") == Ok(2)
+            })
+            .times(1)
+            .returning(|_, _, _, _| Box::pin(async { Err(duplicate_key_error()) }));
+
+        let text = HeaderMap::from_iter([(CONTENT_TYPE, "text/plain".parse().unwrap())]);
+        let attachment = |item: &str, rev: &str| {
+            (
+                Path(("db".to_string(), item.to_string(), "note.txt".to_string())),
+                Query(HashMap::from([("rev".to_string(), rev.to_string())])),
+            )
+        };
+        let state = state(mock);
+
+        let (path, query) = attachment("a", "1-a");
+        let result = put_attachment(
+            Extension(IfMatch(None)),
+            State(state.clone()),
+            query,
+            path,
+            text.clone(),
+            Bytes::from_static(b"hi"),
+        )
+        .await;
+        // The write is refused by the mock once it's been checked.
+        assert_eq!(result.unwrap_err().0, StatusCode::CONFLICT);
+
+        let (path, query) = attachment("a", "2-b");
+        let result = put_attachment(
+            Extension(IfMatch(None)),
+            State(state.clone()),
+            query,
+            path,
+            text.clone(),
+            Bytes::from_static(b"hi"),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().0, StatusCode::CONFLICT);
+
+        let (path, query) = attachment("_design", "1-a");
+        let result = put_attachment(
+            Extension(IfMatch(None)),
+            State(state),
+            query,
+            path,
+            text,
+            Bytes::from_static(b"hi"),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_conflicts_are_merged_by_the_hook() {
+        let folder =
+            std::env::temp_dir().join(format!("couchapi-updates-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(folder.join("carts")).unwrap();
+        std::fs::write(
+            folder.join("carts").join(crate::ops::merge::MERGE_HOOK),
+            "function(incoming, stored) { if (incoming.keep) return null; return {items: \
+             stored.items.concat(incoming.items)}; }",
+        )
+        .unwrap();
+
+        let written = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one().returning(|_, _, _| {
+            let stored = bson::doc! { "_id": "a", "_rev": "2-b", "items": ["x"] };
+            Box::pin(async move { Ok(Some(stored)) })
+        });
+        let w = written.clone();
+        mock.expect_replace_one()
+            .returning(move |_, filter, replacement, _| {
+                w.lock().unwrap().push((filter, replacement));
+                Box::pin(async { Err(duplicate_key_error()) })
+            });
+
+        let mut state = state(mock);
+        Arc::get_mut(&mut state).unwrap().updates_folder =
+            Some(folder.to_str().unwrap().to_string());
+
+        // The stored document keeps changing, so the merged write conflicts too until the hook
+        // has had its retries.
+        let result = inner_new_item(
+            "carts".to_string(),
+            Some("a".to_string()),
+            state.clone(),
+            HashMap::new(),
+            json!({"_rev": "1-a", "items": ["y"]}),
+            None,
+        )
+        .await;
+        assert_eq!(result.unwrap_err().0, StatusCode::CONFLICT);
+
+        let attempts = std::mem::take(&mut *written.lock().unwrap());
+        assert_eq!(attempts.len(), MAX_MERGES + 1);
+        let (filter, merged) = &attempts[1];
+        assert_eq!(
+            filter.get_document("_rev").unwrap().get_str("$eq"),
+            Ok("2-b")
+        );
+        assert_eq!(merged.get_array("items").unwrap().len(), 2);
+
+        // A hook returning null keeps the conflict.
+        let result = inner_new_item(
+            "carts".to_string(),
+            Some("a".to_string()),
+            state,
+            HashMap::new(),
+            json!({"_rev": "1-a", "keep": true}),
+            None,
+        )
+        .await;
+        assert_eq!(result.unwrap_err().0, StatusCode::CONFLICT);
+        assert_eq!(written.lock().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_documents_must_match_the_schema() {
+        // Nothing is written, so the mock fails the test if a write is attempted.
+        let mut state = state(MockDatabase::new());
+        let schema = crate::ops::schema::Schema::new(json!({"required": ["total"]})).unwrap();
+        Arc::get_mut(&mut state).unwrap().schemas =
+            Some(HashMap::from([("orders".to_string(), schema)]));
+
+        let (status, Json(body)) = inner_new_item(
+            "orders".to_string(),
+            Some("a".to_string()),
+            state,
+            HashMap::new(),
+            json!({"n": 1}),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "forbidden");
+        assert_eq!(body["errors"], json!(["/total: is required"]));
+    }
+}
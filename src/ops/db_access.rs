@@ -0,0 +1,180 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::state::AppState;
+use crate::tenancy::{current_tenant, with_tenant};
+use boa_engine::{
+    Context,
+    JsArgs,
+    JsError,
+    JsNativeError,
+    JsResult,
+    JsValue,
+    NativeFunction,
+    Source,
+};
+use boa_gc::{empty_trace, Finalize, Trace};
+use mongodb::options::FindOneOptions;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const GET_DOCUMENT: &str = "__couchapi_get_document";
+
+/// Looks up a document by id, giving `None` when there isn't one.
+pub type DocumentLookup = Arc<dyn Fn(&str) -> Result<Option<Value>, String> + Send + Sync>;
+
+/// Defines `db.get` on top of the native lookup. Only reads are offered.
+pub(crate) const DB: &str = r#"
+var db = (function (get) {
+    return Object.freeze({
+        get: function (id) {
+            return get(String(id));
+        }
+    });
+})(__couchapi_get_document);
+
+delete globalThis.__couchapi_get_document;
+"#;
+
+/// Carries the lookup into the native function.
+struct Lookup(DocumentLookup);
+
+impl Finalize for Lookup {}
+
+// SAFETY: a lookup holds nothing the garbage collector manages, so there is nothing to trace.
+unsafe impl Trace for Lookup {
+    empty_trace!();
+}
+
+/// Makes `db.get(id)` available to a script, returning the document or `null`.
+pub fn register_db(context: &mut Context<'_>, lookup: DocumentLookup) -> JsResult<()> {
+    let get = NativeFunction::from_copy_closure_with_captures(
+        |_, args, lookup: &Lookup, context| {
+            let id = args
+                .get_or_undefined(0)
+                .to_string(context)?
+                .to_std_string_escaped();
+
+            match (lookup.0)(&id) {
+                Ok(Some(document)) => JsValue::from_json(&document, context),
+                Ok(None) => Ok(JsValue::null()),
+                Err(e) => Err(JsError::from(JsNativeError::error().with_message(e))),
+            }
+        },
+        Lookup(lookup),
+    );
+
+    context.register_global_callable(GET_DOCUMENT, 1, get)?;
+    context.eval(Source::from_bytes(DB))?;
+
+    Ok(())
+}
+
+/// A lookup into database `db` for a script running on another thread. Each call blocks that
+/// thread on the runtime the request is running on, as the request's tenant.
+pub fn document_lookup(state: Arc<AppState>, db: String) -> DocumentLookup {
+    let handle = tokio::runtime::Handle::current();
+    let tenant = current_tenant();
+
+    Arc::new(move |id| {
+        let find = state.db.find_one(&db, id, FindOneOptions::default());
+        handle
+            .block_on(with_tenant(tenant.clone(), find))
+            .map(|document| document.map(|d| json!(d)))
+            .map_err(|e| e.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TenancySettings;
+    use crate::db::MockDatabase;
+    use crate::tenancy::TenantDatabase;
+
+    #[test]
+    fn test_register_db() {
+        let lookup: DocumentLookup = Arc::new(|id| match id {
+            "pricing" => Ok(Some(json!({"_id": "pricing", "rate": 0.2}))),
+            "broken" => Err("connection reset".to_string()),
+            _ => Ok(None),
+        });
+
+        let mut context = Context::default();
+        register_db(&mut context, lookup).unwrap();
+
+        let result = context
+            .eval(Source::from_bytes(
+                r#"[db.get("pricing").rate, db.get("missing"), typeof __couchapi_get_document]"#,
+            ))
+            .unwrap()
+            .to_json(&mut context)
+            .unwrap();
+        assert_eq!(result, json!([0.2, null, "undefined"]));
+
+        let error = context.eval(Source::from_bytes(r#"db.get("broken")"#));
+        assert!(error.unwrap_err().to_string().contains("connection reset"));
+    }
+
+    #[tokio::test]
+    async fn test_document_lookup() {
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one()
+            .withf(|coll, id, _| coll == "orders" && id == "pricing")
+            .returning(|_, _, _| {
+                Box::pin(async { Ok(Some(bson::doc! { "_id": "pricing", "rate": 0.2 })) })
+            });
+        mock.expect_find_one()
+            .returning(|_, _, _| Box::pin(async { Ok(None) }));
+
+        let state = Arc::new(AppState::for_tests(mock));
+        let lookup = document_lookup(state, "orders".to_string());
+
+        // Scripts call the lookup from a worker thread outside the runtime.
+        let result = tokio::task::spawn_blocking(move || (lookup("pricing"), lookup("other")))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            result.0.unwrap(),
+            Some(json!({"_id": "pricing", "rate": 0.2}))
+        );
+        assert_eq!(result.1.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_document_lookup_keeps_the_tenant() {
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one().returning(|coll, _, _| {
+            let found = bson::doc! { "coll": coll };
+            Box::pin(async move { Ok(Some(found)) })
+        });
+        let tenancy = TenancySettings {
+            separator: "_".to_string(),
+            databases: Default::default(),
+        };
+        let db = TenantDatabase::new(Box::new(mock), tenancy);
+        let state = Arc::new(AppState::for_tests(db));
+
+        let lookup = with_tenant(Some("acme".to_string()), async move {
+            document_lookup(state, "orders".to_string())
+        })
+        .await;
+        let result = tokio::task::spawn_blocking(move || lookup("pricing"))
+            .await
+            .unwrap();
+
+        assert_eq!(result.unwrap(), Some(json!({"coll": "acme_orders"})));
+    }
+}
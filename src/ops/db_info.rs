@@ -0,0 +1,181 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::auth::is_reserved_db_name;
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use bson::{Bson, Document};
+use futures_util::future::try_join_all;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// The most databases `_dbs_info` describes in one request, as in CouchDB.
+const MAX_DBS_INFO_KEYS: usize = 100;
+
+fn bad_request(reason: &str) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "bad_request", "reason": reason})),
+    )
+}
+
+/// The storage statistics of a database's collection, or `None` when it has none yet.
+async fn stats_for(
+    state: &AppState,
+    db: &str,
+) -> Result<Option<Document>, JsonWithStatusCodeResponse> {
+    state.db.collection_stats(db).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "internal server error", "details": e.to_string()})),
+        )
+    })
+}
+
+/// db_info describes a database from its collection's statistics. Databases are created by their
+/// first write, so one that hasn't been written to yet is described as empty rather than missing.
+pub async fn db_info(
+    State(state): State<Arc<AppState>>,
+    Path(db): Path<String>,
+) -> Result<Json<Value>, JsonWithStatusCodeResponse> {
+    let stats = stats_for(&state, &db).await?.unwrap_or_default();
+    Ok(Json(db_info_object(&db, &stats)))
+}
+
+/// dbs_info describes each of the databases named in `keys`, saving a `GET /:db` for each. Names
+/// with no collection behind them get `"error": "not_found"`, as in CouchDB.
+pub async fn dbs_info(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, JsonWithStatusCodeResponse> {
+    let keys = body
+        .get("keys")
+        .and_then(Value::as_array)
+        .ok_or_else(|| bad_request("`keys` member must exist."))?;
+    if keys.len() > MAX_DBS_INFO_KEYS {
+        return Err(bad_request(&format!(
+            "`keys` member must be less than or equal to {}",
+            MAX_DBS_INFO_KEYS
+        )));
+    }
+
+    let infos = try_join_all(keys.iter().map(|key| {
+        let state = &state;
+        async move {
+            let stats = match key.as_str() {
+                Some(db) if !is_reserved_db_name(db) => stats_for(state, db).await?,
+                _ => None,
+            };
+
+            Ok::<_, JsonWithStatusCodeResponse>(match (key.as_str(), stats) {
+                (Some(db), Some(stats)) => json!({ "key": db, "info": db_info_object(db, &stats) }),
+                _ => json!({ "key": key, "error": "not_found" }),
+            })
+        }
+    }))
+    .await?;
+
+    Ok(Json(json!(infos)))
+}
+
+/// A database's info in CouchDB's shape, with the counts and sizes of its collection.
+fn db_info_object(db: &str, stats: &Document) -> Value {
+    let number = |field: &str| match stats.get(field) {
+        Some(Bson::Int32(n)) => *n as i64,
+        Some(Bson::Int64(n)) => *n,
+        Some(Bson::Double(n)) => *n as i64,
+        _ => 0,
+    };
+    let file = number("storageSize") + number("totalIndexSize");
+    let active = number("size");
+
+    json!({
+        "db_name": db,
+        "doc_count": number("count"),
+        "doc_del_count": 0,
+        "update_seq": 0,
+        "purge_seq": 0,
+        "compact_running": false,
+        "sizes": { "file": file, "external": active, "active": active },
+        "disk_size": file,
+        "data_size": active,
+        "instance_start_time": "0"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+
+    fn state() -> Arc<AppState> {
+        let mut mock = MockDatabase::new();
+        mock.expect_collection_stats().returning(|coll| {
+            let stats = (coll == "orders").then(|| {
+                bson::doc! {
+                    "count": 3,
+                    "size": 2048_i64,
+                    "storageSize": 4096,
+                    "totalIndexSize": 1024.0,
+                }
+            });
+            Box::pin(async move { Ok(stats) })
+        });
+        Arc::new(AppState::for_tests(mock))
+    }
+
+    #[tokio::test]
+    async fn test_db_info() {
+        let Json(info) = db_info(State(state()), Path("orders".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(info["doc_count"], 3);
+        assert_eq!(
+            info["sizes"],
+            json!({"file": 5120, "external": 2048, "active": 2048})
+        );
+
+        // Not written to yet.
+        let Json(info) = db_info(State(state()), Path("fresh".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(info["db_name"], "fresh");
+        assert_eq!(info["doc_count"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_dbs_info() {
+        let body = json!({"keys": ["orders", "missing", "_couchapi_security", 7]});
+        let Json(infos) = dbs_info(State(state()), Json(body)).await.unwrap();
+
+        assert_eq!(infos[0]["key"], "orders");
+        assert_eq!(infos[0]["info"]["doc_count"], 3);
+        assert_eq!(infos[0]["info"]["disk_size"], 5120);
+        assert_eq!(infos[1], json!({"key": "missing", "error": "not_found"}));
+        assert_eq!(
+            infos[2],
+            json!({"key": "_couchapi_security", "error": "not_found"})
+        );
+        assert_eq!(infos[3], json!({"key": 7, "error": "not_found"}));
+
+        let keys: Vec<String> = (0..=MAX_DBS_INFO_KEYS).map(|n| n.to_string()).collect();
+        let (status, _) = dbs_info(State(state()), Json(json!({ "keys": keys })))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
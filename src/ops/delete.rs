@@ -12,9 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::common::IfMatch;
+use crate::auth::check_design_write;
+use crate::common::{etag_rev, IdempotencyKey, IfMatch};
+use crate::concern::write_concern_for_request;
 use crate::couchdb::maybe_write;
-use crate::ops::{check_conflict, JsonWithStatusCodeResponse};
+use crate::ops::idempotency::{
+    record_idempotent_write,
+    replay_idempotent_write,
+    request_fingerprint,
+};
+use crate::ops::{check_conflict, validate_rev, JsonWithStatusCodeResponse};
 use crate::state::AppState;
 use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
@@ -33,17 +40,37 @@ pub async fn inner_delete_item(
     params: HashMap<String, String>,
     if_match: Option<String>,
 ) -> Result<Response, JsonWithStatusCodeResponse> {
+    check_design_write(&item)?;
+    let if_match = if_match.as_deref().map(etag_rev);
+
+    if let (Some(rev), Some(if_match)) = (params.get("rev"), if_match) {
+        if rev != if_match {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": "bad_request",
+                    "reason": "Document rev and etag have different values."
+                })),
+            ));
+        }
+    }
+
     let existing_rev = match params.get("rev") {
         Some(rev) => Some(rev.to_string()),
-        None => if_match,
+        None => if_match.map(str::to_string),
     }
     .ok_or((
         StatusCode::PRECONDITION_FAILED,
         Json(json!({"error": "missing rev"})),
     ))?;
+    validate_rev(&existing_rev)?;
+
+    let write_concern = write_concern_for_request(&state, &params)?;
 
     let filter = bson::doc! { "_id": item.clone(), "_rev": &existing_rev };
-    let options = DeleteOptions::builder().build();
+    let options = DeleteOptions::builder()
+        .write_concern(write_concern)
+        .build();
     match state.db.delete_one(db.as_str(), filter, options).await {
         Ok(_) => (),
         Err(_) => {
@@ -62,6 +89,7 @@ pub async fn inner_delete_item(
 
 pub async fn delete_item(
     Extension(IfMatch(if_match)): Extension<IfMatch>,
+    Extension(IdempotencyKey(idempotency_key)): Extension<IdempotencyKey>,
     State(state): State<Arc<AppState>>,
     Query(params): Query<HashMap<String, String>>,
     Path((db, item)): Path<(String, String)>,
@@ -80,7 +108,14 @@ pub async fn delete_item(
         return Ok(r);
     }
 
-    inner_delete_item(state, db, item, params, if_match).await
+    let path = format!("{}/{}", db, item);
+    let fingerprint = request_fingerprint("DELETE", &path, &params, &[]);
+    if let Some(r) = replay_idempotent_write(&state, &db, &idempotency_key, &fingerprint).await? {
+        return Ok(r);
+    }
+
+    let response = inner_delete_item(state.clone(), db.clone(), item, params, if_match).await?;
+    record_idempotent_write(&state, &db, &idempotency_key, &fingerprint, response).await
 }
 
 #[cfg(test)]
@@ -99,12 +134,7 @@ mod tests {
         mock.expect_delete_one()
             .returning(|_, _, _| Box::pin(async { Ok(u64::try_from(1).unwrap()) }));
 
-        let app_state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let app_state = Arc::new(AppState::for_tests(mock));
 
         let db_name = "test_db".to_string();
         let item_id = "test_item".to_string();
@@ -123,7 +153,7 @@ mod tests {
                 let expected_json_body = json!({
                     "ok": true,
                     "id": item_id,
-                    "rev": "test_rev",
+                    "rev": "1-abc",
                 });
                 assert_json_eq!(actual_json_body, expected_json_body);
             }
@@ -137,18 +167,14 @@ mod tests {
     async fn test_delete_item_no_rev() {
         let mock = MockDatabase::new();
 
-        let app_state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let app_state = Arc::new(AppState::for_tests(mock));
 
         let db_name = "test_db".to_string();
         let item_id = "test_item".to_string();
 
         let result = delete_item(
             Extension(IfMatch(None)),
+            Extension(IdempotencyKey(None)),
             State(app_state),
             Query(HashMap::new()),
             Path((db_name, item_id.clone())),
@@ -168,6 +194,49 @@ mod tests {
         };
     }
 
+    #[tokio::test]
+    async fn test_delete_item_invalid_rev() {
+        // No delete is expected, so the mock fails the test if one is attempted.
+        let app_state = Arc::new(AppState::for_tests(MockDatabase::new()));
+
+        let result = delete_item(
+            Extension(IfMatch(Some("\"{\"$ne\": null}\"".to_string()))),
+            Extension(IdempotencyKey(None)),
+            State(app_state),
+            Query(HashMap::new()),
+            Path(("test_db".to_string(), "test_item".to_string())),
+        )
+        .await;
+
+        let (status_code, Json(body)) = result.unwrap_err();
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert_json_eq!(
+            body,
+            json!({"error": "bad_request", "reason": "Invalid rev format"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_item_rev_and_if_match_must_agree() {
+        let app_state = Arc::new(AppState::for_tests(MockDatabase::new()));
+
+        let result = delete_item(
+            Extension(IfMatch(Some("\"2-def\"".to_string()))),
+            Extension(IdempotencyKey(None)),
+            State(app_state),
+            Query(HashMap::from([("rev".to_string(), "1-abc".to_string())])),
+            Path(("test_db".to_string(), "test_item".to_string())),
+        )
+        .await;
+
+        let (status_code, Json(body)) = result.unwrap_err();
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["reason"],
+            "Document rev and etag have different values."
+        );
+    }
+
     #[tokio::test]
     async fn test_delete_item_error() {
         let mut mock = MockDatabase::new();
@@ -176,14 +245,9 @@ mod tests {
             .returning(|_, _, _| Box::pin(async { Err(mongodb::error::Error::custom("nothing")) }));
 
         mock.expect_find_one()
-            .returning(|_, _| Box::pin(async { Err(mongodb::error::Error::custom("nothing")) }));
+            .returning(|_, _, _| Box::pin(async { Err(mongodb::error::Error::custom("nothing")) }));
 
-        let app_state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let app_state = Arc::new(AppState::for_tests(mock));
 
         let db_name = "test_db".to_string();
         let item_id = "test_item".to_string();
@@ -210,16 +274,11 @@ mod tests {
         mock.expect_delete_one()
             .returning(|_, _, _| Box::pin(async { Err(mongodb::error::Error::custom("nothing")) }));
 
-        mock.expect_find_one().returning(|_, _| {
-            Box::pin(async { Ok(Some(doc! { "_id": "test_item", "_rev": "test_rev" })) })
+        mock.expect_find_one().returning(|_, _, _| {
+            Box::pin(async { Ok(Some(doc! { "_id": "test_item", "_rev": "1-abc" })) })
         });
 
-        let app_state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let app_state = Arc::new(AppState::for_tests(mock));
 
         let db_name = "test_db".to_string();
         let item_id = "test_item".to_string();
@@ -246,10 +305,11 @@ mod tests {
     ) -> Result<Response, JsonWithStatusCodeResponse> {
         delete_item(
             Extension(IfMatch(None)),
+            Extension(IdempotencyKey(None)),
             State(app_state),
             Query({
                 let mut map = HashMap::new();
-                map.insert("rev".to_string(), "test_rev".to_string());
+                map.insert("rev".to_string(), "1-abc".to_string());
                 map
             }),
             Path((db_name, item_id.clone())),
@@ -0,0 +1,327 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs scripts on V8, through deno_core, for workloads where Boa is too slow or its ECMAScript
+//! support falls short. Scripts see the same `require`, `db.get` and CouchDB helpers as on Boa.
+
+use crate::config::ScriptSettings;
+use crate::ops::builtins::BUILTINS;
+use crate::ops::db_access::{DocumentLookup, DB};
+use crate::ops::get::ViewOptions;
+use crate::ops::require::{load_module, REQUIRE};
+use crate::ops::script_engine::ScriptEngine;
+use crate::ops::script_pool::ScriptPool;
+use crate::ops::{script_stack_limit, script_timeout, JsonWithStatusCodeResponse};
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use axum::Json;
+use bson::Document;
+use deno_core::anyhow::{anyhow, Error};
+use deno_core::{extension, op2, serde_v8, v8, JsRuntime, OpState, RuntimeOptions};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::info;
+
+/// The natives `BUILTINS`, `REQUIRE` and `DB` expect, each defined just before the one that
+/// takes it away again.
+const LOG: &str = "globalThis.__couchapi_log = (m) => Deno.core.ops.op_couchapi_log(m);";
+const LOAD_MODULE: &str =
+    "globalThis.__couchapi_load_module = (n) => Deno.core.ops.op_couchapi_load_module(n);";
+const GET_DOCUMENT: &str =
+    "globalThis.__couchapi_get_document = (id) => Deno.core.ops.op_couchapi_get_document(id);";
+
+/// The folder `require` loads modules from.
+struct LibFolder(PathBuf);
+
+/// Where `db.get` looks documents up.
+struct Lookup(DocumentLookup);
+
+#[op2(fast)]
+fn op_couchapi_log(#[string] message: &str) {
+    info!(message = message, "script log");
+}
+
+#[op2]
+#[string]
+fn op_couchapi_load_module(state: &mut OpState, #[string] name: &str) -> Result<String, Error> {
+    let LibFolder(folder) = state.borrow::<LibFolder>();
+    load_module(folder, name).map_err(|e| anyhow!(e))
+}
+
+#[op2]
+#[serde]
+fn op_couchapi_get_document(
+    state: &mut OpState,
+    #[string] id: &str,
+) -> Result<Option<Value>, Error> {
+    let Lookup(lookup) = state.borrow::<Lookup>();
+    lookup(id).map_err(|e| anyhow!(e))
+}
+
+extension!(
+    couchapi_scripts,
+    ops = [
+        op_couchapi_log,
+        op_couchapi_load_module,
+        op_couchapi_get_document
+    ]
+);
+
+/// Runs scripts on V8. Scripts share the threads, queue and timeout of the Boa engine's
+/// `ScriptPool`, but each gets a V8 runtime of its own. V8 can interrupt a script, so one still
+/// running at `timeout_ms` is ended rather than left to hit `loop_iteration_limit`, which, like
+/// `recursion_limit` and `stack_size_limit`, only applies to Boa. V8's own stack limit fails a
+/// script that recurses too deeply with `script_stack_limit`.
+pub struct DenoEngine {
+    pool: ScriptPool,
+    timeout: Duration,
+}
+
+impl DenoEngine {
+    pub fn new(settings: &ScriptSettings) -> Self {
+        DenoEngine {
+            pool: ScriptPool::new(settings),
+            timeout: Duration::from_millis(settings.timeout_ms),
+        }
+    }
+}
+
+#[async_trait]
+impl ScriptEngine for DenoEngine {
+    async fn run_update(
+        &self,
+        source: Arc<str>,
+        doc: Option<Value>,
+        req: Value,
+        lib_folder: PathBuf,
+        lookup: DocumentLookup,
+    ) -> Result<Value, JsonWithStatusCodeResponse> {
+        let timeout = self.timeout;
+
+        self.pool
+            .run(move |_| {
+                let mut runtime = new_runtime(Some(lib_folder), Some(lookup)).map_err(js_error)?;
+                let script = format!(
+                    "var doc = {};\nvar req = {};\nf = {}\n\nJSON.stringify(f(doc, req))",
+                    doc.unwrap_or(Value::Null),
+                    req,
+                    source
+                );
+
+                run_to_json(&mut runtime, script, timeout)
+            })
+            .await
+    }
+
+    async fn run_view_script(
+        &self,
+        source: Arc<str>,
+        view_options: ViewOptions,
+        lib_folder: Option<PathBuf>,
+    ) -> Result<Vec<Document>, JsonWithStatusCodeResponse> {
+        let timeout = self.timeout;
+
+        self.pool
+            .run(move |_| {
+                let view_options = serde_json::to_value(&view_options).map_err(|e| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({"error": e.to_string()})),
+                    )
+                })?;
+
+                let mut runtime = new_runtime(lib_folder, None).map_err(js_error)?;
+                let script = format!(
+                    "var view_options = {};\n{}\n\nJSON.stringify(result)",
+                    view_options, source
+                );
+
+                let Value::Array(pipeline) = run_to_json(&mut runtime, script, timeout)? else {
+                    return Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({"error": "return value is not an array"})),
+                    ));
+                };
+
+                pipeline
+                    .iter()
+                    .map(|stage| {
+                        bson::to_document(stage).map_err(|e| {
+                            (
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                                Json(json!({"error": e.to_string()})),
+                            )
+                        })
+                    })
+                    .collect()
+            })
+            .await
+    }
+}
+
+/// A fresh runtime for one script, with CouchDB's helpers, `require` when there's a folder to load
+/// modules from and `db.get` when there's a database to look in.
+fn new_runtime(
+    lib_folder: Option<PathBuf>,
+    lookup: Option<DocumentLookup>,
+) -> Result<JsRuntime, Error> {
+    let mut runtime = JsRuntime::new(RuntimeOptions {
+        extensions: vec![couchapi_scripts::init_ops()],
+        ..Default::default()
+    });
+
+    runtime.execute_script("builtins", format!("{}\n{}", LOG, BUILTINS))?;
+
+    if let Some(lib_folder) = lib_folder {
+        runtime.op_state().borrow_mut().put(LibFolder(lib_folder));
+        runtime.execute_script("require", format!("{}\n{}", LOAD_MODULE, REQUIRE))?;
+    }
+
+    if let Some(lookup) = lookup {
+        runtime.op_state().borrow_mut().put(Lookup(lookup));
+        runtime.execute_script("db", format!("{}\n{}", GET_DOCUMENT, DB))?;
+    }
+
+    Ok(runtime)
+}
+
+/// Runs a script that ends in a `JSON.stringify`, returning what it stringified, or `null` when
+/// that was `undefined`. A script still running after `timeout` is ended.
+fn run_to_json(
+    runtime: &mut JsRuntime,
+    script: String,
+    timeout: Duration,
+) -> Result<Value, JsonWithStatusCodeResponse> {
+    let isolate = runtime.v8_isolate().thread_safe_handle();
+    let (finished, finishing) = channel::<()>();
+    let watchdog = thread::spawn(move || {
+        let timed_out = finishing.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout);
+        if timed_out {
+            isolate.terminate_execution();
+        }
+        timed_out
+    });
+
+    let result = runtime.execute_script("script", script);
+    let _ = finished.send(());
+    if watchdog.join().unwrap_or(false) {
+        return Err(script_timeout());
+    }
+
+    let result = result.map_err(js_error)?;
+    let scope = &mut runtime.handle_scope();
+    let local = v8::Local::new(scope, result);
+    let json = serde_v8::from_v8::<Option<String>>(scope, local).map_err(|e| js_error(e.into()))?;
+
+    match json {
+        Some(json) => serde_json::from_str(&json).map_err(|e| js_error(e.into())),
+        None => Ok(Value::Null),
+    }
+}
+
+/// Converts an error raised while running a script. One that overflowed V8's stack is reported
+/// as a stack limit, as on Boa.
+fn js_error(e: Error) -> JsonWithStatusCodeResponse {
+    let message = e.to_string();
+    if message.contains("Maximum call stack size exceeded") {
+        return script_stack_limit(&message);
+    }
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": message})),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::is_script_timeout;
+
+    fn engine(timeout_ms: u64) -> DenoEngine {
+        DenoEngine::new(&ScriptSettings {
+            timeout_ms,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_deno_engine() {
+        let update = engine(5000)
+            .run_update(
+                "function(doc, req) { return [doc, {body: req.method + db.get('b').n}]; }".into(),
+                Some(json!({"_id": "a"})),
+                json!({"method": "PUT"}),
+                std::env::temp_dir(),
+                Arc::new(|_| Ok(Some(json!({"n": 1})))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(update, json!([{"_id": "a"}, {"body": "PUT1"}]));
+
+        let view_options = ViewOptions {
+            reduce: false,
+            group: false,
+            group_level: 0,
+            include_docs: false,
+            attachments: false,
+            descending: false,
+            limit: Some(5),
+            skip: 0,
+            start_key: vec![],
+            end_key: vec![],
+            startkey_docid: None,
+            endkey_docid: None,
+            keys: vec![],
+        };
+        let pipeline = engine(5000)
+            .run_view_script(
+                "var result = [{ $limit: view_options.limit }];".into(),
+                view_options,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(pipeline, vec![bson::doc! { "$limit": 5 }]);
+    }
+
+    #[tokio::test]
+    async fn test_deno_engine_limits() {
+        let run = |source: &str, timeout_ms| {
+            let source: Arc<str> = source.into();
+            async move {
+                engine(timeout_ms)
+                    .run_update(
+                        source,
+                        None,
+                        json!({}),
+                        std::env::temp_dir(),
+                        Arc::new(|_| Ok(None)),
+                    )
+                    .await
+                    .unwrap_err()
+            }
+        };
+
+        let e = run("function(doc, req) { while (true) {} }", 50).await;
+        assert!(is_script_timeout(&e));
+
+        let e = run("function f(doc, req) { return f(doc, req); }", 5000).await;
+        assert_eq!(e.1["error"], "script_stack_limit");
+    }
+}
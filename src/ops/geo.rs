@@ -0,0 +1,476 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::access_log::DocsReturned;
+use crate::concern::read_concern_for_request;
+use crate::config::GeoIndex;
+use crate::couchdb::read_through;
+use crate::not_found;
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::request_timeout::remaining_time;
+use crate::state::AppState;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use bson::{doc, Bson, Document};
+use mongodb::options::AggregateOptions;
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Rows returned when the request doesn't give a `limit`.
+const DEFAULT_LIMIT: i64 = 25;
+
+/// The largest `limit` allowed, as for search.
+const MAX_LIMIT: i64 = 200;
+
+/// Where `$geoNear` puts each result's distance while the results are sorted.
+const DISTANCE_FIELD: &str = "_couchapi_distance";
+
+fn bad_request(reason: &str) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "bad_request", "reason": reason})),
+    )
+}
+
+/// Where to look: inside a bounding box, near a point, or both.
+#[derive(Debug, PartialEq)]
+struct GeoQuery {
+    bbox: Option<[f64; 4]>,
+    near: Option<(f64, f64)>,
+    radius: Option<f64>,
+}
+
+fn parse_coordinate(
+    value: &str,
+    name: &str,
+    bound: f64,
+) -> Result<f64, JsonWithStatusCodeResponse> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite() && v.abs() <= bound)
+        .ok_or_else(|| bad_request(&format!("Invalid {}", name)))
+}
+
+/// Parses `bbox=west,south,east,north` and `lat`, `lon` and `radius` (in metres).
+fn parse_query(params: &HashMap<String, String>) -> Result<GeoQuery, JsonWithStatusCodeResponse> {
+    let bbox = match params.get("bbox") {
+        Some(bbox) => {
+            let parts: Vec<&str> = bbox.split(',').collect();
+            let [west, south, east, north] = parts[..] else {
+                return Err(bad_request("bbox must be west,south,east,north"));
+            };
+            let bbox = [
+                parse_coordinate(west, "bbox", 180.0)?,
+                parse_coordinate(south, "bbox", 90.0)?,
+                parse_coordinate(east, "bbox", 180.0)?,
+                parse_coordinate(north, "bbox", 90.0)?,
+            ];
+            if bbox[0] > bbox[2] || bbox[1] > bbox[3] {
+                return Err(bad_request("bbox must be west,south,east,north"));
+            }
+            Some(bbox)
+        }
+        None => None,
+    };
+
+    let near = match (params.get("lat"), params.get("lon")) {
+        (Some(lat), Some(lon)) => Some((
+            parse_coordinate(lon, "lon", 180.0)?,
+            parse_coordinate(lat, "lat", 90.0)?,
+        )),
+        (None, None) => None,
+        _ => return Err(bad_request("lat and lon must be given together")),
+    };
+
+    let radius = match params.get("radius") {
+        Some(_) if near.is_none() => return Err(bad_request("radius needs lat and lon")),
+        Some(radius) => Some(
+            radius
+                .parse::<f64>()
+                .ok()
+                .filter(|r| r.is_finite() && *r >= 0.0)
+                .ok_or_else(|| bad_request("Invalid radius"))?,
+        ),
+        None => None,
+    };
+
+    if bbox.is_none() && near.is_none() {
+        return Err(bad_request("bbox, or lat and lon, are required"));
+    }
+
+    Ok(GeoQuery { bbox, near, radius })
+}
+
+/// The bounding box as a GeoJSON polygon, so it's measured on the sphere like the index.
+fn bbox_polygon([west, south, east, north]: [f64; 4]) -> Document {
+    doc! {
+        "type": "Polygon",
+        "coordinates": [[
+            [west, south], [east, south], [east, north], [west, north], [west, south]
+        ]],
+    }
+}
+
+/// Near queries are ordered by distance, bounding boxes by `_id`.
+fn geo_pipeline(index: &GeoIndex, query: &GeoQuery, skip: i64, limit: i64) -> Vec<Document> {
+    let within = query.bbox.map(|bbox| {
+        doc! { &index.geometry_field: { "$geoIntersects": { "$geometry": bbox_polygon(bbox) } } }
+    });
+
+    let mut pipeline = match query.near {
+        Some((lon, lat)) => {
+            let mut near = doc! {
+                "near": { "type": "Point", "coordinates": [lon, lat] },
+                "distanceField": DISTANCE_FIELD,
+                "key": &index.geometry_field,
+                "spherical": true,
+            };
+            if let Some(radius) = query.radius {
+                near.insert("maxDistance", radius);
+            }
+            if let Some(within) = within {
+                near.insert("query", within);
+            }
+            vec![doc! { "$geoNear": near }]
+        }
+        None => vec![
+            doc! { "$match": within.unwrap_or_default() },
+            doc! { "$sort": { "_id": 1 } },
+        ],
+    };
+
+    pipeline.push(doc! { "$skip": skip });
+    pipeline.push(doc! { "$limit": limit });
+    pipeline
+}
+
+/// A result as a GeoCouch row. One value field is the `value` itself; several make an object.
+fn geo_row(index: &GeoIndex, mut document: Document, include_docs: bool) -> Value {
+    let distance = document.remove(DISTANCE_FIELD);
+
+    let value = match index.value_fields.as_slice() {
+        [] => Value::Null,
+        [field] => json!(document.get(field).unwrap_or(&Bson::Null)),
+        fields => Value::Object(
+            fields
+                .iter()
+                .map(|field| {
+                    let value = document.get(field).unwrap_or(&Bson::Null);
+                    (field.clone(), json!(value))
+                })
+                .collect(),
+        ),
+    };
+
+    let mut row = json!({
+        "id": json!(document.get("_id").unwrap_or(&Bson::Null)),
+        "geometry": json!(document.get(&index.geometry_field).unwrap_or(&Bson::Null)),
+        "value": value,
+    });
+    if let Some(distance) = distance {
+        row["distance"] = json!(distance);
+    }
+    if include_docs {
+        row["doc"] = json!(document);
+    }
+    row
+}
+
+async fn inner_geo(
+    state: &AppState,
+    db: String,
+    index: &GeoIndex,
+    params: HashMap<String, String>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let read_concern = read_concern_for_request(state, &params)?;
+    let query = parse_query(&params)?;
+
+    let limit = match params.get("limit") {
+        Some(limit) => limit
+            .parse::<i64>()
+            .ok()
+            .filter(|limit| *limit > 0)
+            .ok_or_else(|| bad_request("Invalid limit"))?,
+        None => DEFAULT_LIMIT,
+    };
+    if limit > MAX_LIMIT {
+        return Err(bad_request(&format!("limit must be at most {}", MAX_LIMIT)));
+    }
+    let skip = match params.get("skip") {
+        Some(skip) => skip
+            .parse::<i64>()
+            .ok()
+            .filter(|skip| *skip >= 0)
+            .ok_or_else(|| bad_request("Invalid skip"))?,
+        None => 0,
+    };
+    let include_docs = params.get("include_docs").is_some_and(|i| i == "true");
+
+    let pipeline = geo_pipeline(index, &query, skip, limit);
+    let options = AggregateOptions::builder()
+        .read_concern(read_concern)
+        .max_time(remaining_time())
+        .build();
+
+    let permit = match &state.view_limits {
+        Some(view_limits) => view_limits.acquire(&db).await?,
+        None => None,
+    };
+    let results = state.db.aggregate(&db, pipeline, options).await;
+    drop(permit);
+
+    let Ok(results) = results else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "geo_query_failed"})),
+        ));
+    };
+
+    let rows: Vec<Value> = results
+        .iter()
+        .filter_map(|result| result.to_document().ok())
+        .map(|document| geo_row(index, document, include_docs))
+        .collect();
+
+    let returned = rows.len();
+    let mut response = Json(json!({ "rows": rows })).into_response();
+    response.extensions_mut().insert(DocsReturned(returned));
+
+    Ok(response)
+}
+
+fn geo_index<'a>(state: &'a AppState, db: &str, design: &str, index: &str) -> Option<&'a GeoIndex> {
+    state.geo_indexes.as_ref()?.get(db)?.get(design)?.get(index)
+}
+
+async fn geo_or_read_through(
+    state: &AppState,
+    (db, design, index): (String, String, String),
+    params: HashMap<String, String>,
+    endpoint: &str,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    if let Some(geo_index) = geo_index(state, &db, &design, &index) {
+        return inner_geo(state, db, geo_index, params).await;
+    }
+
+    match state
+        .couchdb_details
+        .as_ref()
+        .filter(|c| c.should_read_through(&db))
+    {
+        Some(couchdb_details) => {
+            let mapped_db = couchdb_details.map_for_db(db.as_str());
+            let path = format!("{}/_design/{}/{}/{}", mapped_db, design, endpoint, index);
+            read_through(couchdb_details, Method::GET, None, &path, &params).await
+        }
+        None => Err(not_found!()),
+    }
+}
+
+pub async fn geo(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<(String, String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    geo_or_read_through(&state, path, params, "_geo").await
+}
+
+/// GeoCouch's name for the same query.
+pub async fn spatial(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<(String, String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    geo_or_read_through(&state, path, params, "_spatial").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use bson::RawDocumentBuf;
+    use http_body_util::BodyExt;
+    use maplit::hashmap;
+
+    fn index(value_fields: &[&str]) -> GeoIndex {
+        GeoIndex {
+            geometry_field: "location".to_string(),
+            value_fields: value_fields.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(
+            parse_query(&params(&[("bbox", "-0.5,51.3,0.3,51.7")])).unwrap(),
+            GeoQuery {
+                bbox: Some([-0.5, 51.3, 0.3, 51.7]),
+                near: None,
+                radius: None,
+            }
+        );
+        assert_eq!(
+            parse_query(&params(&[
+                ("lat", "51.5"),
+                ("lon", "-0.12"),
+                ("radius", "500")
+            ]))
+            .unwrap(),
+            GeoQuery {
+                bbox: None,
+                near: Some((-0.12, 51.5)),
+                radius: Some(500.0),
+            }
+        );
+
+        for invalid in [
+            params(&[]),
+            params(&[("bbox", "1,2,3")]),
+            params(&[("bbox", "0.3,51.3,-0.5,51.7")]),
+            params(&[("bbox", "-0.5,91,0.3,92")]),
+            params(&[("lat", "51.5")]),
+            params(&[("lat", "51.5"), ("lon", "200")]),
+            params(&[("radius", "500"), ("bbox", "-0.5,51.3,0.3,51.7")]),
+            params(&[("lat", "51.5"), ("lon", "-0.12"), ("radius", "-1")]),
+        ] {
+            let (status, _) = parse_query(&invalid).unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_geo_pipeline() {
+        let bbox = GeoQuery {
+            bbox: Some([-0.5, 51.3, 0.3, 51.7]),
+            near: None,
+            radius: None,
+        };
+        assert_eq!(
+            geo_pipeline(&index(&[]), &bbox, 0, 10),
+            vec![
+                doc! { "$match": { "location": { "$geoIntersects": { "$geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[
+                        [-0.5, 51.3], [0.3, 51.3], [0.3, 51.7], [-0.5, 51.7], [-0.5, 51.3]
+                    ]],
+                } } } } },
+                doc! { "$sort": { "_id": 1 } },
+                doc! { "$skip": 0i64 },
+                doc! { "$limit": 10i64 },
+            ]
+        );
+
+        let near = GeoQuery {
+            bbox: None,
+            near: Some((-0.12, 51.5)),
+            radius: Some(500.0),
+        };
+        assert_eq!(
+            geo_pipeline(&index(&[]), &near, 5, 10),
+            vec![
+                doc! { "$geoNear": {
+                    "near": { "type": "Point", "coordinates": [-0.12, 51.5] },
+                    "distanceField": DISTANCE_FIELD,
+                    "key": "location",
+                    "spherical": true,
+                    "maxDistance": 500.0,
+                } },
+                doc! { "$skip": 5i64 },
+                doc! { "$limit": 10i64 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_geo_row() {
+        let document = doc! {
+            "_id": "shop1",
+            "name": "Soho",
+            "city": "London",
+            "location": { "type": "Point", "coordinates": [-0.13, 51.51] },
+            DISTANCE_FIELD: 120.5,
+        };
+
+        assert_eq!(
+            geo_row(&index(&["name"]), document.clone(), false),
+            json!({
+                "id": "shop1",
+                "geometry": { "type": "Point", "coordinates": [-0.13, 51.51] },
+                "value": "Soho",
+                "distance": 120.5,
+            })
+        );
+
+        let row = geo_row(&index(&["name", "city"]), document, true);
+        assert_eq!(row["value"], json!({"name": "Soho", "city": "London"}));
+        assert_eq!(row["doc"]["_id"], "shop1");
+        assert!(row["doc"].get(DISTANCE_FIELD).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_inner_geo() {
+        let mut mock = MockDatabase::new();
+        mock.expect_aggregate()
+            .times(1)
+            .returning(|coll, pipeline, _| {
+                assert_eq!(coll, "shops");
+                assert!(pipeline[0].contains_key("$geoNear"));
+
+                let result = vec![RawDocumentBuf::from_document(&doc! {
+                    "_id": "shop1",
+                    "name": "Soho",
+                    "location": { "type": "Point", "coordinates": [-0.13, 51.51] },
+                    DISTANCE_FIELD: 120.5,
+                })
+                .unwrap()];
+                Box::pin(async move { Ok(result) })
+            });
+
+        let state = AppState::for_tests(mock);
+
+        let params = hashmap! {
+            "lat".to_string() => "51.5".to_string(),
+            "lon".to_string() => "-0.12".to_string(),
+        };
+        let response = inner_geo(&state, "shops".to_string(), &index(&["name"]), params)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({"rows": [{
+                "id": "shop1",
+                "geometry": { "type": "Point", "coordinates": [-0.13, 51.51] },
+                "value": "Soho",
+                "distance": 120.5,
+            }]})
+        );
+    }
+}
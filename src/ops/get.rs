@@ -12,27 +12,45 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::common::IfNoneMatch;
+use crate::access_log::DocsReturned;
+use crate::auth::forbidden;
+use crate::common::{if_none_match_matches, IfNoneMatch};
+use crate::concern::read_concern_for_request;
 use crate::config::DesignView;
 use crate::couchdb::read_through;
+use crate::couchdb::stale::read_through_view;
+use crate::metrics::{record_script_execution, record_view_result};
 use crate::not_found;
 use crate::ops::get_js::execute_script;
-use crate::ops::{get_item_from_db, JsonWithStatusCodeResponse};
+use crate::ops::view_rows::{ViewResponse, ViewRow};
+use crate::ops::{
+    get_item_from_db,
+    is_script_timeout,
+    stub_attachments,
+    validate_rev,
+    JsonWithStatusCodeResponse,
+};
+use crate::request_timeout::remaining_time;
 use crate::state::AppState;
+use crate::tombstones;
+use axum::body::Bytes;
 use axum::extract::{Path, Query, State};
-use axum::http::StatusCode;
+use axum::http::header::{self, ETAG};
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::{Extension, Json};
-use boa_gc::Finalize;
 use bson::{doc, Bson, Document};
 use http_body_util::BodyExt;
 use indexmap::IndexMap;
 use maplit::hashmap;
+use mongodb::options::{AggregateOptions, FindOneOptions};
 use reqwest::Method;
 use serde_derive::Serialize;
 use serde_json::{json, Value};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 use tracing::info;
 
 /// Create a DesignView that will return all documents in the database
@@ -59,6 +77,9 @@ pub fn create_all_docs_design_view() -> DesignView {
         single_item_value_is_dict: true,
         break_glass_js_script: None,
         omit_null_keys_in_value: false,
+        doc_fields: vec![],
+        value_field_names: Default::default(),
+        omit_id: false,
     }
 }
 
@@ -68,54 +89,76 @@ pub async fn get_item(
     Query(params): Query<HashMap<String, String>>,
     Path((db, item)): Path<(String, String)>,
 ) -> Result<Response, JsonWithStatusCodeResponse> {
-    let document = get_item_from_db(state, db, item).await?;
+    if let Some(rev) = params.get("rev") {
+        validate_rev(rev)?;
+    }
 
-    // Emulate https://datatracker.ietf.org/doc/html/rfc7232#section-3.2
-    if if_none_match.is_some() {
-        return if if_none_match.as_ref().unwrap() == document.get_str("_rev").unwrap() {
-            Err((StatusCode::NOT_MODIFIED, Json(json!({}))))
-        } else {
-            let mut r = Response::default();
-            *r.status_mut() = StatusCode::PRECONDITION_FAILED;
-            Ok(r)
-        };
+    // A database being soft launched has only some of its documents read from MongoDB.
+    if let Some(couchdb_details) = state
+        .couchdb_details
+        .as_ref()
+        .filter(|c| c.soft_launch.as_ref().is_some_and(|s| s.contains_key(&db)))
+    {
+        let from_mongo = couchdb_details.reads_from_mongo(&db, &item);
+        let source = if from_mongo { "mongodb" } else { "couchdb" };
+        metrics::increment_counter!("couchapi_soft_launch_reads_total", "db" => db.clone(), "source" => source);
+
+        if !from_mongo {
+            let path = format!("{}/{}", couchdb_details.map_for_db(&db), item);
+            return read_through(couchdb_details, Method::GET, None, &path, &params).await;
+        }
     }
 
+    let read_concern = read_concern_for_request(&state, &params)?;
+    let mut document = get_item_from_db(state, db, item, read_concern).await?;
+    tombstones::strip(&mut document);
+
     // Forces retrieving latest "leaf" revision, no matter what rev was requested. Default is false
     let latest = params
         .get("latest")
         .map(|b| b.as_str() == "true")
         .unwrap_or(false);
 
-    // Forces the use of the rev parameter to match the document revision but only if latest is
-    // false
-    let rev = match params.get("rev") {
-        Some(rev) => {
-            if !latest && rev.as_str() != document.get_str("_rev").unwrap() {
-                return Err(not_found!());
-            }
-            Some(rev)
+    // Only the current revision is kept, so it's the leaf `latest` resolves to, and any other rev
+    // is missing. A deleted document is still returned when its rev is asked for, as in CouchDB.
+    match params.get("rev") {
+        Some(rev) if !latest && document.get_str("_rev").ok() != Some(rev.as_str()) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "not_found", "reason": "missing"})),
+            ));
         }
+        Some(_) => {}
         None => {
             if document.get_bool("_deleted").unwrap_or(false) {
                 return Err(not_found!());
             }
-
-            None
         }
+    }
+
+    let current_rev = document.get_str("_rev").ok();
+
+    // https://datatracker.ietf.org/doc/html/rfc7232#section-3.2: a cache that already holds this
+    // revision gets a 304 carrying the ETag and no body. Anything else is a normal read.
+    let not_modified = match (&if_none_match, current_rev) {
+        (Some(if_none_match), Some(rev)) => if_none_match_matches(if_none_match, rev),
+        _ => false,
     };
 
-    let mut json_document = Json(json!(document)).into_response();
+    let mut json_document = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mut body = json!(document);
+        if params.get("attachments").map_or(true, |a| a != "true") {
+            stub_attachments(&mut body);
+        }
+        Json(body).into_response()
+    };
 
-    if let Some(rev) = document.get("_rev") {
+    if let Some(rev) = current_rev {
         json_document
             .headers_mut()
-            .insert("Etag", rev.to_string().parse().unwrap());
-    }
-
-    if rev.is_some() {
-        // This will remove the body from the response but return the 304 as required
-        *json_document.status_mut() = StatusCode::NOT_MODIFIED;
+            .insert(ETAG, format!("\"{}\"", rev).parse().unwrap());
     }
 
     Ok(json_document)
@@ -128,12 +171,13 @@ fn get_param(params: &HashMap<String, String>, key: &str, fallback_key: &str) ->
         .or_else(|| params.get(fallback_key).cloned())
 }
 
-#[derive(Debug, Clone, Finalize, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ViewOptions {
     pub reduce: bool,
     pub group: bool,
     pub group_level: i64,
     pub include_docs: bool,
+    pub attachments: bool,
     pub descending: bool,
     pub limit: Option<i64>,
     pub skip: i64,
@@ -144,7 +188,11 @@ pub struct ViewOptions {
     pub keys: Vec<Value>,
 }
 
-fn extract_view_options_from_params(params: HashMap<String, String>) -> ViewOptions {
+fn extract_view_options_from_params(
+    params: HashMap<String, String>,
+    default_limit: Option<i64>,
+    max_limit: Option<i64>,
+) -> ViewOptions {
     let start_key = get_param(&params, "startkey", "start_key");
     let end_key = get_param(&params, "endkey", "end_key");
 
@@ -184,6 +232,8 @@ fn extract_view_options_from_params(params: HashMap<String, String>) -> ViewOpti
         .unwrap_or("false".to_string())
         == "true";
 
+    let attachments = params.get("attachments").is_some_and(|a| a == "true");
+
     let descending = params
         .get("descending")
         .cloned()
@@ -191,32 +241,42 @@ fn extract_view_options_from_params(params: HashMap<String, String>) -> ViewOpti
         .unwrap_or("false".to_string())
         == "true";
 
-    // Optionally see if we have a Limit or Skip parameter.
+    // Optionally see if we have a Limit or Skip parameter. Without one we fall back to the
+    // configured default, and nobody gets more rows than the configured maximum.
     let limit = params
         .get("limit")
         .cloned()
-        .and_then(|s| s.parse::<i64>().ok());
+        .and_then(|s| s.parse::<i64>().ok())
+        .or(default_limit);
 
-    let mut key = vec![json!(extract_key_json(params.get("key").cloned()))];
-    let mut keys = extract_key_json(params.get("keys").cloned());
+    let limit = match (limit, max_limit) {
+        (Some(limit), Some(max)) => Some(limit.min(max)),
+        (None, max) => max,
+        (limit, None) => limit,
+    };
 
-    if params.get("key").is_some() {
-        keys.append(&mut key);
-    }
+    let keys = extract_key_json(params.get("keys").cloned());
 
     // Skip is more nuanced, we assume 0 if it's not present
     let skip = params
         .get("skip")
         .map_or(0, |v| v.parse::<i64>().unwrap_or(0));
 
-    let start_key = extract_key_json(start_key);
-    let end_key = extract_key_json(end_key);
+    // As in CouchDB, `key` is a range starting and ending at that key.
+    let (start_key, end_key) = match params.get("key") {
+        Some(key) => (
+            extract_key_json(Some(key.clone())),
+            extract_key_json(Some(key.clone())),
+        ),
+        None => (extract_key_json(start_key), extract_key_json(end_key)),
+    };
 
     ViewOptions {
         reduce,
         group,
         group_level,
         include_docs,
+        attachments,
         descending,
         limit,
         skip,
@@ -228,21 +288,42 @@ fn extract_view_options_from_params(params: HashMap<String, String>) -> ViewOpti
     }
 }
 
-async fn inner_get_view(
+/// Runs a view and builds the response CouchDB would give for it.
+pub async fn inner_get_view(
     v: &DesignView,
     db: String,
+    design: &str,
+    view: &str,
     state: &AppState,
     params: HashMap<String, String>,
 ) -> Result<Response, JsonWithStatusCodeResponse> {
-    let view_options = extract_view_options_from_params(params);
+    let read_concern = read_concern_for_request(state, &params)?;
+    let explain = params.get("explain").map(|e| e == "true").unwrap_or(false);
+    let view_options =
+        extract_view_options_from_params(params, state.default_limit, state.max_limit);
+
+    let pipeline = create_view_pipeline(state, v, &db, design, view, &view_options).await?;
 
-    let pipeline = if let Some(f) = &v.break_glass_js_script {
-        execute_script(f.as_str(), &view_options)?
+    // When debugging a view it is handy to see the plan MongoDB chose alongside the rows
+    let explain_output = if explain {
+        Some(explain_pipeline(db.as_str(), state, pipeline.clone()).await?)
     } else {
-        create_automated_pipeline(v, &view_options).await?
+        None
+    };
+
+    let options = AggregateOptions::builder()
+        .read_concern(read_concern.clone())
+        .max_time(remaining_time())
+        .build();
+
+    let permit = match &state.view_limits {
+        Some(view_limits) => view_limits.acquire(&db).await?,
+        None => None,
     };
 
-    let results_run = state.db.aggregate(db.as_str(), pipeline).await;
+    let results_run = state.db.aggregate(db.as_str(), pipeline, options).await;
+    drop(permit);
+
     if results_run.is_err() {
         return Err((
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -253,49 +334,11 @@ async fn inner_get_view(
     let results = results_run.unwrap();
 
     // This 'magic' takes the aggregated results and the configuration for the view
-    // and creates the JSON response that CouchDB would return.
+    // and creates the JSON response that CouchDB would return, writing each row straight from
+    // the raw BSON.
     let mut items = results
-        .into_iter()
-        .map(|doc| {
-            let k = v
-                .key_fields
-                .iter()
-                .map(|x| doc.get(x).unwrap_or(&Bson::Null))
-                .collect::<Vec<_>>();
-
-            let value = v
-                .value_fields
-                .iter()
-                .map(|x| (x, doc.get(x)))
-                .filter(|(_, val)| {
-                    !v.omit_null_keys_in_value || val.is_some_and(|v| *v != Bson::Null)
-                })
-                .collect::<HashMap<_, _>>();
-
-            // If k is only one item then we can just return the value, otherwise we need to
-            // return an array of the values
-            let k = if k.len() == 1 && !v.single_item_key_is_list {
-                json!(k[0].clone())
-            } else {
-                json!(k)
-            };
-
-            // If v is only one item then we can just return the value, otherwise we need to
-            // return the actual HashMap.
-            let v = if value.keys().len() == 1 && !v.single_item_value_is_dict {
-                // We want the only item in the list so we Collect the values into a Vec, and
-                // grab the first item. This is safe because we know there is only one item.
-                json!(value.values().collect::<Vec<_>>().first().unwrap())
-            } else {
-                json!(value)
-            };
-
-            json!({
-                "id": json!(doc.get("_id").unwrap_or(&Bson::Null)),
-                "key": k,
-                "value": v,
-            })
-        })
+        .iter()
+        .map(|doc| ViewRow::new(v, doc))
         .collect::<Vec<_>>();
 
     // As per CouchDB documentation, include_docs is rarely sensible for views because for every
@@ -306,13 +349,24 @@ async fn inner_get_view(
     // iterator to the server.
     if view_options.include_docs {
         for item in &mut items {
-            let id = item.get("id").unwrap().as_str().unwrap();
-            let doc_result = state.db.find_one(db.as_str(), id).await;
-            let doc = match doc_result {
-                Ok(doc) => doc.unwrap_or_else(|| doc! {}),
-                Err(_) => doc! {},
+            let doc = match item.id() {
+                Some(id) => {
+                    let options = FindOneOptions::builder()
+                        .read_concern(read_concern.clone())
+                        .max_time(remaining_time())
+                        .build();
+                    match state.db.find_one(db.as_str(), id, options).await {
+                        Ok(doc) => doc.unwrap_or_else(|| doc! {}),
+                        Err(_) => doc! {},
+                    }
+                }
+                None => doc! {},
             };
-            item["doc"] = json!(doc);
+            let mut doc = json!(doc);
+            if !view_options.attachments {
+                stub_attachments(&mut doc);
+            }
+            item.included = Some(doc);
         }
     }
 
@@ -323,16 +377,146 @@ async fn inner_get_view(
         )
     })?;
 
-    let return_value = json!({
-        "total_rows": count,
-        "offset": view_options.skip,
-        "rows": items,
-    });
+    let offset = match view {
+        "_all_docs" => view_options.skip + all_docs_rows_before(state, &db, &view_options).await?,
+        _ => view_options.skip,
+    };
+    let offset = match state.compatibility {
+        Some(compatibility) if view == "_all_docs" => {
+            compatibility.all_docs_offset(!view_options.keys.is_empty(), offset)
+        }
+        _ => Some(offset),
+    };
+
+    let rows = items.len();
+    let body = serde_json::to_vec(&ViewResponse {
+        total_rows: count,
+        offset,
+        rows: &items,
+        explain: explain_output,
+    })
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+    })?;
+
+    let bytes = body.len() as u64;
+    let mut json_document = ([(header::CONTENT_TYPE, "application/json")], body).into_response();
+    json_document.extensions_mut().insert(DocsReturned(rows));
+
+    record_view_result(&db, design, view, rows, bytes);
 
-    let json_document = Json(return_value).into_response();
     Ok(json_document)
 }
 
+/// Returns the aggregation pipeline for a view, either from the break glass script or generated
+/// from the view definition.
+async fn create_view_pipeline(
+    state: &AppState,
+    v: &DesignView,
+    db: &str,
+    design: &str,
+    view: &str,
+    view_options: &ViewOptions,
+) -> Result<Vec<Document>, JsonWithStatusCodeResponse> {
+    if let Some(f) = &v.break_glass_js_script {
+        if !state.features_for(db).break_glass_scripts {
+            return Err(forbidden(
+                "Break glass scripts are disabled for this database.",
+            ));
+        }
+
+        let start = Instant::now();
+        let result = execute_script(
+            &state.script_cache,
+            state.script_engine.as_ref(),
+            state.view_folder.as_deref().map(std::path::Path::new),
+            f.as_str(),
+            view_options,
+        )
+        .await;
+
+        record_script_execution(
+            "view",
+            db,
+            design,
+            view,
+            view_script_outcome(f.as_str(), &result),
+            start.elapsed().as_secs_f64(),
+        );
+
+        result
+    } else {
+        create_automated_pipeline(v, view_options).await
+    }
+}
+
+/// Classifies the result of a break glass script for metrics.
+fn view_script_outcome(
+    source_file: &str,
+    result: &Result<Vec<Document>, JsonWithStatusCodeResponse>,
+) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(_) if !std::path::Path::new(source_file).is_file() => "script_not_found",
+        Err(e) if is_script_timeout(e) => "timeout",
+        Err(_) => "script_error",
+    }
+}
+
+/// Runs explain on the pipeline and returns the pipeline, the winning plan and the full explain
+/// output as JSON.
+async fn explain_pipeline(
+    db: &str,
+    state: &AppState,
+    pipeline: Vec<Document>,
+) -> Result<Value, JsonWithStatusCodeResponse> {
+    let explain = state
+        .db
+        .explain_aggregate(db, pipeline.clone())
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": e.to_string()})),
+            )
+        })?;
+
+    Ok(json!({
+        "dbname": db,
+        "pipeline": pipeline,
+        "winning_plan": find_winning_plan(&explain),
+        "explain": explain,
+    }))
+}
+
+/// The location of the winning plan in the explain output depends on the MongoDB version and
+/// whether the pipeline could be pushed down into the query layer, so we go looking for it.
+fn find_winning_plan(explain: &Document) -> Option<&Bson> {
+    for (k, v) in explain {
+        if k == "winningPlan" {
+            return Some(v);
+        }
+
+        let found = match v {
+            Bson::Document(d) => find_winning_plan(d),
+            Bson::Array(a) => a
+                .iter()
+                .filter_map(Bson::as_document)
+                .find_map(find_winning_plan),
+            _ => None,
+        };
+
+        if found.is_some() {
+            return found;
+        }
+    }
+
+    None
+}
+
 async fn create_automated_pipeline(
     v: &DesignView,
     view_options: &ViewOptions,
@@ -371,7 +555,7 @@ async fn create_automated_pipeline(
                     if k == "$and" {
                         let new_doc = doc! { "$and": v };
 
-                        unwrapped_match_entry.extend(new_doc.into_iter());
+                        unwrapped_match_entry.extend(new_doc);
                         continue;
                     }
 
@@ -380,7 +564,7 @@ async fn create_automated_pipeline(
                         .or_insert_with(|| Bson::Document(doc! {}));
                     if let Some(entry_doc) = entry.as_document_mut() {
                         if let Some(v_doc) = v.as_document() {
-                            entry_doc.extend(v_doc.clone().into_iter());
+                            entry_doc.extend(v_doc.clone());
                         } else {
                             return Err((
                                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -505,9 +689,11 @@ fn create_filter(
                     false => (bson::to_bson(start).ok(), bson::to_bson(end).ok()),
                 };
 
-                if start == end && start.is_some() && start != Some(Bson::Null) {
-                    filter.insert(v.clone(), doc! {"$eq": start.unwrap()});
-                    continue;
+                if start == end {
+                    if let Some(start) = start.clone().filter(|val| *val != Bson::Null) {
+                        filter.insert(v.clone(), doc! {"$eq": start});
+                        continue;
+                    }
                 }
 
                 let field = start
@@ -531,25 +717,13 @@ fn create_filter(
                 }
             }
 
-            if let Some(start_key_doc_id) = start_key_doc_id {
-                if !start_key_doc_id.is_empty() {
-                    filter.insert(
-                        "_id",
-                        doc! {
-                            "$gte": start_key_doc_id
-                        },
-                    );
-                }
-            }
-
-            if let Some(end_key_doc_id) = end_key_doc_id {
-                if !end_key_doc_id.is_empty() {
-                    filter.insert(
-                        "_id",
-                        doc! {
-                            "$lte": end_key_doc_id
-                        },
-                    );
+            let id_range = doc_id_range(start_key_doc_id, end_key_doc_id, flipped);
+            if !id_range.is_empty() {
+                if filter.contains_key("_id") {
+                    // The view is keyed by `_id` too, as `_all_docs` is, so both have to hold
+                    filter.insert("$and", vec![doc! {"_id": id_range}]);
+                } else {
+                    filter.insert("_id", id_range);
                 }
             }
         }
@@ -561,6 +735,24 @@ fn create_filter(
     filter
 }
 
+/// The `_id` bounds from `startkey_docid` and `endkey_docid`, which swap over when descending just
+/// as the keys do.
+fn doc_id_range(start: &Option<String>, end: &Option<String>, flipped: bool) -> Document {
+    let (low, high) = match flipped {
+        true => (end, start),
+        false => (start, end),
+    };
+
+    let mut range = doc! {};
+    if let Some(low) = low.as_ref().filter(|id| !id.is_empty()) {
+        range.insert("$gte", low);
+    }
+    if let Some(high) = high.as_ref().filter(|id| !id.is_empty()) {
+        range.insert("$lte", high);
+    }
+    range
+}
+
 fn map_keys(v: &DesignView, keys: &[Value], filter: &mut Document) {
     // Convert keys to Bson
     let vec_keys = keys
@@ -596,32 +788,43 @@ pub async fn get_view(
 ) -> Result<Response, JsonWithStatusCodeResponse> {
     let actual_view = extract_view_from_views(&state, db.as_str(), design.as_str(), view.as_str());
     if actual_view.is_err() {
-        if state.couchdb_details.is_some()
-            && state
-                .couchdb_details
-                .as_ref()
-                .unwrap()
-                .should_read_through(&db)
+        if let Some(couchdb_details) = state
+            .couchdb_details
+            .as_ref()
+            .filter(|c| c.should_read_through(&db))
         {
-            let couchdb_details = state.couchdb_details.as_ref().unwrap();
             let mapped_db = couchdb_details.map_for_db(db.as_str());
 
             let path = format!("{}/_design/{}/_view/{}", mapped_db, design, view);
-            return read_through(couchdb_details, Method::GET, None, &path, &params).await;
+            return read_through_view(&state, couchdb_details, Method::GET, None, &path, &params)
+                .await;
         }
 
         return Err(actual_view.err().unwrap());
     }
 
-    inner_get_view(actual_view.unwrap(), db.to_string(), state.as_ref(), params).await
+    inner_get_view(
+        &actual_view.unwrap(),
+        db.to_string(),
+        &design,
+        &view,
+        state.as_ref(),
+        params,
+    )
+    .await
 }
 
+/// Finds a view, preferring a definition reloaded since startup.
 fn extract_view_from_views<'a>(
     state: &'a Arc<AppState>,
     db: &'a str,
     design: &'a str,
     view: &'a str,
-) -> Result<&'a DesignView, (StatusCode, Json<Value>)> {
+) -> Result<Cow<'a, DesignView>, (StatusCode, Json<Value>)> {
+    if let Some(reloaded) = state.reloaded_views.get(db, design, view) {
+        return Ok(Cow::Owned(reloaded));
+    }
+
     if state.views.is_none() {
         return Err((
             StatusCode::NOT_IMPLEMENTED,
@@ -652,7 +855,122 @@ fn extract_view_from_views<'a>(
         }
     };
 
-    Ok(actual_view)
+    Ok(Cow::Borrowed(actual_view))
+}
+
+/// get_view_explain returns the pipeline that a view would run for the given parameters along
+/// with MongoDB's explain output, so the winning plan can be checked without shelling into
+/// mongosh.
+pub async fn get_view_explain(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path((db, design, view)): Path<(String, String, String)>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let actual_view = extract_view_from_views(&state, db.as_str(), design.as_str(), view.as_str())?;
+    let view_options =
+        extract_view_options_from_params(params, state.default_limit, state.max_limit);
+
+    let pipeline = create_view_pipeline(
+        state.as_ref(),
+        &actual_view,
+        &db,
+        &design,
+        &view,
+        &view_options,
+    )
+    .await?;
+    let explain = explain_pipeline(db.as_str(), state.as_ref(), pipeline).await?;
+
+    Ok(Json(explain).into_response())
+}
+
+/// Rows a dry run returns when no `limit` is given, and the most it will return.
+const DRY_RUN_DEFAULT_LIMIT: i64 = 10;
+const DRY_RUN_MAX_LIMIT: i64 = 100;
+
+/// Reads a candidate view from a dry run's body: TOML, as the view files are written, when the
+/// content type says so and JSON otherwise.
+fn parse_candidate_view(
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<DesignView, JsonWithStatusCodeResponse> {
+    let is_toml = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .is_some_and(|c| c.contains("toml"));
+
+    let parsed = if is_toml {
+        std::str::from_utf8(body)
+            .map_err(|e| e.to_string())
+            .and_then(|body| toml::from_str(body).map_err(|e| e.to_string()))
+    } else {
+        serde_json::from_slice(body).map_err(|e| e.to_string())
+    };
+
+    parsed.map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "bad_request", "reason": e})),
+        )
+    })
+}
+
+/// view_dry_run runs a candidate view that isn't in the view folder yet against a database, with
+/// the query string as its parameters, and returns the pipeline, MongoDB's explain output and
+/// the first rows. The number of rows is always limited.
+pub async fn view_dry_run(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path(db): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let candidate = parse_candidate_view(&headers, &body)?;
+    let read_concern = read_concern_for_request(&state, &params)?;
+    let view_options = extract_view_options_from_params(
+        params,
+        Some(DRY_RUN_DEFAULT_LIMIT),
+        Some(DRY_RUN_MAX_LIMIT),
+    );
+
+    let pipeline = create_view_pipeline(
+        state.as_ref(),
+        &candidate,
+        &db,
+        "_dry_run",
+        "_dry_run",
+        &view_options,
+    )
+    .await?;
+    let mut explain = explain_pipeline(db.as_str(), state.as_ref(), pipeline.clone()).await?;
+
+    let options = AggregateOptions::builder()
+        .read_concern(read_concern)
+        .max_time(remaining_time())
+        .build();
+    let results = state
+        .db
+        .aggregate(db.as_str(), pipeline, options)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": e.to_string()})),
+            )
+        })?;
+
+    let rows = results
+        .iter()
+        .map(|doc| ViewRow::new(&candidate, doc))
+        .collect::<Vec<_>>();
+    explain["rows"] = serde_json::to_value(&rows).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+    })?;
+
+    Ok(Json(explain).into_response())
 }
 
 pub async fn post_get_view(
@@ -666,18 +984,16 @@ pub async fn post_get_view(
 
     let actual_view = extract_view_from_views(&state, db.as_str(), design.as_str(), view.as_str());
     if actual_view.is_err() {
-        if state.couchdb_details.is_some()
-            && state
-                .couchdb_details
-                .as_ref()
-                .unwrap()
-                .should_read_through(&db)
+        if let Some(couchdb_details) = state
+            .couchdb_details
+            .as_ref()
+            .filter(|c| c.should_read_through(&db))
         {
-            let couchdb_details = state.couchdb_details.as_ref().unwrap();
             let mapped_db = couchdb_details.map_for_db(db.as_str());
 
             let path = format!("{}/_design/{}/_view/{}", mapped_db, design, view);
-            return read_through(
+            return read_through_view(
+                &state,
                 couchdb_details,
                 Method::POST,
                 Some(&payload),
@@ -691,8 +1007,10 @@ pub async fn post_get_view(
     }
 
     inner_get_view(
-        actual_view.unwrap(),
+        &actual_view.unwrap(),
         db.to_string(),
+        &design,
+        &view,
         state.as_ref(),
         payload_map,
     )
@@ -708,18 +1026,16 @@ pub async fn post_multi_query(
     let actual_view = extract_view_from_views(&state, db.as_str(), design.as_str(), view.as_str());
 
     if actual_view.is_err() {
-        if state.couchdb_details.is_some()
-            && state
-                .couchdb_details
-                .as_ref()
-                .unwrap()
-                .should_read_through(&db)
+        if let Some(couchdb_details) = state
+            .couchdb_details
+            .as_ref()
+            .filter(|c| c.should_read_through(&db))
         {
-            let couchdb_details = state.couchdb_details.as_ref().unwrap();
             let mapped_db = couchdb_details.map_for_db(db.as_str());
 
             let path = format!("{}/_design/{}/_view/{}/queries", mapped_db, design, view);
-            return read_through(
+            return read_through_view(
+                &state,
                 couchdb_details,
                 Method::POST,
                 Some(&payload),
@@ -751,8 +1067,15 @@ pub async fn post_multi_query(
                 let mut payload_map = convert_payload(p);
                 payload_map.extend(params.clone());
 
-                let result =
-                    inner_get_view(actual_view, db.clone(), state.as_ref(), payload_map).await;
+                let result = inner_get_view(
+                    &actual_view,
+                    db.clone(),
+                    &design,
+                    &view,
+                    state.as_ref(),
+                    payload_map,
+                )
+                .await;
                 results.push(result);
             }
             let mut json_results = Vec::new();
@@ -792,7 +1115,17 @@ pub async fn all_docs(
     Query(params): Query<HashMap<String, String>>,
     Path(db): Path<String>,
 ) -> Result<Response, (StatusCode, Json<Value>)> {
-    inner_get_view(&create_all_docs_design_view(), db, state.as_ref(), params).await
+    check_all_docs_enabled(&state, &db)?;
+
+    inner_get_view(
+        &create_all_docs_design_view(),
+        db,
+        "",
+        "_all_docs",
+        state.as_ref(),
+        params,
+    )
+    .await
 }
 
 pub async fn post_all_docs(
@@ -801,19 +1134,68 @@ pub async fn post_all_docs(
     Query(params): Query<HashMap<String, String>>,
     Json(payload): Json<Value>,
 ) -> Result<Response, (StatusCode, Json<Value>)> {
+    check_all_docs_enabled(&state, &db)?;
+
     let mut payload_map = convert_payload(payload);
     payload_map.extend(params);
 
     inner_get_view(
         &create_all_docs_design_view(),
         db,
+        "",
+        "_all_docs",
         state.as_ref(),
         payload_map,
     )
     .await
 }
 
-fn convert_payload(payload: Value) -> HashMap<String, String> {
+/// Counts the documents `_all_docs` passes over before reaching its start key, in the order it's
+/// read, as CouchDB includes them in `offset`. Reading by `keys` passes over none.
+async fn all_docs_rows_before(
+    state: &AppState,
+    db: &str,
+    view_options: &ViewOptions,
+) -> Result<i64, JsonWithStatusCodeResponse> {
+    let start = match view_options.start_key.first() {
+        Some(start) if view_options.keys.is_empty() && !start.is_null() => start,
+        _ => return Ok(0),
+    };
+
+    let start = bson::to_bson(start).unwrap_or(Bson::Null);
+    let before = match view_options.descending {
+        true => doc! { "_id": { "$gt": start } },
+        false => doc! { "_id": { "$lt": start } },
+    };
+    let pipeline = vec![doc! { "$match": before }, doc! { "$count": "rows" }];
+
+    let counted = state
+        .db
+        .aggregate(db, pipeline, AggregateOptions::default())
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": e.to_string()})),
+            )
+        })?;
+
+    Ok(counted
+        .first()
+        .and_then(|c| c.get_i32("rows").ok())
+        .map_or(0, i64::from))
+}
+
+fn check_all_docs_enabled(state: &AppState, db: &str) -> Result<(), JsonWithStatusCodeResponse> {
+    if state.features_for(db).all_docs {
+        Ok(())
+    } else {
+        Err(forbidden("_all_docs is disabled for this database."))
+    }
+}
+
+/// Flattens a JSON body into parameters, as if they had been given in the query string.
+pub fn convert_payload(payload: Value) -> HashMap<String, String> {
     match payload.as_object() {
         Some(object) => object
             .iter()
@@ -845,26 +1227,21 @@ fn extract_key_json(key: Option<String>) -> Vec<Value> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::DesignMapping;
+    use crate::config::{CouchDb, DatabaseFeatures, DesignMapping};
     use crate::db::*;
     use assert_json_diff::assert_json_eq;
-    use bson::doc;
+    use bson::{doc, RawDocumentBuf};
     use maplit::hashmap;
 
     #[tokio::test]
     async fn test_get_item_basic() {
         let mut mock = MockDatabase::new();
 
-        mock.expect_find_one().returning(|_, _| {
+        mock.expect_find_one().returning(|_, _, _| {
             Box::pin(async { Ok(Some(doc! { "_id": "test_item", "_rev": "test_rev" })) })
         });
 
-        let app_state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let app_state = Arc::new(AppState::for_tests(mock));
 
         // Assume the test data exists in MongoDB
         let db_name = "test_db".to_string();
@@ -900,19 +1277,113 @@ mod tests {
         };
     }
 
+    #[tokio::test]
+    async fn test_get_item_soft_launch() {
+        let server = httpmock::MockServer::start_async().await;
+        let couch = server
+            .mock_async(|when, then| {
+                when.path("/couch_orders/a");
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .body(r#"{"_id":"a","_rev":"1-c","from":"couchdb"}"#);
+            })
+            .await;
+
+        // Nothing is read from MongoDB, so the mock fails the test if it's asked.
+        let app_state = Arc::new(AppState {
+            couchdb_details: Some(CouchDb {
+                url: server.base_url(),
+                username: None,
+                password: None,
+                read_through: false,
+                read_only: false,
+                read_through_databases: None,
+                read_only_databases: None,
+                stale_fallback: false,
+                soft_launch: Some(hashmap! { "orders".to_string() => 0 }),
+                mappings: Some(hashmap! { "orders".to_string() => "couch_orders".to_string() }),
+            }),
+            ..AppState::for_tests(MockDatabase::new())
+        });
+
+        let response = get_item(
+            Extension(IfNoneMatch(None)),
+            State(app_state),
+            Query(HashMap::new()),
+            Path(("orders".to_string(), "a".to_string())),
+        )
+        .await
+        .unwrap();
+
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["from"], "couchdb");
+        couch.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_get_item_rev() {
+        let mut mock = MockDatabase::new();
+
+        mock.expect_find_one().returning(|_, _, _| {
+            Box::pin(async {
+                Ok(Some(
+                    doc! { "_id": "test_item", "_rev": "2-b", "_deleted": true },
+                ))
+            })
+        });
+
+        let app_state = Arc::new(AppState::for_tests(mock));
+
+        let get = |params: HashMap<String, String>| {
+            get_item(
+                Extension(IfNoneMatch(None)),
+                State(app_state.clone()),
+                Query(params),
+                Path(("test_db".to_string(), "test_item".to_string())),
+            )
+        };
+
+        // The current rev comes back with its body, even once deleted.
+        let response = get(hashmap! { "rev".to_string() => "2-b".to_string() })
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["_rev"], "2-b");
+
+        // An older rev isn't kept.
+        let (status, Json(body)) = get(hashmap! { "rev".to_string() => "1-a".to_string() })
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["reason"], "missing");
+
+        // But latest resolves it to the current rev.
+        let response = get(hashmap! {
+            "rev".to_string() => "1-a".to_string(),
+            "latest".to_string() => "true".to_string(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
     #[tokio::test]
     async fn test_get_item_not_found() {
         let mut mock = MockDatabase::new();
 
         mock.expect_find_one()
-            .returning(|_, _| Box::pin(async { Ok(None) }));
+            .returning(|_, _, _| Box::pin(async { Ok(None) }));
 
-        let app_state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let app_state = Arc::new(AppState::for_tests(mock));
 
         let db_name = "test_db".to_string();
         let item_id = "test_item".to_string();
@@ -952,22 +1423,17 @@ mod tests {
     async fn test_get_item_if_none_match() {
         let mut mock = MockDatabase::new();
 
-        mock.expect_find_one().returning(|_, _| {
+        mock.expect_find_one().returning(|_, _, _| {
             Box::pin(async { Ok(Some(doc! { "_id": "test_item", "_rev": "test_rev" })) })
         });
 
-        let app_state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let app_state = Arc::new(AppState::for_tests(mock));
 
         let db_name = "test_db".to_string();
         let item_id = "test_item".to_string();
 
         let result = get_item(
-            Extension(IfNoneMatch(Some("test_rev".to_string()))),
+            Extension(IfNoneMatch(Some("\"other_rev\", \"test_rev\"".to_string()))),
             State(app_state),
             Query(HashMap::new()),
             Path((db_name, item_id)),
@@ -976,21 +1442,20 @@ mod tests {
 
         match result {
             Ok(response) => {
-                panic!(
-                    "Expected NOT_MODIFIED, got error with status code {:?}",
-                    response.status()
-                );
-            }
-            Err((status_code, json)) => {
-                assert_eq!(status_code, StatusCode::NOT_MODIFIED);
+                assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+                assert_eq!(response.headers()[ETAG], "\"test_rev\"");
 
-                let body = BodyExt::collect(json.into_response().into_body())
+                let body = BodyExt::collect(response.into_body())
                     .await
                     .unwrap()
                     .to_bytes();
-                let actual_json_body: Value = serde_json::from_slice(&body).unwrap();
-                let expected_json_body = json!({});
-                assert_json_eq!(actual_json_body, expected_json_body);
+                assert_eq!(body, "");
+            }
+            Err((status_code, _json)) => {
+                panic!(
+                    "Expected NOT_MODIFIED, got error with status code {:?}",
+                    status_code
+                );
             }
         };
     }
@@ -999,16 +1464,11 @@ mod tests {
     async fn test_get_item_if_none_match_different_rev() {
         let mut mock = MockDatabase::new();
 
-        mock.expect_find_one().returning(|_, _| {
+        mock.expect_find_one().returning(|_, _, _| {
             Box::pin(async { Ok(Some(doc! { "_id": "test_item", "_rev": "test_rev" })) })
         });
 
-        let app_state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let app_state = Arc::new(AppState::for_tests(mock));
 
         let db_name = "test_db".to_string();
         let item_id = "test_item".to_string();
@@ -1023,19 +1483,18 @@ mod tests {
 
         match result {
             Ok(response) => {
-                assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()[ETAG], "\"test_rev\"");
 
                 let body = BodyExt::collect(response.into_body())
                     .await
                     .unwrap()
                     .to_bytes();
-                assert_eq!(body, "");
+                let actual_json_body: Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(actual_json_body["_rev"], "test_rev");
             }
             Err((status_code, _json)) => {
-                panic!(
-                    "Expected PRECONDITION_FAILED, got error with status code {:?}",
-                    status_code
-                );
+                panic!("Expected OK, got error with status code {:?}", status_code);
             }
         };
     }
@@ -1044,12 +1503,7 @@ mod tests {
     fn test_extract_view_from_views_none_views() {
         let mock = MockDatabase::new();
 
-        let state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let state = Arc::new(AppState::for_tests(mock));
 
         let result = extract_view_from_views(&state, "db", "design", "view");
         assert!(result.is_err());
@@ -1060,10 +1514,8 @@ mod tests {
         let mock = MockDatabase::new();
 
         let state = Arc::new(AppState {
-            db: Box::new(mock),
             views: Some(HashMap::new()),
-            updates_folder: None,
-            couchdb_details: None,
+            ..AppState::for_tests(mock)
         });
 
         let result = extract_view_from_views(&state, "db", "design", "view");
@@ -1075,12 +1527,10 @@ mod tests {
         let mock = MockDatabase::new();
 
         let state = Arc::new(AppState {
-            db: Box::new(mock),
             views: Some(hashmap! {
                 "db".into() => DesignMapping { view_groups: HashMap::new() }
             }),
-            updates_folder: None,
-            couchdb_details: None,
+            ..AppState::for_tests(mock)
         });
 
         let result = extract_view_from_views(&state, "db", "design", "view");
@@ -1092,14 +1542,12 @@ mod tests {
         let mock = MockDatabase::new();
 
         let state = Arc::new(AppState {
-            db: Box::new(mock),
             views: Some(hashmap! {
                 "db".into() => DesignMapping { view_groups: hashmap! {
                     "design".into() => HashMap::new()
                 } }
             }),
-            updates_folder: None,
-            couchdb_details: None,
+            ..AppState::for_tests(mock)
         });
 
         let result = extract_view_from_views(&state, "db", "design", "view");
@@ -1120,12 +1568,14 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let mock = MockDatabase::new();
 
         let state = Arc::new(AppState {
-            db: Box::new(mock),
             views: Some(hashmap! {
                 "db".into() => DesignMapping { view_groups: hashmap! {
                     "design".into() => hashmap! {
@@ -1133,13 +1583,12 @@ mod tests {
                     }
                 } }
             }),
-            updates_folder: None,
-            couchdb_details: None,
+            ..AppState::for_tests(mock)
         });
 
         let result = extract_view_from_views(&state, "db", "design", "view");
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), &design_view);
+        assert_eq!(*result.unwrap(), design_view);
     }
 
     #[test]
@@ -1228,21 +1677,42 @@ mod tests {
     fn test_extract_view_options_from_params() {
         let mut params = HashMap::new();
         params.insert("key".to_string(), "[1, 2]".to_string());
+        params.insert("startkey".to_string(), "[0]".to_string());
 
-        let check = vec![json!(vec![1, 2])];
-
-        let result = extract_view_options_from_params(params);
-        assert_eq!(result.keys, check);
+        let result = extract_view_options_from_params(params, None, None);
+        assert!(result.keys.is_empty());
+        assert_eq!(result.start_key, vec![json!(1), json!(2)]);
+        assert_eq!(result.end_key, vec![json!(1), json!(2)]);
 
         let mut params = HashMap::new();
         params.insert("keys".to_string(), "[1]".to_string());
 
         let check = vec![json!(1)];
 
-        let result = extract_view_options_from_params(params);
+        let result = extract_view_options_from_params(params, None, None);
         assert_eq!(result.keys, check);
     }
 
+    #[test]
+    fn test_extract_view_options_limits() {
+        let result = extract_view_options_from_params(HashMap::new(), None, None);
+        assert_eq!(result.limit, None);
+
+        let result = extract_view_options_from_params(HashMap::new(), Some(100), Some(1000));
+        assert_eq!(result.limit, Some(100));
+
+        let result = extract_view_options_from_params(HashMap::new(), None, Some(1000));
+        assert_eq!(result.limit, Some(1000));
+
+        let params = HashMap::from([("limit".to_string(), "5000".to_string())]);
+        let result = extract_view_options_from_params(params, Some(100), Some(1000));
+        assert_eq!(result.limit, Some(1000));
+
+        let params = HashMap::from([("limit".to_string(), "10".to_string())]);
+        let result = extract_view_options_from_params(params, Some(100), Some(1000));
+        assert_eq!(result.limit, Some(10));
+    }
+
     #[test]
     fn test_create_filter_no_keys() {
         let design_view = DesignView {
@@ -1257,6 +1727,9 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let keys = vec![];
@@ -1303,6 +1776,9 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let keys = vec![];
@@ -1342,6 +1818,9 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let keys = vec![];
@@ -1392,6 +1871,9 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let keys = vec![json![vec![json!("key1"), json!("key2")]]];
@@ -1440,6 +1922,9 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let keys = vec![json!("key1"), json!("key2")];
@@ -1497,6 +1982,9 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let keys = vec![json!(1), json!(2)];
@@ -1540,6 +2028,9 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let key = vec![json!(1), json!(2)];
@@ -1579,6 +2070,9 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let keys = vec![];
@@ -1610,6 +2104,81 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_create_filter_doc_id_range() {
+        let design_view = DesignView {
+            match_fields: vec!["field1".to_string()],
+            sort_fields: None,
+            aggregation: vec![],
+            key_fields: vec![],
+            value_fields: vec![],
+            filter_insert_index: 0,
+            reduce: None,
+            single_item_key_is_list: false,
+            single_item_value_is_dict: false,
+            break_glass_js_script: None,
+            omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
+        };
+        let filter = |start: Option<&str>, end: Option<&str>, descending: bool| {
+            create_filter(
+                &design_view,
+                &[],
+                &[json!("a")],
+                &[json!("a")],
+                &start.map(str::to_string),
+                &end.map(str::to_string),
+                descending,
+            )
+        };
+
+        // Paging through rows that share a key, by document id.
+        assert_eq!(
+            filter(Some("doc-10"), Some("doc-19"), false),
+            doc! {
+                "field1": {"$eq": "a"},
+                "_id": {"$gte": "doc-10", "$lte": "doc-19"},
+            }
+        );
+
+        // Descending, the start id is the upper bound.
+        assert_eq!(
+            filter(Some("doc-19"), Some("doc-10"), true),
+            doc! {
+                "field1": {"$eq": "a"},
+                "_id": {"$gte": "doc-10", "$lte": "doc-19"},
+            }
+        );
+
+        assert_eq!(
+            filter(None, Some("doc-19"), false),
+            doc! {"field1": {"$eq": "a"}, "_id": {"$lte": "doc-19"}}
+        );
+        assert_eq!(filter(Some(""), None, false), doc! {"field1": {"$eq": "a"}});
+    }
+
+    #[test]
+    fn test_create_filter_doc_id_range_on_id_key() {
+        let result = create_filter(
+            &create_all_docs_design_view(),
+            &[],
+            &[json!("a")],
+            &[json!("z")],
+            &Some("b".to_string()),
+            &Some("c".to_string()),
+            false,
+        );
+
+        let expected = doc! {
+            "_id": {"$gte": "a", "$lte": "z"},
+            "$and": [{"_id": {"$gte": "b", "$lte": "c"}}],
+        };
+
+        assert_eq!(result, expected);
+    }
+
     #[test]
     fn test_invalid_json_in_aggregation() {
         let design_view = DesignView {
@@ -1624,6 +2193,9 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let v = extract_pipeline_bson(&design_view, false, 0);
@@ -1644,10 +2216,332 @@ mod tests {
             single_item_value_is_dict: false,
             break_glass_js_script: None,
             omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
         };
 
         let v = extract_pipeline_bson(&design_view, false, 0);
         assert!(v.is_ok());
         assert_eq!(v.unwrap().len(), 1);
     }
+
+    #[test]
+    fn test_find_winning_plan() {
+        let explain = doc! {
+            "stages": [
+                { "$cursor": { "queryPlanner": { "winningPlan": { "stage": "IXSCAN" } } } },
+                { "$project": { "key": 1 } },
+            ]
+        };
+
+        assert_eq!(
+            find_winning_plan(&explain),
+            Some(&Bson::Document(doc! { "stage": "IXSCAN" }))
+        );
+        assert_eq!(find_winning_plan(&doc! { "ok": 1 }), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_view_explain() {
+        let design_view = DesignView {
+            match_fields: vec!["field1".to_string()],
+            sort_fields: None,
+            aggregation: vec![r#"{"$match": {}}"#.to_string()],
+            key_fields: vec!["field1".to_string()],
+            value_fields: vec![],
+            filter_insert_index: 0,
+            reduce: None,
+            single_item_key_is_list: false,
+            single_item_value_is_dict: false,
+            break_glass_js_script: None,
+            omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
+        };
+
+        let mut mock = MockDatabase::new();
+
+        mock.expect_explain_aggregate()
+            .withf(|coll, pipeline| coll == "db" && pipeline.len() == 2)
+            .returning(|_, _| {
+                Box::pin(async {
+                    Ok(doc! { "queryPlanner": { "winningPlan": { "stage": "COLLSCAN" } } })
+                })
+            });
+
+        let state = Arc::new(AppState {
+            views: Some(hashmap! {
+                "db".into() => DesignMapping { view_groups: hashmap! {
+                    "design".into() => hashmap! {
+                        "view".into() => design_view
+                    }
+                } }
+            }),
+            ..AppState::for_tests(mock)
+        });
+
+        let response = get_view_explain(
+            State(state),
+            Query(hashmap! { "key".to_string() => "\"a\"".to_string() }),
+            Path(("db".to_string(), "design".to_string(), "view".to_string())),
+        )
+        .await
+        .unwrap();
+
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let actual_json_body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_json_eq!(
+            actual_json_body["winning_plan"],
+            json!({ "stage": "COLLSCAN" })
+        );
+        assert_eq!(
+            actual_json_body["pipeline"][0]["$match"],
+            json!({ "field1": { "$eq": "a" } })
+        );
+        assert_eq!(actual_json_body["pipeline"][1], json!({ "$skip": 0 }));
+    }
+
+    #[tokio::test]
+    async fn test_include_docs_attachments() {
+        let mut mock = MockDatabase::new();
+        mock.expect_aggregate().returning(|_, _, _| {
+            let row = RawDocumentBuf::from_document(&doc! { "_id": "a", "_rev": "1-a" }).unwrap();
+            Box::pin(async move { Ok(vec![row]) })
+        });
+        mock.expect_find_one().returning(|_, _, _| {
+            Box::pin(async {
+                Ok(Some(doc! {
+                    "_id": "a",
+                    "_rev": "1-a",
+                    "_attachments": { "note.txt": { "content_type": "text/plain", "data": "aGk=" } },
+                }))
+            })
+        });
+        mock.expect_count().returning(|_| Box::pin(async { Ok(1) }));
+
+        let state = AppState::for_tests(mock);
+
+        let attachment = |attachments: &'static str| {
+            let params = hashmap! {
+                "include_docs".to_string() => "true".to_string(),
+                "attachments".to_string() => attachments.to_string(),
+            };
+            let state = &state;
+            async move {
+                let response = inner_get_view(
+                    &create_all_docs_design_view(),
+                    "db".to_string(),
+                    "",
+                    "_all_docs",
+                    state,
+                    params,
+                )
+                .await
+                .unwrap();
+                let body = BodyExt::collect(response.into_body())
+                    .await
+                    .unwrap()
+                    .to_bytes();
+                let body: Value = serde_json::from_slice(&body).unwrap();
+                body["rows"][0]["doc"]["_attachments"]["note.txt"].clone()
+            }
+        };
+
+        assert_json_eq!(
+            attachment("false").await,
+            json!({ "content_type": "text/plain", "length": 2, "stub": true })
+        );
+        assert_json_eq!(
+            attachment("true").await,
+            json!({ "content_type": "text/plain", "data": "aGk=" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_view_dry_run() {
+        let mut mock = MockDatabase::new();
+        mock.expect_explain_aggregate().returning(|_, _| {
+            Box::pin(async { Ok(doc! { "winningPlan": { "stage": "IXSCAN" } }) })
+        });
+        mock.expect_aggregate()
+            .withf(|coll, pipeline, _| {
+                coll == "db" && pipeline.last() == Some(&doc! { "$limit": DRY_RUN_MAX_LIMIT })
+            })
+            .returning(|_, _, _| {
+                let row =
+                    RawDocumentBuf::from_document(&doc! { "_id": "a", "field1": "x" }).unwrap();
+                Box::pin(async move { Ok(vec![row]) })
+            });
+
+        let state = Arc::new(AppState::for_tests(mock));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/toml".parse().unwrap());
+        let candidate = r#"
+            match_fields = ["field1"]
+            aggregation = ['{"$match": {}}']
+            key_fields = ["field1"]
+            value_fields = []
+            filter_insert_index = 0
+        "#;
+
+        let response = view_dry_run(
+            State(state),
+            Query(hashmap! { "limit".to_string() => "5000".to_string() }),
+            Path("db".to_string()),
+            headers,
+            Bytes::from(candidate),
+        )
+        .await
+        .unwrap();
+
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let actual_json_body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_json_eq!(
+            actual_json_body["winning_plan"],
+            json!({ "stage": "IXSCAN" })
+        );
+        assert_eq!(
+            actual_json_body["pipeline"].as_array().unwrap().last(),
+            Some(&json!({ "$limit": DRY_RUN_MAX_LIMIT }))
+        );
+        assert_json_eq!(
+            actual_json_body["rows"],
+            json!([{ "id": "a", "key": "x", "value": {} }])
+        );
+    }
+
+    #[test]
+    fn test_parse_candidate_view_rejects_bad_json() {
+        let (status, _) = parse_candidate_view(&HeaderMap::new(), b"{").unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_view_script_outcome() {
+        let error: JsonWithStatusCodeResponse = (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "boom"})),
+        );
+
+        assert_eq!(view_script_outcome("Cargo.toml", &Ok(vec![])), "ok");
+        assert_eq!(
+            view_script_outcome("Cargo.toml", &Err(error.clone())),
+            "script_error"
+        );
+        assert_eq!(
+            view_script_outcome("does/not/exist.js", &Err(error)),
+            "script_not_found"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_features() {
+        let design_view = DesignView {
+            match_fields: vec![],
+            sort_fields: None,
+            aggregation: vec![],
+            key_fields: vec![],
+            value_fields: vec![],
+            filter_insert_index: 0,
+            reduce: None,
+            single_item_key_is_list: false,
+            single_item_value_is_dict: false,
+            break_glass_js_script: Some("script.js".to_string()),
+            omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
+        };
+
+        let state = Arc::new(AppState {
+            views: Some(hashmap! {
+                "db".into() => DesignMapping { view_groups: hashmap! {
+                    "design".into() => hashmap! {
+                        "view".into() => design_view
+                    }
+                } }
+            }),
+            features: Some(hashmap! {
+                "*".into() => DatabaseFeatures {
+                    all_docs: false,
+                    bulk_deletes: true,
+                    break_glass_scripts: false,
+                    changes_websocket: false,
+                    changes_feed: false,
+                },
+            }),
+            ..AppState::for_tests(MockDatabase::new())
+        });
+
+        let (status, body) = all_docs(
+            State(state.clone()),
+            Query(hashmap! {}),
+            Path("db".to_string()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body.0["reason"], "_all_docs is disabled for this database.");
+
+        let (status, _) = get_view(
+            State(state),
+            Query(hashmap! {}),
+            Path(("db".to_string(), "design".to_string(), "view".to_string())),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_all_docs_key_and_descending_offset() {
+        let mut mock = MockDatabase::new();
+        mock.expect_aggregate().returning(|_, pipeline, _| {
+            let rows = if pipeline.iter().any(|stage| stage.contains_key("$count")) {
+                // Two documents sort after "c", so a descending read passes over them first.
+                assert_eq!(pipeline[0], doc! { "$match": { "_id": { "$gt": "c" } } });
+                vec![RawDocumentBuf::from_document(&doc! { "rows": 2 }).unwrap()]
+            } else {
+                assert_eq!(pipeline[0], doc! { "$match": { "_id": { "$eq": "c" } } });
+                vec![RawDocumentBuf::from_document(&doc! { "_id": "c", "rev": "1-c" }).unwrap()]
+            };
+            Box::pin(async move { Ok(rows) })
+        });
+        mock.expect_count().returning(|_| Box::pin(async { Ok(5) }));
+
+        let state = Arc::new(AppState {
+            compatibility: Some(crate::compat::Compatibility::V3_3),
+            ..AppState::for_tests(mock)
+        });
+
+        let response = all_docs(
+            State(state),
+            Query(hashmap! {
+                "key".to_string() => "\"c\"".to_string(),
+                "descending".to_string() => "true".to_string(),
+            }),
+            Path("db".to_string()),
+        )
+        .await
+        .unwrap();
+
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["offset"], 2);
+        assert_eq!(body["rows"][0]["id"], "c");
+    }
 }
@@ -12,19 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::config::ScriptSettings;
 use crate::ops::get::ViewOptions;
-use crate::ops::JsonWithStatusCodeResponse;
+use crate::ops::require::register_require;
+use crate::ops::script_cache::ScriptCache;
+use crate::ops::script_engine::ScriptEngine;
+use crate::ops::{apply_script_limits, script_error, JsonWithStatusCodeResponse};
 use axum::http::StatusCode;
 use axum::Json;
 use boa_engine::property::Attribute;
 use boa_engine::{Context, JsValue, Source};
-use boa_runtime::Console;
 use bson::Document;
 use serde_json::{json, Value};
 use std::panic;
+use std::path::Path;
 use tracing::warn;
 
-pub fn execute_script(
+/// Runs a break glass script. `require` loads modules from `lib_folder`, when there is one.
+pub async fn execute_script(
+    script_cache: &ScriptCache,
+    script_engine: &dyn ScriptEngine,
+    lib_folder: Option<&Path>,
     source_file: &str,
     view_options: &ViewOptions,
 ) -> Result<Vec<Document>, JsonWithStatusCodeResponse> {
@@ -33,31 +41,34 @@ pub fn execute_script(
         "** BREAK GLASS ** execute_script"
     );
 
-    let script_source = std::fs::read_to_string(source_file).map_err(|e| {
+    let script_source = script_cache.load(Path::new(source_file)).map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({"error": e.to_string()})),
         )
     })?;
 
-    inner_execute_script(&script_source, view_options)
+    script_engine
+        .run_view_script(
+            script_source,
+            view_options.clone(),
+            lib_folder.map(Path::to_path_buf),
+        )
+        .await
 }
 
-fn inner_execute_script(
+pub(crate) fn inner_execute_script(
+    context: &mut Context<'_>,
     script: &str,
     view_options: &ViewOptions,
+    limits: &ScriptSettings,
+    lib_folder: Option<&Path>,
 ) -> Result<Vec<Document>, JsonWithStatusCodeResponse> {
-    let mut context = Context::default();
+    apply_script_limits(context, limits);
 
-    let console = Console::init(&mut context);
-    context
-        .register_global_property(Console::NAME, console, Attribute::all())
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": e.to_string()})),
-            )
-        })?;
+    if let Some(lib_folder) = lib_folder {
+        register_require(context, lib_folder).map_err(script_error)?;
+    }
 
     // convert view_options to a serde_json::Value
     let view_options_value = serde_json::to_value(view_options).map_err(|e| {
@@ -67,7 +78,7 @@ fn inner_execute_script(
         )
     })?;
 
-    let view_options_js = JsValue::from_json(&view_options_value, &mut context).map_err(|e| {
+    let view_options_js = JsValue::from_json(&view_options_value, context).map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({"error": e.to_string()})),
@@ -85,16 +96,11 @@ fn inner_execute_script(
 
     let src = Source::from_bytes(script.as_bytes());
 
-    context.eval(src).map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": e.to_string()})),
-        )
-    })?;
+    context.eval(src).map_err(script_error)?;
 
     let result = context
         .global_object()
-        .get("result", &mut context)
+        .get("result", context)
         .map_err(|e| {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -103,7 +109,7 @@ fn inner_execute_script(
         })?;
 
     let json = panic::catch_unwind(panic::AssertUnwindSafe(|| {
-        result.to_json(&mut context).unwrap()
+        result.to_json(context).unwrap()
     })).map_err(|_| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -137,6 +143,7 @@ fn inner_execute_script(
 mod tests {
     use super::*;
     use crate::ops::get::ViewOptions;
+    use crate::ops::{is_script_timeout, new_script_context};
 
     #[tokio::test]
     async fn execute_script_returns_a_document() {
@@ -145,6 +152,7 @@ mod tests {
             group: false,
             group_level: 0,
             include_docs: false,
+            attachments: false,
             descending: false,
             limit: None,
             skip: 0,
@@ -167,7 +175,14 @@ mod tests {
 
             result = main(view_options)"#;
 
-        let result = inner_execute_script(script, &view_options).unwrap();
+        let result = inner_execute_script(
+            &mut new_script_context().unwrap(),
+            script,
+            &view_options,
+            &ScriptSettings::default(),
+            None,
+        )
+        .unwrap();
 
         assert_eq!(result.len(), 1);
     }
@@ -179,6 +194,7 @@ mod tests {
             group: false,
             group_level: 0,
             include_docs: false,
+            attachments: false,
             descending: false,
             limit: None,
             skip: 0,
@@ -201,8 +217,93 @@ mod tests {
 
             result = main(view_options)"#;
 
-        let result = inner_execute_script(script, &view_options);
+        let result = inner_execute_script(
+            &mut new_script_context().unwrap(),
+            script,
+            &view_options,
+            &ScriptSettings::default(),
+            None,
+        );
 
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn execute_script_stops_a_runaway_loop() {
+        let view_options = ViewOptions {
+            reduce: false,
+            group: false,
+            group_level: 0,
+            include_docs: false,
+            attachments: false,
+            descending: false,
+            limit: None,
+            skip: 0,
+            start_key: vec![],
+            end_key: vec![],
+            startkey_docid: None,
+            endkey_docid: None,
+            keys: vec![],
+        };
+
+        let script = r#"
+            while (true) {}
+
+            result = []"#;
+
+        let limits = ScriptSettings {
+            loop_iteration_limit: 1000,
+            ..Default::default()
+        };
+        let result = inner_execute_script(
+            &mut new_script_context().unwrap(),
+            script,
+            &view_options,
+            &limits,
+            None,
+        );
+
+        assert!(is_script_timeout(&result.unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn execute_script_stops_runaway_recursion() {
+        let view_options = ViewOptions {
+            reduce: false,
+            group: false,
+            group_level: 0,
+            include_docs: false,
+            attachments: false,
+            descending: false,
+            limit: None,
+            skip: 0,
+            start_key: vec![],
+            end_key: vec![],
+            startkey_docid: None,
+            endkey_docid: None,
+            keys: vec![],
+        };
+
+        let script = r#"
+            function grow(depth) {
+                return grow(depth + 1);
+            }
+
+            result = grow(0)"#;
+
+        // Kept low so an unoptimised build doesn't run out of native stack first.
+        let limits = ScriptSettings {
+            recursion_limit: 50,
+            ..Default::default()
+        };
+        let result = inner_execute_script(
+            &mut new_script_context().unwrap(),
+            script,
+            &view_options,
+            &limits,
+            None,
+        );
+
+        assert_eq!(result.unwrap_err().1 .0["error"], "script_stack_limit");
+    }
 }
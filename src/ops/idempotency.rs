@@ -0,0 +1,368 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::db::Database;
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use crate::tenancy::with_tenant;
+use axum::body::Body;
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use bson::{doc, Bson, DateTime, Document};
+use http_body_util::BodyExt;
+use mongodb::options::{FindOneOptions, ReplaceOptions};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use tracing::{info, warn};
+
+/// The collection used to record completed writes. Records carry an `expires_at` date, which the
+/// TTL index `create_ttl_indexes` makes has MongoDB delete them by.
+pub const IDEMPOTENCY_COLLECTION: &str = "_couchapi_idempotency";
+
+const EXPIRES_FIELD: &str = "expires_at";
+
+fn record_id(db: &str, key: &str) -> String {
+    format!("{}/{}", db, key)
+}
+
+/// Identifies the write a key was first used for, so the key can't replay its response for a
+/// different one. Covers the method, the document, the query string and the body.
+pub fn request_fingerprint(
+    method: &str,
+    path: &str,
+    params: &HashMap<String, String>,
+    body: &[u8],
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method);
+    hasher.update([0]);
+    hasher.update(path);
+    for (name, value) in params.iter().collect::<BTreeMap<_, _>>() {
+        hasher.update([0]);
+        hasher.update(name);
+        hasher.update("=");
+        hasher.update(value);
+    }
+    hasher.update([0]);
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Creates the TTL index that expires idempotency records, in the shared collection and in each
+/// tenant's. A failure is logged, and leaves records to be kept until they're removed by hand.
+pub async fn create_ttl_indexes(db: &(dyn Database + Send + Sync), tenants: Vec<Option<String>>) {
+    for tenant in tenants {
+        let created = with_tenant(
+            tenant.clone(),
+            db.create_ttl_index(IDEMPOTENCY_COLLECTION, EXPIRES_FIELD),
+        )
+        .await;
+
+        match created {
+            Ok(()) => info!(tenant = tenant, "idempotency ttl index ready"),
+            Err(e) => warn!(
+                tenant = tenant,
+                error = e.to_string(),
+                "unable to create idempotency ttl index"
+            ),
+        }
+    }
+}
+
+/// Returns the stored response for a write that has already completed with the given key, if
+/// there is one. This lets a client retry a write after a network failure without creating a
+/// second revision or tripping over a conflict with its own first attempt. A key first used for
+/// a different write gets a `422` rather than that write's response.
+pub async fn replay_idempotent_write(
+    state: &AppState,
+    db: &str,
+    key: &Option<String>,
+    fingerprint: &str,
+) -> Result<Option<Response>, JsonWithStatusCodeResponse> {
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+
+    let record = state
+        .db
+        .find_one(
+            IDEMPOTENCY_COLLECTION,
+            &record_id(db, key),
+            FindOneOptions::default(),
+        )
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal server error", "details": e.to_string()})),
+            )
+        })?;
+
+    let record = match record {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+
+    if record.get_str("fingerprint") != Ok(fingerprint) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({
+                "error": "idempotency_key_reused",
+                "reason": "The idempotency key was already used for a different request."
+            })),
+        ));
+    }
+
+    let status = record
+        .get_i32("status")
+        .ok()
+        .and_then(|s| StatusCode::from_u16(s as u16).ok())
+        .unwrap_or(StatusCode::OK);
+    let body = record
+        .get("body")
+        .map(|b| b.clone().into_relaxed_extjson())
+        .unwrap_or(Value::Null);
+
+    let mut response = Json(body).into_response();
+    *response.status_mut() = status;
+
+    if let Ok(headers) = record.get_document("headers") {
+        for (k, v) in headers {
+            let header = (
+                HeaderName::from_bytes(k.as_bytes()),
+                v.as_str().map(HeaderValue::from_str),
+            );
+
+            if let (Ok(name), Some(Ok(value))) = header {
+                response.headers_mut().insert(name, value);
+            }
+        }
+    }
+
+    response
+        .headers_mut()
+        .insert("X-Idempotency-Replayed", HeaderValue::from_static("true"));
+
+    Ok(Some(response))
+}
+
+/// Records a successful write against the given key so a retry can be answered with the same
+/// response. A failure to record is logged rather than returned, as the write itself has
+/// already happened.
+pub async fn record_idempotent_write(
+    state: &AppState,
+    db: &str,
+    key: &Option<String>,
+    fingerprint: &str,
+    response: Response,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let key = match key {
+        Some(key) if response.status().is_success() => key,
+        _ => return Ok(response),
+    };
+
+    let (parts, body) = response.into_parts();
+    let bytes = BodyExt::collect(body)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal server error", "details": e.to_string()})),
+            )
+        })?
+        .to_bytes();
+
+    let body: Value = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+
+    let headers = parts
+        .headers
+        .iter()
+        .filter(|(k, _)| *k != axum::http::header::CONTENT_LENGTH)
+        .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.to_string(), Bson::from(v))))
+        .collect::<Document>();
+
+    let id = record_id(db, key);
+    let created_at = DateTime::now();
+    let expires_at = created_at.to_system_time() + state.idempotency.ttl();
+    let record = doc! {
+        "_id": &id,
+        "db": db,
+        "fingerprint": fingerprint,
+        "status": parts.status.as_u16() as i32,
+        "body": bson::to_bson(&body).unwrap_or(Bson::Null),
+        "headers": headers,
+        "created_at": created_at,
+        EXPIRES_FIELD: DateTime::from_system_time(expires_at),
+    };
+
+    let options = ReplaceOptions::builder().upsert(true).build();
+
+    if let Err(e) = state
+        .db
+        .replace_one(IDEMPOTENCY_COLLECTION, doc! { "_id": &id }, record, options)
+        .await
+    {
+        warn!(
+            db = db,
+            key = key.as_str(),
+            error = e.to_string(),
+            "unable to record idempotency key"
+        );
+    }
+
+    Ok(Response::from_parts(parts, Body::from(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+
+    fn state(mock: MockDatabase) -> AppState {
+        AppState::for_tests(mock)
+    }
+
+    #[tokio::test]
+    async fn test_replay_without_key_does_nothing() {
+        let state = state(MockDatabase::new());
+
+        let result = replay_idempotent_write(&state, "db", &None, "fingerprint")
+            .await
+            .unwrap();
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replay_returns_stored_response() {
+        let mut mock = MockDatabase::new();
+
+        mock.expect_find_one()
+            .withf(|coll, id, _| coll == IDEMPOTENCY_COLLECTION && id == "db/key")
+            .returning(|_, _, _| {
+                Box::pin(async {
+                    Ok(Some(doc! {
+                        "_id": "db/key",
+                        "fingerprint": "fingerprint",
+                        "status": 201,
+                        "body": { "ok": true, "id": "doc", "rev": "1-abc" },
+                        "headers": { "location": "/doc" },
+                    }))
+                })
+            });
+
+        let state = state(mock);
+
+        let key = Some("key".to_string());
+        let response = replay_idempotent_write(&state, "db", &key, "fingerprint")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["location"], "/doc");
+        assert_eq!(response.headers()["X-Idempotency-Replayed"], "true");
+
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({ "ok": true, "id": "doc", "rev": "1-abc" }));
+
+        // The same key for another write isn't answered with this one's response.
+        let (status, Json(body)) = replay_idempotent_write(&state, "db", &key, "other")
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "idempotency_key_reused");
+    }
+
+    #[test]
+    fn test_request_fingerprint() {
+        let params = HashMap::from([("rev".to_string(), "1-abc".to_string())]);
+        let fingerprint = request_fingerprint("PUT", "db/doc", &params, b"{}");
+
+        assert_eq!(
+            fingerprint,
+            request_fingerprint("PUT", "db/doc", &params, b"{}")
+        );
+        assert_ne!(
+            fingerprint,
+            request_fingerprint("DELETE", "db/doc", &params, b"{}")
+        );
+        assert_ne!(
+            fingerprint,
+            request_fingerprint("PUT", "db/other", &params, b"{}")
+        );
+        assert_ne!(
+            fingerprint,
+            request_fingerprint("PUT", "db/doc", &HashMap::new(), b"{}")
+        );
+        assert_ne!(
+            fingerprint,
+            request_fingerprint("PUT", "db/doc", &params, b"{\"a\":1}")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_stores_successful_response() {
+        // UpdateResult can't be built outside of the driver, so the mock fails the write which
+        // also shows the original response is still returned.
+        let mut mock = MockDatabase::new();
+
+        mock.expect_replace_one()
+            .withf(|coll, filter, record, _| {
+                coll == IDEMPOTENCY_COLLECTION
+                    && filter.get_str("_id") == Ok("db/key")
+                    && record.get_str("fingerprint") == Ok("fingerprint")
+                    && record.get_datetime("expires_at").is_ok()
+                    && record.get_i32("status") == Ok(201)
+                    && record.get_document("body").unwrap().get_str("rev") == Ok("1-abc")
+            })
+            .times(1)
+            .returning(|_, _, _, _| {
+                Box::pin(async { Err(mongodb::error::Error::custom("nothing")) })
+            });
+
+        let state = state(mock);
+
+        let mut response = Json(json!({ "ok": true, "id": "doc", "rev": "1-abc" })).into_response();
+        *response.status_mut() = StatusCode::CREATED;
+
+        let key = Some("key".to_string());
+        let response = record_idempotent_write(&state, "db", &key, "fingerprint", response)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_record_skips_failed_response() {
+        let state = state(MockDatabase::new());
+
+        let mut response = Json(json!({ "error": "conflict" })).into_response();
+        *response.status_mut() = StatusCode::CONFLICT;
+
+        let key = Some("key".to_string());
+        let response = record_idempotent_write(&state, "db", &key, "fingerprint", response)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+}
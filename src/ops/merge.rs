@@ -0,0 +1,116 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::metrics::record_script_execution;
+use crate::ops::db_access::document_lookup;
+use crate::ops::{is_script_timeout, JsonWithStatusCodeResponse};
+use crate::state::AppState;
+use axum::http::StatusCode;
+use axum::Json;
+use mongodb::options::FindOneOptions;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The merge hook's file name within a database's folder of the updates folder.
+pub const MERGE_HOOK: &str = "_merge.js";
+
+/// How many times a conflicting write is merged and retried before its `409` is returned.
+pub const MAX_MERGES: usize = 3;
+
+/// The database's merge hook, if it has one.
+fn merge_hook(state: &AppState, db: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(state.updates_folder.as_ref()?)
+        .join(db)
+        .join(MERGE_HOOK);
+    path.is_file().then_some(path)
+}
+
+/// Offers a write that conflicted to the database's merge hook, called as
+/// `function(incoming, stored)`. Returns the document to write in its place, at the stored rev, or
+/// `None` to keep the conflict: there's no hook, nothing stored, or the hook returned `null`.
+pub async fn merge_conflict(
+    state: &Arc<AppState>,
+    db: &str,
+    id: &str,
+    incoming: &Value,
+) -> Result<Option<Value>, JsonWithStatusCodeResponse> {
+    let Some(path) = merge_hook(state, db) else {
+        return Ok(None);
+    };
+
+    let stored = state
+        .db
+        .find_one(db, id, FindOneOptions::default())
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": e.to_string()})),
+            )
+        })?;
+    let Some(stored) = stored.map(|s| json!(s)) else {
+        return Ok(None);
+    };
+
+    let source = state.script_cache.load(&path).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+    })?;
+
+    // The hook is run as an update function would be, its arguments in place of `doc` and `req`.
+    let start = Instant::now();
+    let result = state
+        .script_engine
+        .run_update(
+            source,
+            Some(incoming.clone()),
+            stored.clone(),
+            PathBuf::from(state.updates_folder.as_ref().unwrap()),
+            document_lookup(state.clone(), db.to_string()),
+        )
+        .await;
+
+    record_script_execution(
+        "merge",
+        db,
+        "",
+        MERGE_HOOK,
+        match &result {
+            Ok(Value::Object(_)) | Ok(Value::Null) => "ok",
+            Ok(_) => "invalid_result",
+            Err(e) if is_script_timeout(e) => "timeout",
+            Err(_) => "script_error",
+        },
+        start.elapsed().as_secs_f64(),
+    );
+
+    let mut merged = match result? {
+        Value::Object(merged) => merged,
+        Value::Null => return Ok(None),
+        _ => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "merge hook must return an object or null"})),
+            ))
+        }
+    };
+
+    merged.insert("_id".to_string(), json!(id));
+    merged.insert("_rev".to_string(), stored["_rev"].clone());
+    Ok(Some(Value::Object(merged)))
+}
@@ -12,17 +12,49 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod admin;
+mod builtins;
 pub mod bulk;
+pub mod bulk_stream;
+pub mod changes;
 pub mod create_update;
+pub mod db_access;
+pub mod db_info;
 pub mod delete;
+#[cfg(feature = "deno")]
+mod deno_engine;
+pub mod geo;
 pub mod get;
 mod get_js;
+pub mod idempotency;
+mod merge;
+pub mod query_server;
+pub mod replication;
+mod require;
+pub mod schema;
+pub mod script_cache;
+pub mod script_engine;
+pub mod script_pool;
+pub mod search;
+pub mod security;
+pub mod snapshot;
+mod transform;
 pub mod update;
+pub mod uuids;
+pub mod view_rows;
 
+use crate::config::ScriptSettings;
+use crate::ops::builtins::register_builtins;
+use crate::request_timeout::remaining_time;
 use crate::state::AppState;
 use axum::http::StatusCode;
 use axum::Json;
+use base64::Engine;
+use boa_engine::property::Attribute;
+use boa_engine::{Context, JsError, JsResult};
+use boa_runtime::Console;
 use bson::Document;
+use mongodb::options::{FindOneOptions, ReadConcern};
 use serde_json::{json, Value};
 use std::error::Error;
 use std::sync::Arc;
@@ -39,6 +71,49 @@ macro_rules! not_found {
 
 pub type JsonWithStatusCodeResponse = (StatusCode, Json<Value>);
 
+/// Checks a rev from a request is in CouchDB's `N-hex` form before it goes anywhere near a query.
+pub fn validate_rev(rev: &str) -> Result<(), JsonWithStatusCodeResponse> {
+    let valid = rev.split_once('-').is_some_and(|(number, hash)| {
+        number.parse::<u64>().is_ok()
+            && !number.starts_with('+')
+            && !hash.is_empty()
+            && hash.chars().all(|c| c.is_ascii_hexdigit())
+    });
+
+    if valid {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "bad_request", "reason": "Invalid rev format"})),
+        ))
+    }
+}
+
+/// Replaces inline `_attachments` data with CouchDB's stubs, as responses carry unless
+/// `attachments=true` is asked for.
+pub fn stub_attachments(doc: &mut Value) {
+    let Some(attachments) = doc.get_mut("_attachments").and_then(Value::as_object_mut) else {
+        return;
+    };
+
+    for attachment in attachments.values_mut().filter_map(Value::as_object_mut) {
+        let Some(data) = attachment.remove("data") else {
+            continue;
+        };
+        if !attachment.contains_key("length") {
+            let length = data
+                .as_str()
+                .and_then(|data| base64::engine::general_purpose::STANDARD.decode(data).ok())
+                .map(|data| data.len());
+            if let Some(length) = length {
+                attachment.insert("length".to_string(), json!(length));
+            }
+        }
+        attachment.insert("stub".to_string(), json!(true));
+    }
+}
+
 /// check_conflict checks to see if the document exists and if it does, returns a 409
 /// conflict error.
 pub async fn check_conflict(
@@ -47,7 +122,11 @@ pub async fn check_conflict(
     id: &str,
 ) -> Result<JsonWithStatusCodeResponse, Box<dyn Error>> {
     // Grab the document to determine if it exists or not
-    let document = match state.db.find_one(&collection, id).await {
+    let document = match state
+        .db
+        .find_one(&collection, id, FindOneOptions::default())
+        .await
+    {
         Ok(document) => document,
         Err(e) => {
             return Err(Box::new(e));
@@ -68,8 +147,14 @@ pub async fn get_item_from_db(
     state: Arc<AppState>,
     db: String,
     id: String,
+    read_concern: Option<ReadConcern>,
 ) -> Result<Document, JsonWithStatusCodeResponse> {
-    let document = match state.db.find_one(&db, &id).await {
+    let options = FindOneOptions::builder()
+        .read_concern(read_concern)
+        .max_time(remaining_time())
+        .build();
+
+    let document = match state.db.find_one(&db, &id, options).await {
         Ok(d) => match d {
             Some(d) => d,
             None => {
@@ -87,6 +172,60 @@ pub async fn get_item_from_db(
     Ok(document)
 }
 
+/// Returns a `Context` ready for a script, with the console and CouchDB's helpers defined.
+pub fn new_script_context() -> JsResult<Context<'static>> {
+    let mut context = Context::default();
+
+    let console = Console::init(&mut context);
+    context.register_global_property(Console::NAME, console, Attribute::all())?;
+    register_builtins(&mut context)?;
+
+    Ok(context)
+}
+
+/// Applies the configured runtime limits to a script's `Context`.
+pub fn apply_script_limits(context: &mut Context<'_>, limits: &ScriptSettings) {
+    let runtime_limits = context.runtime_limits_mut();
+    runtime_limits.set_loop_iteration_limit(limits.loop_iteration_limit);
+    runtime_limits.set_recursion_limit(limits.recursion_limit);
+    runtime_limits.set_stack_size_limit(limits.stack_size_limit);
+}
+
+/// Converts an error raised while evaluating a script. A script that hit the loop iteration limit
+/// is reported as a timeout, and one that hit the recursion or stack limits as a stack limit.
+pub fn script_error(e: JsError) -> JsonWithStatusCodeResponse {
+    if e.as_native().is_some_and(|n| n.is_runtime_limit()) {
+        if e.to_string().contains("loop iteration") {
+            return script_timeout();
+        }
+
+        return script_stack_limit(&e.to_string());
+    }
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": e.to_string()})),
+    )
+}
+
+pub fn script_timeout() -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "script_timeout", "reason": "The script took too long to run."})),
+    )
+}
+
+pub fn script_stack_limit(reason: &str) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "script_stack_limit", "reason": reason})),
+    )
+}
+
+pub fn is_script_timeout(e: &JsonWithStatusCodeResponse) -> bool {
+    e.1.get("error") == Some(&json!("script_timeout"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,6 +234,55 @@ mod tests {
     use mongodb::error::Error as MongoError;
     use std::sync::Arc;
 
+    #[test]
+    fn test_validate_rev() {
+        for rev in ["1-abc", "12-0123456789abcdef0123456789abcdef", "3-ABC"] {
+            assert!(validate_rev(rev).is_ok(), "{}", rev);
+        }
+
+        for rev in [
+            "",
+            "1",
+            "1-",
+            "-abc",
+            "a-abc",
+            "+1-abc",
+            "1-xyz",
+            "1-abc-def",
+            "test_rev",
+        ] {
+            let (status, Json(body)) = validate_rev(rev).unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["reason"], "Invalid rev format");
+        }
+    }
+
+    #[test]
+    fn test_stub_attachments() {
+        let mut doc = json!({
+            "_id": "a",
+            "_attachments": {
+                "note.txt": {"content_type": "text/plain", "data": "aGVsbG8="},
+                "logo.png": {"content_type": "image/png", "length": 10, "stub": true},
+            }
+        });
+        stub_attachments(&mut doc);
+        assert_json_eq!(
+            doc,
+            json!({
+                "_id": "a",
+                "_attachments": {
+                    "note.txt": {"content_type": "text/plain", "length": 5, "stub": true},
+                    "logo.png": {"content_type": "image/png", "length": 10, "stub": true},
+                }
+            })
+        );
+
+        let mut doc = json!({"_id": "b"});
+        stub_attachments(&mut doc);
+        assert_json_eq!(doc, json!({"_id": "b"}));
+    }
+
     #[tokio::test]
     async fn get_item_from_db_returns_document_when_found() {
         let mut mock = MockDatabase::new();
@@ -102,21 +290,21 @@ mod tests {
         let expected_document = bson::doc! { "name": "test" };
 
         mock.expect_find_one()
-            .withf(move |_, id| id == "test_id")
-            .returning(move |_, _| {
+            .withf(move |_, id, _| id == "test_id")
+            .returning(move |_, _, _| {
                 Box::pin(async move { Ok(Some(bson::doc! { "name": "test" })) })
             });
 
-        let state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let state = Arc::new(AppState::for_tests(mock));
 
-        let result = get_item_from_db(state.clone(), "test_db".to_string(), "test_id".to_string())
-            .await
-            .unwrap();
+        let result = get_item_from_db(
+            state.clone(),
+            "test_db".to_string(),
+            "test_id".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
 
         assert_eq!(result, expected_document);
     }
@@ -126,18 +314,18 @@ mod tests {
         let mut mock = MockDatabase::new();
 
         mock.expect_find_one()
-            .returning(|_, _| Box::pin(async { Ok(None) }));
+            .returning(|_, _, _| Box::pin(async { Ok(None) }));
 
-        let state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let state = Arc::new(AppState::for_tests(mock));
 
-        let result = get_item_from_db(state.clone(), "test_db".to_string(), "test_id".to_string())
-            .await
-            .unwrap_err();
+        let result = get_item_from_db(
+            state.clone(),
+            "test_db".to_string(),
+            "test_id".to_string(),
+            None,
+        )
+        .await
+        .unwrap_err();
 
         assert_eq!(result.0, StatusCode::NOT_FOUND);
         assert_json_eq!(result.1 .0, json!({ "error": "not_found" }));
@@ -148,18 +336,18 @@ mod tests {
         let mut mock = MockDatabase::new();
 
         mock.expect_find_one()
-            .returning(|_, _| Box::pin(async { Err(MongoError::custom("nothing")) }));
+            .returning(|_, _, _| Box::pin(async { Err(MongoError::custom("nothing")) }));
 
-        let state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let state = Arc::new(AppState::for_tests(mock));
 
-        let result = get_item_from_db(state.clone(), "test_db".to_string(), "test_id".to_string())
-            .await
-            .unwrap_err();
+        let result = get_item_from_db(
+            state.clone(),
+            "test_db".to_string(),
+            "test_id".to_string(),
+            None,
+        )
+        .await
+        .unwrap_err();
 
         assert_eq!(result.0, StatusCode::INTERNAL_SERVER_ERROR);
         assert!(result.1 .0.get("error").is_some());
@@ -170,14 +358,9 @@ mod tests {
         let mut mock = MockDatabase::new();
 
         mock.expect_find_one()
-            .returning(|_, _| Box::pin(async { Err(MongoError::custom("nothing")) }));
+            .returning(|_, _, _| Box::pin(async { Err(MongoError::custom("nothing")) }));
 
-        let state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let state = Arc::new(AppState::for_tests(mock));
 
         let result = check_conflict(state.clone(), "test_db".to_string(), "test_id").await;
 
@@ -189,14 +372,9 @@ mod tests {
         let mut mock = MockDatabase::new();
 
         mock.expect_find_one()
-            .returning(|_, _| Box::pin(async { Ok(None) }));
+            .returning(|_, _, _| Box::pin(async { Ok(None) }));
 
-        let state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let state = Arc::new(AppState::for_tests(mock));
 
         let result = check_conflict(state.clone(), "test_db".to_string(), "test_id")
             .await
@@ -211,14 +389,9 @@ mod tests {
         let mut mock = MockDatabase::new();
 
         mock.expect_find_one()
-            .returning(|_, _| Box::pin(async { Ok(Some(bson::doc! { "_id": "test_id" })) }));
+            .returning(|_, _, _| Box::pin(async { Ok(Some(bson::doc! { "_id": "test_id" })) }));
 
-        let state = Arc::new(AppState {
-            db: Box::new(mock),
-            views: None,
-            updates_folder: None,
-            couchdb_details: None,
-        });
+        let state = Arc::new(AppState::for_tests(mock));
 
         let result = check_conflict(state.clone(), "test_db".to_string(), "test_id")
             .await
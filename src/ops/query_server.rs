@@ -0,0 +1,333 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::{QueryServerSettings, ScriptSettings};
+use crate::ops::{script_timeout, JsonWithStatusCodeResponse};
+use axum::http::StatusCode;
+use axum::Json;
+use serde_json::{json, Value};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// A CouchDB query server running as a child process. Commands and replies are one line of JSON
+/// each, so requests take turns on the one process. It's started on first use, and started again
+/// after it crashes or a script times out.
+pub struct QueryServer {
+    settings: QueryServerSettings,
+    timeout: Duration,
+    process: Mutex<Option<Process>>,
+}
+
+struct Process {
+    // Held so the process is killed when this is dropped.
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+/// Why a query server command failed.
+enum QueryServerError {
+    /// The process couldn't be reached or replied with something other than the protocol.
+    Process(String),
+
+    /// The function threw, with CouchDB's error name and reason.
+    Script(String, String),
+}
+
+impl QueryServer {
+    pub fn new(settings: QueryServerSettings, script_settings: &ScriptSettings) -> Self {
+        QueryServer {
+            settings,
+            timeout: Duration::from_millis(script_settings.timeout_ms),
+            process: Mutex::new(None),
+        }
+    }
+
+    /// Runs the update function `func` from the design document `ddoc`, returning `[doc, response]`
+    /// as a script run by the built-in engine does.
+    pub async fn update(
+        &self,
+        ddoc_id: &str,
+        ddoc: &Value,
+        func: &str,
+        doc: &Value,
+        req: &Value,
+    ) -> Result<Value, JsonWithStatusCodeResponse> {
+        let mut process = self.process.lock().await;
+        if process.is_none() {
+            *process = Some(self.spawn()?);
+        }
+
+        let result = tokio::time::timeout(self.timeout, async {
+            let running = process.as_mut().unwrap();
+
+            // The query server keeps design documents by id, so sending it again picks up any
+            // change to the script.
+            running
+                .exchange(&json!(["ddoc", "new", ddoc_id, ddoc]))
+                .await?;
+            running
+                .exchange(&json!(["ddoc", ddoc_id, ["updates", func], [doc, req]]))
+                .await
+        })
+        .await;
+
+        match result {
+            Ok(Ok(Value::Array(reply))) if reply.len() == 3 && reply[0] == "up" => {
+                Ok(json!([reply[1], reply[2]]))
+            }
+            Ok(Ok(reply)) => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "unexpected reply from query server", "reason": reply})),
+            )),
+            Ok(Err(QueryServerError::Script(error, reason))) => {
+                let status = match error.as_str() {
+                    "forbidden" => StatusCode::FORBIDDEN,
+                    "unauthorized" => StatusCode::UNAUTHORIZED,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                Err((status, Json(json!({"error": error, "reason": reason}))))
+            }
+            Ok(Err(QueryServerError::Process(e))) => {
+                *process = None;
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "query server failed", "reason": e})),
+                ))
+            }
+            Err(_) => {
+                // There's no telling where the process is in the conversation, so start afresh.
+                *process = None;
+                Err(script_timeout())
+            }
+        }
+    }
+
+    fn spawn(&self) -> Result<Process, JsonWithStatusCodeResponse> {
+        let mut child = Command::new(&self.settings.command)
+            .args(&self.settings.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "unable to start query server", "reason": e.to_string()})),
+                )
+            })?;
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+
+        Ok(Process {
+            _child: child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+impl Process {
+    /// Sends a command and waits for its reply, passing any `log` lines on to the server log.
+    async fn exchange(&mut self, command: &Value) -> Result<Value, QueryServerError> {
+        let mut line = command.to_string();
+        line.push('\n');
+        self.stdin
+            .write_all(line.as_bytes())
+            .await
+            .map_err(|e| QueryServerError::Process(e.to_string()))?;
+        self.stdin
+            .flush()
+            .await
+            .map_err(|e| QueryServerError::Process(e.to_string()))?;
+
+        loop {
+            let line = self
+                .stdout
+                .next_line()
+                .await
+                .map_err(|e| QueryServerError::Process(e.to_string()))?
+                .ok_or_else(|| QueryServerError::Process("query server exited".to_string()))?;
+
+            let reply: Value = serde_json::from_str(&line)
+                .map_err(|e| QueryServerError::Process(format!("{}: {}", e, line)))?;
+
+            match reply_kind(&reply) {
+                Reply::Log(message) => info!(message = message.as_str(), "script log"),
+                Reply::Error(error, reason) => {
+                    warn!(
+                        error = error.as_str(),
+                        reason = reason.as_str(),
+                        "query server error"
+                    );
+                    return Err(QueryServerError::Script(error, reason));
+                }
+                Reply::Value => return Ok(reply),
+            }
+        }
+    }
+}
+
+enum Reply {
+    Log(String),
+    Error(String, String),
+    Value,
+}
+
+/// Sorts a line from the query server into a log message, an error, or a reply. Errors come as
+/// `["error", name, reason]`, or as `{"forbidden": reason}` and `{"unauthorized": reason}` when
+/// a function throws one of those.
+fn reply_kind(reply: &Value) -> Reply {
+    let text = |v: &Value| v.as_str().map_or_else(|| v.to_string(), str::to_string);
+
+    match reply {
+        Value::Array(parts) if parts.len() == 2 && parts[0] == "log" => Reply::Log(text(&parts[1])),
+        Value::Array(parts) if parts.len() == 3 && parts[0] == "error" => {
+            Reply::Error(text(&parts[1]), text(&parts[2]))
+        }
+        Value::Object(o) if o.len() == 1 => {
+            let (error, reason) = o.iter().next().unwrap();
+            if error == "forbidden" || error == "unauthorized" {
+                Reply::Error(error.clone(), text(reason))
+            } else {
+                Reply::Value
+            }
+        }
+        _ => Reply::Value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stand-in query server: accepts any design document, logs, then answers every update with
+    /// the request's method, throws for `deny`, and exits for `crash`.
+    const FAKE_QUERY_SERVER: &str = r#"
+while read -r line; do
+  case "$line" in
+    '["ddoc","new"'*) echo true ;;
+    *'"deny"'*) echo '{"forbidden":"not allowed"}' ;;
+    *'"crash"'*) exit 1 ;;
+    *'"slow"'*) sleep 1; echo true ;;
+    *) echo '["log","updating"]'; echo '["up",{"_id":"a"},{"body":"done"}]' ;;
+  esac
+done
+"#;
+
+    fn query_server(timeout_ms: u64) -> QueryServer {
+        QueryServer::new(
+            QueryServerSettings {
+                command: "sh".to_string(),
+                args: vec!["-c".to_string(), FAKE_QUERY_SERVER.to_string()],
+            },
+            &ScriptSettings {
+                timeout_ms,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let server = query_server(5000);
+        let ddoc = json!({"_id": "_design/orders", "updates": {}});
+
+        let result = server
+            .update(
+                "orders/_design/orders",
+                &ddoc,
+                "ok",
+                &Value::Null,
+                &json!({}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result, json!([{"_id": "a"}, {"body": "done"}]));
+
+        let denied = server
+            .update(
+                "orders/_design/orders",
+                &ddoc,
+                "deny",
+                &Value::Null,
+                &json!({}),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(denied.0, StatusCode::FORBIDDEN);
+        assert_eq!(denied.1 .0["reason"], "not allowed");
+
+        let crashed = server
+            .update(
+                "orders/_design/orders",
+                &ddoc,
+                "crash",
+                &Value::Null,
+                &json!({}),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(crashed.1 .0["error"], "query server failed");
+
+        // A fresh process takes over after a crash.
+        assert!(server
+            .update(
+                "orders/_design/orders",
+                &ddoc,
+                "ok",
+                &Value::Null,
+                &json!({})
+            )
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_update_times_out() {
+        let server = query_server(100);
+        let ddoc = json!({"_id": "_design/orders", "updates": {}});
+
+        let result = server
+            .update(
+                "orders/_design/orders",
+                &ddoc,
+                "slow",
+                &Value::Null,
+                &json!({}),
+            )
+            .await;
+        assert!(crate::ops::is_script_timeout(&result.unwrap_err()));
+    }
+
+    #[test]
+    fn test_reply_kind() {
+        assert!(matches!(reply_kind(&json!(["log", "hi"])), Reply::Log(m) if m == "hi"));
+        assert!(matches!(
+            reply_kind(&json!(["error", "bad", "why"])),
+            Reply::Error(e, r) if e == "bad" && r == "why"
+        ));
+        assert!(matches!(
+            reply_kind(&json!({"unauthorized": "who?"})),
+            Reply::Error(e, _) if e == "unauthorized"
+        ));
+        assert!(matches!(reply_kind(&json!(true)), Reply::Value));
+        assert!(matches!(reply_kind(&json!(["up", null, {}])), Reply::Value));
+    }
+}
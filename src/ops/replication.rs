@@ -0,0 +1,690 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What CouchDB's replicator needs of a target: checkpoints kept in `_local` documents,
+//! `_revs_diff`, writes with `new_edits=false` and `_ensure_full_commit`. Only the winning rev of
+//! each document is kept, so a replicated rev that loses to the stored one is dropped rather than
+//! kept as a conflict.
+
+use crate::auth::check_design_write;
+use crate::concern::{read_concern_for_request, write_concern_for_request};
+use crate::couchdb::maybe_write;
+use crate::db::is_duplicate_key;
+use crate::not_found;
+use crate::ops::bulk::{bulk_concurrency, bulk_failure};
+use crate::ops::{check_conflict, get_item_from_db, validate_rev, JsonWithStatusCodeResponse};
+use crate::state::AppState;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use bson::{doc, Document};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use mongodb::options::{DeleteOptions, FindOneOptions, ReadConcern, ReplaceOptions};
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// The prefix of local document ids.
+const LOCAL_PREFIX: &str = "_local/";
+
+/// Local documents live in a collection of their own, so `_all_docs`, views, `_changes` and
+/// exports never see them.
+fn local_collection(db: &str) -> String {
+    format!("{}._local", db)
+}
+
+/// The replication endpoints don't exist unless `replication_target` is set.
+fn check_enabled(state: &AppState) -> Result<(), JsonWithStatusCodeResponse> {
+    if state.replication_target {
+        Ok(())
+    } else {
+        Err(not_found!())
+    }
+}
+
+fn bad_request(reason: &str) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "bad_request", "reason": reason})),
+    )
+}
+
+fn internal_error(e: mongodb::error::Error) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "internal server error", "details": e.to_string()})),
+    )
+}
+
+/// The generation and hash of a rev, which order revs as CouchDB does when picking a winner.
+fn rev_order(rev: &str) -> Option<(u64, &str)> {
+    let (generation, hash) = rev.split_once('-')?;
+    Some((generation.parse().ok()?, hash))
+}
+
+/// Returns `true` if `rev` beats the stored rev, so replicating it replaces the document.
+fn wins(rev: &str, current: Option<&str>) -> bool {
+    match (rev_order(rev), current.and_then(rev_order)) {
+        (Some(rev), Some(current)) => rev > current,
+        (Some(_), None) => current.is_none(),
+        (None, _) => false,
+    }
+}
+
+async fn current_document(
+    state: &AppState,
+    coll: &str,
+    id: &str,
+    read_concern: Option<ReadConcern>,
+) -> Result<Option<Document>, JsonWithStatusCodeResponse> {
+    let options = FindOneOptions::builder().read_concern(read_concern).build();
+    state
+        .db
+        .find_one(coll, id, options)
+        .await
+        .map_err(internal_error)
+}
+
+/// get_local returns a local document, such as a replication checkpoint.
+pub async fn get_local(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path((db, id)): Path<(String, String)>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    check_enabled(&state)?;
+
+    let path = format!("{}{}", LOCAL_PREFIX, id);
+    if let Some(r) = maybe_write(
+        &state.couchdb_details,
+        &db,
+        Method::GET,
+        None,
+        &path,
+        &params,
+    )
+    .await?
+    {
+        return Ok(r);
+    }
+
+    let read_concern = read_concern_for_request(&state, &params)?;
+    let document = get_item_from_db(state, local_collection(&db), path, read_concern).await?;
+    Ok(Json(json!(document)).into_response())
+}
+
+/// put_local writes a local document. Local revs are `0-N`, counting the writes, and as in CouchDB
+/// an update has to name the current one.
+pub async fn put_local(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path((db, id)): Path<(String, String)>,
+    Json(mut payload): Json<Value>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    check_enabled(&state)?;
+
+    let path = format!("{}{}", LOCAL_PREFIX, id);
+    if let Some(r) = maybe_write(
+        &state.couchdb_details,
+        &db,
+        Method::PUT,
+        Some(&payload),
+        &path,
+        &params,
+    )
+    .await?
+    {
+        return Ok(r);
+    }
+
+    let Some(fields) = payload.as_object_mut() else {
+        return Err(bad_request("Document must be a JSON object"));
+    };
+
+    let rev = fields
+        .get("_rev")
+        .and_then(Value::as_str)
+        .or(params.get("rev").map(String::as_str))
+        .map(str::to_string);
+    let generation = match &rev {
+        Some(rev) => local_generation(rev)?,
+        None => 0,
+    };
+    let new_rev = format!("0-{}", generation + 1);
+    fields.insert("_id".to_string(), json!(path));
+    fields.insert("_rev".to_string(), json!(new_rev));
+
+    let document = bson::to_document(&payload).map_err(|e| bad_request(&e.to_string()))?;
+    let filter = match rev {
+        Some(rev) => doc! { "_id": &path, "_rev": rev },
+        None => doc! { "_id": &path, "_rev": { "$exists": false } },
+    };
+    let options = ReplaceOptions::builder()
+        .upsert(true)
+        .write_concern(write_concern_for_request(&state, &params)?)
+        .build();
+
+    match state
+        .db
+        .replace_one(&local_collection(&db), filter, document, options)
+        .await
+    {
+        Ok(_) => {}
+        Err(e) if is_duplicate_key(&e) => {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(json!({"error": "conflict", "reason": "Document update conflict."})),
+            ));
+        }
+        Err(e) => return Err(internal_error(e)),
+    }
+
+    let response = Json(json!({"ok": true, "id": path, "rev": new_rev}));
+    Ok((StatusCode::CREATED, response).into_response())
+}
+
+/// The write count in a local rev.
+fn local_generation(rev: &str) -> Result<u64, JsonWithStatusCodeResponse> {
+    rev.strip_prefix("0-")
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| bad_request("Invalid rev format"))
+}
+
+/// delete_local deletes a local document at the given rev. Nothing of it is kept.
+pub async fn delete_local(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path((db, id)): Path<(String, String)>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    check_enabled(&state)?;
+
+    let path = format!("{}{}", LOCAL_PREFIX, id);
+    if let Some(r) = maybe_write(
+        &state.couchdb_details,
+        &db,
+        Method::DELETE,
+        None,
+        &path,
+        &params,
+    )
+    .await?
+    {
+        return Ok(r);
+    }
+
+    let Some(rev) = params.get("rev") else {
+        return Err((
+            StatusCode::PRECONDITION_FAILED,
+            Json(json!({"error": "missing rev"})),
+        ));
+    };
+    local_generation(rev)?;
+
+    let coll = local_collection(&db);
+    let options = DeleteOptions::builder()
+        .write_concern(write_concern_for_request(&state, &params)?)
+        .build();
+    let deleted = state
+        .db
+        .delete_one(&coll, doc! { "_id": &path, "_rev": rev }, options)
+        .await
+        .map_err(internal_error)?;
+
+    if deleted == 0 {
+        return match check_conflict(state, coll, &path).await {
+            Ok(e) => Err(e),
+            Err(e) => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal server error", "details": e.to_string()})),
+            )),
+        };
+    }
+
+    Ok(Json(json!({"ok": true, "id": path, "rev": "0-0"})).into_response())
+}
+
+/// revs_diff tells the replicator which of the revs it has the target is missing. Only the current
+/// rev of each document is stored, so a rev is missing if it would replace it.
+pub async fn revs_diff(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path(db): Path<String>,
+    Json(payload): Json<BTreeMap<String, Vec<String>>>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    check_enabled(&state)?;
+
+    if let Some(r) = maybe_write(
+        &state.couchdb_details,
+        &db,
+        Method::POST,
+        Some(&json!(payload)),
+        "_revs_diff",
+        &params,
+    )
+    .await?
+    {
+        return Ok(r);
+    }
+
+    let read_concern = read_concern_for_request(&state, &params)?;
+    let diffs = stream::iter(payload)
+        .map(|(id, revs)| {
+            let (state, db, read_concern) = (&state, &db, read_concern.clone());
+            async move {
+                let current = current_document(state, db, &id, read_concern).await?;
+                let current_rev = current.as_ref().and_then(|d| d.get_str("_rev").ok());
+                let missing = revs
+                    .into_iter()
+                    .filter(|rev| wins(rev, current_rev))
+                    .collect::<Vec<_>>();
+
+                let diff = match current_rev {
+                    Some(rev) => json!({ "missing": missing, "possible_ancestors": [rev] }),
+                    None => json!({ "missing": missing }),
+                };
+                Ok::<_, JsonWithStatusCodeResponse>((id, diff, !missing.is_empty()))
+            }
+        })
+        .buffered(bulk_concurrency(&state))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let diffs = diffs
+        .into_iter()
+        .filter(|(_, _, missing)| *missing)
+        .map(|(id, diff, _)| (id, diff))
+        .collect::<serde_json::Map<_, _>>();
+
+    Ok(Json(Value::Object(diffs)).into_response())
+}
+
+/// ensure_full_commit is a no-op kept for older replicators, as writes are already acknowledged
+/// at the write concern asked for.
+pub async fn ensure_full_commit(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path(db): Path<String>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    check_enabled(&state)?;
+
+    if let Some(r) = maybe_write(
+        &state.couchdb_details,
+        &db,
+        Method::POST,
+        Some(&json!({})),
+        "_ensure_full_commit",
+        &params,
+    )
+    .await?
+    {
+        return Ok(r);
+    }
+
+    let response = Json(json!({"ok": true, "instance_start_time": "0"}));
+    Ok((StatusCode::CREATED, response).into_response())
+}
+
+/// Writes with `new_edits=false` come from the replicator, so they're refused unless the server
+/// is a replication target.
+fn check_replicating(state: &AppState) -> Result<(), JsonWithStatusCodeResponse> {
+    if state.replication_target {
+        Ok(())
+    } else {
+        Err(bad_request(
+            "new_edits=false is only accepted when replication_target is set.",
+        ))
+    }
+}
+
+/// Writes a batch from `_bulk_docs` with `new_edits=false`, returning entries only for the
+/// documents that failed, as CouchDB does.
+pub(crate) async fn replicate_docs(
+    state: &Arc<AppState>,
+    db: &str,
+    params: &HashMap<String, String>,
+    docs: Vec<Value>,
+) -> Result<Vec<Value>, JsonWithStatusCodeResponse> {
+    check_replicating(state)?;
+    let write_concern = write_concern_for_request(state, params)?;
+
+    let failures = stream::iter(docs)
+        .map(|doc| {
+            let write_concern = write_concern.clone();
+            async move {
+                let id = doc.get("_id").and_then(Value::as_str).map(str::to_string);
+                replicate_doc(state, db, write_concern, doc)
+                    .await
+                    .err()
+                    .map(|e| bulk_failure(&id, e))
+            }
+        })
+        .buffered(bulk_concurrency(state))
+        .filter_map(|failure| async move { failure })
+        .collect()
+        .await;
+
+    Ok(failures)
+}
+
+/// Writes one document sent with `PUT /:db/:item?new_edits=false`.
+pub(crate) async fn replicate_item(
+    state: &Arc<AppState>,
+    db: &str,
+    item: String,
+    params: &HashMap<String, String>,
+    mut doc: Value,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    check_replicating(state)?;
+    check_design_write(&item)?;
+    let write_concern = write_concern_for_request(state, params)?;
+
+    doc["_id"] = json!(item);
+    let rev = doc.get("_rev").cloned();
+    replicate_doc(state, db, write_concern, doc).await?;
+
+    let response = Json(json!({"ok": true, "id": item, "rev": rev}));
+    Ok((StatusCode::CREATED, response).into_response())
+}
+
+/// Stores a document at the rev it was sent with, if that rev beats the stored one. Attachment
+/// stubs are filled in from the stored document, which the replicator relies on when an
+/// attachment hasn't changed.
+async fn replicate_doc(
+    state: &AppState,
+    db: &str,
+    write_concern: Option<mongodb::options::WriteConcern>,
+    mut doc: Value,
+) -> Result<(), JsonWithStatusCodeResponse> {
+    let Some(fields) = doc.as_object_mut() else {
+        return Err(bad_request("Document must be a JSON object"));
+    };
+    let Some(id) = fields
+        .get("_id")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+    else {
+        return Err(bad_request("Document id is required to replicate it."));
+    };
+    check_design_write(&id)?;
+    if id.starts_with('_') && !id.starts_with("_design/") {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "illegal_docid",
+                "reason": "Only design documents may be replicated with a reserved id."
+            })),
+        ));
+    }
+    let Some(rev) = fields
+        .get("_rev")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+    else {
+        return Err(bad_request("Document rev is required to replicate it."));
+    };
+    validate_rev(&rev)?;
+
+    // The rev history is only needed to build a rev tree, which isn't kept.
+    fields.remove("_revisions");
+
+    let current = current_document(state, db, &id, None).await?;
+    let current_rev = current.as_ref().and_then(|d| d.get_str("_rev").ok());
+    if !wins(&rev, current_rev) {
+        return Ok(());
+    }
+
+    if let Some(attachments) = fields
+        .get_mut("_attachments")
+        .and_then(Value::as_object_mut)
+    {
+        let stored = current
+            .as_ref()
+            .and_then(|d| d.get_document("_attachments").ok());
+        for (name, attachment) in attachments.iter_mut() {
+            if attachment.get("stub").and_then(Value::as_bool) != Some(true) {
+                continue;
+            }
+            match stored.and_then(|s| s.get_document(name).ok()) {
+                Some(stored) if stored.contains_key("data") => *attachment = json!(stored),
+                _ => {
+                    return Err((
+                        StatusCode::PRECONDITION_FAILED,
+                        Json(json!({
+                            "error": "missing_stub",
+                            "reason": format!("Attachment {} has no stored data.", name)
+                        })),
+                    ));
+                }
+            }
+        }
+    }
+
+    let document = bson::to_document(&doc).map_err(|e| bad_request(&e.to_string()))?;
+    let filter = match current_rev {
+        Some(current_rev) => doc! { "_id": &id, "_rev": current_rev },
+        None => doc! { "_id": &id, "_rev": { "$exists": false } },
+    };
+    let options = ReplaceOptions::builder()
+        .upsert(true)
+        .write_concern(write_concern)
+        .build();
+
+    match state.db.replace_one(db, filter, document, options).await {
+        Ok(_) => Ok(()),
+        Err(e) if is_duplicate_key(&e) => Err((
+            StatusCode::CONFLICT,
+            Json(json!({"error": "conflict", "reason": "Document update conflict."})),
+        )),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{duplicate_key_error, MockDatabase};
+    use http_body_util::BodyExt;
+    use std::sync::Mutex;
+
+    fn state(mock: MockDatabase) -> Arc<AppState> {
+        Arc::new(AppState {
+            replication_target: true,
+            ..AppState::for_tests(mock)
+        })
+    }
+
+    async fn body(response: Response) -> Value {
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_wins() {
+        assert!(wins("1-a", None));
+        assert!(wins("2-a", Some("1-f")));
+        assert!(wins("2-b", Some("2-a")));
+        assert!(!wins("2-a", Some("2-a")));
+        assert!(!wins("2-a", Some("2-b")));
+        assert!(!wins("1-f", Some("2-a")));
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let mut disabled = state(MockDatabase::new());
+        Arc::get_mut(&mut disabled).unwrap().replication_target = false;
+
+        let (status, _) = ensure_full_commit(
+            State(disabled.clone()),
+            Query(HashMap::new()),
+            Path("db".to_string()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, Json(body)) =
+            replicate_docs(&disabled, "db", &HashMap::new(), vec![json!({"_id": "a"})])
+                .await
+                .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["reason"],
+            "new_edits=false is only accepted when replication_target is set."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revs_diff() {
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one().returning(|_, id, _| {
+            let found = match id {
+                "a" => Some(doc! { "_id": "a", "_rev": "2-b" }),
+                "b" => Some(doc! { "_id": "b", "_rev": "3-c" }),
+                _ => None,
+            };
+            Box::pin(async move { Ok(found) })
+        });
+
+        let payload = BTreeMap::from([
+            ("a".to_string(), vec!["2-b".to_string(), "3-d".to_string()]),
+            ("b".to_string(), vec!["1-a".to_string(), "3-c".to_string()]),
+            ("c".to_string(), vec!["1-x".to_string()]),
+        ]);
+        let response = revs_diff(
+            State(state(mock)),
+            Query(HashMap::new()),
+            Path("db".to_string()),
+            Json(payload),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            body(response).await,
+            json!({
+                "a": {"missing": ["3-d"], "possible_ancestors": ["2-b"]},
+                "c": {"missing": ["1-x"]},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_local() {
+        let written = Arc::new(Mutex::new(vec![]));
+        let mut mock = MockDatabase::new();
+        let w = written.clone();
+        mock.expect_replace_one()
+            .returning(move |coll, filter, replacement, _| {
+                w.lock()
+                    .unwrap()
+                    .push((coll.to_string(), filter, replacement));
+                Box::pin(async { Err(duplicate_key_error()) })
+            });
+
+        let (status, _) = put_local(
+            State(state(mock)),
+            Query(HashMap::new()),
+            Path(("db".to_string(), "checkpoint".to_string())),
+            Json(json!({"_rev": "0-3", "history": []})),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let written = written.lock().unwrap();
+        let (coll, filter, replacement) = &written[0];
+        assert_eq!(coll, "db._local");
+        assert_eq!(filter, &doc! { "_id": "_local/checkpoint", "_rev": "0-3" });
+        assert_eq!(replacement.get_str("_id").unwrap(), "_local/checkpoint");
+        assert_eq!(replacement.get_str("_rev").unwrap(), "0-4");
+    }
+
+    #[tokio::test]
+    async fn test_replicate_docs() {
+        let written = Arc::new(Mutex::new(vec![]));
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one().returning(|_, id, _| {
+            let found = match id {
+                "old" => Some(doc! { "_id": "old", "_rev": "5-a" }),
+                "att" => Some(doc! {
+                    "_id": "att",
+                    "_rev": "1-a",
+                    "_attachments": { "a.txt": { "content_type": "text/plain", "data": "aGk=" } },
+                }),
+                _ => None,
+            };
+            Box::pin(async move { Ok(found) })
+        });
+        let w = written.clone();
+        mock.expect_replace_one()
+            .returning(move |_, filter, replacement, _| {
+                w.lock().unwrap().push((filter, replacement));
+                Box::pin(async { Err(mongodb::error::Error::custom("written")) })
+            });
+
+        let failures = replicate_docs(
+            &state(mock),
+            "db",
+            &HashMap::new(),
+            vec![
+                json!({"_id": "new", "_rev": "3-c", "_revisions": {"start": 3, "ids": ["c", "b", "a"]}}),
+                json!({"_id": "old", "_rev": "4-f"}),
+                json!({"_id": "att", "_rev": "2-b", "_attachments": {"a.txt": {"stub": true}}}),
+                json!({"_id": "gone", "_rev": "2-d", "_deleted": true}),
+                json!({"_id": "norev"}),
+            ],
+        )
+        .await
+        .unwrap();
+
+        // The writes fail in the mock, so each written document reports an error.
+        let ids = failures.iter().map(|f| f["id"].clone()).collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![json!("new"), json!("att"), json!("gone"), json!("norev")]
+        );
+        assert_eq!(failures[3]["error"], "bad_request");
+
+        let written = written.lock().unwrap();
+        assert_eq!(written.len(), 3);
+        let find = |id: &str| {
+            written
+                .iter()
+                .find(|(filter, _)| filter.get_str("_id") == Ok(id))
+                .unwrap()
+        };
+
+        let (filter, replacement) = find("new");
+        assert_eq!(filter, &doc! { "_id": "new", "_rev": { "$exists": false } });
+        assert!(!replacement.contains_key("_revisions"));
+
+        let (filter, replacement) = find("att");
+        assert_eq!(filter, &doc! { "_id": "att", "_rev": "1-a" });
+        assert_eq!(
+            replacement
+                .get_document("_attachments")
+                .unwrap()
+                .get_document("a.txt")
+                .unwrap()
+                .get_str("data")
+                .unwrap(),
+            "aGk="
+        );
+        assert!(find("gone").1.get_bool("_deleted").unwrap());
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use boa_engine::{
+    Context,
+    JsArgs,
+    JsError,
+    JsNativeError,
+    JsResult,
+    JsString,
+    JsValue,
+    NativeFunction,
+    Source,
+};
+use std::path::{Component, Path, PathBuf};
+
+const LOAD_MODULE: &str = "__couchapi_load_module";
+
+/// Defines `require` on top of the native loader. Modules are CommonJS: each one runs once per
+/// script with `module`, `exports` and `require` in scope, and later calls get the same exports.
+pub(crate) const REQUIRE: &str = r#"
+var require = (function (load) {
+    var cache = {};
+
+    return function require(name) {
+        name = String(name).replace(/\.js$/, "");
+
+        if (Object.prototype.hasOwnProperty.call(cache, name)) {
+            return cache[name].exports;
+        }
+
+        var module = { exports: {} };
+        cache[name] = module;
+
+        var body = new Function("module", "exports", "require", load(name));
+        body(module, module.exports, require);
+
+        return module.exports;
+    };
+})(__couchapi_load_module);
+
+delete globalThis.__couchapi_load_module;
+"#;
+
+/// Makes `require("lib/foo")` available to a script, loading `lib/foo.js` from `folder`. Module
+/// names can't be absolute or climb out of the folder.
+pub fn register_require(context: &mut Context<'_>, folder: &Path) -> JsResult<()> {
+    let loader = NativeFunction::from_copy_closure_with_captures(
+        |_, args, folder: &PathBuf, context| {
+            let name = args
+                .get_or_undefined(0)
+                .to_string(context)?
+                .to_std_string_escaped();
+
+            let source = load_module(folder, &name)
+                .map_err(|e| JsError::from(JsNativeError::error().with_message(e)))?;
+
+            Ok(JsValue::from(JsString::from(source)))
+        },
+        folder.to_path_buf(),
+    );
+
+    context.register_global_callable(LOAD_MODULE, 1, loader)?;
+    context.eval(Source::from_bytes(REQUIRE))?;
+
+    Ok(())
+}
+
+pub(crate) fn load_module(folder: &Path, name: &str) -> Result<String, String> {
+    let relative = Path::new(name);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!("invalid module name {}", name));
+    }
+
+    let mut path = folder.join(relative);
+    if path.extension().is_none() {
+        path.set_extension("js");
+    }
+
+    std::fs::read_to_string(&path).map_err(|e| format!("unable to load module {}: {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_require() {
+        let dir = std::env::temp_dir().join(format!("couchapi-require-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("lib").join("format.js"),
+            r#"
+            var counter = require("lib/counter");
+            exports.price = function (p) { counter.calls += 1; return "£" + p.toFixed(2); };
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("lib").join("counter.js"),
+            "module.exports = { calls: 0 };",
+        )
+        .unwrap();
+
+        let mut context = Context::default();
+        register_require(&mut context, &dir).unwrap();
+
+        let result = context
+            .eval(Source::from_bytes(
+                r#"
+                var format = require("lib/format");
+                [format.price(3), require("lib/format.js") === format, require("lib/counter").calls]
+                "#,
+            ))
+            .unwrap()
+            .to_json(&mut context)
+            .unwrap();
+
+        let escape = context.eval(Source::from_bytes(r#"require("../secrets")"#));
+        let missing = context.eval(Source::from_bytes(r#"require("lib/missing")"#));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result, serde_json::json!(["£3.00", true, 1]));
+        assert!(escape
+            .unwrap_err()
+            .to_string()
+            .contains("invalid module name"));
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .contains("unable to load module"));
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use axum::http::StatusCode;
+use axum::Json;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A JSON Schema documents written to a database have to match. The validation keywords of draft
+/// 2020-12 are supported, along with `properties`, `additionalProperties`, `items` and the `allOf`,
+/// `anyOf`, `oneOf` and `not` combinations. References aren't followed.
+#[derive(Debug, Clone)]
+pub struct Schema {
+    schema: Value,
+    patterns: HashMap<String, Regex>,
+}
+
+impl Schema {
+    pub fn new(schema: Value) -> Result<Self, String> {
+        let mut patterns = HashMap::new();
+        collect_patterns(&schema, &mut patterns)?;
+
+        Ok(Schema { schema, patterns })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let schema = serde_json::from_str(&contents).map_err(|e| e.to_string())?;
+        Schema::new(schema)
+    }
+
+    /// Returns what's wrong with a document, each error prefixed with the JSON pointer to the
+    /// value at fault. CouchDB's own fields, those starting with `_`, are left out of the check.
+    pub fn validate(&self, document: &Value) -> Vec<String> {
+        let mut document = document.clone();
+        if let Some(fields) = document.as_object_mut() {
+            fields.retain(|field, _| !field.starts_with('_'));
+        }
+
+        let mut errors = vec![];
+        self.check(&self.schema, &document, "", &mut errors);
+        errors
+    }
+
+    fn check(&self, schema: &Value, value: &Value, at: &str, errors: &mut Vec<String>) {
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => {
+                errors.push(format!("{}: is not allowed", pointer(at)));
+                return;
+            }
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+        let mut fail = |reason: String| errors.push(format!("{}: {}", pointer(at), reason));
+
+        if let Some(types) = schema.get("type") {
+            let types = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                _ => types.as_str().into_iter().collect::<Vec<_>>(),
+            };
+            if !types.iter().any(|t| is_type(value, t)) {
+                fail(format!("must be of type {}", types.join(" or ")));
+            }
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum") {
+            if !allowed.contains(value) {
+                fail("must be one of the allowed values".to_string());
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if value != constant {
+                fail(format!("must be {}", constant));
+            }
+        }
+
+        if let Some(number) = value.as_f64() {
+            let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+            if let Some(minimum) = bound("minimum").filter(|m| number < *m) {
+                fail(format!("must be at least {}", minimum));
+            }
+            if let Some(maximum) = bound("maximum").filter(|m| number > *m) {
+                fail(format!("must be at most {}", maximum));
+            }
+            if let Some(minimum) = bound("exclusiveMinimum").filter(|m| number <= *m) {
+                fail(format!("must be more than {}", minimum));
+            }
+            if let Some(maximum) = bound("exclusiveMaximum").filter(|m| number >= *m) {
+                fail(format!("must be less than {}", maximum));
+            }
+            if let Some(divisor) = bound("multipleOf").filter(|d| (number / d).fract() != 0.0) {
+                fail(format!("must be a multiple of {}", divisor));
+            }
+        }
+
+        if let Some(string) = value.as_str() {
+            let length = string.chars().count() as u64;
+            if let Some(min) = count(schema, "minLength").filter(|m| length < *m) {
+                fail(format!("must be at least {} characters long", min));
+            }
+            if let Some(max) = count(schema, "maxLength").filter(|m| length > *m) {
+                fail(format!("must be at most {} characters long", max));
+            }
+            if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                if !self.patterns[pattern].is_match(string) {
+                    fail(format!("must match {}", pattern));
+                }
+            }
+        }
+
+        if let Some(items) = value.as_array() {
+            let length = items.len() as u64;
+            if let Some(min) = count(schema, "minItems").filter(|m| length < *m) {
+                fail(format!("must have at least {} items", min));
+            }
+            if let Some(max) = count(schema, "maxItems").filter(|m| length > *m) {
+                fail(format!("must have at most {} items", max));
+            }
+            let unique = schema.get("uniqueItems") == Some(&Value::Bool(true));
+            if unique
+                && items
+                    .iter()
+                    .enumerate()
+                    .any(|(i, v)| items[..i].contains(v))
+            {
+                fail("must not have duplicate items".to_string());
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    self.check(item_schema, item, &format!("{}/{}", at, i), errors);
+                }
+            }
+        }
+
+        if let Some(fields) = value.as_object() {
+            self.check_object(schema, fields, at, errors);
+        }
+
+        let matching = |keyword: &str| {
+            schema
+                .get(keyword)
+                .and_then(Value::as_array)
+                .map(|schemas| {
+                    schemas
+                        .iter()
+                        .filter(|s| self.matches(s, value, at))
+                        .count()
+                })
+        };
+        let any_of = matching("anyOf");
+        let one_of = matching("oneOf");
+        let not = schema.get("not").map(|s| self.matches(s, value, at));
+        let mut fail = |reason: &str| errors.push(format!("{}: {}", pointer(at), reason));
+        if any_of == Some(0) {
+            fail("must match at least one of anyOf");
+        }
+        if one_of.is_some_and(|n| n != 1) {
+            fail("must match exactly one of oneOf");
+        }
+        if not == Some(true) {
+            fail("must not match not");
+        }
+        for all_of in schema
+            .get("allOf")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            self.check(all_of, value, at, errors);
+        }
+    }
+
+    fn check_object(
+        &self,
+        schema: &Map<String, Value>,
+        fields: &Map<String, Value>,
+        at: &str,
+        errors: &mut Vec<String>,
+    ) {
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            if let Some(name) = required.as_str().filter(|n| !fields.contains_key(*n)) {
+                errors.push(format!("{}: is required", pointer(&field_at(at, name))));
+            }
+        }
+
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, field) in fields {
+            let field_at = field_at(at, name);
+            match properties.and_then(|p| p.get(name)) {
+                Some(property) => self.check(property, field, &field_at, errors),
+                None => {
+                    if let Some(additional) = schema.get("additionalProperties") {
+                        self.check(additional, field, &field_at, errors);
+                    }
+                }
+            }
+        }
+    }
+
+    fn matches(&self, schema: &Value, value: &Value, at: &str) -> bool {
+        let mut errors = vec![];
+        self.check(schema, value, at, &mut errors);
+        errors.is_empty()
+    }
+}
+
+/// Compiles each `pattern` in a schema up front, so a bad one is found when the schema is loaded.
+fn collect_patterns(schema: &Value, patterns: &mut HashMap<String, Regex>) -> Result<(), String> {
+    match schema {
+        Value::Object(fields) => {
+            if let Some(pattern) = fields.get("pattern").and_then(Value::as_str) {
+                let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+                patterns.insert(pattern.to_string(), regex);
+            }
+            for field in fields.values() {
+                collect_patterns(field, patterns)?;
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                collect_patterns(item, patterns)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        _ => false,
+    }
+}
+
+fn count(schema: &Map<String, Value>, keyword: &str) -> Option<u64> {
+    schema.get(keyword).and_then(Value::as_u64)
+}
+
+fn field_at(at: &str, name: &str) -> String {
+    format!("{}/{}", at, name.replace('~', "~0").replace('/', "~1"))
+}
+
+fn pointer(at: &str) -> &str {
+    if at.is_empty() {
+        "/"
+    } else {
+        at
+    }
+}
+
+/// Fails with CouchDB's `403 forbidden`, listing what's wrong, when a document doesn't match its
+/// database's schema. Deletions aren't checked.
+pub fn check_schema(
+    state: &AppState,
+    db: &str,
+    document: &Value,
+) -> Result<(), JsonWithStatusCodeResponse> {
+    let Some(schema) = state.schemas.as_ref().and_then(|s| s.get(db)) else {
+        return Ok(());
+    };
+    if document.get("_deleted") == Some(&Value::Bool(true)) {
+        return Ok(());
+    }
+
+    let errors = schema.validate(document);
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    metrics::increment_counter!("couchapi_schema_rejections_total", "db" => db.to_string());
+    Err((
+        StatusCode::FORBIDDEN,
+        Json(json!({
+            "error": "forbidden",
+            "reason": format!("Document doesn't match the schema: {}", errors.join("; ")),
+            "errors": errors,
+        })),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> Schema {
+        Schema::new(json!({
+            "type": "object",
+            "required": ["type", "total"],
+            "additionalProperties": false,
+            "properties": {
+                "type": { "const": "order" },
+                "total": { "type": "number", "minimum": 0 },
+                "status": { "enum": ["open", "paid"] },
+                "email": { "type": "string", "pattern": "^[^@]+@[^@]+$" },
+                "lines": {
+                    "type": "array",
+                    "minItems": 1,
+                    "items": {
+                        "type": "object",
+                        "required": ["sku"],
+                        "properties": { "sku": { "type": "string", "maxLength": 8 } }
+                    }
+                },
+                "ref": { "oneOf": [{ "type": "string" }, { "type": "integer" }] }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = schema();
+
+        let valid = json!({
+            "_id": "a",
+            "_rev": "1-a",
+            "type": "order",
+            "total": 10.5,
+            "status": "paid",
+            "email": "a@example.com",
+            "lines": [{ "sku": "SKU1" }],
+            "ref": 7
+        });
+        assert_eq!(schema.validate(&valid), Vec::<String>::new());
+
+        let invalid = json!({
+            "_id": "a",
+            "type": "refund",
+            "total": -1,
+            "status": "lost",
+            "email": "nobody",
+            "lines": [{ "sku": "A-VERY-LONG-SKU" }, {}],
+            "ref": 1.5,
+            "extra": true
+        });
+        assert_eq!(
+            schema.validate(&invalid),
+            vec![
+                "/type: must be \"order\"",
+                "/total: must be at least 0",
+                "/status: must be one of the allowed values",
+                "/email: must match ^[^@]+@[^@]+$",
+                "/lines/0/sku: must be at most 8 characters long",
+                "/lines/1/sku: is required",
+                "/ref: must match exactly one of oneOf",
+                "/extra: is not allowed",
+            ]
+        );
+
+        assert_eq!(
+            schema.validate(&json!({"type": "order"})),
+            vec!["/total: is required"]
+        );
+        assert!(Schema::new(json!({"pattern": "("})).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Holds the source of every script that has run, keyed by path. An entry is only used while the
+/// file's modification time matches, so an edited script is picked up on its next run.
+///
+/// Boa ties a compiled script to the `Context` that compiled it, and a `Context` can't move
+/// between threads, so it's the source that's kept rather than the compiled script.
+#[derive(Default)]
+pub struct ScriptCache {
+    entries: Mutex<HashMap<PathBuf, CachedScript>>,
+}
+
+struct CachedScript {
+    modified: SystemTime,
+    source: Arc<str>,
+}
+
+impl ScriptCache {
+    /// Returns the source of the script at `path`, reading it only when it isn't cached or has
+    /// changed since it was.
+    pub fn load(&self, path: &Path) -> io::Result<Arc<str>> {
+        let modified = path.metadata()?.modified()?;
+
+        if let Some(cached) = self.entries.lock().unwrap().get(path) {
+            if cached.modified == modified {
+                return Ok(cached.source.clone());
+            }
+        }
+
+        let source: Arc<str> = std::fs::read_to_string(path)?.into();
+        self.entries.lock().unwrap().insert(
+            path.to_path_buf(),
+            CachedScript {
+                modified,
+                source: source.clone(),
+            },
+        );
+
+        Ok(source)
+    }
+
+    /// Returns the path and modification time of every cached script, in path order.
+    pub fn loaded(&self) -> Vec<(PathBuf, SystemTime)> {
+        let mut loaded = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, cached)| (path.clone(), cached.modified))
+            .collect::<Vec<_>>();
+        loaded.sort();
+        loaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_load_reads_changed_scripts() {
+        let path =
+            std::env::temp_dir().join(format!("couchapi-script-{}.js", uuid::Uuid::new_v4()));
+        let cache = ScriptCache::default();
+
+        std::fs::write(&path, "result = [1];").unwrap();
+        assert_eq!(&*cache.load(&path).unwrap(), "result = [1];");
+
+        // Swap the contents underneath the cache without touching the modification time, to
+        // show the second load came from the cache.
+        let modified = path.metadata().unwrap().modified().unwrap();
+        std::fs::write(&path, "result = [2];").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified).unwrap();
+        assert_eq!(&*cache.load(&path).unwrap(), "result = [1];");
+
+        file.set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(&*cache.load(&path).unwrap(), "result = [2];");
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(cache.load(&path).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::{ScriptEngineKind, ScriptSettings};
+use crate::ops::db_access::DocumentLookup;
+use crate::ops::get::ViewOptions;
+use crate::ops::get_js::inner_execute_script;
+use crate::ops::script_pool::ScriptPool;
+use crate::ops::update::execute_javascript;
+use crate::ops::JsonWithStatusCodeResponse;
+use async_trait::async_trait;
+use bson::Document;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Runs update handlers and break glass scripts. Each engine applies the limits in
+/// `ScriptSettings` and offers `require` and CouchDB's helpers to the scripts it runs.
+#[async_trait]
+pub trait ScriptEngine: Send + Sync {
+    /// Calls the update function in `source` with `doc` and `req`, returning `[doc, response]`.
+    /// `db.get` looks documents up through `lookup`.
+    async fn run_update(
+        &self,
+        source: Arc<str>,
+        doc: Option<Value>,
+        req: Value,
+        lib_folder: PathBuf,
+        lookup: DocumentLookup,
+    ) -> Result<Value, JsonWithStatusCodeResponse>;
+
+    /// Runs a break glass script with `view_options` defined, returning the pipeline it leaves in
+    /// `result`.
+    async fn run_view_script(
+        &self,
+        source: Arc<str>,
+        view_options: ViewOptions,
+        lib_folder: Option<PathBuf>,
+    ) -> Result<Vec<Document>, JsonWithStatusCodeResponse>;
+}
+
+/// Builds the engine chosen by `scripts.engine`.
+pub fn new_script_engine(settings: &ScriptSettings) -> Box<dyn ScriptEngine> {
+    match settings.engine {
+        ScriptEngineKind::Boa => Box::new(BoaEngine::new(settings)),
+
+        #[cfg(feature = "deno")]
+        ScriptEngineKind::Deno => Box::new(crate::ops::deno_engine::DenoEngine::new(settings)),
+
+        #[cfg(not(feature = "deno"))]
+        ScriptEngineKind::Deno => {
+            panic!("scripts.engine is deno, but this build doesn't have the deno feature")
+        }
+    }
+}
+
+impl Default for Box<dyn ScriptEngine> {
+    fn default() -> Self {
+        new_script_engine(&ScriptSettings::default())
+    }
+}
+
+/// The built-in engine, running scripts with Boa on a `ScriptPool`.
+pub struct BoaEngine {
+    pool: ScriptPool,
+    settings: ScriptSettings,
+}
+
+impl BoaEngine {
+    pub fn new(settings: &ScriptSettings) -> Self {
+        BoaEngine {
+            pool: ScriptPool::new(settings),
+            settings: *settings,
+        }
+    }
+}
+
+#[async_trait]
+impl ScriptEngine for BoaEngine {
+    async fn run_update(
+        &self,
+        source: Arc<str>,
+        doc: Option<Value>,
+        req: Value,
+        lib_folder: PathBuf,
+        lookup: DocumentLookup,
+    ) -> Result<Value, JsonWithStatusCodeResponse> {
+        let limits = self.settings;
+
+        self.pool
+            .run(move |context| {
+                execute_javascript(context, &source, &req, &doc, &limits, &lib_folder, lookup)
+            })
+            .await
+    }
+
+    async fn run_view_script(
+        &self,
+        source: Arc<str>,
+        view_options: ViewOptions,
+        lib_folder: Option<PathBuf>,
+    ) -> Result<Vec<Document>, JsonWithStatusCodeResponse> {
+        let limits = self.settings;
+
+        self.pool
+            .run(move |context| {
+                inner_execute_script(
+                    context,
+                    &source,
+                    &view_options,
+                    &limits,
+                    lib_folder.as_deref(),
+                )
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_boa_engine() {
+        let engine: Box<dyn ScriptEngine> = Default::default();
+
+        let update = engine
+            .run_update(
+                "function(doc, req) { return [doc, {body: req.method + db.get('b').n}]; }".into(),
+                Some(json!({"_id": "a"})),
+                json!({"method": "PUT"}),
+                std::env::temp_dir(),
+                Arc::new(|_| Ok(Some(json!({"n": 1})))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(update, json!([{"_id": "a"}, {"body": "PUT1"}]));
+
+        let view_options = ViewOptions {
+            reduce: false,
+            group: false,
+            group_level: 0,
+            include_docs: false,
+            attachments: false,
+            descending: false,
+            limit: Some(5),
+            skip: 0,
+            start_key: vec![],
+            end_key: vec![],
+            startkey_docid: None,
+            endkey_docid: None,
+            keys: vec![],
+        };
+        let pipeline = engine
+            .run_view_script(
+                "result = [{ $match: { descending: view_options.descending } }]".into(),
+                view_options,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            pipeline,
+            vec![bson::doc! { "$match": { "descending": false } }]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::ScriptSettings;
+use crate::ops::{new_script_context, script_timeout, JsonWithStatusCodeResponse};
+use axum::http::StatusCode;
+use axum::Json;
+use boa_engine::Context;
+use serde_json::json;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce(&mut Context<'static>) + Send>;
+
+/// Threads dedicated to running scripts, so a heavy script holds up other scripts rather than
+/// the threads serving HTTP requests. Scripts wait in a bounded queue for a free thread; once it's
+/// full, new ones are turned away with a `503`.
+///
+/// Each thread keeps a `Context` ready, with the console and CouchDB's helpers defined, and hands
+/// it to the next script that runs there.
+pub struct ScriptPool {
+    sender: SyncSender<Job>,
+    timeout: Duration,
+}
+
+impl ScriptPool {
+    pub fn new(settings: &ScriptSettings) -> Self {
+        let workers = settings.workers.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4)
+        });
+
+        let (sender, receiver) = sync_channel::<Job>(settings.queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..workers {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("couchapi-script-{}", i))
+                .spawn(move || work(&receiver))
+                .expect("unable to start script worker");
+        }
+
+        ScriptPool {
+            sender,
+            timeout: Duration::from_millis(settings.timeout_ms),
+        }
+    }
+
+    /// Runs a script on the pool and gives up waiting for it after the configured timeout, which
+    /// includes any time spent queued. A script can't be stopped once it's started; the loop
+    /// iteration limit set on its `Context` is what eventually ends a runaway one.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, JsonWithStatusCodeResponse>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Context<'static>) -> Result<T, JsonWithStatusCodeResponse> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |context| {
+            let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(|| f(context))));
+        });
+
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                metrics::increment_counter!("couchapi_scripts_rejected_total");
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(json!({
+                        "error": "service_unavailable",
+                        "reason": "Too many scripts are waiting to run."
+                    })),
+                ));
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "script workers have stopped"})),
+                ));
+            }
+        }
+
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(Ok(result))) => result,
+            Ok(Ok(Err(_))) | Ok(Err(_)) => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "the script panicked"})),
+            )),
+            Err(_) => Err(script_timeout()),
+        }
+    }
+}
+
+impl Default for ScriptPool {
+    fn default() -> Self {
+        ScriptPool::new(&ScriptSettings::default())
+    }
+}
+
+/// Runs jobs until the pool is dropped.
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    let mut context = new_script_context().expect("unable to create script context");
+
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        job(&mut context);
+
+        // A script leaves its globals behind, and `let` and `const` declarations can't be removed,
+        // so each script gets a context of its own. The next one is built now, while the thread
+        // would otherwise be idle, rather than while a request waits for it.
+        context = new_script_context().expect("unable to create script context");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::{is_script_timeout, script_error};
+    use boa_engine::Source;
+
+    fn pool(workers: usize, queue_size: usize, timeout_ms: u64) -> ScriptPool {
+        ScriptPool::new(&ScriptSettings {
+            workers: Some(workers),
+            queue_size,
+            timeout_ms,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let pool = pool(1, 1, 5000);
+
+        assert_eq!(pool.run(|_| Ok(42)).await.unwrap(), 42);
+        assert_eq!(
+            pool.run(|_| -> Result<(), _> { panic!("boom") })
+                .await
+                .unwrap_err()
+                .1
+                 .0["error"],
+            "the script panicked"
+        );
+
+        // The worker survives a panicking script.
+        assert_eq!(pool.run(|_| Ok("still here")).await.unwrap(), "still here");
+    }
+
+    #[tokio::test]
+    async fn test_run_times_out() {
+        let pool = pool(1, 1, 10);
+
+        let result = pool
+            .run(|_| {
+                thread::sleep(Duration::from_millis(200));
+                Ok(())
+            })
+            .await;
+        assert!(is_script_timeout(&result.unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn test_run_rejects_when_queue_is_full() {
+        let pool = pool(1, 1, 5000);
+        let (release, wait) = std::sync::mpsc::channel::<()>();
+        let (started, is_started) = std::sync::mpsc::channel::<()>();
+
+        // Occupy the only worker, then fill the queue behind it.
+        pool.sender
+            .try_send(Box::new(move |_| {
+                started.send(()).unwrap();
+                wait.recv().unwrap();
+            }))
+            .unwrap();
+        is_started.recv().unwrap();
+        let (drained, is_drained) = std::sync::mpsc::channel::<()>();
+        pool.sender
+            .try_send(Box::new(move |_| drained.send(()).unwrap()))
+            .unwrap();
+
+        let rejected = pool.run(|_| Ok(())).await.unwrap_err();
+        assert_eq!(rejected.0, StatusCode::SERVICE_UNAVAILABLE);
+
+        release.send(()).unwrap();
+        is_drained.recv().unwrap();
+        assert_eq!(pool.run(|_| Ok(1)).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_gives_each_script_a_fresh_context() {
+        let pool = pool(1, 1, 5000);
+
+        for _ in 0..2 {
+            let result = pool
+                .run(|context| {
+                    context
+                        .eval(Source::from_bytes(
+                            "const seen = typeof leftover; var leftover = 1; [seen, sum([1, 2])]",
+                        ))
+                        .map_err(script_error)?
+                        .to_json(context)
+                        .map_err(script_error)
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(result, json!(["undefined", 3]));
+        }
+    }
+}
@@ -0,0 +1,642 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::access_log::DocsReturned;
+use crate::concern::read_concern_for_request;
+use crate::config::SearchIndex;
+use crate::couchdb::read_through;
+use crate::not_found;
+use crate::ops::get::convert_payload;
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::request_timeout::remaining_time;
+use crate::state::AppState;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bson::{doc, Bson, Document};
+use mongodb::options::AggregateOptions;
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Rows returned when the request doesn't give a `limit`, as in Cloudant.
+const DEFAULT_LIMIT: i64 = 25;
+
+/// The largest `limit` Cloudant allows.
+const MAX_LIMIT: i64 = 200;
+
+/// Where each result's relevance is kept while the results are sorted.
+const SCORE_FIELD: &str = "_couchapi_score";
+
+fn bad_request(reason: &str) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "bad_request", "reason": reason})),
+    )
+}
+
+/// A Lucene query split into what MongoDB can run: the free text goes to the text index and
+/// `field:value` terms become equality matches.
+#[derive(Debug, PartialEq, Default)]
+struct SearchQuery {
+    text: String,
+    fields: Document,
+}
+
+/// Splits on whitespace outside of double quotes.
+fn tokenize(q: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+
+    for c in q.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                token.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn term_value(value: &str) -> Bson {
+    if let Some(quoted) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        return Bson::String(quoted.to_string());
+    }
+
+    match value {
+        "true" => Bson::Boolean(true),
+        "false" => Bson::Boolean(false),
+        _ => value
+            .parse::<i64>()
+            .map(Bson::Int64)
+            .or_else(|_| value.parse::<f64>().map(Bson::Double))
+            .unwrap_or_else(|_| Bson::String(value.to_string())),
+    }
+}
+
+/// Parses the subset of Lucene that maps onto MongoDB: words, quoted phrases, `-` to exclude,
+/// `field:value` terms and `*:*`. Terms are combined as a text index combines them.
+fn parse_query(q: &str) -> Result<SearchQuery, JsonWithStatusCodeResponse> {
+    let mut query = SearchQuery::default();
+    let mut text = Vec::new();
+
+    for token in tokenize(q) {
+        if matches!(token.as_str(), "AND" | "OR" | "&&" | "||" | "*:*") {
+            continue;
+        }
+
+        let unquoted = token.split('"').step_by(2).collect::<String>();
+        if unquoted.contains(['(', ')', '[', ']', '{', '}', '^', '~']) {
+            return Err(bad_request(
+                "Search queries support words, phrases, -exclusions and field:value terms only",
+            ));
+        }
+
+        match token.split_once(':') {
+            Some((field, value)) if !field.is_empty() && !field.starts_with(['"', '-']) => {
+                let value = match value {
+                    "*" => Bson::Document(doc! { "$exists": true }),
+                    _ => term_value(value),
+                };
+                query.fields.insert(field, value);
+            }
+            _ => text.push(token),
+        }
+    }
+
+    query.text = text.join(" ");
+    Ok(query)
+}
+
+/// Parses Cloudant's `sort`, a field or a JSON list of them, each optionally prefixed with `-`
+/// for descending and suffixed with a type such as `<number>`.
+fn parse_sort(sort: &str) -> Result<Vec<(String, i32)>, JsonWithStatusCodeResponse> {
+    let fields: Vec<String> = match sort.trim_start().chars().next() {
+        Some('[') => serde_json::from_str(sort).map_err(|_| bad_request("Invalid sort"))?,
+        Some('"') => vec![serde_json::from_str(sort).map_err(|_| bad_request("Invalid sort"))?],
+        _ => vec![sort.to_string()],
+    };
+
+    Ok(fields
+        .iter()
+        .map(|field| {
+            let field = field.split('<').next().unwrap_or_default();
+            match field.strip_prefix('-') {
+                Some(field) => (field.to_string(), -1),
+                None => (field.trim_start_matches('+').to_string(), 1),
+            }
+        })
+        .collect())
+}
+
+fn encode_bookmark(skip: i64) -> String {
+    URL_SAFE_NO_PAD.encode(skip.to_string())
+}
+
+fn decode_bookmark(bookmark: &str) -> Result<i64, JsonWithStatusCodeResponse> {
+    URL_SAFE_NO_PAD
+        .decode(bookmark)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|skip| skip.parse::<i64>().ok())
+        .filter(|skip| *skip >= 0)
+        .ok_or_else(|| bad_request("Invalid bookmark"))
+}
+
+/// Builds the aggregation that finds, sorts and pages the results, counting every match
+/// alongside the page.
+fn search_pipeline(
+    index: &SearchIndex,
+    query: &SearchQuery,
+    sort: &[(String, i32)],
+    skip: i64,
+    limit: i64,
+) -> Vec<Document> {
+    let mut pipeline = Vec::new();
+    let has_text = !query.text.is_empty();
+
+    let score = match &index.atlas_index {
+        Some(atlas_index) => {
+            if has_text {
+                pipeline.push(doc! {
+                    "$search": {
+                        "index": atlas_index,
+                        "text": { "query": &query.text, "path": { "wildcard": "*" } },
+                    }
+                });
+            }
+            if !query.fields.is_empty() {
+                pipeline.push(doc! { "$match": query.fields.clone() });
+            }
+            "searchScore"
+        }
+        None => {
+            let mut filter = query.fields.clone();
+            if has_text {
+                filter.insert("$text", doc! { "$search": &query.text });
+            }
+            if !filter.is_empty() {
+                pipeline.push(doc! { "$match": filter });
+            }
+            "textScore"
+        }
+    };
+
+    if has_text {
+        pipeline.push(doc! { "$addFields": { SCORE_FIELD: { "$meta": score } } });
+    }
+
+    let mut order = Document::new();
+    for (field, direction) in sort {
+        order.insert(field, direction);
+    }
+    if sort.is_empty() && has_text {
+        order.insert(SCORE_FIELD, -1);
+    }
+    if !order.contains_key("_id") {
+        order.insert("_id", 1);
+    }
+
+    pipeline.push(doc! {
+        "$facet": {
+            "rows": [{ "$sort": order }, { "$skip": skip }, { "$limit": limit }],
+            "total": [{ "$count": "n" }],
+        }
+    });
+    pipeline
+}
+
+fn search_row(
+    index: &SearchIndex,
+    mut document: Document,
+    sort: &[(String, i32)],
+    include_docs: bool,
+) -> Value {
+    let score = document.remove(SCORE_FIELD);
+
+    let order: Vec<Value> = match sort.is_empty() {
+        true => score.iter().map(|s| json!(s)).collect(),
+        false => sort
+            .iter()
+            .map(|(field, _)| json!(document.get(field).unwrap_or(&Bson::Null)))
+            .collect(),
+    };
+
+    let fields: serde_json::Map<String, Value> = index
+        .fields
+        .iter()
+        .filter_map(|field| Some((field.clone(), json!(document.get(field)?))))
+        .collect();
+
+    let mut row = json!({
+        "id": json!(document.get("_id").unwrap_or(&Bson::Null)),
+        "order": order,
+        "fields": fields,
+    });
+    if include_docs {
+        row["doc"] = json!(document);
+    }
+    row
+}
+
+async fn inner_search(
+    state: &AppState,
+    db: String,
+    index: &SearchIndex,
+    params: HashMap<String, String>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let read_concern = read_concern_for_request(state, &params)?;
+
+    let query = parse_query(
+        params
+            .get("q")
+            .or_else(|| params.get("query"))
+            .ok_or_else(|| bad_request("q is required"))?,
+    )?;
+
+    let sort = match params.get("sort") {
+        Some(sort) => parse_sort(sort)?,
+        None => vec![],
+    };
+
+    let limit = match params.get("limit") {
+        Some(limit) => limit
+            .parse::<i64>()
+            .ok()
+            .filter(|limit| *limit > 0)
+            .ok_or_else(|| bad_request("Invalid limit"))?,
+        None => DEFAULT_LIMIT,
+    };
+    if limit > MAX_LIMIT {
+        return Err(bad_request(&format!("limit must be at most {}", MAX_LIMIT)));
+    }
+
+    let skip = match params.get("bookmark") {
+        Some(bookmark) => decode_bookmark(bookmark)?,
+        None => 0,
+    };
+    let include_docs = params.get("include_docs").is_some_and(|i| i == "true");
+
+    let pipeline = search_pipeline(index, &query, &sort, skip, limit);
+    let options = AggregateOptions::builder()
+        .read_concern(read_concern)
+        .max_time(remaining_time())
+        .build();
+
+    let permit = match &state.view_limits {
+        Some(view_limits) => view_limits.acquire(&db).await?,
+        None => None,
+    };
+    let results = state.db.aggregate(&db, pipeline, options).await;
+    drop(permit);
+
+    let result = results
+        .ok()
+        .and_then(|results| results.into_iter().next())
+        .and_then(|result| result.to_document().ok());
+    let Some(result) = result else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "search_failed"})),
+        ));
+    };
+
+    let total_rows = result
+        .get_array("total")
+        .ok()
+        .and_then(|total| total.first())
+        .and_then(Bson::as_document)
+        .and_then(|total| total.get("n"))
+        .and_then(|n| n.as_i32().map(i64::from).or_else(|| n.as_i64()))
+        .unwrap_or(0);
+
+    let rows: Vec<Value> = result
+        .get_array("rows")
+        .map(|rows| rows.iter().filter_map(Bson::as_document).cloned().collect())
+        .unwrap_or_else(|_| Vec::<Document>::new())
+        .into_iter()
+        .map(|document| search_row(index, document, &sort, include_docs))
+        .collect();
+
+    let returned = rows.len();
+    let mut response = Json(json!({
+        "total_rows": total_rows,
+        "bookmark": encode_bookmark(skip + returned as i64),
+        "rows": rows,
+    }))
+    .into_response();
+    response.extensions_mut().insert(DocsReturned(returned));
+
+    Ok(response)
+}
+
+fn search_index<'a>(
+    state: &'a AppState,
+    db: &str,
+    design: &str,
+    index: &str,
+) -> Option<&'a SearchIndex> {
+    state
+        .search_indexes
+        .as_ref()?
+        .get(db)?
+        .get(design)?
+        .get(index)
+}
+
+async fn search_or_read_through(
+    state: &AppState,
+    (db, design, index): (String, String, String),
+    params: HashMap<String, String>,
+    payload: Option<&Value>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    if let Some(search_index) = search_index(state, &db, &design, &index) {
+        let mut all_params = payload
+            .map(|p| convert_payload(p.clone()))
+            .unwrap_or_default();
+        all_params.extend(params);
+        return inner_search(state, db, search_index, all_params).await;
+    }
+
+    match state
+        .couchdb_details
+        .as_ref()
+        .filter(|c| c.should_read_through(&db))
+    {
+        Some(couchdb_details) => {
+            let mapped_db = couchdb_details.map_for_db(db.as_str());
+            let path = format!("{}/_design/{}/_search/{}", mapped_db, design, index);
+            let method = match payload {
+                Some(_) => Method::POST,
+                None => Method::GET,
+            };
+            read_through(couchdb_details, method, payload, &path, &params).await
+        }
+        None => Err(not_found!()),
+    }
+}
+
+pub async fn search(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<(String, String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    search_or_read_through(&state, path, params, None).await
+}
+
+pub async fn post_search(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<(String, String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(payload): Json<Value>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    search_or_read_through(&state, path, params, Some(&payload)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use bson::RawDocumentBuf;
+    use http_body_util::BodyExt;
+    use maplit::hashmap;
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(
+            parse_query("red \"running shoes\" -kids type:shoe size:9 price:9.5 sale:true")
+                .unwrap(),
+            SearchQuery {
+                text: "red \"running shoes\" -kids".to_string(),
+                fields: doc! {
+                    "type": "shoe",
+                    "size": 9i64,
+                    "price": 9.5,
+                    "sale": true,
+                },
+            }
+        );
+
+        assert_eq!(parse_query("*:*").unwrap(), SearchQuery::default());
+        assert_eq!(
+            parse_query("brand:\"Acme Co\" AND colour:*").unwrap(),
+            SearchQuery {
+                text: "".to_string(),
+                fields: doc! { "brand": "Acme Co", "colour": { "$exists": true } },
+            }
+        );
+        assert_eq!(
+            parse_query("\"10:30 train\"").unwrap().text,
+            "\"10:30 train\""
+        );
+
+        for unsupported in ["(a OR b)", "price:[1 TO 2]", "roam~", "a^2"] {
+            let (status, _) = parse_query(unsupported).unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", unsupported);
+        }
+    }
+
+    #[test]
+    fn test_parse_sort() {
+        assert_eq!(
+            parse_sort("-price<number>").unwrap(),
+            vec![("price".to_string(), -1)]
+        );
+        assert_eq!(
+            parse_sort("\"name<string>\"").unwrap(),
+            vec![("name".to_string(), 1)]
+        );
+        assert_eq!(
+            parse_sort("[\"-price\", \"name\"]").unwrap(),
+            vec![("price".to_string(), -1), ("name".to_string(), 1)]
+        );
+        assert!(parse_sort("[\"price\"").is_err());
+    }
+
+    #[test]
+    fn test_bookmark() {
+        assert_eq!(decode_bookmark(&encode_bookmark(50)).unwrap(), 50);
+        assert!(decode_bookmark("not a bookmark").is_err());
+        assert!(decode_bookmark(&URL_SAFE_NO_PAD.encode("-1")).is_err());
+    }
+
+    #[test]
+    fn test_search_pipeline() {
+        let query = parse_query("shoes type:shoe").unwrap();
+
+        assert_eq!(
+            search_pipeline(&SearchIndex::default(), &query, &[], 25, 10),
+            vec![
+                doc! { "$match": { "type": "shoe", "$text": { "$search": "shoes" } } },
+                doc! { "$addFields": { SCORE_FIELD: { "$meta": "textScore" } } },
+                doc! { "$facet": {
+                    "rows": [
+                        { "$sort": { SCORE_FIELD: -1, "_id": 1 } },
+                        { "$skip": 25i64 },
+                        { "$limit": 10i64 },
+                    ],
+                    "total": [{ "$count": "n" }],
+                } },
+            ]
+        );
+
+        let atlas = SearchIndex {
+            fields: vec![],
+            atlas_index: Some("products".to_string()),
+        };
+        let pipeline = search_pipeline(&atlas, &query, &[("price".to_string(), -1)], 0, 10);
+        assert_eq!(
+            pipeline[0],
+            doc! { "$search": {
+                "index": "products",
+                "text": { "query": "shoes", "path": { "wildcard": "*" } },
+            } }
+        );
+        assert_eq!(pipeline[1], doc! { "$match": { "type": "shoe" } });
+        assert_eq!(
+            pipeline[2],
+            doc! { "$addFields": { SCORE_FIELD: { "$meta": "searchScore" } } }
+        );
+        assert_eq!(
+            pipeline[3]
+                .get_document("$facet")
+                .unwrap()
+                .get_array("rows")
+                .unwrap()[0],
+            Bson::Document(doc! { "$sort": { "price": -1, "_id": 1 } })
+        );
+
+        // Matching everything needs no stage of its own, and has no relevance to sort by.
+        let pipeline = search_pipeline(&SearchIndex::default(), &SearchQuery::default(), &[], 0, 1);
+        assert_eq!(pipeline.len(), 1);
+    }
+
+    fn state(mock: MockDatabase) -> AppState {
+        AppState {
+            search_indexes: Some(hashmap! {
+                "products".to_string() => hashmap! {
+                    "catalogue".to_string() => hashmap! {
+                        "by_name".to_string() => SearchIndex {
+                            fields: vec!["name".to_string()],
+                            atlas_index: None,
+                        },
+                    },
+                },
+            }),
+            ..AppState::for_tests(mock)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let mut mock = MockDatabase::new();
+        mock.expect_aggregate()
+            .times(1)
+            .returning(|coll, pipeline, _| {
+                assert_eq!(coll, "products");
+                assert_eq!(
+                    pipeline[0],
+                    doc! { "$match": { "$text": { "$search": "boots" } } }
+                );
+
+                let result = doc! {
+                    "rows": [
+                        { "_id": "a", "name": "Hiking boots", "price": 80, SCORE_FIELD: 1.5 },
+                        { "_id": "b", "name": "Rain boots", "price": 30, SCORE_FIELD: 0.75 },
+                    ],
+                    "total": [{ "n": 7 }],
+                };
+                let result = RawDocumentBuf::from_document(&result).unwrap();
+                Box::pin(async move { Ok(vec![result]) })
+            });
+
+        let state = Arc::new(state(mock));
+        let path = (
+            "products".to_string(),
+            "catalogue".to_string(),
+            "by_name".to_string(),
+        );
+        let params = hashmap! {
+            "q".to_string() => "boots".to_string(),
+            "limit".to_string() => "2".to_string(),
+            "bookmark".to_string() => encode_bookmark(2),
+        };
+
+        let res = search(State(state), Path(path), Query(params))
+            .await
+            .unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            body,
+            json!({
+                "total_rows": 7,
+                "bookmark": encode_bookmark(4),
+                "rows": [
+                    { "id": "a", "order": [1.5], "fields": { "name": "Hiking boots" } },
+                    { "id": "b", "order": [0.75], "fields": { "name": "Rain boots" } },
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_errors() {
+        let state = Arc::new(state(MockDatabase::new()));
+        let path = |index: &str| {
+            Path((
+                "products".to_string(),
+                "catalogue".to_string(),
+                index.to_string(),
+            ))
+        };
+        let q = |extra: (&str, &str)| {
+            Query(hashmap! {
+                "q".to_string() => "boots".to_string(),
+                extra.0.to_string() => extra.1.to_string(),
+            })
+        };
+
+        let (status, _) = search(State(state.clone()), path("missing"), q(("limit", "1")))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = search(State(state.clone()), path("by_name"), q(("limit", "201")))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = search(State(state.clone()), path("by_name"), Query(HashMap::new()))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
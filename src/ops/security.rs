@@ -0,0 +1,226 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::SecurityObject;
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use bson::doc;
+use mongodb::options::{FindOneOptions, ReplaceOptions};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// The collection `_security` objects are stored in, keyed by database name.
+pub const SECURITY_COLLECTION: &str = "_couchapi_security";
+
+/// Returns the security object for a database. One set in the configuration wins over one stored
+/// through `PUT /:db/_security`.
+pub async fn security_for_db(
+    state: &AppState,
+    db: &str,
+) -> Result<Option<SecurityObject>, JsonWithStatusCodeResponse> {
+    if let Some(security) = state.security.as_ref().and_then(|s| s.get(db)) {
+        return Ok(Some(security.clone()));
+    }
+
+    let document = state
+        .db
+        .find_one(SECURITY_COLLECTION, db, FindOneOptions::default())
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": e.to_string()})),
+            )
+        })?;
+
+    match document {
+        Some(mut document) => {
+            document.remove("_id");
+            bson::from_document(document).map(Some).map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": e.to_string()})),
+                )
+            })
+        }
+        None => Ok(None),
+    }
+}
+
+pub async fn get_security(
+    State(state): State<Arc<AppState>>,
+    Path(db): Path<String>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let security = security_for_db(&state, &db).await?;
+
+    Ok(match security {
+        Some(security) => Json(json!(security)).into_response(),
+        None => Json(json!({})).into_response(),
+    })
+}
+
+pub async fn put_security(
+    State(state): State<Arc<AppState>>,
+    Path(db): Path<String>,
+    Json(payload): Json<Value>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    if state.security.as_ref().is_some_and(|s| s.contains_key(&db)) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "error": "forbidden",
+                "reason": "The security object for this database is set in the configuration."
+            })),
+        ));
+    }
+
+    let security: SecurityObject = serde_json::from_value(payload).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "bad_request", "reason": e.to_string()})),
+        )
+    })?;
+
+    let mut document = bson::to_document(&security).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+    })?;
+    document.insert("_id", &db);
+
+    let options = ReplaceOptions::builder().upsert(true).build();
+
+    state
+        .db
+        .replace_one(SECURITY_COLLECTION, doc! { "_id": &db }, document, options)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": e.to_string()})),
+            )
+        })?;
+
+    Ok(Json(json!({"ok": true})).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SecurityGroup;
+    use crate::db::MockDatabase;
+    use http_body_util::BodyExt;
+    use maplit::hashmap;
+
+    fn state(mock: MockDatabase) -> AppState {
+        AppState::for_tests(mock)
+    }
+
+    #[tokio::test]
+    async fn test_security_for_db_prefers_config() {
+        let security = SecurityObject {
+            admins: SecurityGroup::default(),
+            members: SecurityGroup {
+                names: vec![],
+                roles: vec!["reader".to_string()],
+            },
+            writers: None,
+        };
+
+        let state = AppState {
+            security: Some(hashmap! { "db".to_string() => security.clone() }),
+            ..state(MockDatabase::new())
+        };
+
+        let result = security_for_db(&state, "db").await.unwrap();
+        assert_eq!(result, Some(security));
+    }
+
+    #[tokio::test]
+    async fn test_security_for_db_reads_stored_document() {
+        let mut mock = MockDatabase::new();
+
+        mock.expect_find_one()
+            .withf(|coll, id, _| coll == SECURITY_COLLECTION && id == "db")
+            .returning(|_, _, _| {
+                Box::pin(async {
+                    Ok(Some(doc! {
+                        "_id": "db",
+                        "members": { "names": ["alice"], "roles": [] },
+                    }))
+                })
+            });
+
+        let state = state(mock);
+
+        let result = security_for_db(&state, "db").await.unwrap().unwrap();
+        assert_eq!(result.members.names, vec!["alice".to_string()]);
+        assert!(result.admins.names.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_security_when_unset() {
+        let mut mock = MockDatabase::new();
+
+        mock.expect_find_one()
+            .returning(|_, _, _| Box::pin(async { Ok(None) }));
+
+        let response = get_security(State(Arc::new(state(mock))), Path("db".to_string()))
+            .await
+            .unwrap();
+
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({}));
+    }
+
+    #[tokio::test]
+    async fn test_put_security_rejects_configured_database() {
+        let state = AppState {
+            security: Some(hashmap! { "db".to_string() => SecurityObject::default() }),
+            ..state(MockDatabase::new())
+        };
+
+        let (status, _) = put_security(
+            State(Arc::new(state)),
+            Path("db".to_string()),
+            Json(json!({})),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_put_security_rejects_invalid_object() {
+        let (status, _) = put_security(
+            State(Arc::new(state(MockDatabase::new()))),
+            Path("db".to_string()),
+            Json(json!({ "members": "everyone" })),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
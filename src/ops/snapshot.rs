@@ -0,0 +1,604 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::concern::{read_concern_for_request, write_concern_for_request};
+use crate::couchdb::maybe_write;
+use crate::decompression::body_error;
+use crate::ops::bulk::{bulk_concurrency, bulk_error};
+use crate::ops::bulk_stream::{MAX_LINE_BYTES, MAX_REPORTED_ERRORS};
+use crate::ops::{stub_attachments, validate_rev, JsonWithStatusCodeResponse};
+use crate::request_timeout::remaining_time;
+use crate::state::AppState;
+use crate::tombstones;
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use bson::{doc, Bson, Document};
+use bytes::Bytes;
+use futures_util::StreamExt;
+use http_body_util::BodyExt;
+use mongodb::options::{AggregateOptions, ReadConcern, ReplaceOptions, WriteConcern};
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// How many documents each read of an export fetches.
+const EXPORT_BATCH_SIZE: i64 = 1000;
+
+/// Where an export has got to. Documents are read in `_id` order, so each batch starts after the
+/// last `_id` of the one before.
+pub(crate) struct ExportCursor {
+    state: Arc<AppState>,
+    db: String,
+    batch_size: i64,
+    attachments: bool,
+    read_concern: Option<ReadConcern>,
+    after: Option<Bson>,
+    done: bool,
+}
+
+impl ExportCursor {
+    /// Starts an export of every document, with attachment data inline when `attachments` is set.
+    pub(crate) fn new(state: Arc<AppState>, db: &str, attachments: bool) -> Self {
+        ExportCursor {
+            state,
+            db: db.to_string(),
+            batch_size: EXPORT_BATCH_SIZE,
+            attachments,
+            read_concern: None,
+            after: None,
+            done: false,
+        }
+    }
+
+    /// Reads the next batch as NDJSON, or `None` once every document has been read.
+    pub(crate) async fn next_batch(&mut self) -> Option<Result<Bytes, mongodb::error::Error>> {
+        if self.done {
+            return None;
+        }
+
+        let mut pipeline = vec![];
+        if let Some(after) = &self.after {
+            pipeline.push(doc! { "$match": { "_id": { "$gt": after.clone() } } });
+        }
+        pipeline.push(doc! { "$sort": { "_id": 1 } });
+        pipeline.push(doc! { "$limit": self.batch_size });
+
+        let options = AggregateOptions::builder()
+            .read_concern(self.read_concern.clone())
+            .max_time(remaining_time())
+            .build();
+
+        let batch = match self.state.db.aggregate(&self.db, pipeline, options).await {
+            Ok(batch) => batch,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+
+        self.done = (batch.len() as i64) < self.batch_size;
+        if batch.is_empty() {
+            return None;
+        }
+
+        let mut lines = Vec::new();
+        for raw in batch {
+            let mut document = match Document::try_from(raw) {
+                Ok(document) => document,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(mongodb::error::Error::custom(e.to_string())));
+                }
+            };
+            self.after = document.get("_id").cloned();
+            tombstones::strip(&mut document);
+
+            let mut line = json!(document);
+            if !self.attachments {
+                stub_attachments(&mut line);
+            }
+            lines.extend_from_slice(line.to_string().as_bytes());
+            lines.push(b'\n');
+        }
+
+        Some(Ok(Bytes::from(lines)))
+    }
+}
+
+/// Streams every document of a database, one JSON document per line, for backups and for seeding
+/// other environments. Attachments are stubs unless `attachments=true` is given.
+pub async fn export(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path(db): Path<String>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    export_in_batches(state, db, params, EXPORT_BATCH_SIZE).await
+}
+
+async fn export_in_batches(
+    state: Arc<AppState>,
+    db: String,
+    params: HashMap<String, String>,
+    batch_size: i64,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let mut cursor = ExportCursor {
+        read_concern: read_concern_for_request(&state, &params)?,
+        attachments: params.get("attachments").is_some_and(|a| a == "true"),
+        state,
+        db,
+        batch_size,
+        after: None,
+        done: false,
+    };
+
+    // The first batch is read before the response starts, so a database that can't be read gets
+    // an error rather than an empty export. A later failure ends the export early.
+    let first = match cursor.next_batch().await {
+        Some(Err(e)) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": e.to_string()})),
+            ))
+        }
+        first => first,
+    };
+
+    let rest = futures_util::stream::unfold(cursor, |mut cursor| async move {
+        let batch = cursor.next_batch().await?;
+        Some((batch, cursor))
+    });
+    let body = futures_util::stream::iter(first).chain(rest);
+
+    Ok((
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(body),
+    )
+        .into_response())
+}
+
+/// How many documents an import writes at a time.
+const IMPORT_BATCH_SIZE: usize = 500;
+
+/// Tallies an import as its batches are written, using the names replication tasks give the same
+/// counts.
+#[derive(Default)]
+struct ImportProgress {
+    docs_read: usize,
+    docs_written: usize,
+    doc_write_failures: usize,
+    errors: Vec<Value>,
+}
+
+impl ImportProgress {
+    fn failed(&mut self, error: Value) {
+        self.doc_write_failures += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(error);
+        }
+    }
+
+    fn counts(&self) -> Value {
+        json!({
+            "docs_read": self.docs_read,
+            "docs_written": self.docs_written,
+            "doc_write_failures": self.doc_write_failures,
+        })
+    }
+}
+
+/// Restores documents from an export, or from the body of an `_all_docs?include_docs=true`
+/// response when sent as `application/json`. Documents are written as they are, revs included,
+/// replacing whatever is stored under their ids. Its progress is listed by `_active_tasks`.
+pub async fn import(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    Path(db): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let task = state.active_tasks.start("import", &db);
+    let mut progress = ImportProgress::default();
+
+    let is_dump = headers
+        .get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .is_some_and(|c| c.starts_with("application/json"));
+
+    if is_dump {
+        let body = body.collect().await.map_err(body_error)?;
+        let dump: Value = serde_json::from_slice(&body.to_bytes())
+            .map_err(|_| bad_request("invalid UTF-8 JSON"))?;
+        let rows = dump
+            .get("rows")
+            .and_then(Value::as_array)
+            .ok_or_else(|| bad_request("Expected the rows of an _all_docs response."))?;
+
+        // Rows without a document are the ones `_all_docs` couldn't find.
+        let docs = rows
+            .iter()
+            .filter_map(|row| row.get("doc").filter(|doc| doc.is_object()))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for batch in docs.chunks(IMPORT_BATCH_SIZE) {
+            restore_batch(&state, &db, &params, batch.to_vec(), &mut progress).await?;
+            task.update(progress.counts());
+        }
+    } else {
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut body = body.into_data_stream();
+
+        loop {
+            let chunk = body.next().await.transpose().map_err(body_error)?;
+            let finished = chunk.is_none();
+
+            match chunk {
+                Some(chunk) => buffer.extend_from_slice(&chunk),
+                // The last line doesn't need a newline.
+                None => buffer.push(b'\n'),
+            }
+
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+
+                match serde_json::from_slice::<Value>(&line) {
+                    Ok(doc) => batch.push(doc),
+                    Err(e) => {
+                        progress.docs_read += 1;
+                        progress.failed(bulk_error(
+                            &None,
+                            "bad_request",
+                            &format!("invalid JSON: {}", e),
+                        ));
+                    }
+                }
+
+                if batch.len() >= IMPORT_BATCH_SIZE {
+                    let docs = std::mem::take(&mut batch);
+                    restore_batch(&state, &db, &params, docs, &mut progress).await?;
+                    task.update(progress.counts());
+                }
+            }
+
+            if buffer.len() > MAX_LINE_BYTES {
+                return Err((
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    Json(json!({
+                        "error": "document_too_large",
+                        "reason": format!(
+                            "A line is longer than {} bytes. {} documents were written before it.",
+                            MAX_LINE_BYTES, progress.docs_written
+                        )
+                    })),
+                ));
+            }
+
+            if finished {
+                break;
+            }
+        }
+
+        if !batch.is_empty() {
+            restore_batch(&state, &db, &params, batch, &mut progress).await?;
+        }
+    }
+
+    let mut summary = progress.counts();
+    summary["ok"] = json!(true);
+    summary["errors"] = json!(progress.errors);
+
+    let mut response = Json(summary).into_response();
+    *response.status_mut() = StatusCode::CREATED;
+    Ok(response)
+}
+
+fn bad_request(reason: &str) -> JsonWithStatusCodeResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "bad_request", "reason": reason})),
+    )
+}
+
+/// Writes one batch of an import. A database that's still written to CouchDB gets it as a
+/// `_bulk_docs` with `new_edits` off, which keeps the revs as they are in the same way.
+async fn restore_batch(
+    state: &Arc<AppState>,
+    db: &str,
+    params: &HashMap<String, String>,
+    docs: Vec<Value>,
+    progress: &mut ImportProgress,
+) -> Result<(), JsonWithStatusCodeResponse> {
+    progress.docs_read += docs.len();
+    let count = docs.len();
+
+    let couchdb_response = maybe_write(
+        &state.couchdb_details,
+        db,
+        Method::POST,
+        Some(&json!({ "docs": docs, "new_edits": false })),
+        "_bulk_docs",
+        params,
+    )
+    .await?;
+
+    if let Some(response) = couchdb_response {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let Ok(Value::Array(results)) = serde_json::from_slice(&body) else {
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                Json(json!({
+                    "error": "bad_gateway",
+                    "reason": format!(
+                        "CouchDB rejected a batch. {} documents were written before it.",
+                        progress.docs_written
+                    )
+                })),
+            ));
+        };
+
+        // Without new edits CouchDB only lists the documents it couldn't write.
+        let failures = results
+            .into_iter()
+            .filter(|result| result.get("error").is_some())
+            .collect::<Vec<_>>();
+        progress.docs_written += count - failures.len();
+        for failure in failures {
+            progress.failed(failure);
+        }
+        return Ok(());
+    }
+
+    let write_concern = write_concern_for_request(state, params)?;
+    let results = futures_util::stream::iter(docs)
+        .map(|doc| restore_doc(state, db, write_concern.clone(), doc))
+        .buffered(bulk_concurrency(state))
+        .collect::<Vec<_>>()
+        .await;
+
+    for result in results {
+        match result {
+            Ok(()) => progress.docs_written += 1,
+            Err(error) => progress.failed(error),
+        }
+    }
+    Ok(())
+}
+
+/// Writes a document exactly as it was exported, or gives the `{"id", "error", "reason"}` saying
+/// why it couldn't be.
+async fn restore_doc(
+    state: &Arc<AppState>,
+    db: &str,
+    write_concern: Option<WriteConcern>,
+    doc: Value,
+) -> Result<(), Value> {
+    let id = doc.get("_id").and_then(Value::as_str).map(str::to_string);
+
+    let Some(item) = id.clone() else {
+        return Err(bulk_error(
+            &id,
+            "bad_request",
+            "Document id is required to import it.",
+        ));
+    };
+    let Some(rev) = doc.get("_rev").and_then(Value::as_str) else {
+        return Err(bulk_error(
+            &id,
+            "bad_request",
+            "Document rev is required to import it.",
+        ));
+    };
+    if validate_rev(rev).is_err() {
+        return Err(bulk_error(&id, "bad_request", "Invalid rev format"));
+    }
+
+    let has_stubs = doc
+        .get("_attachments")
+        .and_then(Value::as_object)
+        .is_some_and(|attachments| attachments.values().any(|a| a.get("stub").is_some()));
+    if has_stubs {
+        return Err(bulk_error(
+            &id,
+            "bad_request",
+            "Attachment stubs can't be imported. Export with attachments=true.",
+        ));
+    }
+
+    let document =
+        bson::to_document(&doc).map_err(|e| bulk_error(&id, "bad_request", &e.to_string()))?;
+    let options = ReplaceOptions::builder()
+        .upsert(true)
+        .write_concern(write_concern)
+        .build();
+
+    state
+        .db
+        .replace_one(db, doc! { "_id": item }, document, options)
+        .await
+        .map(|_| ())
+        .map_err(|e| bulk_error(&id, "unknown_error", &e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use bson::RawDocumentBuf;
+
+    fn state(mock: MockDatabase) -> Arc<AppState> {
+        Arc::new(AppState::for_tests(mock))
+    }
+
+    fn raw(document: Document) -> RawDocumentBuf {
+        RawDocumentBuf::from_document(&document).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let mut mock = MockDatabase::new();
+        mock.expect_aggregate()
+            .withf(|_, pipeline, _| pipeline.len() == 2)
+            .times(1)
+            .returning(|_, _, _| {
+                let batch = vec![
+                    raw(doc! { "_id": "a", "_rev": "1-a" }),
+                    raw(doc! {
+                        "_id": "b",
+                        "_rev": "1-b",
+                        "_attachments": { "note.txt": { "content_type": "text/plain", "data": "aGk=" } },
+                    }),
+                ];
+                Box::pin(async move { Ok(batch) })
+            });
+        mock.expect_aggregate()
+            .withf(|_, pipeline, _| pipeline[0] == doc! { "$match": { "_id": { "$gt": "b" } } })
+            .times(1)
+            .returning(|_, _, _| {
+                let batch = vec![raw(doc! {
+                    "_id": "c",
+                    "_rev": "2-c",
+                    "_deleted": true,
+                    tombstones::DELETED_AT_FIELD: bson::DateTime::now(),
+                })];
+                Box::pin(async move { Ok(batch) })
+            });
+
+        let response = export_in_batches(state(mock), "db".to_string(), HashMap::new(), 2)
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/x-ndjson");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let lines = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                json!({ "_id": "a", "_rev": "1-a" }),
+                json!({
+                    "_id": "b",
+                    "_rev": "1-b",
+                    "_attachments": {
+                        "note.txt": { "content_type": "text/plain", "length": 2, "stub": true }
+                    },
+                }),
+                json!({ "_id": "c", "_rev": "2-c", "_deleted": true }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_fails_before_it_starts() {
+        let mut mock = MockDatabase::new();
+        mock.expect_aggregate().returning(|_, _, _| {
+            Box::pin(async { Err(mongodb::error::Error::custom("connection reset")) })
+        });
+
+        let (status, _) = export_in_batches(state(mock), "db".to_string(), HashMap::new(), 2)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    async fn imported(mock: MockDatabase, content_type: &str, body: &'static str) -> Value {
+        let state = state(mock);
+        let headers = HeaderMap::from_iter([(CONTENT_TYPE, content_type.parse().unwrap())]);
+
+        let response = import(
+            State(state.clone()),
+            Query(HashMap::new()),
+            Path("db".to_string()),
+            headers,
+            Body::from(body),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(state.active_tasks.list().is_empty());
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_import() {
+        let mut mock = MockDatabase::new();
+        // UpdateResult can't be built outside of the driver, so the write fails once it's shown
+        // the document it was given.
+        mock.expect_replace_one()
+            .withf(|coll, filter, replacement, _| {
+                coll == "db"
+                    && filter == &doc! { "_id": "a" }
+                    && replacement.get_str("_rev") == Ok("3-a")
+            })
+            .times(1)
+            .returning(|_, _, _, _| {
+                Box::pin(async { Err(mongodb::error::Error::custom("written")) })
+            });
+
+        let body = concat!(
+            "{\"_id\": \"a\", \"_rev\": \"3-a\", \"n\": 1}\n",
+            "\n",
+            "{\"_id\": \"b\"}\n",
+            "{not json}\n",
+            "{\"_id\": \"c\", \"_rev\": \"1-c\", \"_attachments\": {\"a.txt\": {\"stub\": true}}}",
+        );
+        let summary = imported(mock, "application/x-ndjson", body).await;
+
+        assert_eq!(summary["docs_read"], 4);
+        assert_eq!(summary["doc_write_failures"], 4);
+        let reasons = summary["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["reason"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(reasons.len(), 4);
+        assert!(reasons.contains(&"Document rev is required to import it.".to_string()));
+        assert!(reasons.iter().any(|r| r.starts_with("invalid JSON")));
+        assert!(reasons.iter().any(|r| r.starts_with("Attachment stubs")));
+    }
+
+    #[tokio::test]
+    async fn test_import_all_docs_dump() {
+        let mut mock = MockDatabase::new();
+        mock.expect_replace_one()
+            .withf(|_, filter, _, _| filter == &doc! { "_id": "a" })
+            .times(1)
+            .returning(|_, _, _, _| {
+                Box::pin(async { Err(mongodb::error::Error::custom("written")) })
+            });
+
+        let body = r#"{"total_rows": 2, "offset": 0, "rows": [
+            {"id": "a", "key": "a", "value": {"rev": "1-a"}, "doc": {"_id": "a", "_rev": "1-a"}},
+            {"key": "b", "error": "not_found"}
+        ]}"#;
+        let summary = imported(mock, "application/json", body).await;
+
+        assert_eq!(summary["docs_read"], 1);
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::WriteTransforms;
+use crate::metrics::record_script_execution;
+use crate::ops::db_access::document_lookup;
+use crate::ops::{is_script_timeout, JsonWithStatusCodeResponse};
+use crate::state::AppState;
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The transform hook's file name within a database's folder of the updates folder.
+pub const TRANSFORM_HOOK: &str = "_transform.js";
+
+/// Applies a database's write transforms to a document about to be written: first its field
+/// rules, then its transform hook, called as `function(doc)` and returning the document to write.
+/// Deletions are left alone.
+pub async fn transform(
+    state: &Arc<AppState>,
+    db: &str,
+    mut document: Value,
+) -> Result<Value, JsonWithStatusCodeResponse> {
+    if document.get("_deleted") == Some(&Value::Bool(true)) {
+        return Ok(document);
+    }
+
+    if let Some(rules) = state.write_transforms.as_ref().and_then(|t| t.get(db)) {
+        apply_rules(rules, &mut document);
+    }
+
+    let Some(path) = transform_hook(state, db) else {
+        return Ok(document);
+    };
+    let source = state.script_cache.load(&path).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+    })?;
+
+    // The hook is run as an update function would be, with no request.
+    let start = Instant::now();
+    let result = state
+        .script_engine
+        .run_update(
+            source,
+            Some(document.clone()),
+            Value::Null,
+            PathBuf::from(state.updates_folder.as_ref().unwrap()),
+            document_lookup(state.clone(), db.to_string()),
+        )
+        .await;
+
+    record_script_execution(
+        "transform",
+        db,
+        "",
+        TRANSFORM_HOOK,
+        match &result {
+            Ok(Value::Object(_)) => "ok",
+            Ok(_) => "invalid_result",
+            Err(e) if is_script_timeout(e) => "timeout",
+            Err(_) => "script_error",
+        },
+        start.elapsed().as_secs_f64(),
+    );
+
+    let Value::Object(mut transformed) = result? else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "transform hook must return an object"})),
+        ));
+    };
+
+    // The hook can't move the document or change the rev it's written against.
+    for field in ["_id", "_rev"] {
+        match document.get(field) {
+            Some(value) => transformed.insert(field.to_string(), value.clone()),
+            None => transformed.remove(field),
+        };
+    }
+    Ok(Value::Object(transformed))
+}
+
+fn transform_hook(state: &AppState, db: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(state.updates_folder.as_ref()?)
+        .join(db)
+        .join(TRANSFORM_HOOK);
+    path.is_file().then_some(path)
+}
+
+/// Applies the field rules: fields are normalised, then copied, then stamped. Rules naming a field
+/// the document doesn't have, or one of the wrong type, are skipped.
+fn apply_rules(rules: &WriteTransforms, document: &mut Value) {
+    for name in &rules.lowercase_fields {
+        if let Some(Value::String(s)) = field_mut(document, name) {
+            *s = s.to_lowercase();
+        }
+    }
+    for name in &rules.uppercase_fields {
+        if let Some(Value::String(s)) = field_mut(document, name) {
+            *s = s.to_uppercase();
+        }
+    }
+    for name in &rules.trim_fields {
+        if let Some(Value::String(s)) = field_mut(document, name) {
+            *s = s.trim().to_string();
+        }
+    }
+
+    for (target, source) in &rules.copy_fields {
+        if let Some(value) = field(document, source).cloned() {
+            set_field(document, target, value);
+        }
+    }
+
+    let now = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    for name in &rules.timestamp_fields {
+        set_field(document, name, json!(now));
+    }
+}
+
+/// Finds a field by its dotted path, such as `customer.email`.
+fn field<'a>(document: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(document, |value, name| value.get(name))
+}
+
+fn field_mut<'a>(document: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    path.split('.')
+        .try_fold(document, |value, name| value.get_mut(name))
+}
+
+/// Sets a field by its dotted path, creating the objects on the way that don't exist yet.
+fn set_field(document: &mut Value, path: &str, value: Value) {
+    let mut names = path.split('.').peekable();
+    let mut current = document;
+
+    while let Some(name) = names.next() {
+        let Some(object) = current.as_object_mut() else {
+            return;
+        };
+        if names.peek().is_none() {
+            object.insert(name.to_string(), value);
+            return;
+        }
+        current = object.entry(name).or_insert_with(|| json!({}));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_apply_rules() {
+        let rules = WriteTransforms {
+            timestamp_fields: vec!["updated_at".to_string()],
+            lowercase_fields: vec!["customer.email".to_string(), "missing".to_string()],
+            uppercase_fields: vec!["sku".to_string()],
+            trim_fields: vec!["name".to_string()],
+            copy_fields: HashMap::from([(
+                "search.email".to_string(),
+                "customer.email".to_string(),
+            )]),
+        };
+
+        let mut document = json!({
+            "_id": "a",
+            "sku": "ab-1",
+            "name": "  Widget ",
+            "customer": { "email": "Someone@Example.COM" },
+        });
+        apply_rules(&rules, &mut document);
+
+        assert!(document["updated_at"].as_str().unwrap().ends_with('Z'));
+        document.as_object_mut().unwrap().remove("updated_at");
+        assert_eq!(
+            document,
+            json!({
+                "_id": "a",
+                "sku": "AB-1",
+                "name": "Widget",
+                "customer": { "email": "someone@example.com" },
+                "search": { "email": "someone@example.com" },
+            })
+        );
+    }
+}
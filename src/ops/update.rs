@@ -12,25 +12,51 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::couchdb::maybe_write;
+use crate::auth::signing::SIGNATURE_HEADER;
+use crate::auth::UserCtx;
+use crate::config::ScriptSettings;
+use crate::couchdb::maybe_write_raw;
+use crate::metrics::record_script_execution;
+use crate::ops::bulk::is_deletion;
 use crate::ops::create_update::inner_new_item;
-use crate::ops::{get_item_from_db, JsonWithStatusCodeResponse};
+use crate::ops::db_access::{document_lookup, register_db, DocumentLookup};
+use crate::ops::delete::inner_delete_item;
+use crate::ops::require::register_require;
+use crate::ops::security::security_for_db;
+use crate::ops::{
+    apply_script_limits,
+    get_item_from_db,
+    is_script_timeout,
+    script_error,
+    JsonWithStatusCodeResponse,
+};
 use crate::state::AppState;
-use axum::extract::{Path, State};
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, State};
 use axum::http::header::CONTENT_TYPE;
-use axum::http::{HeaderValue, StatusCode};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
-use axum::Json;
+use axum::{Extension, Json};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use boa_engine::property::Attribute;
 use boa_engine::{Context, JsValue, Source};
-use boa_runtime::Console;
-use bson::Document;
 use http_body_util::BodyExt;
 use maplit::hashmap;
 use reqwest::Method;
 use serde_json::{json, Map, Value};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
+
+/// The parts of the HTTP request an update handler sees in `req`, besides its body.
+pub struct UpdateRequest {
+    pub method: axum::http::Method,
+    pub headers: HeaderMap,
+    pub query: HashMap<String, String>,
+    pub user_ctx: Option<UserCtx>,
+}
 
 /// Execute an update script
 ///
@@ -41,7 +67,8 @@ pub async fn inner_execute_update_script(
     func: String,
     document_id: Option<String>,
     state: Arc<AppState>,
-    payload: Value,
+    body: Bytes,
+    request: UpdateRequest,
 ) -> Result<Response, JsonWithStatusCodeResponse> {
     let updates_folder = state.updates_folder.clone().ok_or_else(|| {
         (
@@ -50,7 +77,7 @@ pub async fn inner_execute_update_script(
         )
     })?;
 
-    let mut path = PathBuf::from(updates_folder);
+    let mut path = PathBuf::from(&updates_folder);
     path.push(&db);
     path.push(&design);
     path.push(format!("{}.js", func));
@@ -64,7 +91,7 @@ pub async fn inner_execute_update_script(
     }
 
     let document = if let Some(document_id) = document_id.clone() {
-        match get_item_from_db(state.clone(), db.clone(), document_id.to_string()).await {
+        match get_item_from_db(state.clone(), db.clone(), document_id.to_string(), None).await {
             Ok(d) => Some(d),
             Err((status_code, _)) => {
                 // We're actually OK here - some update handler scripts expect no document
@@ -84,9 +111,62 @@ pub async fn inner_execute_update_script(
         None
     };
 
-    let document_json = document.as_ref().map_or_else(|| json!({}), |d| json!(d));
+    let document_json = document.as_ref().map(|d| json!(d));
+    let security = security_for_db(&state, &db).await?;
+    let req = request_object(&db, &document_id, &body, &request, security.as_ref());
+
+    let start = Instant::now();
+    let lib_folder = PathBuf::from(updates_folder);
+    let result = match state.script_cache.load(path) {
+        Ok(source) if state.query_server.is_some() => {
+            let ddoc_id = format!("{}/_design/{}", db, design);
+            let ddoc = json!({
+                "_id": format!("_design/{}", design),
+                "language": "javascript",
+                "updates": {&func: &*source},
+            });
+
+            state
+                .query_server
+                .as_ref()
+                .unwrap()
+                .update(
+                    &ddoc_id,
+                    &ddoc,
+                    &func,
+                    document_json.as_ref().unwrap_or(&Value::Null),
+                    &req,
+                )
+                .await
+        }
+        Ok(source) => {
+            state
+                .script_engine
+                .run_update(
+                    source,
+                    document_json,
+                    req,
+                    lib_folder,
+                    document_lookup(state.clone(), db.clone()),
+                )
+                .await
+        }
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )),
+    };
+
+    record_script_execution(
+        "update",
+        &db,
+        &design,
+        &func,
+        update_script_outcome(&result),
+        start.elapsed().as_secs_f64(),
+    );
 
-    let return_value = execute_javascript(path, &document_id, &document, &document_json, &payload)?;
+    let return_value = result?;
 
     let return_value_vector = if let Value::Array(v) = return_value {
         v
@@ -102,7 +182,7 @@ pub async fn inner_execute_update_script(
     let returned_response =
         get_returned_value(&return_value_vector, 1, "return value is not an object")?;
 
-    let mut response = Response::new(String::new());
+    let mut response = Response::new(Body::empty());
 
     if let Some(returned_document) = returned_document {
         let new_document_id = returned_document
@@ -122,15 +202,43 @@ pub async fn inner_execute_update_script(
             })?
             .to_string();
 
-        let item_response = inner_new_item(
-            db.clone(),
-            Some(new_document_id),
-            state,
-            hashmap! {},
-            json!(returned_document),
-            None,
-        )
-        .await?;
+        let returned_document = json!(returned_document);
+
+        // A document returned with `_deleted` set is deleted, as CouchDB does, rather than
+        // written with the flag in it.
+        let item_response = if is_deletion(&returned_document) {
+            let rev = returned_document
+                .get("_rev")
+                .and_then(|r| r.as_str())
+                .ok_or_else(|| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({
+                            "error": "bad_request",
+                            "reason": "Document rev is required to delete it."
+                        })),
+                    )
+                })?;
+
+            inner_delete_item(
+                state,
+                db.clone(),
+                new_document_id,
+                hashmap! { "rev".to_string() => rev.to_string() },
+                None,
+            )
+            .await?
+        } else {
+            inner_new_item(
+                db.clone(),
+                Some(new_document_id),
+                state,
+                hashmap! {},
+                returned_document,
+                None,
+            )
+            .await?
+        };
 
         let body = BodyExt::collect(item_response.into_body())
             .await
@@ -184,64 +292,52 @@ pub async fn inner_execute_update_script(
     .unwrap();
 
     if let Some(json) = returned_response.unwrap().get("json") {
-        *response.body_mut() = json.to_string();
+        *response.body_mut() = Body::from(json.to_string());
         response
             .headers_mut()
             .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
     }
 
     if let Some(body) = returned_response.unwrap().get("body") {
-        *response.body_mut() = body.as_str().unwrap().to_string();
+        *response.body_mut() = Body::from(body.as_str().unwrap().to_string());
         response.headers_mut().insert(
             CONTENT_TYPE,
             HeaderValue::from_static("text/html; charset=utf-8"),
         );
     }
 
-    if returned_response.unwrap().get("base64").is_some() {
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "base64 is not implemented yet"})),
-        ));
+    // Binary bodies, such as images, arrive base64 encoded. CouchDB serves them as
+    // application/binary unless the handler declares a content type in its headers.
+    if let Some(base64) = returned_response.unwrap().get("base64") {
+        *response.body_mut() = Body::from(decode_base64_body(base64)?);
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/binary"));
     }
 
-    // TODO(lee): this code causes a borrow check fail as the return_value_vector does not live long
-    //            enough. I'm not sure how to fix this yet.
-    // if let Some(headers) = returned_response.unwrap().get("headers") {
-    //     if let Value::Object(headers) = headers {
-    //         for (key, value) in headers {
-    //             let header_string = value.as_str().ok_or_else(|| {
-    //                 (
-    //                     StatusCode::INTERNAL_SERVER_ERROR,
-    //                     Json(json!({"error": "header value is not a string"})),
-    //                 )
-    //             })?;
-    //             let header_value = HeaderValue::from_str(header_string).map_err(|_| {
-    //                 (
-    //                     StatusCode::INTERNAL_SERVER_ERROR,
-    //                     Json(json!({"error": "header value is not a valid value"})),
-    //                 )
-    //             })?;
-    //
-    //             response.headers_mut().insert(key.as_str(), header_value);
-    //         }
-    //     }
-    // }
+    // Headers from the handler go last so they can replace the content type chosen above.
+    if let Some(headers) = returned_response.unwrap().get("headers") {
+        apply_returned_headers(&mut response, headers)?;
+    }
 
     Ok(response.into_response())
 }
 
-fn execute_javascript(
-    path: &std::path::Path,
-    req_id: &Option<String>,
-    document: &Option<Document>,
-    document_json: &Value,
-    payload: &Value,
+pub(crate) fn execute_javascript(
+    context: &mut Context<'_>,
+    source: &str,
+    req: &Value,
+    document_json: &Option<Value>,
+    limits: &ScriptSettings,
+    lib_folder: &std::path::Path,
+    lookup: DocumentLookup,
 ) -> Result<Value, JsonWithStatusCodeResponse> {
-    let mut context = Context::default();
+    apply_script_limits(context, limits);
+    register_require(context, lib_folder).map_err(script_error)?;
+    register_db(context, lookup).map_err(script_error)?;
 
-    let doc_js = if let Some(_document) = &document {
-        JsValue::from_json(document_json, &mut context).map_err(|e| {
+    let doc_js = if let Some(document_json) = document_json {
+        JsValue::from_json(document_json, context).map_err(|e| {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({"error": e.to_string()})),
@@ -251,13 +347,7 @@ fn execute_javascript(
         JsValue::null()
     };
 
-    let req = json!({
-        "id": req_id,
-        "body": payload.to_string(),
-        "uuid": uuid::Uuid::new_v4().to_string(),
-    });
-
-    let req_js = JsValue::from_json(&req, &mut context).map_err(|e| {
+    let req_js = JsValue::from_json(req, context).map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({"error": e.to_string()})),
@@ -281,34 +371,12 @@ fn execute_javascript(
             )
         })?;
 
-    let console = Console::init(&mut context);
-    context
-        .register_global_property(Console::NAME, console, Attribute::all())
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": e.to_string()})),
-            )
-        })?;
-
-    let javascript_file = std::fs::read_to_string(path).map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": e.to_string()})),
-        )
-    })?;
-
-    let javascript_file = format!("f = {}", javascript_file);
+    let javascript_file = format!("f = {}", source);
     let javascript_file = format!("{}\n\nresult = f(doc, req)", javascript_file);
 
     let src = Source::from_bytes(javascript_file.as_bytes());
 
-    context.eval(src).map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": e.to_string()})),
-        )
-    })?;
+    context.eval(src).map_err(script_error)?;
 
     // Bump the result through a back n forth through JSON to ensure that we have a valid
     // JSON object at the end of the process. This will strip things like undefined etc.
@@ -325,7 +393,7 @@ fn execute_javascript(
 
     let result = context
         .global_object()
-        .get("result", &mut context)
+        .get("result", context)
         .map_err(|e| {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -333,23 +401,101 @@ fn execute_javascript(
             )
         })?;
 
-    Ok(result.to_json(&mut context).unwrap())
+    Ok(result.to_json(context).unwrap())
+}
+
+/// The fields of a form-encoded body, for `req.form`. Other bodies have none.
+fn form_fields(headers: &HeaderMap, body: &[u8]) -> Map<String, Value> {
+    let is_form = headers
+        .get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .is_some_and(|c| c.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Map::new();
+    }
+
+    url::form_urlencoded::parse(body)
+        .map(|(k, v)| (k.into_owned(), json!(v)))
+        .collect()
+}
+
+/// Headers carrying the caller's credentials, which handlers don't see.
+const CREDENTIAL_HEADERS: [&str; 5] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+    SIGNATURE_HEADER,
+];
+
+/// Builds the `req` object passed to an update handler, in the shape of CouchDB's request object.
+/// Header names are lower case, as they arrive, and repeated headers are joined with commas.
+/// Credentials are left out. The body is passed as it was sent, whatever its content type, and is
+/// `"undefined"` when there isn't one, as in CouchDB.
+fn request_object(
+    db: &str,
+    document_id: &Option<String>,
+    body: &[u8],
+    request: &UpdateRequest,
+    security: Option<&crate::config::SecurityObject>,
+) -> Value {
+    let mut headers = Map::new();
+    for name in request.headers.keys().filter(|name| {
+        !CREDENTIAL_HEADERS
+            .iter()
+            .any(|c| name.as_str().eq_ignore_ascii_case(c))
+    }) {
+        let values: Vec<&str> = request
+            .headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        headers.insert(name.to_string(), json!(values.join(", ")));
+    }
+
+    let user_ctx = request.user_ctx.clone().unwrap_or_default();
+
+    json!({
+        "id": document_id,
+        "body": match body {
+            [] => "undefined".into(),
+            body => String::from_utf8_lossy(body),
+        },
+        "form": form_fields(&request.headers, body),
+        "uuid": uuid::Uuid::new_v4().to_string(),
+        "method": request.method.as_str(),
+        "headers": headers,
+        "query": request.query,
+        "userCtx": {
+            "db": db,
+            "name": user_ctx.name,
+            "roles": user_ctx.roles,
+        },
+        "secObj": security.map_or_else(|| json!({}), |s| json!(s)),
+    })
 }
 
 pub async fn execute_update_script(
     State(state): State<Arc<AppState>>,
     Path((db, design, function)): Path<(String, String, String)>,
-    Json(payload): Json<Value>,
+    method: axum::http::Method,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+    user_ctx: Option<Extension<UserCtx>>,
+    body: Bytes,
 ) -> Result<Response, JsonWithStatusCodeResponse> {
     let u = format!("_design/{}/_update/{}", design, function);
 
-    let c = maybe_write(
+    // The handler reads the same flags from the query string wherever it runs
+    let c = maybe_write_raw(
         &state.couchdb_details,
         &db,
         Method::PUT,
-        Some(&payload),
+        body.clone(),
+        headers.get(CONTENT_TYPE).and_then(|c| c.to_str().ok()),
         &u,
-        &hashmap! {},
+        &query,
     )
     .await?;
 
@@ -357,23 +503,36 @@ pub async fn execute_update_script(
         return Ok(r);
     }
 
-    inner_execute_update_script(db, design, function, None, state, payload).await
+    let request = UpdateRequest {
+        method,
+        headers,
+        query,
+        user_ctx: user_ctx.map(|Extension(u)| u),
+    };
+
+    inner_execute_update_script(db, design, function, None, state, body, request).await
 }
 
 pub async fn execute_update_script_with_doc(
     State(state): State<Arc<AppState>>,
     Path((db, design, func, document_id)): Path<(String, String, String, String)>,
-    Json(payload): Json<Value>,
+    method: axum::http::Method,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+    user_ctx: Option<Extension<UserCtx>>,
+    body: Bytes,
 ) -> Result<Response, JsonWithStatusCodeResponse> {
     let u = format!("_design/{}/_update/{}/{}", design, func, document_id);
 
-    let c = maybe_write(
+    // The handler reads the same flags from the query string wherever it runs
+    let c = maybe_write_raw(
         &state.couchdb_details,
         &db,
         Method::PUT,
-        Some(&payload),
+        body.clone(),
+        headers.get(CONTENT_TYPE).and_then(|c| c.to_str().ok()),
         &u,
-        &hashmap! {},
+        &query,
     )
     .await?;
 
@@ -381,7 +540,86 @@ pub async fn execute_update_script_with_doc(
         return Ok(r);
     }
 
-    inner_execute_update_script(db, design, func, Some(document_id), state, payload).await
+    let request = UpdateRequest {
+        method,
+        headers,
+        query,
+        user_ctx: user_ctx.map(|Extension(u)| u),
+    };
+
+    inner_execute_update_script(db, design, func, Some(document_id), state, body, request).await
+}
+
+/// Copies the `headers` object an update handler returned onto the response. Strings are used as
+/// they are and numbers and booleans are converted, as CouchDB does.
+fn apply_returned_headers<B>(
+    response: &mut Response<B>,
+    headers: &Value,
+) -> Result<(), JsonWithStatusCodeResponse> {
+    let Value::Object(headers) = headers else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "headers is not an object"})),
+        ));
+    };
+
+    for (key, value) in headers {
+        let header_string = match value {
+            Value::String(s) => s.clone(),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            _ => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "header value is not a string"})),
+                ))
+            }
+        };
+
+        let header_name = HeaderName::from_bytes(key.as_bytes()).map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "header name is not a valid name"})),
+            )
+        })?;
+        let header_value = HeaderValue::from_str(&header_string).map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "header value is not a valid value"})),
+            )
+        })?;
+
+        response.headers_mut().insert(header_name, header_value);
+    }
+
+    Ok(())
+}
+
+fn decode_base64_body(value: &Value) -> Result<Vec<u8>, JsonWithStatusCodeResponse> {
+    value
+        .as_str()
+        .and_then(|s| STANDARD.decode(s).ok())
+        .ok_or_else(|| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "base64 is not valid base64"})),
+            )
+        })
+}
+
+/// Classifies the result of an update handler for metrics. A script that runs but doesn't return a
+/// `[document, response]` pair counts as an invalid result rather than a success.
+fn update_script_outcome(result: &Result<Value, JsonWithStatusCodeResponse>) -> &'static str {
+    match result {
+        Ok(Value::Array(v))
+            if get_returned_value(v, 0, "").is_ok() && get_returned_value(v, 1, "").is_ok() =>
+        {
+            "ok"
+        }
+        Ok(_) => "invalid_result",
+        Err(e) if is_script_timeout(e) => "timeout",
+        Err(_) => "script_error",
+    }
 }
 
 fn get_returned_value<'a>(
@@ -406,6 +644,7 @@ fn get_returned_value<'a>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ops::script_timeout;
     use assert_json_diff::assert_json_eq;
 
     #[test]
@@ -439,4 +678,239 @@ mod tests {
             json!({"error": "return value is empty"})
         );
     }
+
+    #[test]
+    fn test_apply_returned_headers() {
+        let mut response = Response::new(Body::empty());
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+
+        apply_returned_headers(
+            &mut response,
+            &json!({
+                "Location": "/orders/123",
+                "Cache-Control": "max-age=60",
+                "Content-Type": "text/plain",
+                "X-Retry-After": 30,
+            }),
+        )
+        .unwrap();
+
+        let headers = response.headers();
+        assert_eq!(headers["location"], "/orders/123");
+        assert_eq!(headers["cache-control"], "max-age=60");
+        assert_eq!(headers[CONTENT_TYPE], "text/plain");
+        assert_eq!(headers["x-retry-after"], "30");
+
+        assert!(apply_returned_headers(&mut response, &json!({"Bad Name": "x"})).is_err());
+        assert!(apply_returned_headers(&mut response, &json!({"X-List": ["a"]})).is_err());
+        assert!(apply_returned_headers(&mut response, &json!(["Location"])).is_err());
+    }
+
+    #[test]
+    fn test_decode_base64_body() {
+        assert_eq!(
+            decode_base64_body(&json!("iVBORw0KGgo=")).unwrap(),
+            b"\x89PNG\r\n\x1a\n"
+        );
+        assert!(decode_base64_body(&json!("not base64!")).is_err());
+        assert!(decode_base64_body(&json!(42)).is_err());
+    }
+
+    #[test]
+    fn test_request_object() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", HeaderValue::from_static("abc"));
+        headers.append("accept", HeaderValue::from_static("text/html"));
+        headers.append("accept", HeaderValue::from_static("application/json"));
+        headers.insert("authorization", HeaderValue::from_static("Basic YTpi"));
+        headers.insert("cookie", HeaderValue::from_static("AuthSession=abc"));
+        headers.insert("x-api-key", HeaderValue::from_static("writer-key"));
+        headers.insert("x-couchapi-signature", HeaderValue::from_static("abc"));
+
+        let request = UpdateRequest {
+            method: axum::http::Method::POST,
+            headers,
+            query: HashMap::from([("dry_run".to_string(), "true".to_string())]),
+            user_ctx: Some(UserCtx {
+                name: Some("alice".to_string()),
+                roles: vec!["editor".to_string()],
+            }),
+        };
+        let security: crate::config::SecurityObject = serde_json::from_value(json!({
+            "members": {"roles": ["editor"]}
+        }))
+        .unwrap();
+
+        let req = request_object(
+            "orders",
+            &Some("a".to_string()),
+            br#"{"n":1}"#,
+            &request,
+            Some(&security),
+        );
+
+        assert_eq!(req["id"], "a");
+        assert_eq!(req["body"], r#"{"n":1}"#);
+        assert_eq!(req["method"], "POST");
+        assert_eq!(req["query"], json!({"dry_run": "true"}));
+        assert_eq!(
+            req["headers"],
+            json!({"x-request-id": "abc", "accept": "text/html, application/json"})
+        );
+        assert_eq!(
+            req["userCtx"],
+            json!({"db": "orders", "name": "alice", "roles": ["editor"]})
+        );
+        assert_eq!(req["secObj"]["members"]["roles"], json!(["editor"]));
+
+        let request = UpdateRequest {
+            user_ctx: None,
+            ..request
+        };
+        let req = request_object("orders", &None, b"", &request, None);
+        assert_eq!(
+            req["userCtx"],
+            json!({"db": "orders", "name": null, "roles": []})
+        );
+        assert_eq!(req["secObj"], json!({}));
+        assert_eq!(req["body"], "undefined");
+        assert_eq!(req["form"], json!({}));
+    }
+
+    #[test]
+    fn test_request_object_form_body() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded; charset=utf-8"),
+        );
+        let request = UpdateRequest {
+            method: axum::http::Method::POST,
+            headers,
+            query: HashMap::new(),
+            user_ctx: None,
+        };
+
+        let req = request_object(
+            "orders",
+            &None,
+            b"status=shipped&note=left+at+door",
+            &request,
+            None,
+        );
+
+        assert_eq!(req["body"], "status=shipped&note=left+at+door");
+        assert_eq!(
+            req["form"],
+            json!({"status": "shipped", "note": "left at door"})
+        );
+    }
+
+    #[test]
+    fn test_execute_javascript_sees_request() {
+        let mut context = crate::ops::new_script_context().unwrap();
+        let req = json!({
+            "method": "PUT",
+            "userCtx": {"db": "orders", "name": "alice", "roles": ["editor"]},
+            "secObj": {},
+        });
+
+        let result = execute_javascript(
+            &mut context,
+            "function(doc, req) { return [null, {body: req.method + ' ' + req.userCtx.name}]; }",
+            &req,
+            &None,
+            &ScriptSettings::default(),
+            &std::env::temp_dir(),
+            Arc::new(|_| Ok(None)),
+        )
+        .unwrap();
+
+        assert_eq!(result, json!([null, {"body": "PUT alice"}]));
+    }
+
+    #[tokio::test]
+    async fn test_update_handler_deletes_document() {
+        let folder =
+            std::env::temp_dir().join(format!("couchapi-updates-{}", uuid::Uuid::new_v4()));
+        let design = folder.join("orders").join("cleanup");
+        std::fs::create_dir_all(&design).unwrap();
+        std::fs::write(
+            design.join("remove.js"),
+            "function(doc, req) { doc._deleted = true; return [doc, {body: 'gone'}]; }",
+        )
+        .unwrap();
+
+        let mut mock = crate::db::MockDatabase::new();
+        mock.expect_find_one().returning(|coll, id, _| {
+            let found = (coll == "orders" && id == "a")
+                .then(|| bson::doc! { "_id": "a", "_rev": "1-abc", "n": 1 });
+            Box::pin(async move { Ok(found) })
+        });
+        mock.expect_delete_one()
+            .times(1)
+            .withf(|coll, filter, _| {
+                coll == "orders" && *filter == bson::doc! { "_id": "a", "_rev": "1-abc" }
+            })
+            .returning(|_, _, _| Box::pin(async { Ok(1) }));
+        mock.expect_replace_one().times(0);
+
+        let state = Arc::new(AppState {
+            updates_folder: Some(folder.to_str().unwrap().to_string()),
+            ..AppState::for_tests(mock)
+        });
+        let request = UpdateRequest {
+            method: axum::http::Method::PUT,
+            headers: HeaderMap::new(),
+            query: HashMap::new(),
+            user_ctx: None,
+        };
+
+        let response = inner_execute_update_script(
+            "orders".to_string(),
+            "cleanup".to_string(),
+            "remove".to_string(),
+            Some("a".to_string()),
+            state,
+            Bytes::new(),
+            request,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.headers()["x-couch-update-newrev"], "1-abc");
+        let body = BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        assert_eq!(&body[..], b"gone");
+
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn test_update_script_outcome() {
+        assert_eq!(
+            update_script_outcome(&Ok(json!([{"_id": "a"}, {"body": "ok"}]))),
+            "ok"
+        );
+        assert_eq!(update_script_outcome(&Ok(json!([null, {}]))), "ok");
+        assert_eq!(
+            update_script_outcome(&Ok(json!({"_id": "a"}))),
+            "invalid_result"
+        );
+        assert_eq!(update_script_outcome(&Ok(json!([1, {}]))), "invalid_result");
+        assert_eq!(update_script_outcome(&Ok(json!([null]))), "invalid_result");
+        assert_eq!(
+            update_script_outcome(&Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "boom"}))
+            ))),
+            "script_error"
+        );
+        assert_eq!(update_script_outcome(&Err(script_timeout())), "timeout");
+    }
 }
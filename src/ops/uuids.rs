@@ -0,0 +1,184 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::{UuidAlgorithm, UuidSettings};
+use crate::ops::JsonWithStatusCodeResponse;
+use crate::state::AppState;
+use axum::extract::{Query, State};
+use axum::http::header::{CACHE_CONTROL, PRAGMA};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Once the counter of a sequential id passes this, a new prefix is started.
+const SEQUENTIAL_ROLLOVER: u32 = 0xfff000;
+
+/// Makes document ids with the configured algorithm.
+pub struct UuidGenerator {
+    settings: UuidSettings,
+    sequence: Mutex<Sequence>,
+}
+
+/// Where the `sequential` algorithm is up to.
+struct Sequence {
+    prefix: String,
+    counter: u32,
+}
+
+impl Sequence {
+    fn new() -> Self {
+        Sequence {
+            prefix: random_hex(13),
+            counter: increment(),
+        }
+    }
+}
+
+impl UuidGenerator {
+    pub fn new(settings: UuidSettings) -> Self {
+        UuidGenerator {
+            settings,
+            sequence: Mutex::new(Sequence::new()),
+        }
+    }
+
+    pub fn next(&self) -> String {
+        match self.settings.algorithm {
+            UuidAlgorithm::Random => random_hex(16),
+            UuidAlgorithm::Sequential => {
+                let mut sequence = self.sequence.lock().unwrap();
+                sequence.counter += increment();
+                if sequence.counter >= SEQUENTIAL_ROLLOVER {
+                    *sequence = Sequence::new();
+                }
+                format!("{}{:06x}", sequence.prefix, sequence.counter)
+            }
+            UuidAlgorithm::UtcRandom => format!("{}{}", utc_hex(), random_hex(9)),
+            UuidAlgorithm::UtcId => format!("{}{}", utc_hex(), self.settings.utc_id_suffix),
+        }
+    }
+}
+
+impl Default for UuidGenerator {
+    fn default() -> Self {
+        UuidGenerator::new(UuidSettings::default())
+    }
+}
+
+/// `bytes` random bytes as hex, at most 16.
+fn random_hex(bytes: usize) -> String {
+    hex::encode(&Uuid::new_v4().as_bytes()[..bytes])
+}
+
+/// A random step between 1 and 0xffe, as CouchDB uses.
+fn increment() -> u32 {
+    let bytes = Uuid::new_v4();
+    let random = u16::from_be_bytes([bytes.as_bytes()[0], bytes.as_bytes()[1]]);
+    u32::from(random) % 0xffe + 1
+}
+
+/// Microseconds since the epoch as 14 hex digits.
+fn utc_hex() -> String {
+    format!("{:014x}", chrono::Utc::now().timestamp_micros())
+}
+
+/// Hands out `count` new ids, one by default, as CouchDB's `/_uuids` does.
+pub async fn get_uuids(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let count = match params.get("count") {
+        Some(count) => count.parse::<usize>().map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "bad_request", "reason": "Invalid count parameter"})),
+            )
+        })?,
+        None => 1,
+    };
+
+    if count > state.uuids.settings.max_count {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "bad_request", "reason": "count parameter too large"})),
+        ));
+    }
+
+    let uuids: Vec<String> = (0..count).map(|_| state.uuids.next()).collect();
+
+    // Every response is different, so nothing along the way should cache one.
+    let mut response = Json(json!({ "uuids": uuids })).into_response();
+    let headers = response.headers_mut();
+    headers.insert(CACHE_CONTROL, "must-revalidate, no-cache".parse().unwrap());
+    headers.insert(PRAGMA, "no-cache".parse().unwrap());
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(algorithm: UuidAlgorithm) -> UuidGenerator {
+        UuidGenerator::new(UuidSettings {
+            algorithm,
+            utc_id_suffix: "-node1".to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_random() {
+        let id = generator(UuidAlgorithm::Random).next();
+        assert_eq!(id.len(), 32);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn test_sequential() {
+        let generator = generator(UuidAlgorithm::Sequential);
+        let ids: Vec<String> = (0..100).map(|_| generator.next()).collect();
+
+        assert!(ids.iter().all(|id| id.len() == 32));
+        assert!(ids.iter().all(|id| id[..26] == ids[0][..26]));
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_sequential_rolls_over() {
+        let generator = generator(UuidAlgorithm::Sequential);
+        let first = generator.next();
+        generator.sequence.lock().unwrap().counter = SEQUENTIAL_ROLLOVER - 1;
+
+        let next = generator.next();
+        assert_ne!(first[..26], next[..26]);
+        assert!(u32::from_str_radix(&next[26..], 16).unwrap() < 0x1000);
+    }
+
+    #[test]
+    fn test_utc() {
+        let utc_random = generator(UuidAlgorithm::UtcRandom);
+        let (first, second) = (utc_random.next(), utc_random.next());
+        assert_eq!(first.len(), 32);
+        assert!(first[..14] <= second[..14]);
+
+        let id = generator(UuidAlgorithm::UtcId).next();
+        assert_eq!(id.len(), 14 + "-node1".len());
+        assert!(id.ends_with("-node1"));
+    }
+}
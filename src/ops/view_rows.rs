@@ -0,0 +1,276 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::DesignView;
+use bson::{RawBsonRef, RawDocument};
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+/// A row of a view, written straight from the raw document MongoDB returned rather than going
+/// through `Document` and `serde_json::Value` first, which is where large views spent their time.
+pub struct ViewRow<'a> {
+    view: &'a DesignView,
+    doc: &'a RawDocument,
+
+    /// The document itself, for `include_docs`.
+    pub included: Option<Value>,
+}
+
+impl<'a> ViewRow<'a> {
+    pub fn new(view: &'a DesignView, doc: &'a RawDocument) -> Self {
+        ViewRow {
+            view,
+            doc,
+            included: None,
+        }
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.doc.get_str("_id").ok()
+    }
+}
+
+fn field<'a>(doc: &'a RawDocument, name: &str) -> Option<RawBsonRef<'a>> {
+    doc.get(name).ok().flatten()
+}
+
+impl Serialize for ViewRow<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        if !self.view.omit_id {
+            map.serialize_entry("id", &field(self.doc, "_id"))?;
+        }
+        map.serialize_entry("key", &Key(self))?;
+        map.serialize_entry("value", &RowValue(self))?;
+        match &self.included {
+            Some(included) => map.serialize_entry("doc", included)?,
+            None if !self.view.doc_fields.is_empty() => {
+                map.serialize_entry("doc", &DocFields(self))?
+            }
+            None => {}
+        }
+        map.end()
+    }
+}
+
+/// The key fields, on their own when there's just one unless the view always wants a list.
+struct Key<'a>(&'a ViewRow<'a>);
+
+impl Serialize for Key<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ViewRow { view, doc, .. } = self.0;
+
+        if view.key_fields.len() == 1 && !view.single_item_key_is_list {
+            return field(doc, &view.key_fields[0]).serialize(serializer);
+        }
+
+        let mut seq = serializer.serialize_seq(Some(view.key_fields.len()))?;
+        for name in &view.key_fields {
+            seq.serialize_element(&field(doc, name))?;
+        }
+        seq.end()
+    }
+}
+
+/// The value fields as an object, or the value on its own when there's just one unless the view
+/// always wants an object.
+struct RowValue<'a>(&'a ViewRow<'a>);
+
+impl Serialize for RowValue<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ViewRow { view, doc, .. } = self.0;
+
+        let values: Vec<_> = view
+            .value_fields
+            .iter()
+            .map(|name| (name, field(doc, name)))
+            .filter(|(_, value)| {
+                !view.omit_null_keys_in_value
+                    || value.is_some_and(|v| !matches!(v, RawBsonRef::Null))
+            })
+            .collect();
+
+        if values.len() == 1 && !view.single_item_value_is_dict {
+            return values[0].1.serialize(serializer);
+        }
+
+        let mut map = serializer.serialize_map(Some(values.len()))?;
+        for (name, value) in values {
+            let name = view.value_field_names.get(name).unwrap_or(name);
+            map.serialize_entry(name, &value)?;
+        }
+        map.end()
+    }
+}
+
+/// The view's `doc_fields` as an object, leaving out those the row doesn't have. Used for `doc`
+/// when `include_docs` isn't.
+struct DocFields<'a>(&'a ViewRow<'a>);
+
+impl Serialize for DocFields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ViewRow { view, doc, .. } = self.0;
+
+        let mut map = serializer.serialize_map(None)?;
+        for name in &view.doc_fields {
+            if let Some(value) = field(doc, name) {
+                map.serialize_entry(name, &value)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// A view's response, as CouchDB gives it.
+#[derive(Serialize)]
+pub struct ViewResponse<'a> {
+    pub total_rows: u64,
+    pub offset: Option<i64>,
+    pub rows: &'a [ViewRow<'a>],
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explain: Option<Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::{doc, oid::ObjectId, Bson, DateTime, Document, RawDocumentBuf};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn view(key_fields: &[&str], value_fields: &[&str]) -> DesignView {
+        DesignView {
+            match_fields: vec![],
+            sort_fields: None,
+            aggregation: vec![],
+            key_fields: key_fields.iter().map(|f| f.to_string()).collect(),
+            value_fields: value_fields.iter().map(|f| f.to_string()).collect(),
+            filter_insert_index: 0,
+            reduce: None,
+            single_item_key_is_list: false,
+            single_item_value_is_dict: false,
+            break_glass_js_script: None,
+            omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
+        }
+    }
+
+    fn row_json(view: &DesignView, doc: Document) -> Value {
+        let raw = RawDocumentBuf::from_document(&doc).unwrap();
+        serde_json::to_value(ViewRow::new(view, &raw)).unwrap()
+    }
+
+    #[test]
+    fn test_view_row() {
+        let doc = doc! { "_id": "a", "type": "order", "total": 5, "note": null };
+
+        assert_eq!(
+            row_json(&view(&["type"], &["total"]), doc.clone()),
+            json!({"id": "a", "key": "order", "value": 5})
+        );
+        assert_eq!(
+            row_json(&view(&["type", "missing"], &["total", "note"]), doc.clone()),
+            json!({"id": "a", "key": ["order", null], "value": {"total": 5, "note": null}})
+        );
+
+        let mut omitting = view(&["type"], &["total", "note"]);
+        omitting.omit_null_keys_in_value = true;
+        omitting.single_item_key_is_list = true;
+        assert_eq!(
+            row_json(&omitting, doc.clone()),
+            json!({"id": "a", "key": ["order"], "value": 5})
+        );
+
+        omitting.single_item_value_is_dict = true;
+        assert_eq!(
+            row_json(&omitting, doc),
+            json!({"id": "a", "key": ["order"], "value": {"total": 5}})
+        );
+    }
+
+    #[test]
+    fn test_view_row_shaping() {
+        let doc = doc! { "_id": "a", "type": "order", "total": 5, "note": "x", "name": "Ann" };
+
+        let mut shaped = view(&["type"], &["total", "note"]);
+        shaped.value_field_names = HashMap::from([("total".to_string(), "amount".to_string())]);
+        shaped.doc_fields = vec!["name".to_string(), "missing".to_string()];
+        shaped.omit_id = true;
+        assert_eq!(
+            row_json(&shaped, doc.clone()),
+            json!({"key": "order", "value": {"amount": 5, "note": "x"}, "doc": {"name": "Ann"}})
+        );
+
+        // include_docs gives the whole document instead.
+        let raw = RawDocumentBuf::from_document(&doc).unwrap();
+        let mut row = ViewRow::new(&shaped, &raw);
+        row.included = Some(json!({"_id": "a"}));
+        assert_eq!(
+            serde_json::to_value(row).unwrap(),
+            json!({"key": "order", "value": {"amount": 5, "note": "x"}, "doc": {"_id": "a"}})
+        );
+    }
+
+    #[test]
+    fn test_raw_values_match_documents() {
+        // The raw path has to give the same JSON as the `Document` one did.
+        let doc = doc! {
+            "_id": ObjectId::parse_str("65a1b2c3d4e5f60718293a4b").unwrap(),
+            "when": DateTime::from_millis(1_700_000_000_000),
+            "nested": { "list": [1, 2.5, "x", true, null], "i64": 1i64 << 40 },
+        };
+        let raw = RawDocumentBuf::from_document(&doc).unwrap();
+
+        for (name, value) in &doc {
+            assert_eq!(
+                serde_json::to_value(field(&raw, name)).unwrap(),
+                json!(value),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            serde_json::to_value(field(&raw, "missing")).unwrap(),
+            json!(Bson::Null)
+        );
+    }
+
+    #[test]
+    fn test_view_response() {
+        let view = view(&["type"], &["total"]);
+        let raw =
+            RawDocumentBuf::from_document(&doc! { "_id": "a", "type": "t", "total": 1 }).unwrap();
+        let mut row = ViewRow::new(&view, &raw);
+        row.included = Some(json!({ "_id": "a" }));
+
+        let response = ViewResponse {
+            total_rows: 10,
+            offset: Some(0),
+            rows: &[row],
+            explain: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({
+                "total_rows": 10,
+                "offset": 0,
+                "rows": [{"id": "a", "key": "t", "value": 1, "doc": {"_id": "a"}}]
+            })
+        );
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::auth::UserCtx;
+use crate::config::{RateLimit, RateLimitSettings};
+use crate::state::AppState;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Buckets that have been idle this long are full again, so they can be dropped.
+const IDLE_BUCKET_EXPIRY: Duration = Duration::from_secs(300);
+
+/// Once there are this many buckets, idle ones are dropped on the next request.
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        RateLimiter {
+            settings,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn limit_for(&self, db: &str) -> &RateLimit {
+        self.settings
+            .databases
+            .get(db)
+            .unwrap_or(&self.settings.default)
+    }
+
+    /// Takes a token from the client's bucket for the database. When the bucket is empty the
+    /// time until the next token is returned instead.
+    pub fn check(&self, client: &str, db: &str, now: Instant) -> Result<(), Duration> {
+        let limit = self.limit_for(db);
+        let burst = limit.burst as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, b| now.duration_since(b.updated) < IDLE_BUCKET_EXPIRY);
+        }
+
+        let bucket = buckets
+            .entry((client.to_string(), db.to_string()))
+            .or_insert(Bucket {
+                tokens: burst,
+                updated: now,
+            });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.requests_per_second).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if limit.requests_per_second > 0.0 {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / limit.requests_per_second,
+            ))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+}
+
+/// Works out who a request is from: the authenticated user if there is one, otherwise the
+/// address it came from.
+fn client_for_request(req: &Request<Body>) -> String {
+    if let Some(name) = req
+        .extensions()
+        .get::<UserCtx>()
+        .and_then(|u| u.name.as_ref())
+    {
+        return format!("user:{}", name);
+    }
+
+    match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Rejects requests from clients that have used up their allowance for the database with a
+/// `429 Too Many Requests`.
+pub async fn check_rate_limit(
+    State(state): State<Arc<AppState>>,
+    Path((db,)): Path<(String,)>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let rate_limiter = match &state.rate_limiter {
+        Some(rate_limiter) => rate_limiter,
+        None => return next.run(req).await,
+    };
+
+    let client = client_for_request(&req);
+
+    if let Err(retry_after) = rate_limiter.check(&client, &db, Instant::now()) {
+        warn!(
+            client = client.as_str(),
+            db = db.as_str(),
+            "rate limit exceeded"
+        );
+
+        let mut res = (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({
+                "error": "too_many_requests",
+                "reason": "You have exceeded your request rate for this db."
+            })),
+        )
+            .into_response();
+
+        let seconds = retry_after.as_secs_f64().ceil().min(u32::MAX as f64) as u32;
+        res.headers_mut()
+            .insert("Retry-After", HeaderValue::from(seconds.max(1)));
+
+        return res;
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::hashmap;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitSettings {
+            default: RateLimit {
+                requests_per_second: 1.0,
+                burst: 2,
+            },
+            databases: hashmap! {
+                "busy".to_string() => RateLimit {
+                    requests_per_second: 10.0,
+                    burst: 5,
+                },
+            },
+        })
+    }
+
+    #[test]
+    fn test_bucket_empties_and_refills() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        assert!(limiter.check("a", "db", now).is_ok());
+        assert!(limiter.check("a", "db", now).is_ok());
+
+        let retry_after = limiter.check("a", "db", now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        assert!(limiter
+            .check("a", "db", now + Duration::from_secs(1))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_buckets_are_per_client_and_database() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            assert!(limiter.check("a", "db", now).is_ok());
+        }
+        assert!(limiter.check("a", "db", now).is_err());
+
+        assert!(limiter.check("b", "db", now).is_ok());
+        assert!(limiter.check("a", "other", now).is_ok());
+    }
+
+    #[test]
+    fn test_database_override() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert!(limiter.check("a", "busy", now).is_ok());
+        }
+        assert!(limiter.check("a", "busy", now).is_err());
+    }
+}
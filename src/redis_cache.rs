@@ -0,0 +1,414 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::{resolve_secret, RedisCacheSettings};
+use crate::db::{ChangeStream, Database};
+use crate::doc_cache::CacheInvalidator;
+use async_trait::async_trait;
+use bson::{doc, Document, RawArrayBuf, RawDocumentBuf};
+use futures_util::StreamExt;
+use mongodb::error::Error;
+use mongodb::options::{AggregateOptions, DeleteOptions, FindOneOptions, ReplaceOptions};
+use mongodb::results::UpdateResult;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, Client};
+use serde_json::json;
+use std::time::Duration;
+use tracing::warn;
+
+/// Where writes are announced, so every replica can drop the document from its own cache.
+pub const INVALIDATION_CHANNEL: &str = "couchapi:invalidate";
+
+/// Caches documents and view results in Redis, shared by every replica. Each collection has a
+/// generation that writes bump, and cached entries are keyed by it, so a write retires everything
+/// read from the collection before it and a read racing a write can't leave a stale entry behind.
+pub struct RedisCachedDatabase {
+    inner: Box<dyn Database + Send + Sync>,
+    redis: ConnectionManager,
+    ttl: Duration,
+    views: bool,
+}
+
+impl RedisCachedDatabase {
+    pub async fn connect(
+        inner: Box<dyn Database + Send + Sync>,
+        settings: &RedisCacheSettings,
+    ) -> Result<Self, String> {
+        let redis = ConnectionManager::new(client(settings)?)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(RedisCachedDatabase {
+            inner,
+            redis,
+            ttl: Duration::from_millis(settings.ttl_ms),
+            views: settings.views,
+        })
+    }
+
+    async fn generation(&self, coll: &str) -> Option<u64> {
+        let mut redis = self.redis.clone();
+        match redis.get::<_, Option<u64>>(generation_key(coll)).await {
+            Ok(generation) => Some(generation.unwrap_or(0)),
+            Err(e) => {
+                warn!(error = e.to_string(), "unable to read from the redis cache");
+                None
+            }
+        }
+    }
+
+    async fn get<T>(
+        &self,
+        key: &str,
+        kind: &'static str,
+        parse: impl FnOnce(Vec<u8>) -> Option<T>,
+    ) -> Option<T> {
+        let mut redis = self.redis.clone();
+        let cached = match redis.get::<_, Option<Vec<u8>>>(key).await {
+            Ok(cached) => cached,
+            Err(e) => {
+                warn!(error = e.to_string(), "unable to read from the redis cache");
+                None
+            }
+        };
+
+        let value = cached.and_then(parse);
+        let outcome = if value.is_some() { "hit" } else { "miss" };
+        metrics::increment_counter!(
+            "couchapi_redis_cache_requests_total",
+            "kind" => kind,
+            "outcome" => outcome
+        );
+        value
+    }
+
+    async fn set(&self, key: &str, bytes: Vec<u8>) {
+        let mut redis = self.redis.clone();
+        let ttl = self.ttl.as_millis() as u64;
+        let result: redis::RedisResult<()> = redis::cmd("SET")
+            .arg(key)
+            .arg(bytes)
+            .arg("PX")
+            .arg(ttl.max(1))
+            .query_async(&mut redis)
+            .await;
+
+        if let Err(e) = result {
+            warn!(error = e.to_string(), "unable to write to the redis cache");
+        }
+    }
+
+    /// Retires the collection's cached entries and tells the other replicas about the write.
+    async fn written(&self, coll: &str, filter: &Document) {
+        let mut redis = self.redis.clone();
+        let result: redis::RedisResult<()> = redis::pipe()
+            .incr(generation_key(coll), 1)
+            .ignore()
+            .publish(INVALIDATION_CHANNEL, invalidation_message(coll, filter))
+            .ignore()
+            .query_async(&mut redis)
+            .await;
+
+        if let Err(e) = result {
+            warn!(
+                error = e.to_string(),
+                "unable to invalidate the redis cache, entries will expire on their own"
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl Database for RedisCachedDatabase {
+    async fn get_version(&self) -> Result<Document, Error> {
+        self.inner.get_version().await
+    }
+
+    async fn find_one(
+        &self,
+        coll: &str,
+        id: &str,
+        options: FindOneOptions,
+    ) -> Result<Option<Document>, Error> {
+        let Some(generation) = self.generation(coll).await else {
+            return self.inner.find_one(coll, id, options).await;
+        };
+
+        let key = document_key(coll, generation, id);
+        let parse = |bytes: Vec<u8>| bson::from_slice(&bytes).ok();
+        if let Some(document) = self.get(&key, "document", parse).await {
+            return Ok(Some(document));
+        }
+
+        let result = self.inner.find_one(coll, id, options).await;
+        if let Ok(Some(document)) = &result {
+            if let Ok(bytes) = bson::to_vec(document) {
+                self.set(&key, bytes).await;
+            }
+        }
+        result
+    }
+
+    async fn replace_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        replacement: Document,
+        options: ReplaceOptions,
+    ) -> Result<UpdateResult, Error> {
+        let result = self
+            .inner
+            .replace_one(coll, filter.clone(), replacement, options)
+            .await;
+
+        // Even a failed write may have been applied.
+        self.written(coll, &filter).await;
+        result
+    }
+
+    async fn delete_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        options: DeleteOptions,
+    ) -> Result<u64, Error> {
+        let result = self.inner.delete_one(coll, filter.clone(), options).await;
+        self.written(coll, &filter).await;
+        result
+    }
+
+    async fn aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+        options: AggregateOptions,
+    ) -> Result<Vec<RawDocumentBuf>, Error> {
+        let generation = if self.views {
+            self.generation(coll).await
+        } else {
+            None
+        };
+        let Some(generation) = generation else {
+            return self.inner.aggregate(coll, pipeline, options).await;
+        };
+
+        let key = view_key(coll, generation, &pipeline);
+        if let Some(rows) = self.get(&key, "view", rows_from_bytes).await {
+            return Ok(rows);
+        }
+
+        let result = self.inner.aggregate(coll, pipeline, options).await;
+        if let Ok(rows) = &result {
+            self.set(&key, rows_to_bytes(rows)).await;
+        }
+        result
+    }
+
+    async fn explain_aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Document, Error> {
+        self.inner.explain_aggregate(coll, pipeline).await
+    }
+
+    async fn count(&self, coll: &str) -> Result<u64, Error> {
+        self.inner.count(coll).await
+    }
+
+    async fn collection_stats(&self, coll: &str) -> Result<Option<Document>, Error> {
+        self.inner.collection_stats(coll).await
+    }
+
+    async fn watch(
+        &self,
+        coll: &str,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeStream, Error> {
+        self.inner.watch(coll, resume_after).await
+    }
+
+    async fn watch_database(&self, resume_after: Option<Document>) -> Result<ChangeStream, Error> {
+        self.inner.watch_database(resume_after).await
+    }
+
+    async fn create_ttl_index(&self, coll: &str, field: &str) -> Result<(), Error> {
+        self.inner.create_ttl_index(coll, field).await
+    }
+
+    async fn update_many(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<u64, Error> {
+        let result = self.inner.update_many(coll, filter.clone(), update).await;
+        self.written(coll, &filter).await;
+        result
+    }
+
+    async fn delete_many(&self, coll: &str, filter: Document) -> Result<u64, Error> {
+        let result = self.inner.delete_many(coll, filter.clone()).await;
+        self.written(coll, &filter).await;
+        result
+    }
+}
+
+fn client(settings: &RedisCacheSettings) -> Result<Client, String> {
+    let url = resolve_secret(&settings.url)?;
+    Client::open(url).map_err(|e| e.to_string())
+}
+
+fn generation_key(coll: &str) -> String {
+    format!("couchapi:generation:{}", coll)
+}
+
+fn document_key(coll: &str, generation: u64, id: &str) -> String {
+    format!("couchapi:doc:{}:{}:{}", coll, generation, id)
+}
+
+fn view_key(coll: &str, generation: u64, pipeline: &[Document]) -> String {
+    let pipeline = bson::to_vec(&doc! { "pipeline": pipeline }).unwrap_or_default();
+    format!(
+        "couchapi:view:{}:{}:{:x}",
+        coll,
+        generation,
+        md5::compute(pipeline)
+    )
+}
+
+/// Stores view rows as a raw `{"rows": [...]}` document, copying each row's bytes as they are.
+fn rows_to_bytes(rows: &[RawDocumentBuf]) -> Vec<u8> {
+    let mut array = RawArrayBuf::new();
+    for row in rows {
+        array.push(row.clone());
+    }
+
+    let mut document = RawDocumentBuf::new();
+    document.append("rows", array);
+    document.into_bytes()
+}
+
+fn rows_from_bytes(bytes: Vec<u8>) -> Option<Vec<RawDocumentBuf>> {
+    let document = RawDocumentBuf::from_bytes(bytes).ok()?;
+    document
+        .get_array("rows")
+        .ok()?
+        .into_iter()
+        .map(|row| Some(row.ok()?.as_document()?.to_raw_document_buf()))
+        .collect()
+}
+
+/// Names the collection and, when the write's filter names one, the document.
+fn invalidation_message(coll: &str, filter: &Document) -> String {
+    json!({ "coll": coll, "id": filter.get_str("_id").ok() }).to_string()
+}
+
+/// Reads an invalidation back as a collection and a filter for `CacheInvalidator`.
+fn parse_invalidation(message: &str) -> Option<(String, Document)> {
+    let message: serde_json::Value = serde_json::from_str(message).ok()?;
+    let coll = message.get("coll")?.as_str()?.to_string();
+
+    let filter = match message.get("id").and_then(|id| id.as_str()) {
+        Some(id) => doc! { "_id": id },
+        None => doc! {},
+    };
+    Some((coll, filter))
+}
+
+/// Drops documents from this replica's in-memory cache as other replicas write them. Runs until
+/// the server stops, reconnecting to Redis after a failure.
+pub fn subscribe_invalidations(settings: &RedisCacheSettings, invalidator: CacheInvalidator) {
+    let client = client(settings).expect("invalid redis_cache url");
+
+    tokio::spawn(async move {
+        loop {
+            match client.get_async_pubsub().await {
+                Ok(mut pubsub) => match pubsub.subscribe(INVALIDATION_CHANNEL).await {
+                    Ok(()) => {
+                        let mut messages = pubsub.on_message();
+                        while let Some(message) = messages.next().await {
+                            let Ok(payload) = message.get_payload::<String>() else {
+                                continue;
+                            };
+                            if let Some((coll, filter)) = parse_invalidation(&payload) {
+                                invalidator.invalidate(&coll, &filter);
+                            }
+                        }
+                        warn!("lost the redis invalidation subscription, reconnecting");
+                    }
+                    Err(e) => warn!(
+                        error = e.to_string(),
+                        "unable to subscribe to redis invalidations"
+                    ),
+                },
+                Err(e) => warn!(
+                    error = e.to_string(),
+                    "unable to connect to redis for invalidations"
+                ),
+            }
+
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(document_key("orders", 3, "a"), "couchapi:doc:orders:3:a");
+
+        let pipeline = vec![doc! { "$match": { "n": 1 } }];
+        let key = view_key("orders", 3, &pipeline);
+        assert!(key.starts_with("couchapi:view:orders:3:"));
+        assert_eq!(key, view_key("orders", 3, &pipeline));
+        assert_ne!(key, view_key("orders", 4, &pipeline));
+        assert_ne!(key, view_key("orders", 3, &[doc! { "$match": { "n": 2 } }]));
+    }
+
+    #[test]
+    fn test_rows_bytes() {
+        let rows = vec![
+            RawDocumentBuf::from_document(&doc! { "id": "a" }).unwrap(),
+            RawDocumentBuf::from_document(&doc! { "id": "b" }).unwrap(),
+        ];
+        assert_eq!(rows_from_bytes(rows_to_bytes(&rows)), Some(rows));
+        assert_eq!(rows_from_bytes(rows_to_bytes(&[])), Some(vec![]));
+
+        let not_rows = |document| bson::to_vec(&document).unwrap();
+        assert_eq!(rows_from_bytes(not_rows(doc! { "rows": [1] })), None);
+        assert_eq!(rows_from_bytes(not_rows(doc! {})), None);
+        assert_eq!(rows_from_bytes(b"not bson".to_vec()), None);
+    }
+
+    #[test]
+    fn test_invalidation_messages() {
+        let message = invalidation_message("orders", &doc! { "_id": "a", "_rev": "1-a" });
+        assert_eq!(
+            parse_invalidation(&message),
+            Some(("orders".to_string(), doc! { "_id": "a" }))
+        );
+
+        let message = invalidation_message("orders", &doc! { "n": 1 });
+        assert_eq!(
+            parse_invalidation(&message),
+            Some(("orders".to_string(), doc! {}))
+        );
+
+        assert_eq!(parse_invalidation("not json"), None);
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sends server errors and panics to an error tracker as JSON events, so that failing scripts
+//! and MongoDB errors don't only show up in the logs.
+
+use crate::common::{db_from_path, REQUEST_ID};
+use crate::config::ErrorReporting;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use http_body_util::BodyExt;
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::panic;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// The JSON body posted to the error tracker.
+#[derive(Debug, Serialize)]
+pub struct ErrorEvent {
+    pub kind: &'static str,
+    pub message: String,
+    pub status: Option<u16>,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub db: Option<String>,
+    pub request_id: Option<String>,
+    pub environment: Option<String>,
+    pub timestamp: u64,
+}
+
+pub struct ErrorReporter {
+    client: reqwest::Client,
+    settings: ErrorReporting,
+}
+
+impl ErrorReporter {
+    pub fn new(settings: ErrorReporting) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.timeout))
+            .build()
+            .unwrap();
+
+        ErrorReporter { client, settings }
+    }
+
+    fn event(&self, kind: &'static str, message: String) -> ErrorEvent {
+        ErrorEvent {
+            kind,
+            message,
+            status: None,
+            method: None,
+            path: None,
+            db: None,
+            request_id: REQUEST_ID.try_with(|id| id.clone()).ok(),
+            environment: self.settings.environment.clone(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    /// Posts the event in the background. Failing to report is logged but otherwise ignored.
+    fn report(&self, event: ErrorEvent) {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let request = self.client.post(&self.settings.url).json(&event);
+        handle.spawn(async move {
+            if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                warn!(error = e.to_string(), "unable to report error");
+            }
+        });
+    }
+}
+
+/// Pulls a readable message out of an error response body, which is usually CouchDB style JSON.
+fn message_from_body(body: &[u8]) -> String {
+    let json: Option<Value> = serde_json::from_slice(body).ok();
+    let field = |name: &str| {
+        json.as_ref()
+            .and_then(|j| j.get(name))
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+    };
+
+    match (field("error"), field("reason")) {
+        (Some(error), Some(reason)) => format!("{}: {}", error, reason),
+        (Some(error), None) => error,
+        _ => String::from_utf8_lossy(body).to_string(),
+    }
+}
+
+/// Reports every 5xx response along with the request's method, path, database and id.
+pub async fn report_server_errors(
+    State(reporter): State<Arc<ErrorReporter>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+
+    let res = next.run(req).await;
+    if !res.status().is_server_error() {
+        return res;
+    }
+
+    let (parts, body) = res.into_parts();
+    let bytes = BodyExt::collect(body)
+        .await
+        .map(|b| b.to_bytes())
+        .unwrap_or_default();
+
+    let event = ErrorEvent {
+        status: Some(parts.status.as_u16()),
+        method: Some(method),
+        db: db_from_path(&path),
+        path: Some(path),
+        ..reporter.event("response", message_from_body(&bytes))
+    };
+    reporter.report(event);
+
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// Reports panics before handing them on to the existing panic hook.
+pub fn install_panic_hook(reporter: Arc<ErrorReporter>) {
+    let previous = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "panic".to_string());
+
+        let message = match info.location() {
+            Some(location) => format!("{} at {}", payload, location),
+            None => payload,
+        };
+
+        reporter.report(reporter.event("panic", message));
+        previous(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use httpmock::Method::POST;
+    use httpmock::MockServer;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_message_from_body() {
+        assert_eq!(
+            message_from_body(br#"{"error": "script_error", "reason": "boom"}"#),
+            "script_error: boom"
+        );
+        assert_eq!(message_from_body(br#"{"error": "boom"}"#), "boom");
+        assert_eq!(message_from_body(b"plain text"), "plain text");
+    }
+
+    #[tokio::test]
+    async fn test_report_server_errors() {
+        let server = MockServer::start_async().await;
+
+        let mock = server
+            .mock_async(|when, then| {
+                when.method(POST).path("/report").json_body_partial(
+                    json!({
+                        "kind": "response",
+                        "message": "boom",
+                        "status": 500,
+                        "method": "GET",
+                        "path": "/orders/abc",
+                        "db": "orders",
+                        "request_id": "abc-123",
+                        "environment": "test",
+                    })
+                    .to_string(),
+                );
+                then.status(200);
+            })
+            .await;
+
+        let reporter = Arc::new(ErrorReporter::new(ErrorReporting {
+            url: server.url("/report"),
+            environment: Some("test".to_string()),
+            timeout: 5,
+        }));
+
+        let app = Router::new()
+            .route(
+                "/:db/:item",
+                get(|| async {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        axum::Json(json!({"error": "boom"})),
+                    )
+                }),
+            )
+            .route("/:db", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                reporter,
+                report_server_errors,
+            ));
+
+        let req = Request::builder()
+            .uri("/orders")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder()
+            .uri("/orders/abc")
+            .body(Body::empty())
+            .unwrap();
+        let res = REQUEST_ID
+            .scope("abc-123".to_string(), app.oneshot(req))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        assert_eq!(body, json!({"error": "boom"}).to_string());
+
+        // The report is sent in the background.
+        for _ in 0..50 {
+            if mock.hits_async().await == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::RequestTimeoutSettings;
+use axum::body::Body;
+use axum::extract::{MatchedPath, State};
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+tokio::task_local! {
+    /// When the request being handled will be abandoned.
+    static DEADLINE: Instant;
+}
+
+/// How long each route may take before its request is abandoned.
+#[derive(Debug)]
+pub struct RequestTimeouts {
+    default: Option<Duration>,
+    routes: HashMap<String, Duration>,
+}
+
+impl RequestTimeouts {
+    pub fn new(settings: &RequestTimeoutSettings) -> Self {
+        RequestTimeouts {
+            default: settings.default_ms.map(Duration::from_millis),
+            routes: settings
+                .routes
+                .iter()
+                .map(|(route, ms)| (route.clone(), Duration::from_millis(*ms)))
+                .collect(),
+        }
+    }
+
+    fn for_route(&self, route: Option<&str>) -> Option<Duration> {
+        route
+            .and_then(|route| self.routes.get(route))
+            .copied()
+            .or(self.default)
+    }
+}
+
+/// Responds with a `503` when a request runs past its route's timeout. Dropping the handler stops
+/// its work here, and MongoDB operations started through `remaining_time` stop there too.
+pub async fn enforce_timeout(
+    State(timeouts): State<Arc<RequestTimeouts>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+
+    let Some(timeout) = timeouts.for_route(route.as_deref()) else {
+        return next.run(req).await;
+    };
+
+    let deadline = Instant::now() + timeout;
+    match tokio::time::timeout_at(deadline, DEADLINE.scope(deadline, next.run(req))).await {
+        Ok(res) => res,
+        Err(_) => {
+            metrics::increment_counter!("couchapi_requests_timed_out_total");
+
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({
+                    "error": "timeout",
+                    "reason": format!("The request took longer than {}ms.", timeout.as_millis())
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// The time left before the current request is abandoned, for MongoDB's `maxTimeMS`. `None` when
+/// the request has no timeout. Never zero, as MongoDB reads a zero as no limit.
+pub fn remaining_time() -> Option<Duration> {
+    DEADLINE
+        .try_with(|deadline| deadline.saturating_duration_since(Instant::now()))
+        .ok()
+        .map(|remaining| remaining.max(Duration::from_millis(1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_for_route() {
+        let timeouts = RequestTimeouts::new(&RequestTimeoutSettings {
+            default_ms: Some(1000),
+            routes: HashMap::from([("/:db/_all_docs".to_string(), 5000)]),
+        });
+
+        assert_eq!(
+            timeouts.for_route(Some("/:db/_all_docs")),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            timeouts.for_route(Some("/:db/:item")),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(timeouts.for_route(None), Some(Duration::from_secs(1)));
+
+        let no_default = RequestTimeouts::new(&RequestTimeoutSettings {
+            default_ms: None,
+            routes: HashMap::new(),
+        });
+        assert_eq!(no_default.for_route(Some("/:db/:item")), None);
+    }
+
+    #[tokio::test]
+    async fn test_enforce_timeout() {
+        let timeouts = Arc::new(RequestTimeouts::new(&RequestTimeoutSettings {
+            default_ms: Some(50),
+            routes: HashMap::from([("/fast".to_string(), 5000)]),
+        }));
+
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "done"
+                }),
+            )
+            .route(
+                "/fast",
+                get(|| async {
+                    let remaining = remaining_time().unwrap();
+                    assert!(remaining <= Duration::from_secs(5));
+                    "done"
+                }),
+            )
+            .layer(middleware::from_fn_with_state(timeouts, enforce_timeout));
+
+        let request = |uri| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let res = app.clone().oneshot(request("/slow")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = http_body_util::BodyExt::collect(res.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "timeout");
+
+        let res = app.oneshot(request("/fast")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_remaining_time_outside_a_request() {
+        assert_eq!(remaining_time(), None);
+    }
+}
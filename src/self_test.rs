@@ -0,0 +1,254 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::{load_views_from_folder, CouchDb, DesignMapping, Settings};
+use boa_engine::{Context, Script, Source};
+use bson::doc;
+use std::collections::HashMap;
+use walkdir::WalkDir;
+
+/// The outcome of one self-test check.
+struct Check {
+    name: String,
+    result: Result<String, String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: Result<String, String>) -> Self {
+        Check {
+            name: name.into(),
+            result,
+        }
+    }
+}
+
+/// Checks everything the server needs before it can take traffic: the secrets, the views, the
+/// MongoDB and CouchDB connections, and every break glass and update script. Prints a report and
+/// returns the process exit code.
+pub async fn run_self_test(mut settings: Settings) -> i32 {
+    let mut checks = Vec::new();
+
+    checks.push(Check::new(
+        "secrets",
+        settings.resolve_secrets().map(|_| "resolved".to_string()),
+    ));
+
+    if settings.views.is_none() {
+        if let Some(folder) = &settings.view_folder {
+            let (views, errors) = load_views_from_folder(folder);
+            checks.extend(errors.into_iter().map(|e| Check::new("views", Err(e))));
+            settings.views = Some(views);
+        }
+    }
+
+    let view_count = settings
+        .views
+        .iter()
+        .flat_map(|v| v.values())
+        .flat_map(|m| m.view_groups.values())
+        .map(|g| g.len())
+        .sum::<usize>();
+    checks.push(Check::new("views", Ok(format!("{} loaded", view_count))));
+
+    checks.push(Check::new("mongodb", check_mongodb(&settings).await));
+
+    if let Some(couchdb) = &settings.couchdb_settings {
+        checks.push(Check::new("couchdb", check_couchdb(couchdb).await));
+    }
+
+    if let Some(views) = &settings.views {
+        checks.extend(check_break_glass_scripts(views));
+    }
+
+    if let Some(folder) = &settings.updates_folder {
+        checks.extend(check_update_scripts(folder));
+    }
+
+    for check in &checks {
+        match &check.result {
+            Ok(detail) => println!("ok   {}: {}", check.name, detail),
+            Err(detail) => println!("FAIL {}: {}", check.name, detail),
+        }
+    }
+
+    let failures = checks.iter().filter(|c| c.result.is_err()).count();
+    if failures > 0 {
+        println!("self-test failed: {} of {} checks", failures, checks.len());
+        return 1;
+    }
+
+    println!("self-test passed");
+    0
+}
+
+async fn check_mongodb(settings: &Settings) -> Result<String, String> {
+    let db = settings
+        .get_mongodb_database()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // The driver connects lazily, so nothing has touched the server until this command runs.
+    db.run_command(doc! { "ping": 1 }, None)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(format!("connected to {}", settings.mongodb_database))
+}
+
+async fn check_couchdb(couchdb: &CouchDb) -> Result<String, String> {
+    let mut req = reqwest::Client::new().get(&couchdb.url);
+
+    if let (Some(username), Some(password)) = (&couchdb.username, &couchdb.password) {
+        req = req.basic_auth(username, Some(password));
+    }
+
+    let response = req.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} returned {}", couchdb.url, response.status()));
+    }
+
+    Ok(format!("reached {}", couchdb.url))
+}
+
+fn check_break_glass_scripts(views: &HashMap<String, DesignMapping>) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    for (db, mapping) in views {
+        for (design, group) in &mapping.view_groups {
+            for (view, v) in group {
+                let Some(script) = &v.break_glass_js_script else {
+                    continue;
+                };
+
+                let result = std::fs::read_to_string(script)
+                    .map_err(|e| format!("{}: {}", script, e))
+                    .and_then(|source| compile_script(&source))
+                    .map(|_| "compiled".to_string());
+
+                checks.push(Check::new(
+                    format!("break glass script {}/{}/{}", db, design, view),
+                    result,
+                ));
+            }
+        }
+    }
+
+    checks
+}
+
+/// Compiles every script under `folder`, wrapped the same way they are when they run. Update
+/// handlers live at `db/design/function.js`; anything else is a module loaded with `require`.
+fn check_update_scripts(folder: &str) -> Vec<Check> {
+    WalkDir::new(folder)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "js"))
+        .map(|entry| {
+            let path = entry.path();
+            let is_handler = entry.depth() == 3;
+            let result = std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|source| {
+                    if is_handler {
+                        compile_script(&format!("f = {}", source))
+                    } else {
+                        compile_script(&format!(
+                            "(function (module, exports, require) {{\n{}\n}})",
+                            source
+                        ))
+                    }
+                })
+                .map(|_| "compiled".to_string());
+
+            let kind = if is_handler {
+                "update script"
+            } else {
+                "module"
+            };
+            Check::new(format!("{} {}", kind, path.display()), result)
+        })
+        .collect()
+}
+
+fn compile_script(source: &str) -> Result<(), String> {
+    let mut context = Context::default();
+
+    Script::parse(Source::from_bytes(source.as_bytes()), None, &mut context)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+
+    #[test]
+    fn test_compile_script() {
+        assert!(compile_script("result = [1, 2, 3];").is_ok());
+        assert!(compile_script("result = [1, 2, 3;").is_err());
+    }
+
+    #[test]
+    fn test_check_update_scripts() {
+        let dir = std::env::temp_dir().join(format!("couchapi-self-test-{}", uuid::Uuid::new_v4()));
+        let design = dir.join("orders").join("orders");
+        std::fs::create_dir_all(&design).unwrap();
+        std::fs::write(
+            design.join("good.js"),
+            "function(doc, req) { return [doc, 'ok']; }",
+        )
+        .unwrap();
+        std::fs::write(design.join("bad.js"), "function(doc, req) { return [doc, ").unwrap();
+        std::fs::write(design.join("README.md"), "not a script").unwrap();
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("lib").join("good.js"),
+            "var rate = 0.2;\nexports.tax = function (p) { return p * rate; };",
+        )
+        .unwrap();
+
+        let checks = check_update_scripts(dir.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(checks.len(), 3);
+        for check in checks {
+            assert_eq!(check.result.is_ok(), check.name.ends_with("good.js"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_couchdb() {
+        let server = MockServer::start();
+        let root = server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200).body(r#"{"couchdb":"Welcome"}"#);
+        });
+
+        let couchdb: CouchDb = serde_json::from_value(serde_json::json!({
+            "url": server.base_url(),
+        }))
+        .unwrap();
+
+        assert!(check_couchdb(&couchdb).await.is_ok());
+        root.assert();
+
+        let couchdb: CouchDb = serde_json::from_value(serde_json::json!({
+            "url": server.url("/missing"),
+        }))
+        .unwrap();
+
+        assert!(check_couchdb(&couchdb).await.is_err());
+    }
+}
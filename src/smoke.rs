@@ -0,0 +1,541 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `smoke` subcommand: a quick check that a running server answers the requests common
+//! CouchDB clients make. Each sequence is a hand-written copy of the requests pouchdb, pycouchdb
+//! or nano sends; the libraries themselves aren't run, so it's no substitute for their test
+//! suites.
+
+use crate::bench::parse_headers;
+use crate::compat_report::Support;
+use clap::ValueEnum;
+use reqwest::{Client, RequestBuilder};
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Prefix of the ids of documents the checks write, so they're easy to find and clear up.
+const ID_PREFIX: &str = "couchapi-smoke-";
+
+/// The CouchDB client library a sequence of requests is copied from.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum CouchClient {
+    Pouchdb,
+    Pycouchdb,
+    Nano,
+}
+
+impl CouchClient {
+    fn name(self) -> &'static str {
+        match self {
+            CouchClient::Pouchdb => "pouchdb",
+            CouchClient::Pycouchdb => "pycouchdb",
+            CouchClient::Nano => "nano",
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SmokeArgs {
+    /// Where the server is listening.
+    #[arg(long, default_value = "http://localhost:5984")]
+    url: String,
+
+    /// The database to write the test documents to.
+    #[arg(long)]
+    db: String,
+
+    /// The clients whose requests to make, all of them when none are given.
+    #[arg(long = "client", value_enum)]
+    clients: Vec<CouchClient>,
+
+    /// Print the report as JSON.
+    #[arg(long)]
+    json: bool,
+
+    /// A header for every request, as `Name: value`, e.g. for an API key.
+    #[arg(long = "header")]
+    headers: Vec<String>,
+}
+
+/// What one check did.
+#[derive(Debug, Serialize)]
+struct Outcome {
+    client: &'static str,
+    check: &'static str,
+    endpoint: &'static str,
+    status: &'static str,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    detail: String,
+}
+
+/// Runs a client's checks in turn, skipping those on endpoints the server says it doesn't serve.
+struct Checker {
+    client: Client,
+    url: String,
+    db: String,
+    support: HashMap<String, Support>,
+    current: &'static str,
+    outcomes: Vec<Outcome>,
+}
+
+impl Checker {
+    fn server(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    fn db(&self, path: &str) -> String {
+        format!("{}/{}{}", self.url, self.db, path)
+    }
+
+    /// Sends a request, expecting `status` and a body `verify` is happy with. Returns the body,
+    /// or `None` when the check didn't pass.
+    async fn check(
+        &mut self,
+        check: &'static str,
+        endpoint: &'static str,
+        request: RequestBuilder,
+        status: u16,
+        verify: impl FnOnce(&Value) -> Result<(), String>,
+    ) -> Option<Value> {
+        let result = if self.support.get(endpoint) == Some(&Support::None) {
+            None
+        } else {
+            Some(send(request, status).await.and_then(|body| {
+                verify(&body)?;
+                Ok(body)
+            }))
+        };
+
+        let (outcome, detail, body) = match result {
+            None => ("skipped", "not served".to_string(), None),
+            Some(Ok(body)) => ("passed", String::new(), Some(body)),
+            Some(Err(e)) => ("failed", e, None),
+        };
+        self.outcomes.push(Outcome {
+            client: self.current,
+            check,
+            endpoint,
+            status: outcome,
+            detail,
+        });
+        body
+    }
+}
+
+async fn send(request: RequestBuilder, status: u16) -> Result<Value, String> {
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    let got = response.status().as_u16();
+    let body = response.json::<Value>().await.unwrap_or(Value::Null);
+
+    if got != status {
+        return Err(format!("expected {}, got {}: {}", status, got, body));
+    }
+    Ok(body)
+}
+
+fn has(field: &'static str) -> impl FnOnce(&Value) -> Result<(), String> {
+    move |body| match body.get(field) {
+        Some(_) => Ok(()),
+        None => Err(format!("no {} in {}", field, body)),
+    }
+}
+
+fn any(_: &Value) -> Result<(), String> {
+    Ok(())
+}
+
+fn new_id() -> String {
+    format!("{}{}", ID_PREFIX, Uuid::new_v4().simple())
+}
+
+/// Requests as pycouchdb makes them: checks the server, then writes, reads, conflicts on and
+/// deletes a document.
+async fn pycouchdb(c: &mut Checker) -> Option<()> {
+    let request = c.client.get(c.server("/"));
+    c.check("server info", "GET /", request, 200, has("version"))
+        .await?;
+
+    let id = new_id();
+    let request = c
+        .client
+        .put(c.db(&format!("/{}", id)))
+        .json(&json!({"n": 1}));
+    let created = c
+        .check("create", "PUT /{db}/{docid}", request, 201, has("rev"))
+        .await?;
+    let rev = created["rev"].as_str().unwrap_or_default().to_string();
+
+    let request = c.client.get(c.db(&format!("/{}", id)));
+    let expected = rev.clone();
+    c.check(
+        "read",
+        "GET /{db}/{docid}",
+        request,
+        200,
+        move |body| match body["_rev"].as_str() == Some(&expected) {
+            true => Ok(()),
+            false => Err(format!("expected _rev {} in {}", expected, body)),
+        },
+    )
+    .await?;
+
+    let request = c
+        .client
+        .put(c.db(&format!("/{}", id)))
+        .json(&json!({"_rev": "1-00000000000000000000000000000000", "n": 2}));
+    c.check(
+        "stale rev conflicts",
+        "PUT /{db}/{docid}",
+        request,
+        409,
+        any,
+    )
+    .await?;
+
+    let request = c
+        .client
+        .delete(c.db(&format!("/{}", id)))
+        .query(&[("rev", &rev)]);
+    c.check("delete", "DELETE /{db}/{docid}", request, 200, has("ok"))
+        .await?;
+
+    let request = c.client.get(c.db(&format!("/{}", id)));
+    c.check("deleted is missing", "GET /{db}/{docid}", request, 404, any)
+        .await?;
+    Some(())
+}
+
+/// Requests as nano makes them: gets ids, writes documents singly and in bulk and reads them
+/// back by key.
+async fn nano(c: &mut Checker) -> Option<()> {
+    let request = c.client.get(c.server("/_uuids"));
+    c.check("uuids", "GET /_uuids", request, 200, has("uuids"))
+        .await?;
+
+    let request = c.client.post(c.db("")).json(&json!({"n": 1}));
+    c.check("insert without id", "POST /{db}", request, 201, has("id"))
+        .await?;
+
+    let ids = [new_id(), new_id()];
+    let docs: Vec<Value> = ids.iter().map(|id| json!({"_id": id})).collect();
+    let request = c
+        .client
+        .post(c.db("/_bulk_docs"))
+        .json(&json!({ "docs": docs }));
+    c.check(
+        "bulk insert",
+        "POST /{db}/_bulk_docs",
+        request,
+        201,
+        |body| match body.as_array().map(|r| r.iter().all(|r| r["ok"] == true)) {
+            Some(true) => Ok(()),
+            _ => Err(format!("not every document was written: {}", body)),
+        },
+    )
+    .await?;
+
+    let request = c
+        .client
+        .post(c.db("/_all_docs"))
+        .query(&[("include_docs", "true")])
+        .json(&json!({ "keys": ids }));
+    c.check(
+        "fetch by keys",
+        "POST /{db}/_all_docs",
+        request,
+        200,
+        |body| match body["rows"].as_array().map(Vec::len) {
+            Some(2) => Ok(()),
+            _ => Err(format!("expected 2 rows in {}", body)),
+        },
+    )
+    .await?;
+
+    let request = c.client.get(c.db("/_all_docs")).query(&[
+        ("startkey", format!("\"{}\"", ID_PREFIX)),
+        ("endkey", format!("\"{}\u{fff0}\"", ID_PREFIX)),
+        ("limit", "10".to_string()),
+    ]);
+    c.check(
+        "list by range",
+        "GET /{db}/_all_docs",
+        request,
+        200,
+        has("rows"),
+    )
+    .await?;
+    Some(())
+}
+
+/// Requests as pouchdb makes them when it syncs: checks the database and session, reads the
+/// changes and keeps its checkpoint in a local document.
+async fn pouchdb(c: &mut Checker) -> Option<()> {
+    let request = c.client.get(c.db(""));
+    c.check(
+        "database info",
+        "GET /{db}",
+        request,
+        200,
+        has("update_seq"),
+    )
+    .await?;
+
+    let request = c.client.get(c.server("/_session"));
+    c.check("session", "GET /_session", request, 200, has("userCtx"))
+        .await?;
+
+    // pouchdb starts a new sync from 0, which is refused as past changes aren't kept.
+    let request = c
+        .client
+        .get(c.db("/_changes"))
+        .query(&[("since", "now"), ("limit", "1")]);
+    c.check(
+        "changes",
+        "GET /{db}/_changes",
+        request,
+        200,
+        has("results"),
+    )
+    .await?;
+
+    let id = new_id();
+    let request = c
+        .client
+        .post(c.db("/_revs_diff"))
+        .json(&json!({ id.clone(): ["1-00000000000000000000000000000000"] }));
+    c.check("revs diff", "POST /{db}/_revs_diff", request, 200, any)
+        .await;
+
+    let checkpoint = format!("/_local/{}", id);
+    let request = c
+        .client
+        .put(c.db(&checkpoint))
+        .json(&json!({"last_seq": "0"}));
+    c.check(
+        "write checkpoint",
+        "PUT /{db}/_local/{docid}",
+        request,
+        201,
+        any,
+    )
+    .await;
+
+    let request = c.client.get(c.db(&checkpoint));
+    c.check(
+        "read checkpoint",
+        "GET /{db}/_local/{docid}",
+        request,
+        200,
+        any,
+    )
+    .await;
+
+    let request = c.client.post(c.db("/_ensure_full_commit"));
+    c.check(
+        "ensure full commit",
+        "POST /{db}/_ensure_full_commit",
+        request,
+        201,
+        has("ok"),
+    )
+    .await;
+    Some(())
+}
+
+/// Runs the `smoke` subcommand against a running server and returns the process exit code.
+pub fn run_smoke(args: &SmokeArgs) -> i32 {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("unable to start the runtime");
+
+    let outcomes = match runtime.block_on(smoke(args)) {
+        Ok(outcomes) => outcomes,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    let failures = outcomes.iter().filter(|o| o.status == "failed").count();
+    if args.json {
+        println!(
+            "{}",
+            json!({
+                "passed": outcomes.iter().filter(|o| o.status == "passed").count(),
+                "failed": failures,
+                "checks": outcomes,
+            })
+        );
+    } else {
+        for o in &outcomes {
+            let status = match o.status {
+                "passed" => "ok  ",
+                "failed" => "FAIL",
+                _ => "skip",
+            };
+            println!(
+                "{} {} {} ({}) {}",
+                status, o.client, o.check, o.endpoint, o.detail
+            );
+        }
+    }
+
+    i32::from(failures > 0)
+}
+
+async fn smoke(args: &SmokeArgs) -> Result<Vec<Outcome>, String> {
+    let client = Client::builder()
+        .default_headers(parse_headers(&args.headers)?)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let url = args.url.trim_end_matches('/').to_string();
+
+    let report: Value = send(client.get(format!("{}/_couchapi/compat", url)), 200)
+        .await
+        .map_err(|e| format!("unable to fetch the compatibility report: {}", e))?;
+    let support = report["endpoints"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|e| {
+            Some((
+                e["endpoint"].as_str()?.to_string(),
+                serde_json::from_value(e["support"].clone()).ok()?,
+            ))
+        })
+        .collect();
+
+    let mut checker = Checker {
+        client,
+        url,
+        db: args.db.clone(),
+        support,
+        current: "",
+        outcomes: vec![],
+    };
+
+    let clients = match args.clients.is_empty() {
+        true => vec![
+            CouchClient::Pouchdb,
+            CouchClient::Pycouchdb,
+            CouchClient::Nano,
+        ],
+        false => args.clients.clone(),
+    };
+    for client in clients {
+        checker.current = client.name();
+        match client {
+            CouchClient::Pouchdb => pouchdb(&mut checker).await,
+            CouchClient::Pycouchdb => pycouchdb(&mut checker).await,
+            CouchClient::Nano => nano(&mut checker).await,
+        };
+    }
+
+    Ok(checker.outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat_report::endpoints;
+    use crate::db::MockDatabase;
+    use crate::state::AppState;
+    use httpmock::prelude::*;
+
+    fn state(replication_target: bool) -> AppState {
+        AppState {
+            replication_target,
+            ..AppState::for_tests(MockDatabase::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pouchdb_skips_what_isnt_served() {
+        let server = MockServer::start();
+        let report = json!({
+            "endpoints": endpoints(&state(false)),
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/_couchapi/compat");
+            then.status(200).json_body(report);
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/orders");
+            then.status(200).json_body(json!({"update_seq": "0"}));
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/_session");
+            then.status(200).json_body(json!({"userCtx": {}}));
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/orders/_changes");
+            then.status(200).json_body(json!({"results": []}));
+        });
+
+        let args = SmokeArgs {
+            url: server.base_url(),
+            db: "orders".to_string(),
+            clients: vec![CouchClient::Pouchdb],
+            json: true,
+            headers: vec![],
+        };
+        let outcomes = smoke(&args).await.unwrap();
+        let statuses: Vec<_> = outcomes.iter().map(|o| (o.check, o.status)).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("database info", "passed"),
+                ("session", "passed"),
+                ("changes", "passed"),
+                ("revs diff", "skipped"),
+                ("write checkpoint", "skipped"),
+                ("read checkpoint", "skipped"),
+                ("ensure full commit", "skipped"),
+            ]
+        );
+
+        let server = MockServer::start();
+        let report = json!({
+            "endpoints": endpoints(&state(true)),
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/_couchapi/compat");
+            then.status(200).json_body(report);
+        });
+        server.mock(|when, then| {
+            when.path_contains("/orders");
+            then.status(200)
+                .json_body(json!({"update_seq": "0", "results": [], "ok": true}));
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/_session");
+            then.status(200).json_body(json!({"userCtx": {}}));
+        });
+
+        let args = SmokeArgs {
+            url: server.base_url(),
+            ..args
+        };
+        let outcomes = smoke(&args).await.unwrap();
+        // The catch-all mock answers writes with a 200 rather than a 201.
+        let statuses: Vec<_> = outcomes.iter().map(|o| o.status).collect();
+        assert_eq!(
+            statuses,
+            vec!["passed", "passed", "passed", "passed", "failed", "passed", "failed"]
+        );
+    }
+}
@@ -12,13 +12,207 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::config::{CouchDb, DesignMapping};
+use crate::active_tasks::ActiveTasks;
+use crate::compat::Compatibility;
+use crate::config::{
+    ApiKey,
+    CouchDb,
+    CouchHttpdAuth,
+    DatabaseFeatures,
+    DesignMapping,
+    GeoIndexes,
+    IdempotencySettings,
+    RequestSigning,
+    SearchIndexes,
+    SecurityObject,
+    WriteTransforms,
+};
 use crate::db::Database;
+use crate::maintenance::Maintenance;
+use crate::ops::admin::ReloadedViews;
+use crate::ops::query_server::QueryServer;
+use crate::ops::schema::Schema;
+use crate::ops::script_cache::ScriptCache;
+use crate::ops::script_engine::ScriptEngine;
+use crate::ops::uuids::UuidGenerator;
+use crate::rate_limit::RateLimiter;
+use crate::view_limit::ViewLimits;
+use mongodb::options::{ReadConcern, WriteConcern};
 use std::collections::HashMap;
 
 pub struct AppState {
     pub db: Box<dyn Database + Send + Sync>,
     pub views: Option<HashMap<String, DesignMapping>>,
+    pub reloaded_views: ReloadedViews,
+    pub view_folder: Option<String>,
+    pub search_indexes: Option<SearchIndexes>,
+    pub geo_indexes: Option<GeoIndexes>,
     pub updates_folder: Option<String>,
     pub couchdb_details: Option<CouchDb>,
+    pub default_read_concern: Option<ReadConcern>,
+    pub default_write_concern: Option<WriteConcern>,
+    pub api_keys: Option<Vec<ApiKey>>,
+    pub security: Option<HashMap<String, SecurityObject>>,
+    pub open_admin_routes: bool,
+    pub replication_target: bool,
+    pub rate_limiter: Option<RateLimiter>,
+    pub couch_httpd_auth: Option<CouchHttpdAuth>,
+    pub client_cert_auth: bool,
+    pub request_signing: Option<RequestSigning>,
+    pub features: Option<HashMap<String, DatabaseFeatures>>,
+    pub default_limit: Option<i64>,
+    pub max_limit: Option<i64>,
+    pub bulk_concurrency: Option<usize>,
+    pub script_cache: ScriptCache,
+    pub script_engine: Box<dyn ScriptEngine>,
+    pub query_server: Option<QueryServer>,
+    pub uuids: UuidGenerator,
+    pub view_limits: Option<ViewLimits>,
+    pub compatibility: Option<Compatibility>,
+    pub active_tasks: ActiveTasks,
+    pub maintenance: Maintenance,
+    pub idempotency: IdempotencySettings,
+    pub schemas: Option<HashMap<String, Schema>>,
+    pub write_transforms: Option<HashMap<String, WriteTransforms>>,
+}
+
+impl AppState {
+    /// Returns the endpoints enabled for a database, falling back to the `*` entry and then to
+    /// everything enabled.
+    pub fn features_for(&self, db: &str) -> DatabaseFeatures {
+        self.features
+            .as_ref()
+            .and_then(|f| f.get(db).or_else(|| f.get("*")))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The `features` reported at `/`: CouchDB's names for what's supported, followed by this
+    /// server's own extensions, prefixed `couchapi:`, each listed only when it's enabled.
+    pub fn server_features(&self) -> Vec<&'static str> {
+        let any_database = |enabled: fn(&DatabaseFeatures) -> bool| match &self.features {
+            Some(features) => features.values().any(enabled),
+            None => enabled(&DatabaseFeatures::default()),
+        };
+        let couchdb = self.couchdb_details.as_ref();
+        let reads_through = couchdb.is_some_and(|c| {
+            c.read_through
+                || c.read_through_databases
+                    .as_ref()
+                    .is_some_and(|d| !d.is_empty())
+        });
+
+        [
+            ("changes", any_database(|f| f.changes_feed) || reads_through),
+            ("search", self.search_indexes.is_some()),
+            ("replication_target", self.replication_target),
+            ("couchapi:bulk_docs_stream", true),
+            ("couchapi:idempotency_keys", true),
+            ("couchapi:maintenance", true),
+            ("couchapi:changes_feed", any_database(|f| f.changes_feed)),
+            (
+                "couchapi:changes_websocket",
+                any_database(|f| f.changes_websocket),
+            ),
+            (
+                "couchapi:break_glass_scripts",
+                any_database(|f| f.break_glass_scripts),
+            ),
+            ("couchapi:geo", self.geo_indexes.is_some()),
+            ("couchapi:query_server", self.query_server.is_some()),
+            ("couchapi:schemas", self.schemas.is_some()),
+            ("couchapi:write_transforms", self.write_transforms.is_some()),
+            ("couchapi:read_through", couchdb.is_some()),
+            (
+                "couchapi:stale_fallback",
+                couchdb.is_some_and(|c| c.stale_fallback),
+            ),
+            (
+                "couchapi:soft_launch",
+                couchdb.is_some_and(|c| c.soft_launch.is_some()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(feature, enabled)| enabled.then_some(feature))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    /// A state with nothing configured, for tests to set what they need on with struct update
+    /// syntax.
+    pub fn for_tests(db: impl Database + Send + Sync + 'static) -> Self {
+        AppState {
+            db: Box::new(db),
+            views: None,
+            reloaded_views: Default::default(),
+            view_folder: None,
+            search_indexes: None,
+            geo_indexes: None,
+            updates_folder: None,
+            couchdb_details: None,
+            default_read_concern: None,
+            default_write_concern: None,
+            api_keys: None,
+            security: None,
+            open_admin_routes: false,
+            replication_target: false,
+            rate_limiter: None,
+            couch_httpd_auth: None,
+            client_cert_auth: false,
+            request_signing: None,
+            features: None,
+            default_limit: None,
+            max_limit: None,
+            bulk_concurrency: None,
+            script_cache: Default::default(),
+            script_engine: Default::default(),
+            query_server: None,
+            uuids: Default::default(),
+            view_limits: None,
+            compatibility: None,
+            active_tasks: Default::default(),
+            maintenance: Default::default(),
+            idempotency: Default::default(),
+            schemas: None,
+            write_transforms: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+
+    #[test]
+    fn test_server_features() {
+        let mut state = AppState::for_tests(MockDatabase::new());
+
+        assert_eq!(
+            state.server_features(),
+            vec![
+                "couchapi:bulk_docs_stream",
+                "couchapi:idempotency_keys",
+                "couchapi:maintenance",
+                "couchapi:break_glass_scripts",
+            ]
+        );
+
+        state.replication_target = true;
+        state.features = Some(HashMap::from([(
+            "orders".to_string(),
+            DatabaseFeatures {
+                changes_feed: true,
+                ..Default::default()
+            },
+        )]));
+        let features = state.server_features();
+        assert!(features.contains(&"replication_target"));
+        assert!(features.contains(&"changes"));
+        assert!(features.contains(&"couchapi:changes_feed"));
+        assert!(!features.contains(&"couchapi:changes_websocket"));
+        assert!(!features.contains(&"partitioned"));
+    }
 }
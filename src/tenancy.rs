@@ -0,0 +1,428 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::auth::forbidden;
+use crate::config::TenancySettings;
+use crate::db::{ChangeStream, Database};
+use crate::ops::JsonWithStatusCodeResponse;
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Json;
+use bson::{Document, RawDocumentBuf};
+use futures_util::StreamExt;
+use mongodb::error::Error;
+use mongodb::options::{AggregateOptions, DeleteOptions, FindOneOptions, ReplaceOptions};
+use mongodb::results::UpdateResult;
+use serde_json::json;
+use std::future::Future;
+use std::sync::Arc;
+use tracing::warn;
+
+tokio::task_local! {
+    /// The tenant of the API key behind the request being handled.
+    static TENANT: String;
+}
+
+/// The tenant of the user behind a request, left on it by whichever of `check_api_key`,
+/// `require_server_admin`, `add_session_user` or `check_request_signature` recognised them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tenant(pub String);
+
+/// Makes the tenant of the user behind the request the one whose collections it reaches. Runs
+/// after the user has been recognised. With tenancy configured, requests without a tenant are
+/// refused, as they'd otherwise reach any tenant's collections by their full names. A tenant's
+/// database names can't hold the separator, unless `databases` names their collection outright,
+/// so no two tenants' collections can share a name.
+pub async fn scope_tenant(
+    State(settings): State<Option<Arc<TenancySettings>>>,
+    path: Option<Path<(String,)>>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, JsonWithStatusCodeResponse> {
+    let Some(settings) = settings else {
+        return Ok(next.run(req).await);
+    };
+
+    let Some(Tenant(tenant)) = req.extensions().get::<Tenant>().cloned() else {
+        warn!(path = req.uri().path(), "request without a tenant refused");
+        return Err(forbidden("You don't belong to a tenant."));
+    };
+
+    let db = path.map(|Path((db,))| db).unwrap_or_default();
+    let named = settings
+        .databases
+        .get(&tenant)
+        .is_some_and(|d| d.contains_key(&db));
+    if !named && db.contains(&settings.separator) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "illegal_database_name",
+                "reason": format!(
+                    "Name: '{}'. Database names can't contain the tenant separator '{}'.",
+                    db, settings.separator
+                ),
+            })),
+        ));
+    }
+
+    Ok(TENANT.scope(tenant, next.run(req)).await)
+}
+
+/// The tenant of the request being handled, to carry into work done off its task.
+pub fn current_tenant() -> Option<String> {
+    TENANT.try_with(Clone::clone).ok()
+}
+
+/// Runs `f` as the tenant's, or as no tenant's when there's none.
+pub async fn with_tenant<F: Future>(tenant: Option<String>, f: F) -> F::Output {
+    match tenant {
+        Some(tenant) => TENANT.scope(tenant, f).await,
+        None => f.await,
+    }
+}
+
+/// Checks the tenants of the API keys, session users and signing services can't be confused with
+/// one another: each has to be named, and without the separator, so a collection's prefix says
+/// whose it is.
+pub fn validate_tenants<'a>(
+    settings: &TenancySettings,
+    tenants: impl IntoIterator<Item = &'a str>,
+) -> Result<(), String> {
+    if settings.separator.is_empty() {
+        return Err("the tenancy separator can't be empty".to_string());
+    }
+
+    for tenant in tenants {
+        if tenant.is_empty() || tenant.contains(&settings.separator) {
+            return Err(format!(
+                "tenant '{}' must be named without the separator '{}'",
+                tenant, settings.separator
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends each request to its tenant's collections. Work done outside of a request uses
+/// collections as they're named.
+pub struct TenantDatabase {
+    inner: Box<dyn Database + Send + Sync>,
+    settings: TenancySettings,
+}
+
+impl TenantDatabase {
+    pub fn new(inner: Box<dyn Database + Send + Sync>, settings: TenancySettings) -> Self {
+        TenantDatabase { inner, settings }
+    }
+
+    fn collection(&self, coll: &str) -> String {
+        TENANT
+            .try_with(|tenant| collection_for(&self.settings, tenant, coll))
+            .unwrap_or_else(|_| coll.to_string())
+    }
+}
+
+fn collection_for(settings: &TenancySettings, tenant: &str, db: &str) -> String {
+    match settings.databases.get(tenant).and_then(|d| d.get(db)) {
+        Some(collection) => collection.clone(),
+        None => format!("{}{}{}", tenant, settings.separator, db),
+    }
+}
+
+/// The tenant's database a collection holds, if it's one of theirs: the reverse of
+/// `collection_for`.
+fn db_for(settings: &TenancySettings, tenant: &str, coll: &str) -> Option<String> {
+    let databases = settings.databases.get(tenant);
+    if let Some((db, _)) = databases.and_then(|d| d.iter().find(|(_, c)| *c == coll)) {
+        return Some(db.clone());
+    }
+
+    let db = coll
+        .strip_prefix(tenant)?
+        .strip_prefix(settings.separator.as_str())?;
+    let named = databases.is_some_and(|d| d.contains_key(db));
+    (!db.is_empty() && !db.contains(&settings.separator) && !named).then(|| db.to_string())
+}
+
+/// Keeps the events for the tenant's own collections, named by the database they hold.
+fn tenant_event(settings: &TenancySettings, tenant: &str, mut event: Document) -> Option<Document> {
+    let ns = event.get_document_mut("ns").ok()?;
+    let db = db_for(settings, tenant, ns.get_str("coll").ok()?)?;
+    ns.insert("coll", db);
+    Some(event)
+}
+
+#[async_trait]
+impl Database for TenantDatabase {
+    async fn get_version(&self) -> Result<Document, Error> {
+        self.inner.get_version().await
+    }
+
+    async fn find_one(
+        &self,
+        coll: &str,
+        id: &str,
+        options: FindOneOptions,
+    ) -> Result<Option<Document>, Error> {
+        self.inner
+            .find_one(&self.collection(coll), id, options)
+            .await
+    }
+
+    async fn replace_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        replacement: Document,
+        options: ReplaceOptions,
+    ) -> Result<UpdateResult, Error> {
+        self.inner
+            .replace_one(&self.collection(coll), filter, replacement, options)
+            .await
+    }
+
+    async fn delete_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        options: DeleteOptions,
+    ) -> Result<u64, Error> {
+        self.inner
+            .delete_one(&self.collection(coll), filter, options)
+            .await
+    }
+
+    async fn aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+        options: AggregateOptions,
+    ) -> Result<Vec<RawDocumentBuf>, Error> {
+        self.inner
+            .aggregate(&self.collection(coll), pipeline, options)
+            .await
+    }
+
+    async fn explain_aggregate(
+        &self,
+        coll: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Document, Error> {
+        self.inner
+            .explain_aggregate(&self.collection(coll), pipeline)
+            .await
+    }
+
+    async fn count(&self, coll: &str) -> Result<u64, Error> {
+        self.inner.count(&self.collection(coll)).await
+    }
+
+    async fn collection_stats(&self, coll: &str) -> Result<Option<Document>, Error> {
+        self.inner.collection_stats(&self.collection(coll)).await
+    }
+
+    async fn watch(
+        &self,
+        coll: &str,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeStream, Error> {
+        self.inner.watch(&self.collection(coll), resume_after).await
+    }
+
+    async fn watch_database(&self, resume_after: Option<Document>) -> Result<ChangeStream, Error> {
+        let changes = self.inner.watch_database(resume_after).await?;
+        let Ok(tenant) = TENANT.try_with(Clone::clone) else {
+            return Ok(changes);
+        };
+
+        let settings = self.settings.clone();
+        Ok(changes
+            .filter_map(move |event| {
+                let event = match event {
+                    Ok(event) => tenant_event(&settings, &tenant, event).map(Ok),
+                    Err(e) => Some(Err(e)),
+                };
+                std::future::ready(event)
+            })
+            .boxed())
+    }
+
+    async fn create_ttl_index(&self, coll: &str, field: &str) -> Result<(), Error> {
+        self.inner
+            .create_ttl_index(&self.collection(coll), field)
+            .await
+    }
+
+    async fn update_many(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<u64, Error> {
+        self.inner
+            .update_many(&self.collection(coll), filter, update)
+            .await
+    }
+
+    async fn delete_many(&self, coll: &str, filter: Document) -> Result<u64, Error> {
+        self.inner.delete_many(&self.collection(coll), filter).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use axum::routing::get;
+    use axum::{middleware, Extension, Router};
+    use maplit::hashmap;
+    use tower::ServiceExt;
+
+    fn settings() -> TenancySettings {
+        TenancySettings {
+            separator: "_".to_string(),
+            databases: hashmap! {
+                "globex".to_string() => hashmap! {
+                    "orders".to_string() => "globex_orders_v2".to_string(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_collection_for() {
+        assert_eq!(collection_for(&settings(), "acme", "orders"), "acme_orders");
+        assert_eq!(
+            collection_for(&settings(), "globex", "orders"),
+            "globex_orders_v2"
+        );
+        assert_eq!(
+            collection_for(&settings(), "globex", "invoices"),
+            "globex_invoices"
+        );
+    }
+
+    #[test]
+    fn test_db_for() {
+        assert_eq!(
+            db_for(&settings(), "acme", "acme_orders").as_deref(),
+            Some("orders")
+        );
+        assert_eq!(
+            db_for(&settings(), "globex", "globex_orders_v2").as_deref(),
+            Some("orders")
+        );
+        // Another tenant's, a shared one, and the one `databases` replaced.
+        assert_eq!(db_for(&settings(), "acme", "globex_orders"), None);
+        assert_eq!(db_for(&settings(), "acme", "orders"), None);
+        assert_eq!(db_for(&settings(), "globex", "globex_orders"), None);
+    }
+
+    #[tokio::test]
+    async fn test_database_changes_are_the_tenants_own() {
+        let mut mock = MockDatabase::new();
+        mock.expect_watch_database().returning(|_| {
+            let events = ["acme_orders", "globex_orders_v2", "acme_invoices"]
+                .map(|coll| Ok(bson::doc! { "ns": { "db": "couchapi", "coll": coll } }));
+            Box::pin(async move { Ok(futures_util::stream::iter(events).boxed()) })
+        });
+        let db = TenantDatabase::new(Box::new(mock), settings());
+
+        let changes = TENANT.scope("acme".to_string(), db.watch_database(None));
+        let events = changes.await.unwrap().collect::<Vec<_>>().await;
+        let colls = events
+            .into_iter()
+            .map(|e| {
+                e.unwrap()
+                    .get_document("ns")
+                    .unwrap()
+                    .get_str("coll")
+                    .unwrap()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(colls, vec!["orders", "invoices"]);
+    }
+
+    #[tokio::test]
+    async fn test_requests_reach_their_tenants_collections() {
+        let mut mock = MockDatabase::new();
+        mock.expect_find_one().returning(|coll, _, _| {
+            let found = bson::doc! { "coll": coll };
+            Box::pin(async move { Ok(Some(found)) })
+        });
+        let db = TenantDatabase::new(Box::new(mock), settings());
+
+        let find = || db.find_one("orders", "a", FindOneOptions::default());
+        let coll = |found: Option<Document>| found.unwrap().get_str("coll").unwrap().to_string();
+
+        assert_eq!(
+            coll(TENANT.scope("acme".to_string(), find()).await.unwrap()),
+            "acme_orders"
+        );
+        assert_eq!(
+            coll(TENANT.scope("globex".to_string(), find()).await.unwrap()),
+            "globex_orders_v2"
+        );
+        assert_eq!(coll(find().await.unwrap()), "orders");
+    }
+
+    #[test]
+    fn test_validate_tenants() {
+        assert!(validate_tenants(&settings(), ["acme", "globex"]).is_ok());
+        assert!(validate_tenants(&settings(), ["acme_eu"]).is_err());
+        assert!(validate_tenants(&settings(), [""]).is_err());
+    }
+
+    /// The status of a request for `uri`, made by a user with `tenant`.
+    async fn status(tenant: Option<&str>, uri: &str) -> StatusCode {
+        let mut app = Router::new()
+            .route(
+                "/:db",
+                get(|| async { current_tenant().unwrap_or_default() }),
+            )
+            .layer(middleware::from_fn_with_state(
+                Some(Arc::new(settings())),
+                scope_tenant,
+            ));
+        if let Some(tenant) = tenant {
+            app = app.layer(Extension(Tenant(tenant.to_string())));
+        }
+
+        let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_tenants_database_names_cant_hold_the_separator() {
+        // `acme` with `eu_orders` would otherwise share `globex_eu_orders` with it.
+        assert_eq!(
+            status(Some("globex"), "/eu_orders").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(status(Some("globex"), "/orders").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_requests_without_a_tenant_are_refused() {
+        // Another tenant's collection, by its full name.
+        assert_eq!(status(None, "/acme_orders").await, StatusCode::FORBIDDEN);
+    }
+}
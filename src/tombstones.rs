@@ -0,0 +1,197 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::TombstoneSettings;
+use crate::db::Database;
+use crate::state::AppState;
+use bson::{doc, DateTime, Document};
+use mongodb::error::Error;
+use mongodb::options::AggregateOptions;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// When the purge first saw the tombstone. Retention is counted from then, as documents don't
+/// record when they were deleted.
+pub const DELETED_AT_FIELD: &str = "_couchapi_deleted_at";
+
+/// What one purge of a database reclaimed.
+#[derive(Debug, PartialEq, Default)]
+pub struct Purged {
+    pub documents: u64,
+    pub bytes: u64,
+}
+
+/// Stamps tombstones the purge hasn't seen before, then deletes those seen longer ago than the
+/// retention. Inline attachments go with their documents; deduplicated ones are released by
+/// `DedupedDatabase::delete_many` when `db` is wrapped in one.
+pub async fn purge(
+    db: &(dyn Database + Send + Sync),
+    coll: &str,
+    retention: Duration,
+    now: DateTime,
+) -> Result<Purged, Error> {
+    db.update_many(
+        coll,
+        doc! { "_deleted": true, DELETED_AT_FIELD: { "$exists": false } },
+        doc! { "$set": { DELETED_AT_FIELD: now } },
+    )
+    .await?;
+
+    let cutoff = now.timestamp_millis() - retention.as_millis() as i64;
+    let expired = doc! {
+        "_deleted": true,
+        DELETED_AT_FIELD: { "$lte": DateTime::from_millis(cutoff) },
+    };
+
+    // Sized before deleting, for the metrics
+    let size = db
+        .aggregate(
+            coll,
+            vec![
+                doc! { "$match": expired.clone() },
+                doc! { "$group": { "_id": null, "bytes": { "$sum": { "$bsonSize": "$$ROOT" } } } },
+            ],
+            AggregateOptions::default(),
+        )
+        .await?;
+    let bytes = size
+        .first()
+        .and_then(|size| size.to_document().ok())
+        .and_then(|size| size.get("bytes").cloned())
+        .and_then(|bytes| bytes.as_i64().or_else(|| bytes.as_i32().map(i64::from)))
+        .unwrap_or(0);
+
+    let documents = db.delete_many(coll, expired).await?;
+    Ok(Purged {
+        documents,
+        bytes: bytes.max(0) as u64,
+    })
+}
+
+/// Purges each database's tombstones every `interval_ms`.
+pub fn spawn_purge(state: Arc<AppState>, settings: TombstoneSettings) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(settings.interval_ms.max(1)));
+
+        loop {
+            interval.tick().await;
+
+            for (coll, hours) in &settings.retention_hours {
+                let retention = Duration::from_secs(hours * 3600);
+                match purge(state.db.as_ref(), coll, retention, DateTime::now()).await {
+                    Ok(purged) => {
+                        let labels = [("database", coll.clone())];
+                        metrics::counter!(
+                            "couchapi_tombstones_purged_total",
+                            purged.documents,
+                            &labels
+                        );
+                        metrics::counter!(
+                            "couchapi_tombstone_bytes_reclaimed_total",
+                            purged.bytes,
+                            &labels
+                        );
+                        info!(
+                            database = coll,
+                            documents = purged.documents,
+                            bytes = purged.bytes,
+                            "purged tombstones"
+                        );
+                    }
+                    Err(e) => warn!(
+                        database = coll,
+                        error = e.to_string(),
+                        "unable to purge tombstones"
+                    ),
+                }
+            }
+        }
+    });
+}
+
+/// Leaves out the purge's bookkeeping when a tombstone is shown.
+pub fn strip(document: &mut Document) {
+    document.remove(DELETED_AT_FIELD);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MockDatabase;
+    use bson::RawDocumentBuf;
+
+    #[tokio::test]
+    async fn test_purge() {
+        let now = DateTime::from_millis(10 * 86_400_000);
+        let cutoff = DateTime::from_millis(3 * 86_400_000);
+
+        let mut mock = MockDatabase::new();
+        mock.expect_update_many()
+            .times(1)
+            .returning(move |coll, filter, update| {
+                assert_eq!(coll, "orders");
+                assert_eq!(
+                    filter,
+                    doc! { "_deleted": true, DELETED_AT_FIELD: { "$exists": false } }
+                );
+                assert_eq!(update, doc! { "$set": { DELETED_AT_FIELD: now } });
+                Box::pin(async { Ok(4) })
+            });
+        let expired = doc! { "_deleted": true, DELETED_AT_FIELD: { "$lte": cutoff } };
+        let matched = expired.clone();
+        mock.expect_aggregate()
+            .times(1)
+            .returning(move |_, pipeline, _| {
+                assert_eq!(pipeline[0], doc! { "$match": matched.clone() });
+                let size =
+                    RawDocumentBuf::from_document(&doc! { "_id": null, "bytes": 2048 }).unwrap();
+                Box::pin(async move { Ok(vec![size]) })
+            });
+        mock.expect_delete_many()
+            .times(1)
+            .returning(move |_, filter| {
+                assert_eq!(filter, expired);
+                Box::pin(async { Ok(3) })
+            });
+
+        let purged = purge(&mock, "orders", Duration::from_secs(7 * 86_400), now)
+            .await
+            .unwrap();
+        assert_eq!(
+            purged,
+            Purged {
+                documents: 3,
+                bytes: 2048
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_nothing_expired() {
+        let mut mock = MockDatabase::new();
+        mock.expect_update_many()
+            .returning(|_, _, _| Box::pin(async { Ok(0) }));
+        mock.expect_aggregate()
+            .returning(|_, _, _| Box::pin(async { Ok(vec![]) }));
+        mock.expect_delete_many()
+            .returning(|_, _| Box::pin(async { Ok(0) }));
+
+        let purged = purge(&mock, "orders", Duration::from_secs(3600), DateTime::now())
+            .await
+            .unwrap();
+        assert_eq!(purged, Purged::default());
+    }
+}
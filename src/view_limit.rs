@@ -0,0 +1,152 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::ViewConcurrencySettings;
+use crate::ops::JsonWithStatusCodeResponse;
+use axum::http::StatusCode;
+use axum::Json;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Caps how many view aggregations run at once on each database, so one database's expensive
+/// views can't take every MongoDB connection from the rest.
+pub struct ViewLimits {
+    settings: ViewConcurrencySettings,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl ViewLimits {
+    pub fn new(settings: ViewConcurrencySettings) -> Self {
+        ViewLimits {
+            settings,
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn semaphore(&self, db: &str) -> Option<Arc<Semaphore>> {
+        let limit = self
+            .settings
+            .databases
+            .get(db)
+            .copied()
+            .or(self.settings.max_per_database)?;
+
+        let mut semaphores = self.semaphores.lock().unwrap();
+        Some(
+            semaphores
+                .entry(db.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+                .clone(),
+        )
+    }
+
+    /// Waits up to `queue_ms` for one of the database's slots, held until the permit is dropped.
+    /// `None` when the database has no limit.
+    pub async fn acquire(
+        &self,
+        db: &str,
+    ) -> Result<Option<OwnedSemaphorePermit>, JsonWithStatusCodeResponse> {
+        let Some(semaphore) = self.semaphore(db) else {
+            return Ok(None);
+        };
+
+        let permit = match self.settings.queue_ms {
+            0 => semaphore.try_acquire_owned().ok(),
+            queue_ms => {
+                tokio::time::timeout(Duration::from_millis(queue_ms), semaphore.acquire_owned())
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+            }
+        };
+
+        match permit {
+            Some(permit) => Ok(Some(permit)),
+            None => {
+                metrics::increment_counter!("couchapi_views_shed_total", "db" => db.to_string());
+
+                Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(json!({
+                        "error": "service_unavailable",
+                        "reason": "Too many views are running on this database, try again shortly."
+                    })),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(queue_ms: u64) -> ViewLimits {
+        ViewLimits::new(ViewConcurrencySettings {
+            max_per_database: Some(1),
+            databases: HashMap::from([("reports".to_string(), 2)]),
+            queue_ms,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let limits = limits(0);
+
+        let orders = limits.acquire("orders").await.unwrap();
+        assert!(orders.is_some());
+        assert_eq!(
+            limits.acquire("orders").await.unwrap_err().0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // Other databases have slots of their own.
+        let first = limits.acquire("reports").await.unwrap();
+        let second = limits.acquire("reports").await.unwrap();
+        assert!(first.is_some() && second.is_some());
+        assert!(limits.acquire("reports").await.is_err());
+
+        drop(orders);
+        assert!(limits.acquire("orders").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_queues() {
+        let limits = Arc::new(limits(5000));
+        let held = limits.acquire("orders").await.unwrap();
+
+        let waiting = {
+            let limits = limits.clone();
+            tokio::spawn(async move { limits.acquire("orders").await.map(|p| p.is_some()) })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(held);
+
+        assert!(waiting.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unlimited() {
+        let limits = ViewLimits::new(ViewConcurrencySettings {
+            max_per_database: None,
+            databases: HashMap::new(),
+            queue_ms: 0,
+        });
+
+        assert!(limits.acquire("orders").await.unwrap().is_none());
+    }
+}
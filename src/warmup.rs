@@ -0,0 +1,139 @@
+// Copyright (c) 2024, Green Man Gaming Limited
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::WarmupSettings;
+use crate::ops::get::inner_get_view;
+use crate::state::AppState;
+use maplit::hashmap;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// Gets MongoDB ready for traffic before the server starts listening.
+pub async fn warm_up(client: &mongodb::Client, state: &AppState, settings: &WarmupSettings) {
+    let started = Instant::now();
+    client.warm_connection_pool().await;
+    info!(
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "warmed the mongodb connection pool"
+    );
+
+    if settings.views {
+        let started = Instant::now();
+        let (warmed, failed) = warm_views(state).await;
+        info!(
+            warmed,
+            failed,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "warmed the views"
+        );
+    }
+}
+
+/// Runs every configured view once with `limit=1`, so MongoDB has planned each before a request
+/// needs it. Returns how many ran and how many failed; a failure only costs the view its warmup.
+async fn warm_views(state: &AppState) -> (usize, usize) {
+    let (mut warmed, mut failed) = (0, 0);
+
+    for (db, mapping) in state.views.iter().flatten() {
+        for (design, views) in &mapping.view_groups {
+            for (view, v) in views {
+                let params = hashmap! { "limit".to_string() => "1".to_string() };
+
+                match inner_get_view(v, db.clone(), design, view, state, params).await {
+                    Ok(_) => warmed += 1,
+                    Err((status, body)) => {
+                        warn!(
+                            db,
+                            design,
+                            view,
+                            status = status.as_u16(),
+                            error = body.0.to_string(),
+                            "unable to warm view"
+                        );
+                        failed += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    (warmed, failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DesignMapping, DesignView};
+    use crate::db::MockDatabase;
+    use bson::{doc, RawDocumentBuf};
+    use mongodb::error::{Error, ErrorKind};
+    use std::collections::HashMap;
+
+    fn view(key_field: &str) -> DesignView {
+        DesignView {
+            match_fields: vec![],
+            sort_fields: None,
+            aggregation: vec![],
+            key_fields: vec![key_field.to_string()],
+            value_fields: vec![],
+            filter_insert_index: 0,
+            reduce: None,
+            single_item_key_is_list: false,
+            single_item_value_is_dict: false,
+            break_glass_js_script: None,
+            omit_null_keys_in_value: false,
+            doc_fields: vec![],
+            value_field_names: Default::default(),
+            omit_id: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warm_views() {
+        let mut mock = MockDatabase::new();
+        mock.expect_aggregate()
+            .times(2)
+            .returning(|coll, pipeline, _| {
+                // Every view is asked for a single row.
+                assert!(pipeline.contains(&doc! { "$limit": 1i64 }));
+
+                let result = match coll {
+                    "orders" => Ok(vec![RawDocumentBuf::from_document(
+                        &doc! { "_id": "a", "n": 1 },
+                    )
+                    .unwrap()]),
+                    _ => Err(Error::from(ErrorKind::Custom(std::sync::Arc::new("down")))),
+                };
+                Box::pin(async move { result })
+            });
+        mock.expect_count().returning(|_| Box::pin(async { Ok(1) }));
+
+        let mapping = |view_name: &str| DesignMapping {
+            view_groups: HashMap::from([(
+                "design".to_string(),
+                HashMap::from([(view_name.to_string(), view("n"))]),
+            )]),
+        };
+
+        let state = AppState {
+            views: Some(HashMap::from([
+                ("orders".to_string(), mapping("by_n")),
+                ("broken".to_string(), mapping("by_n")),
+            ])),
+            ..AppState::for_tests(mock)
+        };
+
+        assert_eq!(warm_views(&state).await, (1, 1));
+    }
+}